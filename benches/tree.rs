@@ -0,0 +1,286 @@
+//! Throughput of the basic operations on each storage, as operations per
+//! second, with the page reads and writes each one takes printed alongside.
+//!
+//! Run with `cargo bench`; `cargo bench -- file` limits it to `FileStorage`,
+//! and `cargo bench -- bulk_load` to building trees from sorted records.
+
+use std::hint::black_box;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use btree::{BPlusTree, BTreeError, Durability, FileStorage, InMemoryStorage, Node, Storage};
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+
+/// Operations per benchmark iteration.
+const OPS: usize = 1000;
+/// Records in the tree that random inserts, lookups and scans run against,
+/// under the even keys from 0 so random inserts can take the odd ones.
+const PRELOADED: i32 = 5_000;
+/// Records each range scan returns.
+const SCAN_LEN: i32 = 100;
+
+/// A storage to benchmark, made fresh for each tree.
+trait Backend {
+    type Storage: Storage;
+    const NAME: &'static str;
+    fn create() -> Self::Storage;
+}
+
+struct Memory;
+
+impl Backend for Memory {
+    type Storage = InMemoryStorage;
+    const NAME: &'static str = "memory";
+
+    fn create() -> InMemoryStorage {
+        InMemoryStorage::new()
+    }
+}
+
+struct File;
+
+impl Backend for File {
+    type Storage = FileStorage;
+    const NAME: &'static str = "file";
+
+    /// Never syncs, so the numbers show the tree and the page format rather
+    /// than the disk's flush latency. The file is unlinked as soon as it is
+    /// open, so its space goes back once the storage is dropped.
+    fn create() -> FileStorage {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "btree-bench-{}-{}.db",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let storage =
+            FileStorage::with_durability(path.to_str().unwrap(), Durability::NoSync).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        storage
+    }
+}
+
+/// A file that takes its pages one by one, as every storage did before
+/// [`Storage::write_nodes`], to compare bulk loads against.
+struct PageByPage;
+
+impl Backend for PageByPage {
+    type Storage = OnePage;
+    const NAME: &'static str = "file_page_by_page";
+
+    fn create() -> OnePage {
+        OnePage(File::create())
+    }
+}
+
+/// Forwards everything but [`Storage::write_nodes`], which is left to the
+/// default that writes each page on its own.
+struct OnePage(FileStorage);
+
+impl Storage for OnePage {
+    fn read_node(&mut self, loc: usize) -> Result<Node, BTreeError> {
+        self.0.read_node(loc)
+    }
+
+    fn read_nodes(&mut self, locs: Range<usize>) -> Result<Vec<Node>, BTreeError> {
+        self.0.read_nodes(locs)
+    }
+
+    fn write_node(&mut self, loc: usize, node: &Node) -> Result<(), BTreeError> {
+        self.0.write_node(loc, node)
+    }
+
+    fn flush(&mut self) -> Result<(), BTreeError> {
+        self.0.flush()
+    }
+
+    fn sync(&mut self) -> Result<(), BTreeError> {
+        Storage::sync(&mut self.0)
+    }
+
+    fn write_atomic(&mut self, pages: &[(usize, Node)]) -> Result<(), BTreeError> {
+        self.0.write_atomic(pages)
+    }
+
+    fn total_nodes(&self) -> Result<usize, BTreeError> {
+        self.0.total_nodes()
+    }
+
+    fn truncate(&mut self, pages: usize) -> Result<(), BTreeError> {
+        self.0.truncate(pages)
+    }
+
+    fn page_capacity(&self) -> usize {
+        self.0.page_capacity()
+    }
+
+    fn page_size(&self) -> usize {
+        self.0.page_size()
+    }
+
+    fn codec(&self) -> &'static str {
+        self.0.codec()
+    }
+
+    fn io_counters(&self) -> (usize, usize) {
+        self.0.io_counters()
+    }
+
+    fn trailing_bytes(&self) -> Result<usize, BTreeError> {
+        self.0.trailing_bytes()
+    }
+}
+
+/// `OPS` indexes into the preloaded records, the same on every run.
+fn random_keys(seed: u64) -> Vec<i32> {
+    let mut state = seed;
+    (0..OPS)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((state >> 33) % PRELOADED as u64) as i32
+        })
+        .collect()
+}
+
+fn preloaded_into<S: Storage>(storage: S) -> BPlusTree<S> {
+    BPlusTree::bulk_load(storage, (0..PRELOADED).map(|i| (i * 2, [i * 2; 7]))).unwrap()
+}
+
+fn preloaded<B: Backend>() -> BPlusTree<B::Storage> {
+    preloaded_into(B::create())
+}
+
+/// Runs `op` once over `tree` to print the page I/O of each
+/// operation, which criterion cannot report itself.
+fn report_io<S: Storage>(
+    name: &str,
+    mut tree: BPlusTree<S>,
+    mut op: impl FnMut(&mut BPlusTree<S>, usize),
+) {
+    let (reads, writes) = tree.storage.io_counters();
+    for i in 0..OPS {
+        op(&mut tree, i);
+    }
+    let (after_reads, after_writes) = tree.storage.io_counters();
+    println!(
+        "{}: {:.2} page reads, {:.2} page writes per op",
+        name,
+        (after_reads - reads) as f64 / OPS as f64,
+        (after_writes - writes) as f64 / OPS as f64
+    );
+}
+
+fn bench_backend<B: Backend>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(B::NAME);
+    group.throughput(Throughput::Elements(OPS as u64));
+    group.measurement_time(Duration::from_secs(10));
+    group.sample_size(10);
+    let keys = random_keys(42);
+
+    let sequential = |tree: &mut BPlusTree<B::Storage>, i: usize| {
+        tree.insert(i as i32, [i as i32; 7]).unwrap();
+    };
+    report_io(
+        &format!("{}/sequential_insert", B::NAME),
+        BPlusTree::open(B::create()).unwrap(),
+        sequential,
+    );
+    group.bench_function("sequential_insert", |b| {
+        b.iter_batched(
+            || BPlusTree::open(B::create()).unwrap(),
+            |mut tree| {
+                for i in 0..OPS {
+                    sequential(&mut tree, i);
+                }
+                tree
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    let random = |tree: &mut BPlusTree<B::Storage>, i: usize| {
+        let key = keys[i] * 2 + 1;
+        tree.insert(key, [key; 7]).unwrap();
+    };
+    report_io(
+        &format!("{}/random_insert", B::NAME),
+        preloaded::<B>(),
+        random,
+    );
+    group.bench_function("random_insert", |b| {
+        b.iter_batched(
+            preloaded::<B>,
+            |mut tree| {
+                for i in 0..OPS {
+                    random(&mut tree, i);
+                }
+                tree
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    let lookup = |tree: &mut BPlusTree<B::Storage>, i: usize| {
+        black_box(tree.get(&(keys[i] * 2)).unwrap());
+    };
+    report_io(
+        &format!("{}/point_lookup", B::NAME),
+        preloaded::<B>(),
+        lookup,
+    );
+    let mut tree = preloaded::<B>();
+    group.bench_function("point_lookup", |b| {
+        b.iter(|| {
+            for i in 0..OPS {
+                lookup(&mut tree, i);
+            }
+        })
+    });
+
+    let scan = |tree: &mut BPlusTree<B::Storage>, i: usize| {
+        let lo = keys[i].min(PRELOADED - SCAN_LEN) * 2;
+        black_box(tree.range(&lo, &(lo + SCAN_LEN * 2 - 1)).unwrap());
+    };
+    report_io(&format!("{}/range_scan", B::NAME), preloaded::<B>(), scan);
+    group.bench_function("range_scan", |b| {
+        b.iter(|| {
+            for i in 0..OPS {
+                scan(&mut tree, i);
+            }
+        })
+    });
+
+    group.finish();
+}
+
+/// Builds a tree of the preloaded records from scratch, reporting records
+/// per second.
+fn bench_bulk_load<B: Backend>(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_load");
+    group.throughput(Throughput::Elements(PRELOADED as u64));
+    group.measurement_time(Duration::from_secs(10));
+    group.sample_size(10);
+
+    let tree = preloaded::<B>();
+    let (_, writes) = tree.storage.io_counters();
+    println!("bulk_load/{}: {} page writes", B::NAME, writes);
+    drop(tree);
+    group.bench_function(B::NAME, |b| {
+        b.iter_batched(B::create, preloaded_into, BatchSize::PerIteration)
+    });
+    group.finish();
+}
+
+fn benches(c: &mut Criterion) {
+    bench_backend::<Memory>(c);
+    bench_backend::<File>(c);
+    bench_bulk_load::<Memory>(c);
+    bench_bulk_load::<File>(c);
+    bench_bulk_load::<PageByPage>(c);
+}
+
+criterion_group!(tree_benches, benches);
+criterion_main!(tree_benches);
@@ -0,0 +1,119 @@
+/// Decides which page a newly created node is written to.
+pub trait Allocator {
+    /// Creates an allocator for a storage whose pages below `next` are in
+    /// use, apart from the previously released pages in `free`.
+    fn new(next: usize, free: Vec<usize>) -> Self
+    where
+        Self: Sized;
+    fn allocate(&mut self) -> usize;
+    fn free(&mut self, loc: usize);
+    /// Released pages not yet handed out again, persisted in the header.
+    fn free_pages(&self) -> &[usize] {
+        &[]
+    }
+}
+
+/// Always hands out the page past the end of the file; freed pages are leaked.
+/// A free list the file already has is kept and written back unchanged, so
+/// an allocator that reuses pages can still take it up later.
+pub struct BumpAllocator {
+    next: usize,
+    free: Vec<usize>,
+}
+
+impl Allocator for BumpAllocator {
+    fn new(next: usize, free: Vec<usize>) -> Self {
+        Self { next, free }
+    }
+
+    fn allocate(&mut self) -> usize {
+        let loc = self.next;
+        self.next += 1;
+        loc
+    }
+
+    fn free(&mut self, _loc: usize) {}
+
+    fn free_pages(&self) -> &[usize] {
+        &self.free
+    }
+}
+
+/// Reuses freed pages before growing the file.
+pub struct FreeListAllocator {
+    next: usize,
+    free: Vec<usize>,
+}
+
+impl Allocator for FreeListAllocator {
+    fn new(next: usize, free: Vec<usize>) -> Self {
+        Self { next, free }
+    }
+
+    fn allocate(&mut self) -> usize {
+        match self.free.pop() {
+            Some(loc) => loc,
+            None => {
+                let loc = self.next;
+                self.next += 1;
+                loc
+            }
+        }
+    }
+
+    fn free(&mut self, loc: usize) {
+        self.free.push(loc);
+    }
+
+    fn free_pages(&self) -> &[usize] {
+        &self.free
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::BPlusTree;
+    use crate::memory_storage::InMemoryStorage;
+
+    #[test]
+    fn a_bump_allocator_keeps_the_free_list_it_was_opened_with() {
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        for key in 0..200 {
+            tree.insert(key, [key; 7]).unwrap();
+        }
+        for key in 0..150 {
+            tree.delete(&key).unwrap();
+        }
+        let free = tree.free_pages().to_vec();
+        assert!(!free.is_empty());
+
+        let mut bump: BPlusTree<InMemoryStorage, i32, _, BumpAllocator> =
+            BPlusTree::open(tree.storage).unwrap();
+        for key in 200..260 {
+            bump.insert(key, [key; 7]).unwrap();
+        }
+        assert_eq!(bump.free_pages(), free);
+
+        // Reopened with page reuse, the listed pages are still free
+        let mut reuse: BPlusTree<InMemoryStorage> = BPlusTree::open(bump.storage).unwrap();
+        assert_eq!(reuse.free_pages(), free);
+        reuse.validate().unwrap();
+        for key in 260..400 {
+            reuse.insert(key, [key; 7]).unwrap();
+        }
+        reuse.validate().unwrap();
+        assert_eq!(reuse.len(), 50 + 60 + 140);
+    }
+
+    #[test]
+    fn a_free_list_allocator_hands_out_freed_pages_before_growing() {
+        let mut allocator = FreeListAllocator::new(10, vec![3]);
+        allocator.free(7);
+        assert_eq!(allocator.allocate(), 7);
+        assert_eq!(allocator.allocate(), 3);
+        assert_eq!(allocator.allocate(), 10);
+        assert_eq!(allocator.allocate(), 11);
+        assert!(allocator.free_pages().is_empty());
+    }
+}
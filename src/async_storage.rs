@@ -0,0 +1,105 @@
+use std::future::Future;
+
+use tokio::runtime::Handle;
+
+use crate::codec::BINCODE;
+use crate::config::PAGE_SIZE;
+use crate::error::BTreeError;
+use crate::node::Node;
+use crate::record::Record;
+use crate::storage::Storage;
+
+/// Page storage whose I/O is awaited rather than blocked on, for use with
+/// [`AsyncBPlusTree`](crate::async_tree::AsyncBPlusTree). Mirrors
+/// [`Storage`], without batched or atomic writes.
+pub trait AsyncStorage<K = i32, V = Record> {
+    /// Reads the node at `loc`, failing with [`BTreeError::Corrupt`] if the
+    /// page does not exist or does not decode.
+    fn read_node(
+        &mut self,
+        loc: usize,
+    ) -> impl Future<Output = Result<Node<K, V>, BTreeError>> + Send;
+    fn write_node(
+        &mut self,
+        loc: usize,
+        node: &Node<K, V>,
+    ) -> impl Future<Output = Result<(), BTreeError>> + Send;
+    /// Makes every write so far durable, with the header landing last.
+    fn flush(&mut self) -> impl Future<Output = Result<(), BTreeError>> + Send {
+        async { Ok(()) }
+    }
+    /// Number of whole pages; a partial trailing page is not counted.
+    fn total_nodes(&self) -> impl Future<Output = Result<usize, BTreeError>> + Send;
+    /// Drops every page from `pages` on. The default keeps them.
+    fn truncate(&mut self, pages: usize) -> impl Future<Output = Result<(), BTreeError>> + Send {
+        let _ = pages;
+        async { Ok(()) }
+    }
+    /// Bytes past the last whole page, left behind by an interrupted write.
+    fn trailing_bytes(&self) -> impl Future<Output = Result<usize, BTreeError>> + Send {
+        async { Ok(0) }
+    }
+    /// Bytes of each page available to an encoded node.
+    fn page_capacity(&self) -> usize {
+        PAGE_SIZE
+    }
+    /// Name of the [`PageCodec`](crate::codec::PageCodec) pages are encoded
+    /// with, as [`Storage::codec`].
+    fn codec(&self) -> &'static str {
+        BINCODE
+    }
+    /// Pages read from and written to the underlying medium so far, for
+    /// storages that count them.
+    fn io_counters(&self) -> (usize, usize) {
+        (0, 0)
+    }
+}
+
+/// Presents an [`AsyncStorage`] as a [`Storage`] by blocking on each call,
+/// so the tree code can run over it on one of the runtime's blocking
+/// threads. Calling it from inside an async task panics.
+pub(crate) struct Blocking<S> {
+    pub(crate) inner: S,
+    pub(crate) handle: Handle,
+}
+
+impl<S, K, V> Storage<K, V> for Blocking<S>
+where
+    S: AsyncStorage<K, V>,
+{
+    fn read_node(&mut self, loc: usize) -> Result<Node<K, V>, BTreeError> {
+        self.handle.block_on(self.inner.read_node(loc))
+    }
+
+    fn write_node(&mut self, loc: usize, node: &Node<K, V>) -> Result<(), BTreeError> {
+        self.handle.block_on(self.inner.write_node(loc, node))
+    }
+
+    fn flush(&mut self) -> Result<(), BTreeError> {
+        self.handle.block_on(self.inner.flush())
+    }
+
+    fn total_nodes(&self) -> Result<usize, BTreeError> {
+        self.handle.block_on(self.inner.total_nodes())
+    }
+
+    fn truncate(&mut self, pages: usize) -> Result<(), BTreeError> {
+        self.handle.block_on(self.inner.truncate(pages))
+    }
+
+    fn page_capacity(&self) -> usize {
+        self.inner.page_capacity()
+    }
+
+    fn codec(&self) -> &'static str {
+        self.inner.codec()
+    }
+
+    fn io_counters(&self) -> (usize, usize) {
+        self.inner.io_counters()
+    }
+
+    fn trailing_bytes(&self) -> Result<usize, BTreeError> {
+        self.handle.block_on(self.inner.trailing_bytes())
+    }
+}
@@ -0,0 +1,141 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use tokio::runtime::Handle;
+use tokio::task;
+
+use crate::async_storage::{AsyncStorage, Blocking};
+use crate::btree::{BPlusTree, Upsert};
+use crate::error::BTreeError;
+use crate::key::Key;
+use crate::record::Record;
+use crate::value::Value;
+
+type Inner<S, K, V> = BPlusTree<Blocking<S>, K, V>;
+
+/// A tree over an [`AsyncStorage`] for use from async code. Each operation
+/// runs the regular tree code on tokio's blocking thread pool, awaiting the
+/// storage from there, so page I/O never stalls the runtime's workers.
+/// Operations are serialized; clones share the same tree.
+///
+/// Must be used from within a tokio runtime. Keys are taken by value, as
+/// the work happens on another thread.
+pub struct AsyncBPlusTree<S, K = i32, V = Record> {
+    tree: Arc<Mutex<Inner<S, K, V>>>,
+}
+
+impl<S, K, V> Clone for AsyncBPlusTree<S, K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            tree: Arc::clone(&self.tree),
+        }
+    }
+}
+
+impl<S, K, V> AsyncBPlusTree<S, K, V>
+where
+    S: AsyncStorage<K, V> + Send + 'static,
+    K: Key + Send + 'static,
+    V: Value + Send + 'static,
+{
+    /// Opens the tree held by `storage`, initialising an empty one if the
+    /// storage has no pages yet.
+    pub async fn open(storage: S) -> Result<Self, BTreeError> {
+        let storage = Blocking {
+            inner: storage,
+            handle: Handle::current(),
+        };
+        let tree = task::spawn_blocking(move || BPlusTree::open(storage))
+            .await
+            .map_err(Self::join_error)??;
+        Ok(Self {
+            tree: Arc::new(Mutex::new(tree)),
+        })
+    }
+
+    pub async fn get(&self, key: K) -> Result<Option<V>, BTreeError> {
+        self.run(move |tree| tree.get(&key)).await
+    }
+
+    pub async fn contains_key(&self, key: K) -> Result<bool, BTreeError> {
+        self.run(move |tree| tree.contains_key(&key)).await
+    }
+
+    /// Entries with keys between `lo` and `hi` inclusive, in key order.
+    pub async fn range(&self, lo: K, hi: K) -> Result<Vec<(K, V)>, BTreeError> {
+        self.run(move |tree| tree.range(&lo, &hi)).await
+    }
+
+    pub async fn first_key_value(&self) -> Result<Option<(K, V)>, BTreeError> {
+        self.run(|tree| tree.first_key_value()).await
+    }
+
+    pub async fn last_key_value(&self) -> Result<Option<(K, V)>, BTreeError> {
+        self.run(|tree| tree.last_key_value()).await
+    }
+
+    pub async fn len(&self) -> Result<usize, BTreeError> {
+        self.run(|tree| Ok(tree.len())).await
+    }
+
+    pub async fn is_empty(&self) -> Result<bool, BTreeError> {
+        self.run(|tree| Ok(tree.is_empty())).await
+    }
+
+    pub async fn insert(&self, key: K, value: V) -> Result<(), BTreeError> {
+        self.run(move |tree| tree.insert(key, value)).await
+    }
+
+    /// Inserts, telling whether the key was new; see [`BPlusTree::upsert`].
+    pub async fn upsert(&self, key: K, value: V) -> Result<Upsert<V>, BTreeError> {
+        self.run(move |tree| tree.upsert(key, value)).await
+    }
+
+    /// Inserts every entry in one pass; see [`BPlusTree::insert_batch`].
+    pub async fn insert_batch(&self, entries: Vec<(K, V)>) -> Result<(), BTreeError> {
+        self.run(move |tree| tree.insert_batch(entries)).await
+    }
+
+    pub async fn delete(&self, key: K) -> Result<Option<V>, BTreeError> {
+        self.run(move |tree| tree.delete(&key)).await
+    }
+
+    pub async fn pop_first(&self) -> Result<Option<(K, V)>, BTreeError> {
+        self.run(|tree| tree.pop_first()).await
+    }
+
+    pub async fn pop_last(&self) -> Result<Option<(K, V)>, BTreeError> {
+        self.run(|tree| tree.pop_last()).await
+    }
+
+    pub async fn flush(&self) -> Result<(), BTreeError> {
+        self.run(|tree| tree.flush()).await
+    }
+
+    /// Checks the tree invariants; see [`BPlusTree::validate`].
+    pub async fn validate(&self) -> Result<Result<(), String>, BTreeError> {
+        self.run(|tree| Ok(tree.validate())).await
+    }
+
+    /// Runs `op` on the tree from a blocking thread.
+    async fn run<T, F>(&self, op: F) -> Result<T, BTreeError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Inner<S, K, V>) -> Result<T, BTreeError> + Send + 'static,
+    {
+        let tree = Arc::clone(&self.tree);
+        task::spawn_blocking(move || {
+            let mut tree = tree
+                .lock()
+                .expect("an operation panicked while holding the tree");
+            op(&mut tree)
+        })
+        .await
+        .map_err(Self::join_error)?
+    }
+
+    /// A blocking task that panicked or was cancelled at shutdown.
+    fn join_error(err: task::JoinError) -> BTreeError {
+        BTreeError::Io(io::Error::other(err))
+    }
+}
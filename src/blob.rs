@@ -0,0 +1,67 @@
+use bincode::{Decode, Encode};
+
+use crate::record::SerializedSize;
+
+/// Longest value kept in the leaf itself; longer ones spill to overflow
+/// pages.
+pub const INLINE_LIMIT: usize = 1024;
+/// Bound on a blob's encoding besides its inline bytes: the variant tag and
+/// a length prefix, or the two fields of an overflow reference.
+const BLOB_OVERHEAD: usize = 1 + 2 * 9;
+
+/// A byte string value of any length. Short ones are stored in the leaf;
+/// longer ones in a chain of overflow pages the leaf points to. Trees of
+/// blobs are written and read through
+/// [`insert_bytes`](crate::BPlusTree::insert_bytes) and
+/// [`get_bytes`](crate::BPlusTree::get_bytes), which spill and gather the
+/// overflow pages; an `Overflow` blob made any other way points nowhere,
+/// as does one returned by a delete or overwrite, whose pages are freed.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum Blob {
+    Inline(Vec<u8>),
+    /// `len` bytes kept in overflow pages, starting at `page`.
+    Overflow {
+        page: usize,
+        len: usize,
+    },
+}
+
+impl Blob {
+    /// Length of the value in bytes, wherever it is kept.
+    pub fn len(&self) -> usize {
+        match self {
+            Blob::Inline(bytes) => bytes.len(),
+            Blob::Overflow { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl SerializedSize for Blob {
+    fn max_serialized_size() -> usize {
+        INLINE_LIMIT + BLOB_OVERHEAD
+    }
+
+    fn serialized_size(&self) -> usize {
+        match self {
+            Blob::Inline(bytes) => bytes.len() + BLOB_OVERHEAD,
+            Blob::Overflow { .. } => BLOB_OVERHEAD,
+        }
+    }
+
+    fn overflow(&self) -> Option<usize> {
+        match self {
+            Blob::Inline(_) => None,
+            Blob::Overflow { page, .. } => Some(*page),
+        }
+    }
+
+    fn set_overflow(&mut self, loc: usize) {
+        if let Blob::Overflow { page, .. } = self {
+            *page = loc;
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeSet;
+
+use crate::node::{BloomPage, MAX_BLOOM_PAGES};
+
+/// Bits per key a filter is sized for, for about 1% false positives.
+const BITS_PER_KEY: usize = 10;
+/// Bits set per key, the best count for [`BITS_PER_KEY`].
+pub const BLOOM_HASHES: u32 = 7;
+/// Keys a filter is sized for at the least, so a small tree can grow for a
+/// while before its filter fills up.
+const MIN_KEYS: usize = 4096;
+
+/// A Bloom filter over encoded keys, telling for certain when a key was
+/// never added. The bits are split into blocks, one per page, and all of a
+/// key's bits fall in the one block its hash picks, so adding a key changes
+/// a single page.
+pub(crate) struct BloomFilter {
+    blocks: Vec<Vec<u8>>,
+    hashes: u32,
+    /// Blocks changed since they were last taken by
+    /// [`take_dirty`](Self::take_dirty).
+    dirty: BTreeSet<usize>,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `keys` keys, in blocks of `block_bytes`.
+    pub fn with_capacity(keys: usize, block_bytes: usize) -> Self {
+        let bits = keys.max(MIN_KEYS) * BITS_PER_KEY;
+        let pages = bits.div_ceil(block_bytes * 8).clamp(1, MAX_BLOOM_PAGES);
+        Self {
+            blocks: vec![vec![0; block_bytes]; pages],
+            hashes: BLOOM_HASHES,
+            dirty: (0..pages).collect(),
+        }
+    }
+
+    /// The filter stored in `pages`.
+    pub fn from_pages(pages: Vec<BloomPage>, hashes: u32) -> Self {
+        Self {
+            blocks: pages.into_iter().map(|page| page.bits).collect(),
+            hashes,
+            dirty: BTreeSet::new(),
+        }
+    }
+
+    /// Number of blocks, and so of pages.
+    pub fn pages(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        let (block, bits) = self.probe(key);
+        for bit in bits {
+            self.blocks[block][bit / 8] |= 1 << (bit % 8);
+        }
+        self.dirty.insert(block);
+    }
+
+    /// `false` if `key` was certainly never inserted.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let (block, bits) = self.probe(key);
+        bits.into_iter()
+            .all(|bit| self.blocks[block][bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// The blocks changed since the last call, as pages to write with their
+    /// index.
+    pub fn take_dirty(&mut self) -> Vec<(usize, BloomPage)> {
+        std::mem::take(&mut self.dirty)
+            .into_iter()
+            .map(|i| {
+                let bits = self.blocks[i].clone();
+                (i, BloomPage { bits })
+            })
+            .collect()
+    }
+
+    /// The block `key` falls in and the bits it sets there, by double
+    /// hashing.
+    fn probe(&self, key: &[u8]) -> (usize, Vec<usize>) {
+        let hash = hash(key);
+        let block = (((hash >> 32) * self.blocks.len() as u64) >> 32) as usize;
+        let bits = self.blocks[block].len() * 8;
+        let (h1, h2) = (hash as u32 as usize, mix(hash) as u32 as usize | 1);
+        let probes = (0..self.hashes as usize)
+            .map(|i| h1.wrapping_add(i.wrapping_mul(h2)) % bits)
+            .collect();
+        (block, probes)
+    }
+}
+
+/// FNV-1a, finished with a mix so every bit depends on every input byte.
+/// Fixed rather than `std`'s hasher, as the bits it picks are stored.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+    }
+    mix(hash)
+}
+
+/// MurmurHash3's 64-bit finalizer.
+fn mix(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
@@ -1,390 +1,4935 @@
-use crate::config::MAX_KEYS;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use crate::allocator::{Allocator, FreeListAllocator};
+use crate::blob::{Blob, INLINE_LIMIT};
+use crate::bloom::BloomFilter;
+#[cfg(test)]
+use crate::config::TEST_MAX_KEYS;
+use crate::config::{DEFAULT_MAX_HEIGHT, PAGE_SIZE};
+use crate::cursor::Cursor;
+use crate::diff::DiffIter;
+use crate::entry::Entry;
+use crate::error::{BTreeError, SerializeError};
+use crate::iter::{Keys, Records, RevRecords};
+use crate::key::Key;
 use crate::node::*;
 use crate::record::Record;
-use crate::storage::Storage;
+use crate::storage::{SharedStorage, Storage};
+use crate::txn::Txn;
+use crate::value::Value;
+
+/// Leaves written per storage call during [`BPlusTree::bulk_load`], and
+/// leaves' worth of entries inserted per batch when a tree sharing its file
+/// with buckets is reloaded.
+const BULK_BATCH: usize = 64;
+
+/// Subtrees [`BPlusTree::histogram`] aims to split the keys into for each
+/// bucket asked for, before it stops reading further down.
+const HISTOGRAM_SPREAD: usize = 4;
+
+/// Two sibling leaves and their parent after evening out their keys.
+type Compensated<K, V> = (LeafNode<K, V>, LeafNode<K, V>, InternalNode<K>);
 
-pub struct BPlusTree<S> {
+/// Location of a leaf, the leaf, and the index of an entry in it.
+type Located<K, V> = (usize, LeafNode<K, V>, usize);
+
+/// Nodes with the pages they go to.
+type Pages<K, V> = Vec<(usize, Node<K, V>)>;
+
+/// Location of a leaf, the leaf, and the internal nodes above it.
+pub(crate) type Descent<K, V> = (usize, LeafNode<K, V>, Vec<(usize, InternalNode<K>)>);
+
+pub struct BPlusTree<S, K = i32, V = Record, A = FreeListAllocator> {
     pub storage: S,
     header: HeaderNode,
+    allocator: A,
+    max_height: usize,
+    max_keys: usize,
+    /// Most free pages the header can record; further frees are leaked.
+    max_free: usize,
+    /// Live record count; the header holds the last value written.
+    count: usize,
+    /// Bumped whenever keys move between leaves or internal nodes are
+    /// rewritten, invalidating insert hints.
+    epoch: u64,
+    /// Overflowing leaves evened out with a sibling, and ones split, since
+    /// the tree was opened.
+    leaf_redistributions: usize,
+    leaf_splits: usize,
+    /// Set by [`open_read_only`](Self::open_read_only).
+    read_only: bool,
+    /// Bucket the tree operates on, or `None` for the main tree.
+    bucket: Option<String>,
+    /// Root, height and count of the main tree while a bucket is in use,
+    /// as `header` then describes the bucket instead.
+    main: Option<Bucket>,
+    /// Bloom filter over the main tree's keys, as recorded in the header.
+    bloom: Option<BloomFilter>,
+    marker: PhantomData<(K, V)>,
+}
+
+/// The tree as used by the REPL: `i32` keys mirroring `record[0]`.
+pub type I32Tree<S> = BPlusTree<S, i32, Record>;
+
+/// One field summarised over a range of records by
+/// [`BPlusTree::aggregate`]. The sum is widened so it cannot overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Aggregate {
+    pub count: usize,
+    pub sum: i64,
+    pub min: Option<i32>,
+    pub max: Option<i32>,
+}
+
+/// Shape of the tree and traffic of its storage, as gathered by
+/// [`BPlusTree::stats`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeStats {
+    /// Levels from the root down to the leaves, counting both.
+    pub height: usize,
+    pub internal_nodes: usize,
+    pub leaf_nodes: usize,
+    pub records: usize,
+    /// Average share of each leaf's capacity in use, from 0 to 1.
+    pub leaf_fill: f64,
+    /// Size of the storage in bytes, counting every page at the storage's
+    /// [`page_size`](Storage::page_size).
+    pub file_size: usize,
+    pub page_reads: usize,
+    pub page_writes: usize,
+    /// Overflowing leaves that moved keys to a sibling instead of
+    /// splitting, and ones that split, since the tree was opened.
+    pub leaf_redistributions: usize,
+    pub leaf_splits: usize,
+}
+
+/// A slice of the key space, as found by [`BPlusTree::histogram`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramBucket<K = i32> {
+    /// Lowest key the bucket covers, a separator from an internal node, or
+    /// `None` for the first bucket. The bucket ends where the next starts.
+    pub low: Option<K>,
+    pub records: usize,
+}
+
+/// How a lookup went, as reported by [`BPlusTree::explain`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explain<K = i32, V = Record> {
+    /// Whether the Bloom filter ruled the key out, so no page was visited.
+    pub bloom_ruled_out: bool,
+    /// Pages visited from the root down, leaves last.
+    pub steps: Vec<ExplainStep<K>>,
+    pub value: Option<V>,
+    /// Pages the storage read from its medium during the lookup, which
+    /// pages served from a cache do not add to.
+    pub page_reads: usize,
+}
+
+/// A page visited by [`BPlusTree::explain`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExplainStep<K = i32> {
+    pub loc: usize,
+    pub kind: NodeKind,
+    /// Number of keys in the node: separators for an internal node.
+    pub keys: usize,
+    /// Each key of the node compared with, in order, and how the key looked
+    /// up compares to it.
+    pub comparisons: Vec<(K, Ordering)>,
+    /// For an internal node, the index of the child descended into and its
+    /// location.
+    pub child: Option<(usize, usize)>,
+}
+
+/// What [`BPlusTree::upsert`] did with the key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upsert<V = Record> {
+    /// The key was not there before.
+    Inserted,
+    /// The key was there; this is the value it replaced.
+    Updated(V),
+}
+
+/// Remembers the leaf the previous hinted insert landed in, so sorted
+/// inserts can skip the descent from the root.
+#[derive(Debug, Clone)]
+pub struct InsertHint<K = i32> {
+    leaf: Option<usize>,
+    /// Key range routed to `leaf`: `low` inclusive, `high` exclusive.
+    low: Option<K>,
+    high: Option<K>,
+    /// Internal nodes above `leaf`, root first, as last written, so their
+    /// record counts can go up without reading them again.
+    path: Vec<(usize, InternalNode<K>)>,
+    epoch: u64,
+}
+
+impl<K> Default for InsertHint<K> {
+    fn default() -> Self {
+        InsertHint {
+            leaf: None,
+            low: None,
+            high: None,
+            path: Vec::new(),
+            epoch: 0,
+        }
+    }
+}
+
+impl<K: Ord> InsertHint<K> {
+    fn covers(&self, key: &K, epoch: u64) -> bool {
+        self.epoch == epoch
+            && self.low.as_ref().is_none_or(|low| key >= low)
+            && self.high.as_ref().is_none_or(|high| key < high)
+    }
+}
+
+impl<S, K, V, A> BPlusTree<S, K, V, A>
+where
+    S: Storage<K, V>,
+    K: Key,
+    V: Value,
+    A: Allocator,
+{
+    /// Opens the tree held by `storage`, initialising an empty one if the
+    /// storage has no pages yet. A tree created as a multimap opens as one.
+    pub fn open(storage: S) -> Result<Self, BTreeError> {
+        Self::open_with(storage, false)
+    }
+
+    /// Opens a multimap, where inserting an existing key adds another record
+    /// under it instead of overwriting, initialising an empty one if the
+    /// storage has no pages yet. Equal keys are kept in the order they were
+    /// inserted. Fails with [`BTreeError::NotMultimap`] if the storage holds
+    /// a tree created with unique keys.
+    pub fn open_multimap(storage: S) -> Result<Self, BTreeError> {
+        let tree = Self::open_with(storage, true)?;
+        if !tree.header.multimap {
+            return Err(BTreeError::NotMultimap);
+        }
+        Ok(tree)
+    }
+
+    /// Opens the tree held by `storage`, initialising an empty one, in
+    /// multimap mode if `multimap` is set, when the storage has no pages.
+    fn open_with(mut storage: S, multimap: bool) -> Result<Self, BTreeError> {
+        assert!(
+            K::order().len() <= MAX_ORDER_NAME,
+            "key order name longer than {} bytes",
+            MAX_ORDER_NAME
+        );
+        assert!(
+            storage.codec().len() <= MAX_CODEC_NAME,
+            "page codec name longer than {} bytes",
+            MAX_CODEC_NAME
+        );
+        let trailing = storage.trailing_bytes()?;
+        if trailing != 0 {
+            return Err(BTreeError::PartialPage { trailing });
+        }
+
+        let header: HeaderNode = if storage.total_nodes()? == 0 {
+            let header = HeaderNode {
+                multimap,
+                collation: K::collation(),
+                order: K::order(),
+                codec: storage.codec().to_owned(),
+                ..HeaderNode::new(1, 1, storage.page_capacity())
+            };
+            storage.write_nodes(
+                0,
+                &[Node::Header(header.clone()), Node::Leaf(LeafNode::new())],
+            )?;
+            storage.flush()?;
+            header
+        } else {
+            Self::read_header(&mut storage)?
+        };
+        let allocator = A::new(storage.total_nodes()?, header.free_list.clone());
+        let max_keys = Self::node_capacity(&storage);
+        assert!(max_keys >= 2, "page too small to hold a node");
+        let max_free = max_free_list_for(storage.page_capacity());
+        let count = header.count;
+        let bloom = Self::read_bloom(&mut storage, &header)?;
+        Ok(BPlusTree {
+            storage,
+            header,
+            allocator,
+            max_height: DEFAULT_MAX_HEIGHT,
+            max_keys,
+            max_free,
+            count,
+            epoch: 0,
+            leaf_redistributions: 0,
+            leaf_splits: 0,
+            read_only: false,
+            bucket: None,
+            main: None,
+            bloom,
+            marker: PhantomData,
+        })
+    }
+
+    /// Opens the tree held by `storage` for reading only: inserts, deletes
+    /// and everything else that writes fail with [`BTreeError::ReadOnly`]
+    /// before touching a page. Unlike [`open`](Self::open), an empty
+    /// storage is refused rather than initialised.
+    pub fn open_read_only(storage: S) -> Result<Self, BTreeError> {
+        if storage.total_nodes()? == 0 {
+            return Err(BTreeError::NotAHeader);
+        }
+        let mut tree = Self::open(storage)?;
+        tree.read_only = true;
+        Ok(tree)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn is_multimap(&self) -> bool {
+        self.header.multimap
+    }
+
+    /// Keeps the root of the main tree and of every bucket on the page it
+    /// is on now. A root split then moves the old root's contents to a new
+    /// page and writes the new root in its place, and a root left with a
+    /// single child takes that child's contents, so the header only
+    /// changes for the height. The setting is kept in the header.
+    pub fn set_root_pinned(&mut self, pinned: bool) -> Result<(), BTreeError> {
+        self.check_writable()?;
+        if self.header.pinned_root == pinned {
+            return Ok(());
+        }
+        let header = HeaderNode {
+            pinned_root: pinned,
+            ..self.header.clone()
+        };
+        self.write_header(header)?;
+        self.flush()
+    }
+
+    pub fn is_root_pinned(&self) -> bool {
+        self.header.pinned_root
+    }
+
+    /// The record field recorded in the header by a secondary index.
+    pub(crate) fn indexed_field(&self) -> Option<usize> {
+        self.header.indexed_field
+    }
+
+    pub(crate) fn set_indexed_field(&mut self, field: usize) -> Result<(), BTreeError> {
+        self.check_writable()?;
+        let header = HeaderNode {
+            indexed_field: Some(field),
+            ..self.header.clone()
+        };
+        self.write_header(header)?;
+        self.flush()
+    }
+
+    pub(crate) fn check_writable(&self) -> Result<(), BTreeError> {
+        if self.read_only {
+            return Err(BTreeError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Reads the header from page 0, refusing files that are not trees or
+    /// were written in another format, page size, key collation or key
+    /// order.
+    fn read_header(storage: &mut S) -> Result<HeaderNode, BTreeError> {
+        let header = match storage.read_node(0)? {
+            Node::Header(header) => header,
+            _ => return Err(BTreeError::NotAHeader),
+        };
+        if header.magic != MAGIC {
+            return Err(BTreeError::BadMagic {
+                found: header.magic,
+            });
+        }
+        if header.version != FORMAT_VERSION {
+            return Err(BTreeError::UnsupportedVersion {
+                found: header.version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        if header.page_size != storage.page_capacity() {
+            return Err(BTreeError::PageSizeMismatch {
+                found: header.page_size,
+                expected: storage.page_capacity(),
+            });
+        }
+        if header.collation != K::collation() {
+            return Err(BTreeError::CollationMismatch {
+                found: header.collation,
+                expected: K::collation(),
+            });
+        }
+        if header.order != K::order() {
+            return Err(BTreeError::OrderMismatch {
+                found: header.order,
+                expected: K::order(),
+            });
+        }
+        if header.codec != storage.codec() {
+            return Err(BTreeError::CodecMismatch {
+                found: header.codec,
+                expected: storage.codec().to_owned(),
+            });
+        }
+        Ok(header)
+    }
+
+    /// Reads the Bloom filter `header` points at, if it has one.
+    fn read_bloom(storage: &mut S, header: &HeaderNode) -> Result<Option<BloomFilter>, BTreeError> {
+        let Some(bloom) = &header.bloom else {
+            return Ok(None);
+        };
+        let mut pages = Vec::with_capacity(bloom.pages.len());
+        for &loc in &bloom.pages {
+            match storage.read_node(loc)? {
+                Node::Bloom(page) => pages.push(page),
+                _ => {
+                    return Err(BTreeError::Corrupt {
+                        loc,
+                        reason: "expected a Bloom filter page",
+                    });
+                }
+            }
+        }
+        Ok(Some(BloomFilter::from_pages(pages, bloom.hashes)))
+    }
+
+    /// Former name of [`open`](Self::open), from when `open` panicked.
+    #[deprecated(note = "`open` now returns a `Result`")]
+    pub fn try_open(storage: S) -> Result<Self, BTreeError> {
+        Self::open(storage)
+    }
+
+    /// Builds a tree from entries in strictly ascending key order. Leaves
+    /// are packed full and chained as they stream in, then the internal
+    /// levels are built bottom-up and the header is written last. Whatever
+    /// `storage` held before is overwritten, and pages past the new tree go
+    /// on the free list. An entry out of order fails with
+    /// [`BTreeError::Unsorted`], as does one too large for a leaf with its
+    /// own error, and leaves an empty tree in `storage`.
+    pub fn bulk_load<I>(storage: S, sorted: I) -> Result<Self, BTreeError>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        Self::bulk_load_with_fill(storage, sorted, 1.0)
+    }
+
+    /// Like [`bulk_load`](Self::bulk_load), filling each node to `fill` of
+    /// its capacity, so later inserts find room instead of splitting. The
+    /// fill is clamped so no node ends up underfull.
+    pub fn bulk_load_with_fill<I>(mut storage: S, sorted: I, fill: f64) -> Result<Self, BTreeError>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        Self::write_sorted(&mut storage, sorted, fill, None)?;
+        Self::open(storage)
+    }
+
+    /// Replaces everything in the tree with entries in strictly ascending
+    /// key order, built the way [`bulk_load`](Self::bulk_load) builds them.
+    /// A multimap also takes equal keys, as long as they are adjacent. On
+    /// input out of order the tree is left empty, as with `bulk_load`.
+    pub fn load_sorted<I>(&mut self, sorted: I) -> Result<(), BTreeError>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        self.check_writable()?;
+        if !self.header.buckets.is_empty() {
+            // The pages past this tree may belong to other trees, so rather
+            // than writing over the file, empty the tree and refill it.
+            self.clear_tree()?;
+            let refilled = self.refill_sorted(sorted);
+            if refilled.is_err() {
+                self.clear_tree()?;
+                self.flush()?;
+            }
+            return refilled;
+        }
+        // The new header has no filter, and its pages may be reused
+        let bloom = self.bloom.is_some();
+        let written = Self::write_sorted(&mut self.storage, sorted, 1.0, Some(&self.header));
+        self.reload()?;
+        written?;
+        if bloom {
+            self.build_bloom_filter()?;
+        }
+        Ok(())
+    }
+
+    /// Inserts entries in ascending key order into the emptied tree,
+    /// [`BULK_BATCH`] leaves' worth at a time, so the input is streamed
+    /// rather than held in memory. Fails like [`write_sorted`](Self::write_sorted)
+    /// on input out of order.
+    fn refill_sorted<I>(&mut self, sorted: I) -> Result<(), BTreeError>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut sorted = sorted.into_iter().enumerate();
+        let mut last: Option<K> = None;
+        loop {
+            let mut chunk = Vec::with_capacity(BULK_BATCH * self.max_keys);
+            for (index, (key, value)) in sorted.by_ref().take(BULK_BATCH * self.max_keys) {
+                let ordered = last
+                    .as_ref()
+                    .is_none_or(|last| *last < key || self.header.multimap && *last == key);
+                if !ordered {
+                    return Err(BTreeError::Unsorted { index });
+                }
+                last = Some(key.clone());
+                chunk.push((key, value));
+            }
+            if chunk.is_empty() {
+                return self.flush();
+            }
+            self.insert_batch(chunk)?;
+        }
+    }
+
+    /// Rereads the header after the pages were rewritten behind the tree's
+    /// back, as bulk loads and committed transactions do.
+    pub(crate) fn reload(&mut self) -> Result<(), BTreeError> {
+        let header = Self::read_header(&mut self.storage)?;
+        self.allocator = A::new(self.storage.total_nodes()?, header.free_list.clone());
+        self.bloom = Self::read_bloom(&mut self.storage, &header)?;
+        // Back to the main tree if the bucket in use went away
+        let bucket = self.bucket.take();
+        if self.view(header.clone(), bucket.as_deref()).is_err() {
+            self.view(header, None)?;
+        }
+        Ok(())
+    }
+
+    /// Makes `header`, as stored on page 0, the tree's header, describing
+    /// the bucket named `name` in place of the main tree unless it is
+    /// `None`.
+    fn view(&mut self, mut header: HeaderNode, name: Option<&str>) -> Result<(), BTreeError> {
+        let main = match name {
+            Some(name) => {
+                let bucket = header
+                    .buckets
+                    .iter()
+                    .find(|bucket| bucket.name == name)
+                    .cloned()
+                    .ok_or_else(|| BTreeError::NoSuchBucket {
+                        name: name.to_owned(),
+                    })?;
+                let main = Bucket {
+                    name: String::new(),
+                    root: header.root,
+                    height: header.height,
+                    count: header.count,
+                };
+                header.root = bucket.root;
+                header.height = bucket.height;
+                header.count = bucket.count;
+                Some(main)
+            }
+            None => None,
+        };
+        self.count = header.count;
+        self.header = header;
+        self.bucket = name.map(str::to_owned);
+        self.main = main;
+        self.epoch += 1;
+        Ok(())
+    }
+
+    /// `header` as stored on page 0: the main tree in the root fields, and
+    /// the bucket in use back in the catalog.
+    fn on_disk(&self, mut header: HeaderNode) -> HeaderNode {
+        if let (Some(name), Some(main)) = (&self.bucket, &self.main) {
+            if let Some(bucket) = header
+                .buckets
+                .iter_mut()
+                .find(|bucket| &bucket.name == name)
+            {
+                bucket.root = header.root;
+                bucket.height = header.height;
+                bucket.count = header.count;
+            }
+            header.root = main.root;
+            header.height = main.height;
+            header.count = main.count;
+        }
+        header
+    }
+
+    /// Name of the bucket the tree operates on, or `None` for the main tree.
+    pub fn bucket(&self) -> Option<&str> {
+        self.bucket.as_deref()
+    }
+
+    /// Every bucket in the file besides the main tree, in the order they
+    /// were created.
+    pub fn buckets(&self) -> Vec<Bucket> {
+        let header = HeaderNode {
+            count: self.count,
+            ..self.header.clone()
+        };
+        self.on_disk(header).buckets
+    }
+
+    /// Points the tree at the bucket named `name`, or back at the main tree
+    /// for `None`. Everything but bucket management then acts on that tree
+    /// alone, while pages and the free list stay shared by the whole file.
+    pub fn use_bucket(&mut self, name: Option<&str>) -> Result<(), BTreeError> {
+        self.flush()?;
+        let header = self.on_disk(self.header.clone());
+        self.view(header, name)
+    }
+
+    /// Adds an empty bucket named `name` to the file, without switching to
+    /// it. Buckets share the main tree's multimap setting.
+    pub fn create_bucket(&mut self, name: &str) -> Result<(), BTreeError> {
+        self.check_writable()?;
+        if name.is_empty() || name.len() > MAX_BUCKET_NAME {
+            return Err(BTreeError::BadBucketName {
+                name: name.to_owned(),
+            });
+        }
+        if self.header.buckets.iter().any(|bucket| bucket.name == name) {
+            return Err(BTreeError::BucketExists {
+                name: name.to_owned(),
+            });
+        }
+        if self.header.buckets.len() >= MAX_BUCKETS {
+            return Err(BTreeError::TooManyBuckets { max: MAX_BUCKETS });
+        }
+
+        let root = self.alloc_node();
+        self.storage
+            .write_node(root, &Node::Leaf(LeafNode::new()))?;
+        let mut header = self.header.clone();
+        header.buckets.push(Bucket {
+            name: name.to_owned(),
+            root,
+            height: 1,
+            count: 0,
+        });
+        self.write_header(header)?;
+        self.storage.flush()
+    }
+
+    /// Removes the bucket named `name` with all its records, putting its
+    /// pages on the free list. The bucket in use cannot be dropped.
+    pub fn drop_bucket(&mut self, name: &str) -> Result<(), BTreeError> {
+        self.check_writable()?;
+        if self.bucket.as_deref() == Some(name) {
+            return Err(BTreeError::BucketInUse {
+                name: name.to_owned(),
+            });
+        }
+        let Some(i) = self
+            .header
+            .buckets
+            .iter()
+            .position(|bucket| bucket.name == name)
+        else {
+            return Err(BTreeError::NoSuchBucket {
+                name: name.to_owned(),
+            });
+        };
+
+        let mut header = self.header.clone();
+        let bucket = header.buckets.remove(i);
+        for loc in self.tree_pages(bucket.root)? {
+            self.free_node(loc);
+        }
+        self.write_header(header)?;
+        self.storage.flush()
+    }
+
+    /// Roots of the main tree and of every bucket.
+    fn roots(&self) -> Vec<usize> {
+        let header = self.on_disk(self.header.clone());
+        std::iter::once(header.root)
+            .chain(header.buckets.iter().map(|bucket| bucket.root))
+            .collect()
+    }
+
+    /// Every page of the tree rooted at `root`, along with the overflow
+    /// pages of its values.
+    fn tree_pages(&mut self, root: usize) -> Result<Vec<usize>, BTreeError> {
+        let mut pages = Vec::new();
+        let mut pending = vec![root];
+        while let Some(loc) = pending.pop() {
+            match self.storage.read_node(loc)? {
+                Node::Internal(internal) => pending.extend(internal.children),
+                Node::Leaf(leaf) => {
+                    for value in &leaf.values {
+                        pages.extend(self.overflow_chain(value.overflow())?);
+                    }
+                }
+                _ => {}
+            }
+            pages.push(loc);
+        }
+        Ok(pages)
+    }
+
+    /// The pages of the overflow chain starting at `first`, in order.
+    fn overflow_chain(&mut self, first: Option<usize>) -> Result<Vec<usize>, BTreeError> {
+        let total = self.storage.total_nodes()?;
+        let mut pages = Vec::new();
+        let mut next = first;
+        while let Some(loc) = next {
+            next = match self.storage.read_node(loc)? {
+                Node::Overflow(page) if pages.len() < total => page.next,
+                Node::Overflow(_) => {
+                    return Err(BTreeError::Corrupt {
+                        loc,
+                        reason: "overflow chain loops",
+                    });
+                }
+                _ => {
+                    return Err(BTreeError::Corrupt {
+                        loc,
+                        reason: "overflow chain leads to a page of another kind",
+                    });
+                }
+            };
+            pages.push(loc);
+        }
+        Ok(pages)
+    }
+
+    /// Frees the overflow pages of a value leaving the tree.
+    pub(crate) fn free_overflow(&mut self, value: &V) -> Result<(), BTreeError> {
+        for loc in self.overflow_chain(value.overflow())? {
+            self.free_node(loc);
+        }
+        Ok(())
+    }
+
+    /// Empties the tree in use, keeping its root page as an empty leaf and
+    /// freeing the rest.
+    fn clear_tree(&mut self) -> Result<(), BTreeError> {
+        let root = self.header.root;
+        let pages = self.tree_pages(root)?;
+        self.storage
+            .write_node(root, &Node::Leaf(LeafNode::new()))?;
+        for loc in pages.into_iter().filter(|&loc| loc != root) {
+            self.free_node(loc);
+        }
+        self.count = 0;
+        self.epoch += 1;
+        let header = HeaderNode {
+            height: 1,
+            ..self.header.clone()
+        };
+        self.write_header(header)
+    }
+
+    /// Builds a Bloom filter over the main tree's keys, sized for the
+    /// records it holds, or rebuilds the one it has. With a filter, looking
+    /// up a key that is not there mostly answers without reading a page,
+    /// at the cost of a page write per insert. Deleted keys stay in the
+    /// filter until it is rebuilt, as [`compact`](Self::compact) does.
+    /// Lookups in buckets go without it.
+    pub fn build_bloom_filter(&mut self) -> Result<(), BTreeError> {
+        self.check_writable()?;
+        self.flush()?;
+        let count = self.main.as_ref().map_or(self.count, |main| main.count);
+        let mut bloom =
+            BloomFilter::with_capacity(count, bloom_bytes_for(self.storage.page_capacity()));
+        let mut loc = self.roots()[0];
+        while let Node::Internal(internal) = self.storage.read_node(loc)? {
+            loc = internal.children[0];
+        }
+        let mut next = Some(loc);
+        while let Some(loc) = next {
+            let leaf = self.read_leaf(loc)?;
+            for key in &leaf.keys {
+                bloom.insert(&Self::bloom_key(key)?);
+            }
+            next = leaf.next;
+        }
+
+        // The previous filter's pages are reused as far as they go
+        let mut pages = self.bloom_pages();
+        while pages.len() > bloom.pages() {
+            let loc = pages.pop().unwrap();
+            self.free_node(loc);
+        }
+        while pages.len() < bloom.pages() {
+            pages.push(self.alloc_node());
+        }
+        let header = HeaderNode {
+            bloom: Some(BloomHeader {
+                pages,
+                hashes: bloom.hashes(),
+            }),
+            ..self.header.clone()
+        };
+        self.write_header(header)?;
+        self.bloom = Some(bloom);
+        self.flush()
+    }
+
+    /// Removes the Bloom filter, putting its pages on the free list.
+    pub fn drop_bloom_filter(&mut self) -> Result<(), BTreeError> {
+        self.check_writable()?;
+        if self.bloom.take().is_none() {
+            return Ok(());
+        }
+        for loc in self.bloom_pages() {
+            self.free_node(loc);
+        }
+        let header = HeaderNode {
+            bloom: None,
+            ..self.header.clone()
+        };
+        self.write_header(header)?;
+        self.storage.flush()
+    }
+
+    pub fn has_bloom_filter(&self) -> bool {
+        self.bloom.is_some()
+    }
+
+    fn bloom_pages(&self) -> Vec<usize> {
+        self.header
+            .bloom
+            .as_ref()
+            .map_or_else(Vec::new, |bloom| bloom.pages.clone())
+    }
+
+    /// The bytes of `key` the Bloom filter hashes.
+    fn bloom_key(key: &K) -> Result<Vec<u8>, BTreeError> {
+        let bytes = bincode::encode_to_vec(key.canonical(), bincode::config::standard())
+            .map_err(SerializeError::from)?;
+        Ok(bytes)
+    }
+
+    /// Adds `key` to the Bloom filter, if there is one and the main tree
+    /// is in use. Done before the key is stored, so the filter never lacks
+    /// a key the tree holds.
+    pub(crate) fn bloom_add(&mut self, key: &K) -> Result<(), BTreeError> {
+        if self.bucket.is_none()
+            && let Some(bloom) = &mut self.bloom
+        {
+            bloom.insert(&Self::bloom_key(key)?);
+        }
+        Ok(())
+    }
+
+    /// Whether the Bloom filter shows `key` is not in the tree.
+    fn bloom_rules_out(&self, key: &K) -> Result<bool, BTreeError> {
+        match &self.bloom {
+            Some(bloom) if self.bucket.is_none() => Ok(!bloom.may_contain(&Self::bloom_key(key)?)),
+            _ => Ok(false),
+        }
+    }
+
+    /// Writes the pages of the Bloom filter changed since the last flush.
+    fn write_bloom(&mut self) -> Result<(), BTreeError> {
+        let Some(bloom) = &mut self.bloom else {
+            return Ok(());
+        };
+        let dirty = bloom.take_dirty();
+        let locs = self.bloom_pages();
+        for (i, page) in dirty {
+            self.storage.write_node(locs[i], &Node::Bloom(page))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the bulk-loaded tree and its header over `storage`. The
+    /// settings of the `old` header carry over: whether the tree is a
+    /// multimap, its indexed field, and a pinned root, which is put back on
+    /// its page. Input that cannot be loaded leaves an empty tree instead,
+    /// as its pages may already be overwritten.
+    fn write_sorted<I>(
+        storage: &mut S,
+        sorted: I,
+        fill: f64,
+        old: Option<&HeaderNode>,
+    ) -> Result<(), BTreeError>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let multimap = old.is_some_and(|header| header.multimap);
+        let pinned = old
+            .filter(|header| header.pinned_root)
+            .map(|header| header.root);
+        let existing = storage.total_nodes()?;
+        let max_keys = Self::node_capacity(storage);
+        let min_keys = max_keys / 2;
+        let target = ((max_keys as f64 * fill).ceil() as usize).clamp(min_keys.max(1), max_keys);
+
+        // First key, location and record count of every leaf, in order
+        let mut level: Vec<(K, usize, usize)> = Vec::new();
+        let mut batch: Vec<Node<K, V>> = Vec::new();
+        // The last filled leaf, held back until we know whether the final
+        // leaf needs to borrow from it
+        let mut previous: Option<LeafNode<K, V>> = None;
+        let mut leaf = LeafNode::new();
+        let mut count = 0;
+        // Separator routing to each leaf after the first, from the last key
+        // of the leaf before it
+        let mut last: Option<K> = None;
+        let mut boundary = |leaf: &LeafNode<K, V>| {
+            let first = &leaf.keys[0];
+            let separator = match &last {
+                Some(last) => K::separator(last, first),
+                None => first.clone(),
+            };
+            last = leaf.keys.last().cloned();
+            separator
+        };
+
+        for (key, value) in sorted {
+            let ordered = leaf
+                .keys
+                .last()
+                .or(previous.as_ref().and_then(|p| p.keys.last()))
+                .is_none_or(|last| *last < key || multimap && *last == key);
+            let valid = Self::check_key_size(&key)
+                .and_then(|_| Self::check_value_size(&value))
+                .and(if ordered {
+                    Ok(())
+                } else {
+                    Err(BTreeError::Unsorted { index: count })
+                });
+            if let Err(err) = valid {
+                Self::write_sorted(storage, std::iter::empty(), fill, old)?;
+                return Err(err);
+            }
+            if leaf.keys.len() == target {
+                let full = std::mem::take(&mut leaf);
+                if let Some(mut done) = previous.replace(full) {
+                    let loc = level.len() + 1;
+                    done.next = Some(loc + 1);
+                    done.prev = (loc > 1).then(|| loc - 1);
+                    level.push((boundary(&done), loc, done.keys.len()));
+                    batch.push(Node::Leaf(done));
+                    if batch.len() == BULK_BATCH {
+                        let batch = std::mem::take(&mut batch);
+                        Self::write_built(storage, loc + 1 - batch.len(), batch, pinned, false)?;
+                    }
+                }
+            }
+            leaf.keys.push(key);
+            leaf.values.push(value);
+            count += 1;
+        }
+
+        if let Some(mut done) = previous {
+            if leaf.keys.len() < min_keys && done.keys.len() + leaf.keys.len() <= max_keys {
+                // Both fit in one leaf, which takes the held-back leaf's page
+                done.keys.append(&mut leaf.keys);
+                done.values.append(&mut leaf.values);
+                leaf.keys = done.keys;
+                leaf.values = done.values;
+            } else {
+                if leaf.keys.len() < min_keys {
+                    let mid = (done.keys.len() + leaf.keys.len()) / 2;
+                    let mut keys = done.keys.split_off(mid);
+                    let mut values = done.values.split_off(mid);
+                    keys.append(&mut leaf.keys);
+                    values.append(&mut leaf.values);
+                    leaf.keys = keys;
+                    leaf.values = values;
+                }
+                let loc = level.len() + 1;
+                done.next = Some(loc + 1);
+                done.prev = (loc > 1).then(|| loc - 1);
+                level.push((boundary(&done), loc, done.keys.len()));
+                batch.push(Node::Leaf(done));
+            }
+        }
+        if !leaf.keys.is_empty() {
+            level.push((boundary(&leaf), level.len() + 1, leaf.keys.len()));
+        }
+        // An empty input still leaves an empty root leaf
+        let last_loc = level.len().max(1);
+        leaf.prev = (last_loc > 1).then(|| last_loc - 1);
+        batch.push(Node::Leaf(leaf));
+        let root = level.len() <= 1;
+        Self::write_built(storage, last_loc + 1 - batch.len(), batch, pinned, root)?;
+
+        let mut next_loc = last_loc + 1;
+        let mut height = 1;
+        while level.len() > 1 {
+            let mut parents = Vec::new();
+            let mut nodes = Vec::new();
+            for group in Self::group_children(level, target, max_keys) {
+                let mut children = group.into_iter();
+                let (first, loc, count) = children.next().unwrap();
+                let mut node = InternalNode {
+                    keys: Vec::new(),
+                    children: vec![loc],
+                    counts: vec![count],
+                };
+                for (key, loc, count) in children {
+                    node.keys.push(key);
+                    node.children.push(loc);
+                    node.counts.push(count);
+                }
+                parents.push((first, next_loc + nodes.len(), node.count()));
+                nodes.push(Node::Internal(node));
+            }
+            let (first, built) = (next_loc, nodes.len());
+            Self::write_built(storage, first, nodes, pinned, parents.len() == 1)?;
+            next_loc += built;
+            level = parents;
+            height += 1;
+        }
+
+        // The pages left unused below the end: past the tree, and a gap up to
+        // a pinned root beyond it. Listed highest first, so the lowest page is
+        // handed out first
+        let root = pinned.unwrap_or(next_loc - 1);
+        let free = match pinned {
+            Some(pinned) if pinned >= next_loc => next_loc - 1..existing.max(pinned + 1),
+            _ => next_loc..existing,
+        };
+        let max_free = max_free_list_for(storage.page_capacity());
+        let mut free_list: Vec<usize> = free.filter(|&loc| loc != root).take(max_free).collect();
+        free_list.reverse();
+        let header = HeaderNode {
+            free_list,
+            count,
+            multimap,
+            pinned_root: pinned.is_some(),
+            collation: K::collation(),
+            order: K::order(),
+            codec: storage.codec().to_owned(),
+            indexed_field: old.and_then(|header| header.indexed_field),
+            ..HeaderNode::new(root, height, storage.page_capacity())
+        };
+        storage.write_node(0, &Node::Header(header))?;
+        storage.flush()
+    }
+
+    /// The page of the node a bulk load builds `ordinal`-th, counting from 1,
+    /// when the root goes to the `pinned` page: the nodes from there on move
+    /// up one to leave it free.
+    fn bulk_page(ordinal: usize, pinned: Option<usize>) -> usize {
+        match pinned {
+            Some(pinned) if ordinal >= pinned => ordinal + 1,
+            _ => ordinal,
+        }
+    }
+
+    /// Writes nodes a bulk load built one after another from `first`, with
+    /// their links, numbered the same way, moved to the pages
+    /// [`bulk_page`](Self::bulk_page) gives. With `root` the last of them is
+    /// the root, which goes to the `pinned` page if there is one.
+    fn write_built(
+        storage: &mut S,
+        first: usize,
+        mut nodes: Vec<Node<K, V>>,
+        pinned: Option<usize>,
+        root: bool,
+    ) -> Result<(), BTreeError> {
+        let page = |ordinal| Self::bulk_page(ordinal, pinned);
+        let last = nodes.len() - 1;
+        let mut locs = Vec::with_capacity(nodes.len());
+        for (i, node) in nodes.iter_mut().enumerate() {
+            match node {
+                Node::Leaf(leaf) => {
+                    leaf.prev = leaf.prev.map(page);
+                    leaf.next = leaf.next.map(page);
+                }
+                Node::Internal(internal) => {
+                    for child in &mut internal.children {
+                        *child = page(*child);
+                    }
+                }
+                Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {}
+            }
+            locs.push(match pinned {
+                Some(pinned) if root && i == last => pinned,
+                _ => page(first + i),
+            });
+        }
+        // Each run of consecutive pages goes out in one write
+        let mut start = 0;
+        for end in 1..=nodes.len() {
+            if end == nodes.len() || locs[end] != locs[end - 1] + 1 {
+                storage.write_nodes(locs[start], &nodes[start..end])?;
+                start = end;
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits one level of children into parents of `target + 1` children,
+    /// merging or evening out the last two so neither is underfull.
+    fn group_children<T>(level: Vec<T>, target: usize, max_keys: usize) -> Vec<Vec<T>> {
+        let mut groups = Vec::new();
+        let mut children = level.into_iter().peekable();
+        while children.peek().is_some() {
+            groups.push(children.by_ref().take(target + 1).collect::<Vec<_>>());
+        }
+
+        if groups.len() > 1 && groups.last().unwrap().len() < max_keys / 2 + 1 {
+            let mut last = groups.pop().unwrap();
+            let previous = groups.last_mut().unwrap();
+            if previous.len() + last.len() <= max_keys + 1 {
+                previous.append(&mut last);
+            } else {
+                let mid = (previous.len() + last.len()) / 2;
+                let mut moved = previous.split_off(mid);
+                moved.append(&mut last);
+                groups.push(moved);
+            }
+        }
+        groups
+    }
+
+    /// Refuses keys larger than nodes are sized for, which could otherwise
+    /// overflow a page partway through an insert.
+    pub(crate) fn check_key_size(key: &K) -> Result<(), BTreeError> {
+        let (size, max) = (key.serialized_size(), K::max_serialized_size());
+        if size > max {
+            return Err(SerializeError::KeyTooLarge { size, max }.into());
+        }
+        Ok(())
+    }
+
+    /// Refuses values too large to be stored in a leaf.
+    pub(crate) fn check_value_size(value: &V) -> Result<(), BTreeError> {
+        let (size, max) = (value.serialized_size(), V::max_serialized_size());
+        if size > max {
+            return Err(SerializeError::ValueTooLarge { size, max }.into());
+        }
+        Ok(())
+    }
+
+    /// Most keys a node may hold on `storage`: as many as fit a page. Like
+    /// Bloom filter blocks, nodes of storages without a real bound on pages
+    /// are sized for a file page. The crate's tests cap it at
+    /// [`MAX_KEYS`](crate::config::MAX_KEYS).
+    fn node_capacity(storage: &S) -> usize {
+        let capacity = max_keys_for::<K, V>(storage.page_capacity().min(PAGE_SIZE));
+        #[cfg(test)]
+        let capacity = capacity.min(TEST_MAX_KEYS.get());
+        capacity
+    }
+
+    /// Caps how many levels the tree may grow to; inserts that would add a
+    /// level beyond it fail with [`BTreeError::HeightLimit`].
+    pub fn set_max_height(&mut self, max_height: usize) {
+        self.max_height = max_height;
+    }
+
+    pub fn max_height(&self) -> usize {
+        self.max_height
+    }
+
+    pub fn calculate_depth(&mut self) -> Result<usize, BTreeError> {
+        let mut depth = 0;
+        let mut loc = self.header.root;
+
+        loop {
+            match self.storage.read_node(loc)? {
+                Node::Internal(internal) => {
+                    depth += 1;
+                    loc = internal.children[0];
+                }
+                Node::Leaf(_) => {
+                    return Ok(depth + 1);
+                }
+                Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                    return Err(Self::misplaced_page(loc));
+                }
+            }
+        }
+    }
+
+    /// Walks every node to report the tree's height, node counts and leaf
+    /// fill, along with the storage's size and page counters.
+    pub fn stats(&mut self) -> Result<TreeStats, BTreeError> {
+        let (mut height, mut internal_nodes, mut leaf_nodes, mut leaf_keys) = (0, 0, 0, 0);
+        let mut level = vec![self.header.root];
+        while !level.is_empty() {
+            height += 1;
+            let mut below = Vec::new();
+            for loc in level {
+                match self.storage.read_node(loc)? {
+                    Node::Internal(internal) => {
+                        internal_nodes += 1;
+                        below.extend(internal.children);
+                    }
+                    Node::Leaf(leaf) => {
+                        leaf_nodes += 1;
+                        leaf_keys += leaf.keys.len();
+                    }
+                    Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                        return Err(Self::misplaced_page(loc));
+                    }
+                }
+            }
+            level = below;
+        }
+
+        let (page_reads, page_writes) = self.storage.io_counters();
+        Ok(TreeStats {
+            height,
+            internal_nodes,
+            leaf_nodes,
+            records: self.count,
+            leaf_fill: leaf_keys as f64 / (leaf_nodes * self.max_keys) as f64,
+            file_size: self.storage.total_nodes()? * self.storage.page_size()
+                + self.storage.trailing_bytes()?,
+            page_reads,
+            page_writes,
+            leaf_redistributions: self.leaf_redistributions,
+            leaf_splits: self.leaf_splits,
+        })
+    }
+
+    /// Splits the keys into at most `buckets` slices holding about as many
+    /// records each, for choosing shard boundaries. Only internal nodes are
+    /// read: each level is walked until it has `buckets` subtrees several
+    /// times over or the next one holds the leaves, and whole subtrees are
+    /// then handed out to buckets by the record counts kept for them. The
+    /// counts are exact, but the boundaries can only fall between subtrees,
+    /// so a small tree may give fewer buckets than asked for.
+    pub fn histogram(&mut self, buckets: usize) -> Result<Vec<HistogramBucket<K>>, BTreeError> {
+        if buckets == 0 || self.count == 0 {
+            return Ok(Vec::new());
+        }
+        // Lowest key and record count of each subtree, in key order
+        let mut level = vec![(None, self.header.root, self.count)];
+        let mut depth = 1;
+        while depth < self.header.height && level.len() < buckets * HISTOGRAM_SPREAD {
+            let mut below = Vec::new();
+            for (low, loc, _) in level {
+                let Node::Internal(internal) = self.storage.read_node(loc)? else {
+                    return Err(Self::misplaced_page(loc));
+                };
+                let lows = std::iter::once(low).chain(internal.keys.into_iter().map(Some));
+                below.extend(
+                    lows.zip(internal.children)
+                        .zip(internal.counts)
+                        .map(|((low, loc), count)| (low, loc, count)),
+                );
+            }
+            level = below;
+            depth += 1;
+        }
+
+        let mut histogram: Vec<HistogramBucket<K>> = Vec::new();
+        let mut seen = 0;
+        for (low, _, records) in level {
+            // Start the next bucket once the ones so far hold their share
+            if histogram.is_empty()
+                || (histogram.len() < buckets && seen * buckets >= histogram.len() * self.count)
+            {
+                histogram.push(HistogramBucket { low, records: 0 });
+            }
+            histogram.last_mut().unwrap().records += records;
+            seen += records;
+        }
+        Ok(histogram)
+    }
+
+    /// Counts records by walking the leaf chain, independently of the
+    /// count kept in the header.
+    pub fn count_total_keys(&mut self) -> Result<usize, BTreeError> {
+        let mut count = 0;
+        for entry in self.iter() {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Alias of [`get`](Self::get) kept for existing callers.
+    #[deprecated(note = "use `get`, which matches `BTreeMap`")]
+    pub fn find(&mut self, key: &K) -> Result<Option<V>, BTreeError> {
+        self.get(key)
+    }
+
+    /// Pages released by deletes and not yet reused.
+    pub fn free_pages(&self) -> &[usize] {
+        self.allocator.free_pages()
+    }
+
+    /// Returns the number of records, as tracked in the header.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Whether any record is stored under `key`. Only the keys of the leaf
+    /// it would be in are decoded, where the storage can, so no values are
+    /// read or cloned.
+    pub fn contains_key(&mut self, key: &K) -> Result<bool, BTreeError> {
+        if self.bloom_rules_out(key)? {
+            return Ok(false);
+        }
+        let start = self.find_leaf(key);
+        if !self.header.multimap {
+            let leaf = self.storage.read_keys(start?)?;
+            return Ok(leaf.keys.binary_search(key).is_ok());
+        }
+        // The first leaf that may hold `key` can end before it
+        let found = Keys::new(&mut self.storage, start)
+            .find(|k| k.as_ref().map_or(true, |k| k >= key))
+            .transpose()?;
+        Ok(found.as_ref() == Some(key))
+    }
+
+    pub fn first_key_value(&mut self) -> Result<Option<(K, V)>, BTreeError> {
+        let loc = self.leftmost_leaf()?;
+        let leaf = self.read_leaf(loc)?;
+        Ok(leaf.keys.first().cloned().zip(leaf.values.first().cloned()))
+    }
+
+    pub fn last_key_value(&mut self) -> Result<Option<(K, V)>, BTreeError> {
+        self.rightmost_entry(self.header.root)
+    }
+
+    /// Value with the smallest key, or `None` if the tree is empty.
+    pub fn first(&mut self) -> Result<Option<V>, BTreeError> {
+        Ok(self.first_key_value()?.map(|(_, record)| record))
+    }
+
+    /// Value with the largest key, or `None` if the tree is empty.
+    pub fn last(&mut self) -> Result<Option<V>, BTreeError> {
+        Ok(self.last_key_value()?.map(|(_, record)| record))
+    }
+
+    /// Removes and returns the entry with the smallest key, so the tree can
+    /// serve as a persistent priority queue. In a multimap this is the
+    /// first record under that key.
+    pub fn pop_first(&mut self) -> Result<Option<(K, V)>, BTreeError> {
+        let Some((key, _)) = self.first_key_value()? else {
+            return Ok(None);
+        };
+        Ok(self.delete(&key)?.map(|value| (key, value)))
+    }
+
+    /// Removes and returns the entry with the largest key. In a multimap
+    /// this is the last record under that key.
+    pub fn pop_last(&mut self) -> Result<Option<(K, V)>, BTreeError> {
+        let Some((key, value)) = self.last_key_value()? else {
+            return Ok(None);
+        };
+        self.delete_entry(&key, &value)?;
+        Ok(Some((key, value)))
+    }
+
+    pub fn min_key(&mut self) -> Result<Option<K>, BTreeError> {
+        Ok(self.first_key_value()?.map(|(key, _)| key))
+    }
+
+    pub fn max_key(&mut self) -> Result<Option<K>, BTreeError> {
+        Ok(self.last_key_value()?.map(|(key, _)| key))
+    }
+
+    /// Looks up `key`. Unlike `BTreeMap::get` the record is returned by value,
+    /// since it is decoded from a page rather than borrowed from memory.
+    /// In a multimap this is the first record stored under `key`.
+    pub fn get(&mut self, key: &K) -> Result<Option<V>, BTreeError> {
+        if self.bloom_rules_out(key)? {
+            return Ok(None);
+        }
+        if self.header.multimap {
+            return Ok(self.find_all(key)?.into_iter().next());
+        }
+        let loc = self.find_leaf(key)?;
+        let leaf = self.read_leaf(loc)?;
+        Ok(leaf
+            .keys
+            .binary_search(key)
+            .ok()
+            .map(|i| leaf.values[i].clone()))
+    }
+
+    /// Looks up every key in `keys` as [`get`](Self::get) would, returning
+    /// the values in the same order. The keys are visited sorted, so each
+    /// internal node and leaf on the way is read once for all the keys
+    /// routed through it rather than once per key.
+    pub fn find_many(&mut self, keys: &[K]) -> Result<Vec<Option<V>>, BTreeError> {
+        if self.header.multimap {
+            return keys.iter().map(|key| self.get(key)).collect();
+        }
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| keys[a].cmp(&keys[b]));
+
+        let mut found = vec![None; keys.len()];
+        // Nodes from the root down to the current leaf, each with the
+        // exclusive bound on the keys routed to it
+        let mut path: Vec<(InternalNode<K>, Option<K>)> = Vec::new();
+        let mut leaf: Option<(LeafNode<K, V>, Option<K>)> = None;
+        for i in order {
+            let key = &keys[i];
+            let below = |high: &Option<K>| high.as_ref().is_none_or(|high| key < high);
+            if !leaf.as_ref().is_some_and(|(_, high)| below(high)) {
+                while path.last().is_some_and(|(_, high)| !below(high)) {
+                    path.pop();
+                }
+                let (mut loc, mut high) = match path.last() {
+                    Some((internal, high)) => Self::route(internal, key, high),
+                    None => (self.header.root, None),
+                };
+                leaf = loop {
+                    match self.storage.read_node(loc)? {
+                        Node::Internal(internal) => {
+                            let (child, bound) = Self::route(&internal, key, &high);
+                            path.push((internal, high));
+                            (loc, high) = (child, bound);
+                        }
+                        Node::Leaf(leaf) => break Some((leaf, high)),
+                        Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                            return Err(Self::misplaced_page(loc));
+                        }
+                    }
+                };
+            }
+            if let Some((leaf, _)) = &leaf {
+                found[i] = leaf
+                    .keys
+                    .binary_search(key)
+                    .ok()
+                    .map(|j| leaf.values[j].clone());
+            }
+        }
+        Ok(found)
+    }
+
+    /// Looks up `key` as [`get`](Self::get) does, recording each page
+    /// visited and each key compared on the way, to show why a lookup costs
+    /// what it does. In a multimap this follows the path to the first
+    /// record under `key`.
+    pub fn explain(&mut self, key: &K) -> Result<Explain<K, V>, BTreeError> {
+        let (reads_before, _) = self.storage.io_counters();
+        let mut explain = Explain {
+            bloom_ruled_out: self.bloom_rules_out(key)?,
+            steps: Vec::new(),
+            value: None,
+            page_reads: 0,
+        };
+        if explain.bloom_ruled_out {
+            return Ok(explain);
+        }
+        let multimap = self.header.multimap;
+        let mut loc = self.header.root;
+        loop {
+            match self.storage.read_node(loc)? {
+                Node::Internal(internal) => {
+                    // `child_index`, or `first_child_index` in a multimap
+                    let (i, comparisons, _) = Self::traced_search(&internal.keys, key, !multimap);
+                    explain.steps.push(ExplainStep {
+                        loc,
+                        kind: NodeKind::Internal,
+                        keys: internal.keys.len(),
+                        comparisons,
+                        child: Some((i, internal.children[i])),
+                    });
+                    loc = internal.children[i];
+                }
+                Node::Leaf(leaf) => {
+                    let (i, mut comparisons, ordering) =
+                        Self::traced_search(&leaf.keys, key, false);
+                    let step = |comparisons| ExplainStep {
+                        loc,
+                        kind: NodeKind::Leaf,
+                        keys: leaf.keys.len(),
+                        comparisons,
+                        child: None,
+                    };
+                    // Every key here is smaller, so in a multimap the first
+                    // record under `key`, if any, starts the next leaf
+                    if multimap
+                        && i == leaf.keys.len()
+                        && let Some(next) = leaf.next
+                    {
+                        explain.steps.push(step(comparisons));
+                        loc = next;
+                        continue;
+                    }
+                    let ordering = match (ordering, leaf.keys.get(i)) {
+                        (Some(ordering), _) => Some(ordering),
+                        (None, Some(other)) => {
+                            comparisons.push((other.clone(), key.cmp(other)));
+                            Some(key.cmp(other))
+                        }
+                        (None, None) => None,
+                    };
+                    explain.steps.push(step(comparisons));
+                    if ordering == Some(Ordering::Equal) {
+                        explain.value = Some(leaf.values[i].clone());
+                    }
+                    break;
+                }
+                Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                    return Err(Self::misplaced_page(loc));
+                }
+            }
+        }
+        explain.page_reads = self.storage.io_counters().0 - reads_before;
+        Ok(explain)
+    }
+
+    /// Binary search for the number of `keys` below `key`, or at most `key`
+    /// if `past_equal`, returning it with the comparisons made and how `key`
+    /// compared to the key at that index, if it was compared.
+    fn traced_search(
+        keys: &[K],
+        key: &K,
+        past_equal: bool,
+    ) -> (usize, Vec<(K, Ordering)>, Option<Ordering>) {
+        let (mut lo, mut hi) = (0, keys.len());
+        let mut comparisons = Vec::new();
+        let mut at_hi = None;
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let ordering = key.cmp(&keys[mid]);
+            comparisons.push((keys[mid].clone(), ordering));
+            if ordering == Ordering::Greater || (past_equal && ordering == Ordering::Equal) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+                at_hi = Some(ordering);
+            }
+        }
+        (lo, comparisons, at_hi)
+    }
+
+    /// The child of `internal` that `key` is routed to, with the exclusive
+    /// bound on its keys given `high`, the bound on those of `internal`.
+    fn route(internal: &InternalNode<K>, key: &K, high: &Option<K>) -> (usize, Option<K>) {
+        let i = internal.child_index(key);
+        let bound = match internal.keys.get(i) {
+            Some(separator) => Some(separator.clone()),
+            None => high.clone(),
+        };
+        (internal.children[i], bound)
+    }
+
+    /// Every record stored under `key`, in the order they were inserted.
+    /// Only a multimap holds more than one.
+    pub fn find_all(&mut self, key: &K) -> Result<Vec<V>, BTreeError> {
+        Ok(self
+            .range(key, key)?
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect())
+    }
+
+    /// Entry with the smallest key `>= key`, following the leaf chain when
+    /// `key` itself is absent, or `None` if every key is smaller.
+    pub fn find_ge(&mut self, key: &K) -> Result<Option<(K, V)>, BTreeError> {
+        let start = self.find_leaf(key);
+        Records::new(&mut self.storage, start)
+            .find(|entry| entry.as_ref().map_or(true, |(k, _)| k >= key))
+            .transpose()
+    }
+
+    /// Entry with the largest key `<= key`, following the leaf chain back
+    /// when `key` itself is absent, or `None` if every key is larger.
+    pub fn find_le(&mut self, key: &K) -> Result<Option<(K, V)>, BTreeError> {
+        // The leaf `key` is inserted into is the last that may hold it
+        let start = self.find_leaf_bounded(key).map(|(loc, _, _)| loc);
+        RevRecords::new(&mut self.storage, start)
+            .find(|entry| entry.as_ref().map_or(true, |(k, _)| k <= key))
+            .transpose()
+    }
+
+    fn rightmost_entry(&mut self, loc: usize) -> Result<Option<(K, V)>, BTreeError> {
+        let loc = self.rightmost_leaf_under(loc)?;
+        let leaf = self.read_leaf(loc)?;
+        Ok(leaf.keys.last().cloned().zip(leaf.values.last().cloned()))
+    }
+
+    /// Reads the page at `loc`, which the tree expects to hold a leaf.
+    pub(crate) fn read_leaf(&mut self, loc: usize) -> Result<LeafNode<K, V>, BTreeError> {
+        match self.storage.read_node(loc)? {
+            Node::Leaf(leaf) => Ok(leaf),
+            _ => Err(BTreeError::Corrupt {
+                loc,
+                reason: "expected a leaf",
+            }),
+        }
+    }
+
+    /// Reads the page at `loc`, which the tree expects to hold an internal
+    /// node.
+    fn read_internal(&mut self, loc: usize) -> Result<InternalNode<K>, BTreeError> {
+        match self.storage.read_node(loc)? {
+            Node::Internal(internal) => Ok(internal),
+            _ => Err(BTreeError::Corrupt {
+                loc,
+                reason: "expected an internal node",
+            }),
+        }
+    }
+
+    fn misplaced_page(loc: usize) -> BTreeError {
+        BTreeError::Corrupt {
+            loc,
+            reason: "header, Bloom filter or overflow page reached from inside the tree",
+        }
+    }
+
+    /// Position of `loc` among the children of `parent`, which lives at
+    /// `parent_loc`.
+    fn child_position(
+        parent: &InternalNode<K>,
+        parent_loc: usize,
+        loc: usize,
+    ) -> Result<usize, BTreeError> {
+        parent
+            .children
+            .iter()
+            .position(|&c| c == loc)
+            .ok_or(BTreeError::Corrupt {
+                loc: parent_loc,
+                reason: "child on the search path is missing from its parent",
+            })
+    }
+
+    fn try_compensate_siblings(
+        &mut self,
+        loc: usize,
+        leaf: LeafNode<K, V>,
+        path: &mut [(usize, InternalNode<K>)],
+    ) -> Result<bool, BTreeError> {
+        if path.is_empty() {
+            return Ok(false);
+        }
+
+        let (_, parent) = path.last().unwrap();
+
+        // Find our position in parent
+        let idx_in_parent = match parent.children.iter().position(|&c| c == loc) {
+            Some(idx) => idx,
+            None => return Ok(false),
+        };
+
+        // Try left sibling first
+        if idx_in_parent > 0 {
+            let left_loc = parent.children[idx_in_parent - 1];
+            if let Node::Leaf(left_sibling) = self.storage.read_node(left_loc)?
+                && let Some((new_left, new_right, new_parent)) = Self::compensate_leaves(
+                    left_sibling,
+                    leaf.clone(),
+                    parent.clone(),
+                    left_loc,
+                    loc,
+                    self.max_keys,
+                )
+            {
+                self.storage.write_node(left_loc, &Node::Leaf(new_left))?;
+                self.storage.write_node(loc, &Node::Leaf(new_right))?;
+
+                // The parent is written back with the rest of the path
+                path.last_mut().unwrap().1 = new_parent;
+                self.epoch += 1;
+                return Ok(true);
+            }
+        }
+
+        if idx_in_parent + 1 < parent.children.len() {
+            let right_loc = parent.children[idx_in_parent + 1];
+            if let Node::Leaf(right_sibling) = self.storage.read_node(right_loc)?
+                && let Some((new_left, new_right, new_parent)) = Self::compensate_leaves(
+                    leaf.clone(),
+                    right_sibling,
+                    parent.clone(),
+                    loc,
+                    right_loc,
+                    self.max_keys,
+                )
+            {
+                self.storage.write_node(loc, &Node::Leaf(new_left))?;
+                self.storage.write_node(right_loc, &Node::Leaf(new_right))?;
+
+                // The parent is written back with the rest of the path
+                path.last_mut().unwrap().1 = new_parent;
+                self.epoch += 1;
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn compensate_leaves(
+        mut left: LeafNode<K, V>,
+        mut right: LeafNode<K, V>,
+        mut parent: InternalNode<K>,
+        left_loc: usize,
+        right_loc: usize,
+        max_keys: usize,
+    ) -> Option<Compensated<K, V>> {
+        let total = left.keys.len() + right.keys.len();
+
+        if total > max_keys * 2 {
+            return None;
+        }
+
+        let mut all_keys = left.keys;
+        all_keys.append(&mut right.keys);
+        let mut all_values = left.values;
+        all_values.append(&mut right.values);
+
+        let mid = all_keys.len() / 2;
+
+        right.keys = all_keys.split_off(mid);
+        right.values = all_values.split_off(mid);
+        left.keys = all_keys;
+        left.values = all_values;
+
+        let left_idx = parent.children.iter().position(|&c| c == left_loc)?;
+        let right_idx = parent.children.iter().position(|&c| c == right_loc)?;
+
+        if right_idx != left_idx + 1 {
+            return None;
+        }
+
+        parent.keys[left_idx] = K::separator(left.keys.last()?, &right.keys[0]);
+        parent.counts[left_idx] = left.keys.len();
+        parent.counts[right_idx] = right.keys.len();
+
+        Some((left, right, parent))
+    }
+
+    /// Inserts `value` under `key`, replacing any record already stored there
+    /// unless the tree is a multimap.
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), BTreeError> {
+        self.upsert(key, value).map(|_| ())
+    }
+
+    /// Inserts like [`insert`](Self::insert), telling whether the key was
+    /// new or which value it replaced, without a separate lookup. In a
+    /// multimap every insert is new.
+    pub fn upsert(&mut self, key: K, value: V) -> Result<Upsert<V>, BTreeError> {
+        self.check_writable()?;
+        Self::check_key_size(&key)?;
+        Self::check_value_size(&value)?;
+        let (loc, mut leaf, mut path) = self.descend(&key)?;
+        let upsert = match self.slot(&leaf.keys, &key) {
+            Ok(i) => {
+                let old = std::mem::replace(&mut leaf.values[i], value);
+                self.free_overflow(&old)?;
+                Upsert::Updated(old)
+            }
+            Err(i) => {
+                self.bloom_add(&key)?;
+                leaf.keys.insert(i, key);
+                leaf.values.insert(i, value);
+                Upsert::Inserted
+            }
+        };
+        // Overwriting an existing key leaves the count unchanged
+        self.commit_leaf(loc, leaf, &mut path, upsert == Upsert::Inserted)?;
+        Ok(upsert)
+    }
+
+    /// Descends to the leaf `key` belongs in, returning its location, the
+    /// leaf, and the internal nodes passed on the way down.
+    pub(crate) fn descend(&mut self, key: &K) -> Result<Descent<K, V>, BTreeError> {
+        let mut path = Vec::new();
+        let mut current_loc = self.header.root;
+        let mut current_node = self.storage.read_node(current_loc)?;
+
+        while let Node::Internal(internal) = current_node {
+            path.push((current_loc, internal.clone()));
+            let i = internal.child_index(key);
+            current_loc = internal.children[i];
+            current_node = self.storage.read_node(current_loc)?;
+        }
+
+        match current_node {
+            Node::Leaf(leaf) => Ok((current_loc, leaf, path)),
+            _ => Err(Self::misplaced_page(current_loc)),
+        }
+    }
+
+    /// Stores a leaf from [`descend`](Self::descend) after changing it and
+    /// flushes. A leaf with a new entry may need evening out or splitting,
+    /// and the counts above it go up by one; one with only a value replaced
+    /// is written back as it is.
+    pub(crate) fn commit_leaf(
+        &mut self,
+        loc: usize,
+        leaf: LeafNode<K, V>,
+        path: &mut Vec<(usize, InternalNode<K>)>,
+        inserted: bool,
+    ) -> Result<(), BTreeError> {
+        if inserted {
+            Self::shift_counts(path, loc, 1)?;
+            self.store_leaf(loc, leaf, path)?;
+            self.write_path(path)?;
+            self.count += 1;
+        } else {
+            self.storage.write_node(loc, &Node::Leaf(leaf))?;
+        }
+        self.flush()
+    }
+
+    /// Returns the [`Entry`] for `key`, to read, insert or modify its value
+    /// with a single descent.
+    pub fn entry(&mut self, key: K) -> Result<Entry<'_, S, K, V, A>, BTreeError> {
+        let (loc, leaf, path) = self.descend(&key)?;
+        let slot = self.slot(&leaf.keys, &key);
+        Ok(Entry::new(self, key, loc, leaf, path, slot))
+    }
+
+    /// Writes back the leaf at `loc` after an insert into it. If it
+    /// overflowed, it is evened out with a sibling that has room, keeping
+    /// leaves full and the tree low, or else split.
+    fn store_leaf(
+        &mut self,
+        loc: usize,
+        leaf: LeafNode<K, V>,
+        path: &mut Vec<(usize, InternalNode<K>)>,
+    ) -> Result<(), BTreeError> {
+        if leaf.keys.len() > self.max_keys
+            && self.try_compensate_siblings(loc, leaf.clone(), path)?
+        {
+            self.leaf_redistributions += 1;
+            return Ok(());
+        }
+
+        // A split reaches the root only if every ancestor is already full.
+        if leaf.keys.len() > self.max_keys
+            && path.len() + 1 >= self.max_height
+            && path
+                .iter()
+                .all(|(_, node)| node.keys.len() >= self.max_keys)
+        {
+            return Err(BTreeError::HeightLimit {
+                max_height: self.max_height,
+            });
+        }
+
+        self.storage.write_node(loc, &Node::Leaf(leaf.clone()))?;
+
+        if leaf.keys.len() > self.max_keys {
+            self.split_leaf(loc, leaf, path)?;
+        }
+        Ok(())
+    }
+
+    /// Inserts `value`, going straight to the leaf cached in `hint` when the
+    /// key still routes there and the leaf has room, and raising the record
+    /// counts along the path kept with it. Falls back to a regular insert
+    /// (and clears the hint) when the leaf would split.
+    pub fn insert_with_hint(
+        &mut self,
+        key: K,
+        value: V,
+        hint: &mut InsertHint<K>,
+    ) -> Result<(), BTreeError> {
+        self.check_writable()?;
+        Self::check_key_size(&key)?;
+        Self::check_value_size(&value)?;
+        let (loc, mut leaf) = match hint.leaf {
+            Some(loc) if hint.covers(&key, self.epoch) => (loc, self.read_leaf(loc)?),
+            _ => {
+                let (loc, leaf, path) = self.descend(&key)?;
+                let (mut low, mut high) = (None, None);
+                for (_, internal) in &path {
+                    let i = internal.child_index(&key);
+                    if i > 0 {
+                        low = Some(internal.keys[i - 1].clone());
+                    }
+                    if i < internal.keys.len() {
+                        high = Some(internal.keys[i].clone());
+                    }
+                }
+                *hint = InsertHint {
+                    leaf: Some(loc),
+                    low,
+                    high,
+                    path,
+                    epoch: self.epoch,
+                };
+                (loc, leaf)
+            }
+        };
+
+        let is_new = match self.slot(&leaf.keys, &key) {
+            Ok(i) => {
+                let old = std::mem::replace(&mut leaf.values[i], value);
+                self.free_overflow(&old)?;
+                false
+            }
+            Err(i) => {
+                if leaf.keys.len() >= self.max_keys {
+                    hint.leaf = None;
+                    return self.insert(key, value);
+                }
+                self.bloom_add(&key)?;
+                leaf.keys.insert(i, key);
+                leaf.values.insert(i, value);
+                true
+            }
+        };
+        self.storage.write_node(loc, &Node::Leaf(leaf))?;
+        if is_new {
+            Self::shift_counts(&mut hint.path, loc, 1)?;
+            self.write_path(&hint.path)?;
+            // The path is as just written, so the hint stays good
+            hint.epoch = self.epoch;
+            self.count += 1;
+        }
+        self.flush()
+    }
+
+    /// Inserts every entry as [`insert`](Self::insert) would one at a time,
+    /// but sorted first so entries bound for the same leaf are merged into
+    /// it with a single descent and a single write, and the header is
+    /// written once at the end. A leaf that overflows is split or evened
+    /// out as on insert, and the entries after it descend again. When a key
+    /// repeats, the last entry wins. Fails part-way with
+    /// [`BTreeError::HeightLimit`] like `insert`, keeping the entries that
+    /// went into earlier leaves.
+    pub fn insert_batch<I>(&mut self, entries: I) -> Result<(), BTreeError>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        self.check_writable()?;
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        for (key, value) in &entries {
+            Self::check_key_size(key)?;
+            Self::check_value_size(value)?;
+        }
+        // Stable, so repeated keys are applied in the order they were given
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // The leaves merged before a failure stay, so the header must count
+        // them too
+        let merged = self.merge_sorted(entries);
+        let flushed = self.flush();
+        merged.and(flushed)
+    }
+
+    /// Merges entries sorted by key into the leaves they route to, one
+    /// descent and one write per leaf, for [`insert_batch`](Self::insert_batch).
+    /// Values replaced in a leaf only give up their overflow pages once the
+    /// leaf is stored.
+    fn merge_sorted(&mut self, entries: Vec<(K, V)>) -> Result<(), BTreeError> {
+        let mut entries = entries.into_iter().peekable();
+        while let Some(first) = entries.next() {
+            let mut path = Vec::new();
+            let mut loc = self.header.root;
+            // Bound on the keys routed to the leaf, exclusive
+            let mut high = None;
+            let mut leaf = loop {
+                match self.storage.read_node(loc)? {
+                    Node::Internal(internal) => {
+                        let i = internal.child_index(&first.0);
+                        if i < internal.keys.len() {
+                            high = Some(internal.keys[i].clone());
+                        }
+                        let child = internal.children[i];
+                        path.push((loc, internal));
+                        loc = child;
+                    }
+                    Node::Leaf(leaf) => break leaf,
+                    Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                        return Err(Self::misplaced_page(loc));
+                    }
+                }
+            };
+
+            let mut added = 0;
+            let mut replaced = Vec::new();
+            let mut next = Some(first);
+            while let Some((key, value)) = next.take() {
+                match self.slot(&leaf.keys, &key) {
+                    Ok(i) => replaced.push(std::mem::replace(&mut leaf.values[i], value)),
+                    Err(i) => {
+                        self.bloom_add(&key)?;
+                        leaf.keys.insert(i, key);
+                        leaf.values.insert(i, value);
+                        added += 1;
+                    }
+                }
+                if leaf.keys.len() > self.max_keys {
+                    break;
+                }
+                next = entries.next_if(|(key, _)| high.as_ref().is_none_or(|high| key < high));
+            }
+
+            Self::shift_counts(&mut path, loc, added as isize)?;
+            if leaf.keys.len() > self.max_keys {
+                self.store_leaf(loc, leaf, &mut path)?;
+            } else {
+                self.storage.write_node(loc, &Node::Leaf(leaf))?;
+            }
+            self.write_path(&path)?;
+            self.count += added;
+            for old in &replaced {
+                self.free_overflow(old)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where `key` goes among the sorted `keys` of a leaf: `Ok(i)` to
+    /// overwrite entry `i`, `Err(i)` to insert at `i`. A multimap never
+    /// overwrites, adding after any keys equal to `key`.
+    fn slot(&self, keys: &[K], key: &K) -> Result<usize, usize> {
+        if self.header.multimap {
+            return Err(keys.partition_point(|k| k <= key));
+        }
+        keys.binary_search(key)
+    }
+
+    fn split_leaf(
+        &mut self,
+        loc: usize,
+        leaf: LeafNode<K, V>,
+        path: &mut Vec<(usize, InternalNode<K>)>,
+    ) -> Result<(), BTreeError> {
+        self.epoch += 1;
+        self.leaf_splits += 1;
+        let mid = leaf.keys.len() / 2;
+        let left_loc = self.split_target(loc, path);
+        let new_leaf_loc = self.alloc_node();
+        let new_leaf = LeafNode {
+            keys: leaf.keys[mid..].to_vec(),
+            values: leaf.values[mid..].to_vec(),
+            next: leaf.next,
+            prev: Some(left_loc),
+        };
+
+        let original_leaf = LeafNode {
+            keys: leaf.keys[..mid].to_vec(),
+            values: leaf.values[..mid].to_vec(),
+            next: Some(new_leaf_loc),
+            prev: leaf.prev,
+        };
+
+        self.storage
+            .write_node(left_loc, &Node::Leaf(original_leaf))?;
+        self.storage
+            .write_node(new_leaf_loc, &Node::Leaf(new_leaf.clone()))?;
+        self.set_prev(leaf.next, new_leaf_loc)?;
+
+        let new_key = K::separator(&leaf.keys[mid - 1], &new_leaf.keys[0]);
+        let counts = (mid, new_leaf.keys.len());
+        if path.is_empty() {
+            self.grow_root(left_loc, new_key, new_leaf_loc, counts)?;
+        } else {
+            self.insert_into_parent(loc, new_key, new_leaf_loc, counts, path)?;
+        }
+        Ok(())
+    }
+
+    /// Where the left half of the node at `loc` goes when it splits: a new
+    /// page if it is a pinned root, which keeps its page for the new root,
+    /// and `loc` otherwise.
+    fn split_target(&mut self, loc: usize, path: &[(usize, InternalNode<K>)]) -> usize {
+        if path.is_empty() && self.header.pinned_root {
+            self.alloc_node()
+        } else {
+            loc
+        }
+    }
+
+    /// Puts a new root above the root that split into the nodes at
+    /// `left_loc` and `right_loc` with `key` between them, holding `counts`
+    /// records each. A pinned root is written over the old root's page;
+    /// otherwise it gets a page of its own.
+    fn grow_root(
+        &mut self,
+        left_loc: usize,
+        key: K,
+        right_loc: usize,
+        counts: (usize, usize),
+    ) -> Result<(), BTreeError> {
+        let new_root = Node::Internal(InternalNode {
+            keys: vec![key],
+            children: vec![left_loc, right_loc],
+            counts: vec![counts.0, counts.1],
+        });
+        let mut header = self.header.clone();
+        if !header.pinned_root {
+            header.root = self.alloc_node();
+        }
+        self.storage.write_node(header.root, &new_root)?;
+        header.height += 1;
+        self.write_header(header)
+    }
+
+    /// Adds `new_child_loc`, split off the node at `loc`, to the parent just
+    /// right of `loc` with `key` between them. `counts` are the records
+    /// left at `loc` and moved to the new child.
+    fn insert_into_parent(
+        &mut self,
+        loc: usize,
+        key: K,
+        new_child_loc: usize,
+        counts: (usize, usize),
+        path: &mut Vec<(usize, InternalNode<K>)>,
+    ) -> Result<(), BTreeError> {
+        let (parent_loc, mut parent) = path.pop().unwrap();
+
+        // Insert the new key and child into the parent. Found by position
+        // rather than by key, which a multimap may hold in several children
+        let i = Self::child_position(&parent, parent_loc, loc)?;
+        parent.keys.insert(i, key);
+        parent.children.insert(i + 1, new_child_loc);
+        parent.counts[i] = counts.0;
+        parent.counts.insert(i + 1, counts.1);
+
+        self.storage
+            .write_node(parent_loc, &Node::Internal(parent.clone()))?;
+
+        if parent.keys.len() > self.max_keys {
+            self.split_internal(parent_loc, parent, path)?;
+        }
+        Ok(())
+    }
+
+    fn split_internal(
+        &mut self,
+        loc: usize,
+        internal: InternalNode<K>,
+        path: &mut Vec<(usize, InternalNode<K>)>,
+    ) -> Result<(), BTreeError> {
+        self.epoch += 1;
+        let mid = internal.keys.len() / 2;
+        let new_internal = InternalNode {
+            keys: internal.keys[mid + 1..].to_vec(),
+            children: internal.children[mid + 1..].to_vec(),
+            counts: internal.counts[mid + 1..].to_vec(),
+        };
+        let left_loc = self.split_target(loc, path);
+        let new_internal_loc = self.alloc_node();
+
+        let original_internal = InternalNode {
+            keys: internal.keys[..mid].to_vec(),
+            children: internal.children[..mid + 1].to_vec(),
+            counts: internal.counts[..mid + 1].to_vec(),
+        };
+        let counts = (original_internal.count(), new_internal.count());
+
+        self.storage
+            .write_node(left_loc, &Node::Internal(original_internal))?;
+        self.storage
+            .write_node(new_internal_loc, &Node::Internal(new_internal))?;
+
+        let new_key = internal.keys[mid].clone();
+        if path.is_empty() {
+            self.grow_root(left_loc, new_key, new_internal_loc, counts)?;
+        } else {
+            self.insert_into_parent(loc, new_key, new_internal_loc, counts, path)?;
+        }
+        Ok(())
+    }
+
+    /// Removes `key`, returning its record if it was present. Underfull
+    /// nodes borrow from a sibling or merge with one, and an internal root
+    /// left with a single child is replaced by that child. In a multimap
+    /// only the first record under `key` is removed.
+    pub fn delete(&mut self, key: &K) -> Result<Option<V>, BTreeError> {
+        self.delete_where(key, |_| true)
+    }
+
+    /// Removes the record under `key` equal to `value`, returning whether
+    /// there was one. In a multimap the other records under `key` stay.
+    pub fn delete_entry(&mut self, key: &K, value: &V) -> Result<bool, BTreeError> {
+        Ok(self.delete_where(key, |v| v == value)?.is_some())
+    }
+
+    /// Removes the first record under `key` whose value `matches`.
+    fn delete_where(
+        &mut self,
+        key: &K,
+        matches: impl Fn(&V) -> bool,
+    ) -> Result<Option<V>, BTreeError> {
+        self.check_writable()?;
+        let mut path = Vec::new();
+        let Some((current_loc, mut leaf, i)) =
+            self.find_entry(self.header.root, key, &matches, &mut path)?
+        else {
+            return Ok(None);
+        };
+        leaf.keys.remove(i);
+        let old = leaf.values.remove(i);
+        self.free_overflow(&old)?;
+
+        // A removed first key may still be the separator routing to this
+        // leaf; replace it with the new first key so no stale key lingers
+        if i == 0
+            && let Some(first) = leaf.keys.first()
+            && first != key
+        {
+            self.replace_separator(&mut path, current_loc, key, first)?;
+        }
+
+        Self::shift_counts(&mut path, current_loc, -1)?;
+        if path.is_empty() || leaf.keys.len() >= self.min_keys() {
+            self.storage.write_node(current_loc, &Node::Leaf(leaf))?;
+        } else {
+            self.rebalance_leaf(current_loc, leaf, &mut path)?;
+        }
+        self.write_path(&path)?;
+        self.count -= 1;
+        self.flush()?;
+        Ok(Some(old))
+    }
+
+    /// Finds the first record under `key` whose value `matches`, below the
+    /// node at `loc`. Returns its leaf's location, the leaf and its index
+    /// there, with the internal nodes above it pushed onto `path`. In a
+    /// multimap every child that may hold `key` is searched in turn.
+    fn find_entry(
+        &mut self,
+        loc: usize,
+        key: &K,
+        matches: &impl Fn(&V) -> bool,
+        path: &mut Vec<(usize, InternalNode<K>)>,
+    ) -> Result<Option<Located<K, V>>, BTreeError> {
+        match self.storage.read_node(loc)? {
+            Node::Leaf(leaf) => {
+                let start = leaf.keys.partition_point(|k| k < key);
+                let end = leaf.keys.partition_point(|k| k <= key);
+                let found = (start..end).find(|&i| matches(&leaf.values[i]));
+                Ok(found.map(|i| (loc, leaf, i)))
+            }
+            Node::Internal(internal) => {
+                let first = self.search_index(&internal, key);
+                let last = internal.child_index(key);
+                path.push((loc, internal.clone()));
+                for &child in &internal.children[first..=last] {
+                    if let Some(found) = self.find_entry(child, key, matches, path)? {
+                        return Ok(Some(found));
+                    }
+                }
+                path.pop();
+                Ok(None)
+            }
+            Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => Err(Self::misplaced_page(loc)),
+        }
+    }
+
+    /// Replaces the separator just left of the leaf at `loc` with `new` if
+    /// it equals `old`, in the copy on `path` the caller writes back. That
+    /// separator sits in the nearest ancestor where the path does not take
+    /// the first child. Keys between the two now route to the leaf's left
+    /// neighbour, so insert hints for the leaf are dropped.
+    fn replace_separator(
+        &mut self,
+        path: &mut [(usize, InternalNode<K>)],
+        mut loc: usize,
+        old: &K,
+        new: &K,
+    ) -> Result<(), BTreeError> {
+        for (parent_loc, node) in path.iter_mut().rev() {
+            let idx = Self::child_position(node, *parent_loc, loc)?;
+            if idx > 0 {
+                if node.keys[idx - 1] == *old {
+                    node.keys[idx - 1] = new.clone();
+                    self.epoch += 1;
+                }
+                return Ok(());
+            }
+            loc = *parent_loc;
+        }
+        Ok(())
+    }
+
+    /// Adds `delta` to the record count each node on `path` keeps for the
+    /// child the path goes through, down to the leaf at `loc`.
+    fn shift_counts(
+        path: &mut [(usize, InternalNode<K>)],
+        mut loc: usize,
+        delta: isize,
+    ) -> Result<(), BTreeError> {
+        for (parent_loc, node) in path.iter_mut().rev() {
+            let idx = Self::child_position(node, *parent_loc, loc)?;
+            node.counts[idx] = node.counts[idx].saturating_add_signed(delta);
+            loc = *parent_loc;
+        }
+        Ok(())
+    }
+
+    /// Writes back the internal nodes still on `path` once a change below
+    /// them is done, with the counts and separators it left them. Copies of
+    /// them kept by insert hints are now stale.
+    fn write_path(&mut self, path: &[(usize, InternalNode<K>)]) -> Result<(), BTreeError> {
+        for (loc, node) in path {
+            self.storage
+                .write_node(*loc, &Node::Internal(node.clone()))?;
+        }
+        if !path.is_empty() {
+            self.epoch += 1;
+        }
+        Ok(())
+    }
+
+    /// `BTreeMap`-style name for [`delete`](Self::delete).
+    pub fn remove(&mut self, key: &K) -> Result<Option<V>, BTreeError> {
+        self.delete(key)
+    }
+
+    /// Removes every entry with a key in `range`, returning them in key
+    /// order. Small ranges are deleted key by key; once a range holds half
+    /// the records or more, the tree is rebuilt from the rest instead, which
+    /// writes each remaining leaf once rather than emptying and merging
+    /// leaves one at a time.
+    pub fn delete_range<R: RangeBounds<K>>(&mut self, range: R) -> Result<Vec<(K, V)>, BTreeError> {
+        self.check_writable()?;
+        let removed = self.records_in(&range).collect::<Result<Vec<_>, _>>()?;
+
+        if removed.len() * 2 >= self.count && !removed.is_empty() {
+            let kept = self
+                .iter()
+                .filter(|entry| entry.as_ref().map_or(true, |(key, _)| !range.contains(key)))
+                .collect::<Result<Vec<_>, _>>()?;
+            // Rebuilding would write over the overflow pages kept values use
+            if kept.iter().all(|(_, value)| value.overflow().is_none()) {
+                self.load_sorted(kept)?;
+                return Ok(removed);
+            }
+        }
+        for (key, _) in &removed {
+            self.delete(key)?;
+        }
+        Ok(removed)
+    }
+
+    /// Number of records with a key in `range`, counted along the leaf
+    /// chain from the first one without decoding any values, where the
+    /// storage can.
+    pub fn count_range<R: RangeBounds<K>>(&mut self, range: R) -> Result<usize, BTreeError> {
+        let start = self.range_start(&range);
+        Keys::new(&mut self.storage, start)
+            .skip_while(|key| key.as_ref().is_ok_and(|key| before_start(&range, key)))
+            .take_while(|key| key.as_ref().map_or(true, |key| range.contains(key)))
+            .try_fold(0, |count, key| key.map(|_| count + 1))
+    }
+
+    /// The record `n` places into key order, counting from 0, or `None` if
+    /// the tree holds `n` records or fewer. One descent, steered by the
+    /// record counts internal nodes keep for their children.
+    pub fn kth(&mut self, mut n: usize) -> Result<Option<(K, V)>, BTreeError> {
+        if n >= self.count {
+            return Ok(None);
+        }
+        let mut loc = self.header.root;
+        loop {
+            match self.storage.read_node(loc)? {
+                Node::Internal(internal) => {
+                    let mut i = 0;
+                    while i + 1 < internal.children.len() && n >= internal.counts[i] {
+                        n -= internal.counts[i];
+                        i += 1;
+                    }
+                    loc = internal.children[i];
+                }
+                Node::Leaf(leaf) => {
+                    return Ok(leaf.keys.get(n).cloned().zip(leaf.values.get(n).cloned()));
+                }
+                Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                    return Err(Self::misplaced_page(loc));
+                }
+            }
+        }
+    }
+
+    /// Number of records with a key below `key`, which is where `key` sits
+    /// or would go in key order. One descent, adding up the counts of the
+    /// children left of the path.
+    pub fn rank(&mut self, key: &K) -> Result<usize, BTreeError> {
+        let mut rank = 0;
+        let mut loc = self.header.root;
+        loop {
+            match self.storage.read_node(loc)? {
+                Node::Internal(internal) => {
+                    let i = internal.first_child_index(key);
+                    rank += internal.counts[..i].iter().sum::<usize>();
+                    loc = internal.children[i];
+                }
+                Node::Leaf(leaf) => return Ok(rank + leaf.keys.partition_point(|k| k < key)),
+                Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                    return Err(Self::misplaced_page(loc));
+                }
+            }
+        }
+    }
+
+    /// Iterates over the records with a key in `range`, in key order,
+    /// reading one leaf at a time.
+    fn records_in<'a, R: RangeBounds<K>>(
+        &'a mut self,
+        range: &'a R,
+    ) -> impl Iterator<Item = Result<(K, V), BTreeError>> + 'a {
+        let start = self.range_start(range);
+        Records::new(&mut self.storage, start)
+            .skip_while(|entry| {
+                entry
+                    .as_ref()
+                    .is_ok_and(|(key, _)| before_start(range, key))
+            })
+            .take_while(|entry| entry.as_ref().map_or(true, |(key, _)| range.contains(key)))
+    }
+
+    /// The leaf holding the first key that may be in `range`.
+    fn range_start<R: RangeBounds<K>>(&mut self, range: &R) -> Result<usize, BTreeError> {
+        match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => self.find_leaf(key),
+            Bound::Unbounded => self.leftmost_leaf(),
+        }
+    }
+
+    fn alloc_node(&mut self) -> usize {
+        self.allocator.allocate()
+    }
+
+    fn free_node(&mut self, loc: usize) {
+        if self.allocator.free_pages().len() < self.max_free {
+            self.allocator.free(loc);
+        }
+    }
+
+    /// Writes `header` to page 0 along with the current free list and record
+    /// count, adopting it only once it is safely on disk.
+    fn write_header(&mut self, mut header: HeaderNode) -> Result<(), BTreeError> {
+        header.free_list = self.allocator.free_pages().to_vec();
+        header.count = self.count;
+        let stored = self.on_disk(header.clone());
+        self.storage.write_node(0, &Node::Header(stored))?;
+        self.header = header;
+        Ok(())
+    }
+
+    /// Writes out the header if it changed, then makes every write durable
+    /// with the header landing after the pages it refers to. Each insert and
+    /// delete ends with a flush.
+    pub fn flush(&mut self) -> Result<(), BTreeError> {
+        self.write_bloom()?;
+        self.sync_header()?;
+        self.storage.flush()
+    }
+
+    /// Like [`flush`](Self::flush), but makes every write durable even when
+    /// the storage's durability mode or group commit would hold syncs back.
+    pub fn sync(&mut self) -> Result<(), BTreeError> {
+        self.write_bloom()?;
+        self.sync_header()?;
+        self.storage.sync()
+    }
+
+    /// Rewrites the header if pages were allocated or freed, or the record
+    /// count changed, since it was last written.
+    fn sync_header(&mut self) -> Result<(), BTreeError> {
+        if self.header.free_list != self.allocator.free_pages() || self.header.count != self.count {
+            self.write_header(self.header.clone())?;
+        }
+        Ok(())
+    }
+
+    fn min_keys(&self) -> usize {
+        self.max_keys / 2
+    }
+
+    fn rebalance_leaf(
+        &mut self,
+        loc: usize,
+        mut leaf: LeafNode<K, V>,
+        path: &mut Vec<(usize, InternalNode<K>)>,
+    ) -> Result<(), BTreeError> {
+        self.epoch += 1;
+        let (parent_loc, mut parent) = path.pop().unwrap();
+        let idx = Self::child_position(&parent, parent_loc, loc)?;
+
+        // Borrow the last record of the left sibling
+        if idx > 0 {
+            let left_loc = parent.children[idx - 1];
+            if let Node::Leaf(mut left) = self.storage.read_node(left_loc)?
+                && left.keys.len() > self.min_keys()
+            {
+                leaf.keys.insert(0, left.keys.pop().unwrap());
+                leaf.values.insert(0, left.values.pop().unwrap());
+                parent.keys[idx - 1] = K::separator(left.keys.last().unwrap(), &leaf.keys[0]);
+                parent.counts[idx - 1] -= 1;
+                parent.counts[idx] += 1;
+
+                self.storage.write_node(left_loc, &Node::Leaf(left))?;
+                self.storage.write_node(loc, &Node::Leaf(leaf))?;
+                return self.storage.write_node(parent_loc, &Node::Internal(parent));
+            }
+        }
+
+        // Borrow the first record of the right sibling
+        if idx + 1 < parent.children.len() {
+            let right_loc = parent.children[idx + 1];
+            if let Node::Leaf(mut right) = self.storage.read_node(right_loc)?
+                && right.keys.len() > self.min_keys()
+            {
+                leaf.keys.push(right.keys.remove(0));
+                leaf.values.push(right.values.remove(0));
+                parent.keys[idx] = K::separator(leaf.keys.last().unwrap(), &right.keys[0]);
+                parent.counts[idx] += 1;
+                parent.counts[idx + 1] -= 1;
+
+                self.storage.write_node(right_loc, &Node::Leaf(right))?;
+                self.storage.write_node(loc, &Node::Leaf(leaf))?;
+                return self.storage.write_node(parent_loc, &Node::Internal(parent));
+            }
+        }
+
+        // Neither sibling can spare a record, so merge with one of them
+        if idx > 0 {
+            let left_loc = parent.children[idx - 1];
+            let mut left = self.read_leaf(left_loc)?;
+            left.keys.append(&mut leaf.keys);
+            left.values.append(&mut leaf.values);
+            left.next = leaf.next;
+            self.storage.write_node(left_loc, &Node::Leaf(left))?;
+            self.set_prev(leaf.next, left_loc)?;
+
+            parent.keys.remove(idx - 1);
+            parent.children.remove(idx);
+            parent.counts[idx - 1] += parent.counts.remove(idx);
+            self.free_node(loc);
+        } else {
+            let right_loc = parent.children[idx + 1];
+            let mut right = self.read_leaf(right_loc)?;
+            leaf.keys.append(&mut right.keys);
+            leaf.values.append(&mut right.values);
+            leaf.next = right.next;
+            self.storage.write_node(loc, &Node::Leaf(leaf))?;
+            self.set_prev(right.next, loc)?;
+
+            parent.keys.remove(idx);
+            parent.children.remove(idx + 1);
+            parent.counts[idx] += parent.counts.remove(idx + 1);
+            self.free_node(right_loc);
+        }
+
+        self.rebalance_internal(parent_loc, parent, path)
+    }
+
+    /// Points the `prev` pointer of the leaf at `loc`, if any, at `prev`.
+    fn set_prev(&mut self, loc: Option<usize>, prev: usize) -> Result<(), BTreeError> {
+        if let Some(loc) = loc {
+            let mut leaf = self.read_leaf(loc)?;
+            leaf.prev = Some(prev);
+            self.storage.write_node(loc, &Node::Leaf(leaf))?;
+        }
+        Ok(())
+    }
+
+    fn rebalance_internal(
+        &mut self,
+        loc: usize,
+        mut node: InternalNode<K>,
+        path: &mut Vec<(usize, InternalNode<K>)>,
+    ) -> Result<(), BTreeError> {
+        if path.is_empty() {
+            if !node.keys.is_empty() {
+                return self.storage.write_node(loc, &Node::Internal(node));
+            }
+
+            // The root has a single child left, which becomes the new root.
+            // A pinned root takes over the child's contents instead.
+            let child = node.children[0];
+            let mut header = self.header.clone();
+            if header.pinned_root {
+                let contents = self.storage.read_node(child)?;
+                self.storage.write_node(loc, &contents)?;
+                self.free_node(child);
+            } else {
+                self.free_node(loc);
+                header.root = child;
+            }
+            header.height -= 1;
+            return self.write_header(header);
+        }
+
+        if node.keys.len() >= self.min_keys() {
+            return self.storage.write_node(loc, &Node::Internal(node));
+        }
+
+        let (parent_loc, mut parent) = path.pop().unwrap();
+        let idx = Self::child_position(&parent, parent_loc, loc)?;
+
+        // Rotate a child over from the left sibling through the parent
+        if idx > 0 {
+            let left_loc = parent.children[idx - 1];
+            if let Node::Internal(mut left) = self.storage.read_node(left_loc)?
+                && left.keys.len() > self.min_keys()
+            {
+                let moved = left.counts.pop().unwrap();
+                node.keys.insert(0, parent.keys[idx - 1].clone());
+                node.children.insert(0, left.children.pop().unwrap());
+                node.counts.insert(0, moved);
+                parent.keys[idx - 1] = left.keys.pop().unwrap();
+                parent.counts[idx - 1] -= moved;
+                parent.counts[idx] += moved;
+
+                self.storage.write_node(left_loc, &Node::Internal(left))?;
+                self.storage.write_node(loc, &Node::Internal(node))?;
+                return self.storage.write_node(parent_loc, &Node::Internal(parent));
+            }
+        }
+
+        // Rotate a child over from the right sibling through the parent
+        if idx + 1 < parent.children.len() {
+            let right_loc = parent.children[idx + 1];
+            if let Node::Internal(mut right) = self.storage.read_node(right_loc)?
+                && right.keys.len() > self.min_keys()
+            {
+                let moved = right.counts.remove(0);
+                node.keys.push(parent.keys[idx].clone());
+                node.children.push(right.children.remove(0));
+                node.counts.push(moved);
+                parent.keys[idx] = right.keys.remove(0);
+                parent.counts[idx] += moved;
+                parent.counts[idx + 1] -= moved;
+
+                self.storage.write_node(right_loc, &Node::Internal(right))?;
+                self.storage.write_node(loc, &Node::Internal(node))?;
+                return self.storage.write_node(parent_loc, &Node::Internal(parent));
+            }
+        }
+
+        // Merge with a sibling, pulling the separator down between them
+        if idx > 0 {
+            let left_loc = parent.children[idx - 1];
+            let mut left = self.read_internal(left_loc)?;
+            left.keys.push(parent.keys.remove(idx - 1));
+            left.keys.append(&mut node.keys);
+            left.children.append(&mut node.children);
+            left.counts.append(&mut node.counts);
+            self.storage.write_node(left_loc, &Node::Internal(left))?;
+
+            parent.children.remove(idx);
+            parent.counts[idx - 1] += parent.counts.remove(idx);
+            self.free_node(loc);
+        } else {
+            let right_loc = parent.children[idx + 1];
+            let mut right = self.read_internal(right_loc)?;
+            node.keys.push(parent.keys.remove(idx));
+            node.keys.append(&mut right.keys);
+            node.children.append(&mut right.children);
+            node.counts.append(&mut right.counts);
+            self.storage.write_node(loc, &Node::Internal(node))?;
+
+            parent.children.remove(idx + 1);
+            parent.counts[idx] += parent.counts.remove(idx + 1);
+            self.free_node(right_loc);
+        }
+
+        self.rebalance_internal(parent_loc, parent, path)
+    }
+
+    /// Relinks the leaf chain in the order the leaves appear under the root,
+    /// returning how many leaves had a wrong `next` or `prev` pointer.
+    pub fn repair_leaf_chain(&mut self) -> Result<usize, BTreeError> {
+        self.check_writable()?;
+        let mut leaves = Vec::new();
+        self.collect_leaves(self.header.root, &mut leaves)?;
+
+        let mut fixed = 0;
+        for (i, &loc) in leaves.iter().enumerate() {
+            let next = leaves.get(i + 1).copied();
+            let prev = i.checked_sub(1).map(|i| leaves[i]);
+            let mut leaf = self.read_leaf(loc)?;
+            if leaf.next != next || leaf.prev != prev {
+                leaf.next = next;
+                leaf.prev = prev;
+                self.storage.write_node(loc, &Node::Leaf(leaf))?;
+                fixed += 1;
+            }
+        }
+        self.flush()?;
+        Ok(fixed)
+    }
+
+    /// Walks the whole tree checking the B+ tree invariants: node fill,
+    /// ascending keys, separators bounding their subtrees, leaves at equal
+    /// depth, the leaf chain in both directions, and the record count.
+    /// Returns a description of the first violation found.
+    pub fn validate(&mut self) -> Result<(), String> {
+        let mut leaves = Vec::new();
+        let mut seen = HashSet::new();
+        self.validate_node(self.header.root, 0, (None, None), &mut seen, &mut leaves)?;
+
+        let depth = leaves[0].1;
+        if let Some(&(loc, other)) = leaves.iter().find(|(_, d)| *d != depth) {
+            return Err(format!(
+                "leaf {} is at depth {}, expected {} like leaf {}",
+                loc, other, depth, leaves[0].0
+            ));
+        }
+        if depth + 1 != self.header.height {
+            return Err(format!(
+                "tree has {} levels but the header records {}",
+                depth + 1,
+                self.header.height
+            ));
+        }
+
+        let mut count = 0;
+        for (i, &(loc, _)) in leaves.iter().enumerate() {
+            let leaf = self.read_leaf(loc).map_err(|err| err.to_string())?;
+            let next = leaves.get(i + 1).map(|&(loc, _)| loc);
+            let prev = i.checked_sub(1).map(|i| leaves[i].0);
+            if leaf.next != next {
+                return Err(format!(
+                    "leaf {} has next {:?}, expected {:?}",
+                    loc, leaf.next, next
+                ));
+            }
+            if leaf.prev != prev {
+                return Err(format!(
+                    "leaf {} has prev {:?}, expected {:?}",
+                    loc, leaf.prev, prev
+                ));
+            }
+            for key in &leaf.keys {
+                if self.bloom_rules_out(key).map_err(|err| err.to_string())? {
+                    return Err(format!("key {:?} is missing from the Bloom filter", key));
+                }
+            }
+            for value in &leaf.values {
+                let chain = self
+                    .overflow_chain(value.overflow())
+                    .map_err(|err| err.to_string())?;
+                for page in chain {
+                    if !seen.insert(page) {
+                        return Err(format!("overflow page {} is reached twice", page));
+                    }
+                }
+            }
+            count += leaf.keys.len();
+        }
+
+        if count != self.count {
+            return Err(format!(
+                "tree holds {} records but the header counts {}",
+                count, self.count
+            ));
+        }
+
+        // Pages of the other trees in the file are in use too
+        for root in self.roots() {
+            if root != self.header.root {
+                let pages = self.tree_pages(root).map_err(|err| err.to_string())?;
+                seen.extend(pages);
+            }
+        }
+        seen.extend(self.bloom_pages());
+        let mut listed = HashSet::new();
+        for &loc in self.allocator.free_pages() {
+            if loc == 0 || seen.contains(&loc) {
+                return Err(format!("page {} is on the free list but still in use", loc));
+            }
+            if !listed.insert(loc) {
+                return Err(format!("page {} is on the free list twice", loc));
+            }
+        }
+        Ok(())
+    }
+
+    /// Pages in storage that are neither the header, part of the main tree,
+    /// a bucket, the overflow pages of their values or the Bloom filter,
+    /// nor on the free list. These are leaked: nothing will read or reuse
+    /// them.
+    pub fn unreachable_pages(&mut self) -> Result<Vec<usize>, BTreeError> {
+        let mut used: HashSet<usize> = self.allocator.free_pages().iter().copied().collect();
+        used.insert(0);
+        used.extend(self.bloom_pages());
+        let mut pending = self.roots();
+        while let Some(loc) = pending.pop() {
+            if !used.insert(loc) {
+                continue;
+            }
+            match self.storage.read_node(loc)? {
+                Node::Internal(internal) => pending.extend(internal.children),
+                Node::Leaf(leaf) => pending.extend(leaf.values.iter().filter_map(V::overflow)),
+                Node::Overflow(page) => pending.extend(page.next),
+                Node::Header(_) | Node::Bloom(_) => {}
+            }
+        }
+        let total = self.storage.total_nodes()?;
+        Ok((0..total).filter(|loc| !used.contains(loc)).collect())
+    }
+
+    /// Moves every live node onto consecutive pages right after the header,
+    /// one tree after another, main tree first, each level by level from
+    /// the root so its leaves come last and in key order, then shrinks the
+    /// storage to fit. Dead and free pages are dropped and the leaf chains
+    /// are relinked along the way. Pinned roots stay on their pages, and the
+    /// other nodes fill in around them. The moved pages and the header go
+    /// out in one [`Storage::write_atomic`], so every tree is held in memory
+    /// meanwhile. A Bloom filter moves along after the trees and is then
+    /// rebuilt, forgetting deleted keys. Returns the bytes reclaimed.
+    pub fn compact(&mut self) -> Result<usize, BTreeError> {
+        self.check_writable()?;
+        self.flush()?;
+        let before = self.storage.total_nodes()?;
+
+        let header = self.on_disk(self.header.clone());
+        let pages = Self::compacted(header, |loc| self.storage.read_node(loc))?;
+        let end = pages.iter().map(|(loc, _)| loc + 1).max().unwrap();
+        self.storage.write_atomic(&pages)?;
+        self.storage.truncate(end)?;
+        self.reload()?;
+        if self.bloom.is_some() {
+            self.build_bloom_filter()?;
+        }
+        let after = self.storage.total_nodes()?;
+        Ok(before.saturating_sub(after) * self.storage.page_size())
+    }
+
+    /// Writes a compacted copy of every tree in the file to a new file at
+    /// `path`, replacing whatever it held, and syncs it. The copy is laid
+    /// out as [`compact`](Self::compact) would leave it, in a storage of the
+    /// same kind made by [`Storage::create_copy`], so an encrypted tree is
+    /// backed up encrypted under the same key. `path` naming the tree's own
+    /// file fails with [`BTreeError::BackupOverSource`]. Returns the pages
+    /// written.
+    pub fn backup(&mut self, path: &str) -> Result<usize, BTreeError> {
+        let target = self.storage.create_copy(path)?;
+        self.flush()?;
+        let header = self.on_disk(self.header.clone());
+        Self::write_backup(target, header, |loc| self.storage.read_node(loc))
+    }
+
+    /// Lays out the trees described by `header`, reading their nodes with
+    /// `read`, on consecutive pages from page 1 as [`compact`](Self::compact)
+    /// does, around pinned roots, whose pages are kept. Returns the pages in
+    /// order with the new header last.
+    fn compacted(
+        header: HeaderNode,
+        mut read: impl FnMut(usize) -> Result<Node<K, V>, BTreeError>,
+    ) -> Result<Pages<K, V>, BTreeError> {
+        let roots: Vec<usize> = std::iter::once(header.root)
+            .chain(header.buckets.iter().map(|bucket| bucket.root))
+            .collect();
+        let mut nodes = Vec::new();
+        // Positions in `nodes` of each tree's leaves
+        let mut leaf_runs = Vec::new();
+        for &root in &roots {
+            let mut leaves = 0;
+            let mut level = vec![root];
+            while !level.is_empty() {
+                let mut below = Vec::new();
+                leaves = 0;
+                for loc in level {
+                    let node = read(loc)?;
+                    match &node {
+                        Node::Internal(internal) => below.extend(&internal.children),
+                        Node::Leaf(_) => leaves += 1,
+                        Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                            return Err(Self::misplaced_page(loc));
+                        }
+                    }
+                    nodes.push((loc, node));
+                }
+                level = below;
+            }
+            leaf_runs.push(nodes.len() - leaves..nodes.len());
+        }
+        // Then the overflow pages of their values, each chain in order
+        let chains: Vec<usize> = nodes
+            .iter()
+            .filter_map(|(_, node)| match node {
+                Node::Leaf(leaf) => Some(leaf.values.iter().filter_map(V::overflow)),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        for first in chains {
+            let mut next = Some(first);
+            while let Some(loc) = next {
+                let node = read(loc)?;
+                next = match &node {
+                    Node::Overflow(page) => page.next,
+                    _ => {
+                        return Err(BTreeError::Corrupt {
+                            loc,
+                            reason: "overflow chain leads to a page of another kind",
+                        });
+                    }
+                };
+                nodes.push((loc, node));
+            }
+        }
+        // The Bloom filter's pages come last
+        for &loc in header.bloom.iter().flat_map(|bloom| &bloom.pages) {
+            nodes.push((loc, read(loc)?));
+        }
+
+        // Page 0 stays the header, so the nodes start at page 1. Pinned roots
+        // keep their pages and the other nodes fill in around them.
+        let mut moved: HashMap<usize, usize> = HashMap::new();
+        if header.pinned_root {
+            moved.extend(roots.iter().map(|&root| (root, root)));
+        }
+        let reserved: HashSet<usize> = moved.values().copied().collect();
+        let mut unreserved = (1..).filter(|loc| !reserved.contains(loc));
+        for (loc, _) in &nodes {
+            if !moved.contains_key(loc) {
+                moved.insert(*loc, unreserved.next().unwrap());
+            }
+        }
+        let old: Vec<usize> = nodes.iter().map(|(loc, _)| *loc).collect();
+        let mut pages = Vec::with_capacity(nodes.len() + 1);
+        for (i, (_, mut node)) in nodes.into_iter().enumerate() {
+            let loc = moved[&old[i]];
+            match &mut node {
+                Node::Internal(internal) => {
+                    for child in &mut internal.children {
+                        *child = moved[child];
+                    }
+                }
+                Node::Leaf(leaf) => {
+                    let run = leaf_runs.iter().find(|run| run.contains(&i)).unwrap();
+                    leaf.prev = (i > run.start).then(|| moved[&old[i - 1]]);
+                    leaf.next = (i + 1 < run.end).then(|| moved[&old[i + 1]]);
+                    for value in &mut leaf.values {
+                        if let Some(page) = value.overflow() {
+                            value.set_overflow(moved[&page]);
+                        }
+                    }
+                }
+                Node::Overflow(page) => page.next = page.next.map(|next| moved[&next]),
+                Node::Bloom(_) => {}
+                Node::Header(_) => unreachable!(),
+            }
+            pages.push((loc, node));
+        }
+        pages.sort_by_key(|(loc, _)| *loc);
+        // Pages skipped below a pinned root, listed highest first
+        let used: HashSet<usize> = moved.values().copied().collect();
+        let end = pages.last().map_or(1, |(loc, _)| loc + 1);
+        let mut free_list: Vec<usize> = (1..end)
+            .filter(|loc| !used.contains(loc))
+            .take(max_free_list_for(header.page_size))
+            .collect();
+        free_list.reverse();
+        let header = HeaderNode {
+            root: moved[&header.root],
+            free_list,
+            buckets: header
+                .buckets
+                .iter()
+                .map(|bucket| Bucket {
+                    root: moved[&bucket.root],
+                    ..bucket.clone()
+                })
+                .collect(),
+            bloom: header.bloom.as_ref().map(|bloom| BloomHeader {
+                pages: bloom.pages.iter().map(|loc| moved[loc]).collect(),
+                ..bloom.clone()
+            }),
+            ..header
+        };
+        pages.push((0, Node::Header(header)));
+        Ok(pages)
+    }
+
+    /// Writes the trees described by `header`, compacted, to `target`, pages
+    /// first and the header last.
+    fn write_backup(
+        mut target: S,
+        header: HeaderNode,
+        read: impl FnMut(usize) -> Result<Node<K, V>, BTreeError>,
+    ) -> Result<usize, BTreeError> {
+        let pages = Self::compacted(header, read)?;
+        for (loc, node) in &pages {
+            target.write_node(*loc, node)?;
+        }
+        target.sync()?;
+        Ok(pages.len())
+    }
+
+    /// Checks the subtree at `loc`, returning how many records it holds.
+    fn validate_node(
+        &mut self,
+        loc: usize,
+        depth: usize,
+        (low, high): (Option<&K>, Option<&K>),
+        seen: &mut HashSet<usize>,
+        leaves: &mut Vec<(usize, usize)>,
+    ) -> Result<usize, String> {
+        if !seen.insert(loc) {
+            return Err(format!("page {} is reachable more than once", loc));
+        }
+        let is_root = depth == 0;
+
+        let node = self.storage.read_node(loc).map_err(|err| err.to_string())?;
+        let (keys, records) = match node {
+            Node::Leaf(leaf) => {
+                if leaf.keys.len() != leaf.values.len() {
+                    return Err(format!(
+                        "leaf {} has {} keys but {} values",
+                        loc,
+                        leaf.keys.len(),
+                        leaf.values.len()
+                    ));
+                }
+                leaves.push((loc, depth));
+                let records = leaf.keys.len();
+                (leaf.keys, records)
+            }
+            Node::Internal(internal) => {
+                if internal.children.len() != internal.keys.len() + 1 {
+                    return Err(format!(
+                        "internal {} has {} keys but {} children",
+                        loc,
+                        internal.keys.len(),
+                        internal.children.len()
+                    ));
+                }
+                if internal.keys.is_empty() {
+                    return Err(format!("internal {} has no keys", loc));
+                }
+                if internal.counts.len() != internal.children.len() {
+                    return Err(format!(
+                        "internal {} has {} children but {} counts",
+                        loc,
+                        internal.children.len(),
+                        internal.counts.len()
+                    ));
+                }
+                for (i, &child) in internal.children.iter().enumerate() {
+                    let child_low = if i == 0 {
+                        low
+                    } else {
+                        Some(&internal.keys[i - 1])
+                    };
+                    let child_high = internal.keys.get(i).or(high);
+                    let records = self.validate_node(
+                        child,
+                        depth + 1,
+                        (child_low, child_high),
+                        seen,
+                        leaves,
+                    )?;
+                    if records != internal.counts[i] {
+                        return Err(format!(
+                            "internal {} counts {} records under child {}, which holds {}",
+                            loc, internal.counts[i], child, records
+                        ));
+                    }
+                }
+                let records = internal.count();
+                (internal.keys, records)
+            }
+            Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                return Err(format!(
+                    "page {} is a header, Bloom filter or overflow page inside the tree",
+                    loc
+                ));
+            }
+        };
+
+        if keys.len() > self.max_keys {
+            return Err(format!(
+                "page {} has {} keys, more than the maximum of {}",
+                loc,
+                keys.len(),
+                self.max_keys
+            ));
+        }
+        if !is_root && keys.len() < self.min_keys() {
+            return Err(format!(
+                "page {} has {} keys, fewer than the minimum of {}",
+                loc,
+                keys.len(),
+                self.min_keys()
+            ));
+        }
+        // A multimap may repeat keys, and separators equal to them
+        let multimap = self.header.multimap;
+        if let Some(i) = keys
+            .windows(2)
+            .position(|pair| pair[0] > pair[1] || !multimap && pair[0] == pair[1])
+        {
+            return Err(format!(
+                "page {} has keys out of order: {:?} before {:?}",
+                loc,
+                keys[i],
+                keys[i + 1]
+            ));
+        }
+        if let (Some(low), Some(first)) = (low, keys.first())
+            && first < low
+        {
+            return Err(format!(
+                "page {} holds {:?}, below its separator {:?}",
+                loc, first, low
+            ));
+        }
+        if let (Some(high), Some(last)) = (high, keys.last())
+            && (last > high || !multimap && last == high)
+        {
+            return Err(format!(
+                "page {} holds {:?}, not below its separator {:?}",
+                loc, last, high
+            ));
+        }
+        Ok(records)
+    }
+
+    fn collect_leaves(&mut self, loc: usize, leaves: &mut Vec<usize>) -> Result<(), BTreeError> {
+        match self.storage.read_node(loc)? {
+            Node::Internal(internal) => {
+                for child in internal.children {
+                    self.collect_leaves(child, leaves)?;
+                }
+            }
+            Node::Leaf(_) => leaves.push(loc),
+            Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                return Err(Self::misplaced_page(loc));
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the tree as a Graphviz DOT graph: internal nodes labelled
+    /// with their separator keys, edges to their children, and dashed edges
+    /// along the leaf chain.
+    pub fn to_dot(&mut self) -> Result<String, BTreeError> {
+        let mut dot = String::from("digraph btree {\n    node [shape=box];\n");
+        let mut chain = Vec::new();
+        let mut pending = vec![self.header.root];
+        while let Some(loc) = pending.pop() {
+            match self.storage.read_node(loc)? {
+                Node::Internal(internal) => {
+                    let label = Self::dot_label(loc, &internal.keys);
+                    let _ = writeln!(dot, "    n{} [label=\"{}\"];", loc, label);
+                    for child in &internal.children {
+                        let _ = writeln!(dot, "    n{} -> n{};", loc, child);
+                    }
+                    pending.extend(internal.children.iter().rev());
+                }
+                Node::Leaf(leaf) => {
+                    let label = Self::dot_label(loc, &leaf.keys);
+                    let _ = writeln!(dot, "    n{} [label=\"{}\", style=rounded];", loc, label);
+                    chain.push((loc, leaf.next));
+                }
+                Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                    return Err(Self::misplaced_page(loc));
+                }
+            }
+        }
+
+        let leaves: Vec<String> = chain.iter().map(|(loc, _)| format!("n{}", loc)).collect();
+        let _ = writeln!(dot, "    {{ rank=same; {} }}", leaves.join("; "));
+        for (loc, next) in chain {
+            if let Some(next) = next {
+                let _ = writeln!(
+                    dot,
+                    "    n{} -> n{} [style=dashed, constraint=false];",
+                    loc, next
+                );
+            }
+        }
+        dot.push_str("}\n");
+        Ok(dot)
+    }
+
+    /// Page location and keys, escaped for a quoted DOT label.
+    fn dot_label(loc: usize, keys: &[K]) -> String {
+        let keys: Vec<String> = keys.iter().map(|key| format!("{:?}", key)).collect();
+        format!("[{}] {}", loc, keys.join(" | "))
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    }
+
+    pub fn dump_tree(&mut self) {
+        self.dump_node(self.header.root, 0);
+    }
+
+    fn dump_node(&mut self, loc: usize, depth: usize) {
+        let indent = "  ".repeat(depth);
+
+        match self.storage.read_node(loc) {
+            Ok(Node::Leaf(leaf)) => {
+                println!("{}Leaf [{}] keys={:?}", indent, loc, leaf.keys);
+            }
+            Ok(Node::Internal(internal)) => {
+                println!("{}Internal [{}] keys={:?}", indent, loc, internal.keys);
+
+                for &child in &internal.children {
+                    self.dump_node(child, depth + 1);
+                }
+            }
+            Ok(Node::Header(_)) => {
+                println!("{}<header @{}>", indent, loc);
+            }
+            Ok(Node::Bloom(_)) => {
+                println!("{}<bloom filter @{}>", indent, loc);
+            }
+            Ok(Node::Overflow(_)) => {
+                println!("{}<overflow @{}>", indent, loc);
+            }
+            Err(err) => {
+                println!("{}<{}>", indent, err);
+            }
+        }
+    }
+
+    /// Starts a [`Txn`]: writes made through it stay in memory until
+    /// [`commit`](Txn::commit) applies them all at once. Pending writes to
+    /// the tree are flushed first.
+    pub fn begin(&mut self) -> Result<Txn<'_, S, K, V, A>, BTreeError> {
+        self.check_writable()?;
+        self.flush()?;
+        Ok(Txn::new(self))
+    }
+
+    /// Returns an unpositioned [`Cursor`] over the tree.
+    pub fn cursor(&mut self) -> Cursor<'_, S, K, V, A> {
+        Cursor::new(self)
+    }
+
+    /// Iterates over all records in key order, reading one leaf at a time.
+    pub fn iter(&mut self) -> Records<'_, S, K, V> {
+        let start = self.leftmost_leaf();
+        Records::new(&mut self.storage, start)
+    }
+
+    /// Iterates over all keys in order, decoding only the keys of each leaf
+    /// where the storage can.
+    pub fn keys(&mut self) -> Keys<'_, S, K, V> {
+        let start = self.leftmost_leaf();
+        Keys::new(&mut self.storage, start)
+    }
+
+    /// Iterates over all records in descending key order, following the
+    /// `prev` pointers from the rightmost leaf.
+    pub fn rev_iter(&mut self) -> RevRecords<'_, S, K, V> {
+        let start = self.rightmost_leaf();
+        RevRecords::new(&mut self.storage, start)
+    }
+
+    /// Returns every entry with a key in `lo..=hi`, in descending key order.
+    pub fn range_rev(&mut self, hi: &K, lo: &K) -> Result<Vec<(K, V)>, BTreeError> {
+        if lo > hi {
+            return Ok(Vec::new());
+        }
+        // The leaf `hi` is inserted into is the last that may hold it
+        let start = self.find_leaf_bounded(hi).map(|(loc, _, _)| loc);
+        RevRecords::new(&mut self.storage, start)
+            .skip_while(|entry| entry.as_ref().is_ok_and(|(key, _)| key > hi))
+            .take_while(|entry| entry.as_ref().map_or(true, |(key, _)| key >= lo))
+            .collect()
+    }
+
+    /// Returns every entry with a key in `lo..=hi`, in key order.
+    pub fn range(&mut self, lo: &K, hi: &K) -> Result<Vec<(K, V)>, BTreeError> {
+        if lo > hi {
+            return Ok(Vec::new());
+        }
+        let start = self.find_leaf(lo);
+        Records::new(&mut self.storage, start)
+            .skip_while(|entry| entry.as_ref().is_ok_and(|(key, _)| key < lo))
+            .take_while(|entry| entry.as_ref().map_or(true, |(key, _)| key <= hi))
+            .collect()
+    }
+
+    /// Returns the entries with a key in `range` whose value passes `keep`,
+    /// in key order. The predicate runs on each record during the leaf
+    /// walk, so only the matches are collected.
+    pub fn scan_filter<R, F>(&mut self, range: R, mut keep: F) -> Result<Vec<(K, V)>, BTreeError>
+    where
+        R: RangeBounds<K>,
+        F: FnMut(&V) -> bool,
+    {
+        self.records_in(&range)
+            .filter(|entry| entry.as_ref().map_or(true, |(_, value)| keep(value)))
+            .collect()
+    }
+
+    /// Maps each entry with a key in `range` through `project`, in key
+    /// order, keeping the `Some` results. This filters and narrows the
+    /// records in one pass along the leaves, so only the projected values
+    /// are collected.
+    pub fn select<R, T, F>(&mut self, range: R, mut project: F) -> Result<Vec<T>, BTreeError>
+    where
+        R: RangeBounds<K>,
+        F: FnMut(&K, &V) -> Option<T>,
+    {
+        self.records_in(&range)
+            .filter_map(|entry| match entry {
+                Ok((key, value)) => project(&key, &value).map(Ok),
+                Err(err) => Some(Err(err)),
+            })
+            .collect()
+    }
+
+    /// Compares this tree against `other`, reporting records that `other`
+    /// adds, removes, or changes. Both leaf chains are walked once in step.
+    pub fn diff<'a, S2, A2>(
+        &'a mut self,
+        other: &'a mut BPlusTree<S2, K, V, A2>,
+    ) -> DiffIter<'a, S, S2, K, V>
+    where
+        S2: Storage<K, V>,
+        A2: Allocator,
+    {
+        let old_start = self.leftmost_leaf();
+        let new_start = other.leftmost_leaf();
+        DiffIter::new(
+            Records::new(&mut self.storage, old_start),
+            Records::new(&mut other.storage, new_start),
+        )
+    }
+
+    /// Prints records in key order, starting at the first key `>= from` and
+    /// stopping after `limit` records when given.
+    pub fn dump_records(
+        &mut self,
+        from: Option<&K>,
+        limit: Option<usize>,
+    ) -> Result<(), BTreeError> {
+        let start = match from {
+            Some(key) => self.find_leaf(key),
+            None => self.leftmost_leaf(),
+        };
+
+        for entry in Records::new(&mut self.storage, start)
+            .skip_while(|entry| {
+                entry
+                    .as_ref()
+                    .is_ok_and(|(key, _)| from.is_some_and(|from| key < from))
+            })
+            .take(limit.unwrap_or(usize::MAX))
+        {
+            let (_, record) = entry?;
+            println!("{:?}", record);
+        }
+        Ok(())
+    }
+
+    /// Descends to the leaf that `key` belongs in. In a multimap this is
+    /// the first leaf that may hold `key`, which can be followed by others.
+    pub(crate) fn find_leaf(&mut self, key: &K) -> Result<usize, BTreeError> {
+        if !self.header.multimap {
+            return Ok(self.find_leaf_bounded(key)?.0);
+        }
+        let mut loc = self.header.root;
+        loop {
+            match self.storage.read_node(loc)? {
+                Node::Internal(internal) => {
+                    loc = internal.children[internal.first_child_index(key)]
+                }
+                Node::Leaf(_) => return Ok(loc),
+                Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                    return Err(Self::misplaced_page(loc));
+                }
+            }
+        }
+    }
+
+    /// Child of `internal` to search for `key`: the first that may hold it,
+    /// which in a multimap can come before the one it is inserted into.
+    fn search_index(&self, internal: &InternalNode<K>, key: &K) -> usize {
+        if self.header.multimap {
+            internal.first_child_index(key)
+        } else {
+            internal.child_index(key)
+        }
+    }
+
+    /// Descends to the leaf that `key` is inserted into, also returning the
+    /// range of keys routed to that leaf (`low` inclusive, `high` exclusive).
+    fn find_leaf_bounded(&mut self, key: &K) -> Result<(usize, Option<K>, Option<K>), BTreeError> {
+        let mut loc = self.header.root;
+        let (mut low, mut high) = (None, None);
+
+        loop {
+            match self.storage.read_node(loc)? {
+                Node::Internal(internal) => {
+                    let i = internal.child_index(key);
+                    if i > 0 {
+                        low = Some(internal.keys[i - 1].clone());
+                    }
+                    if i < internal.keys.len() {
+                        high = Some(internal.keys[i].clone());
+                    }
+                    loc = internal.children[i];
+                }
+                Node::Leaf(_) => return Ok((loc, low, high)),
+                Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                    return Err(Self::misplaced_page(loc));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn rightmost_leaf(&mut self) -> Result<usize, BTreeError> {
+        self.rightmost_leaf_under(self.header.root)
+    }
+
+    /// Descends from `loc` along the last children to a leaf.
+    fn rightmost_leaf_under(&mut self, mut loc: usize) -> Result<usize, BTreeError> {
+        loop {
+            match self.storage.read_node(loc)? {
+                Node::Internal(internal) => {
+                    loc = *internal.children.last().ok_or(BTreeError::Corrupt {
+                        loc,
+                        reason: "internal node has no children",
+                    })?;
+                }
+                Node::Leaf(_) => return Ok(loc),
+                Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                    return Err(Self::misplaced_page(loc));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn leftmost_leaf(&mut self) -> Result<usize, BTreeError> {
+        let mut loc = self.header.root;
+
+        loop {
+            match self.storage.read_node(loc)? {
+                Node::Internal(internal) => {
+                    loc = internal.children[0];
+                }
+                Node::Leaf(_) => return Ok(loc),
+                Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                    return Err(Self::misplaced_page(loc));
+                }
+            }
+        }
+    }
+}
+
+/// Lookups through a shared reference, for storages that allow it. They
+/// are what lets [`SharedBPlusTree`](crate::shared::SharedBPlusTree) serve
+/// several readers at once.
+impl<S, K, V, A> BPlusTree<S, K, V, A>
+where
+    S: SharedStorage<K, V>,
+    K: Key,
+    V: Value,
+    A: Allocator,
+{
+    /// Like [`get`](Self::get), through a shared reference.
+    pub fn get_shared(&self, key: &K) -> Result<Option<V>, BTreeError> {
+        if self.bloom_rules_out(key)? {
+            return Ok(None);
+        }
+        if self.header.multimap {
+            let first = self.range_shared(key, key)?.into_iter().next();
+            return Ok(first.map(|(_, value)| value));
+        }
+        let leaf = self.find_leaf_shared(key)?;
+        Ok(leaf
+            .keys
+            .binary_search(key)
+            .ok()
+            .map(|i| leaf.values[i].clone()))
+    }
+
+    /// Like [`range`](Self::range), through a shared reference.
+    pub fn range_shared(&self, lo: &K, hi: &K) -> Result<Vec<(K, V)>, BTreeError> {
+        let mut entries = Vec::new();
+        if lo > hi {
+            return Ok(entries);
+        }
+        self.scan_shared(lo, |key, value| {
+            if key > *hi {
+                return false;
+            }
+            entries.push((key, value));
+            true
+        })?;
+        Ok(entries)
+    }
+
+    /// Up to `limit` entries with keys `>= from`, in key order, through a
+    /// shared reference, for paging through the tree a batch at a time.
+    pub fn range_from_shared(&self, from: &K, limit: usize) -> Result<Vec<(K, V)>, BTreeError> {
+        let mut entries = Vec::new();
+        if limit == 0 {
+            return Ok(entries);
+        }
+        self.scan_shared(from, |key, value| {
+            entries.push((key, value));
+            entries.len() < limit
+        })?;
+        Ok(entries)
+    }
+
+    /// Feeds `visit` the entries with keys `>= lo` in key order, following
+    /// the leaf chain, until it returns `false` or the entries run out.
+    fn scan_shared(&self, lo: &K, mut visit: impl FnMut(K, V) -> bool) -> Result<(), BTreeError> {
+        let mut leaf = self.find_leaf_shared(lo)?;
+        loop {
+            for (key, value) in leaf.keys.into_iter().zip(leaf.values) {
+                if key >= *lo && !visit(key, value) {
+                    return Ok(());
+                }
+            }
+            let Some(next) = leaf.next else {
+                return Ok(());
+            };
+            leaf = match self.storage.read_node_shared(next)? {
+                Node::Leaf(leaf) => leaf,
+                _ => {
+                    return Err(BTreeError::Corrupt {
+                        loc: next,
+                        reason: "expected a leaf",
+                    });
+                }
+            };
+        }
+    }
+
+    /// Like [`backup`](Self::backup), through a shared reference, so
+    /// lookups can go on while the copy is written. Writes that have not
+    /// been flushed yet are copied as well.
+    pub fn backup_shared(&self, path: &str) -> Result<usize, BTreeError> {
+        let target = self.storage.create_copy(path)?;
+        let header = self.on_disk(HeaderNode {
+            count: self.count,
+            ..self.header.clone()
+        });
+        Self::write_backup(target, header, |loc| self.storage.read_node_shared(loc))
+    }
+
+    fn find_leaf_shared(&self, key: &K) -> Result<LeafNode<K, V>, BTreeError> {
+        let mut loc = self.header.root;
+        loop {
+            match self.storage.read_node_shared(loc)? {
+                Node::Internal(internal) => {
+                    loc = internal.children[self.search_index(&internal, key)]
+                }
+                Node::Leaf(leaf) => return Ok(leaf),
+                Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                    return Err(Self::misplaced_page(loc));
+                }
+            }
+        }
+    }
+}
+
+/// Whether `key` comes before every key in `range`.
+fn before_start<K: Ord, R: RangeBounds<K>>(range: &R, key: &K) -> bool {
+    match range.start_bound() {
+        Bound::Included(start) => key < start,
+        Bound::Excluded(start) => key <= start,
+        Bound::Unbounded => false,
+    }
+}
+
+impl<S, A> BPlusTree<S, i32, Record, A>
+where
+    S: Storage,
+    A: Allocator,
+{
+    /// Count, sum, minimum and maximum of field `field` of the records with
+    /// a key in `range`, gathered in one walk along the leaf chain without
+    /// collecting the records. Panics if `field` is not below 7.
+    pub fn aggregate<R: RangeBounds<i32>>(
+        &mut self,
+        range: R,
+        field: usize,
+    ) -> Result<Aggregate, BTreeError> {
+        assert!(field < 7, "records have 7 fields, not {}", field + 1);
+        let mut aggregate = Aggregate::default();
+        for entry in self.records_in(&range) {
+            let value = entry?.1[field];
+            aggregate.count += 1;
+            aggregate.sum += i64::from(value);
+            aggregate.min = Some(aggregate.min.map_or(value, |min| min.min(value)));
+            aggregate.max = Some(aggregate.max.map_or(value, |max| max.max(value)));
+        }
+        Ok(aggregate)
+    }
+
+    /// Sum of field `field` over the records with a key in `range`, as
+    /// [`aggregate`](Self::aggregate) gathers it.
+    pub fn sum_field<R: RangeBounds<i32>>(
+        &mut self,
+        range: R,
+        field: usize,
+    ) -> Result<i64, BTreeError> {
+        Ok(self.aggregate(range, field)?.sum)
+    }
+
+    /// Smallest field `field` among the records with a key in `range`, or
+    /// `None` if there are none.
+    pub fn min_field<R: RangeBounds<i32>>(
+        &mut self,
+        range: R,
+        field: usize,
+    ) -> Result<Option<i32>, BTreeError> {
+        Ok(self.aggregate(range, field)?.min)
+    }
+
+    /// Largest field `field` among the records with a key in `range`, or
+    /// `None` if there are none.
+    pub fn max_field<R: RangeBounds<i32>>(
+        &mut self,
+        range: R,
+        field: usize,
+    ) -> Result<Option<i32>, BTreeError> {
+        Ok(self.aggregate(range, field)?.max)
+    }
+
+    /// Fields `fields` of each record with a key in `range`, in key order,
+    /// as `[key, x]` for `fields = [0, 1]`. Panics if a field is not below 7.
+    pub fn select_fields<R: RangeBounds<i32>, const N: usize>(
+        &mut self,
+        range: R,
+        fields: [usize; N],
+    ) -> Result<Vec<[i32; N]>, BTreeError> {
+        assert!(
+            fields.iter().all(|&field| field < 7),
+            "records have 7 fields"
+        );
+        self.select(range, |_, record| Some(fields.map(|field| record[field])))
+    }
+
+    /// Returns the record whose key is numerically closest to `key`,
+    /// preferring the smaller key on ties.
+    pub fn find_nearest(&mut self, key: i32) -> Result<Option<Record>, BTreeError> {
+        let mut current_loc = self.header.root;
+        // Subtree just left of the descent path, holding the predecessor
+        // when it is not in the target leaf.
+        let mut left_loc = None;
+
+        let leaf = loop {
+            match self.storage.read_node(current_loc)? {
+                Node::Internal(internal) => {
+                    let i = internal.child_index(&key);
+                    if i > 0 {
+                        left_loc = Some(internal.children[i - 1]);
+                    }
+                    current_loc = internal.children[i];
+                }
+                Node::Leaf(leaf) => break leaf,
+                Node::Header(_) | Node::Bloom(_) | Node::Overflow(_) => {
+                    return Err(Self::misplaced_page(current_loc));
+                }
+            }
+        };
+
+        let pos = leaf.keys.partition_point(|&k| k < key);
+
+        let below = if pos > 0 {
+            Some((leaf.keys[pos - 1], leaf.values[pos - 1]))
+        } else {
+            match left_loc {
+                Some(loc) => self.rightmost_entry(loc)?,
+                None => None,
+            }
+        };
+
+        let above = if pos < leaf.keys.len() {
+            Some((leaf.keys[pos], leaf.values[pos]))
+        } else {
+            match leaf.next {
+                Some(next) => {
+                    let next = self.read_leaf(next)?;
+                    next.keys.first().copied().zip(next.values.first().copied())
+                }
+                None => None,
+            }
+        };
+
+        Ok(match (below, above) {
+            (Some((b, below)), Some((a, above))) => {
+                let below_diff = key as i64 - b as i64;
+                let above_diff = a as i64 - key as i64;
+                if above_diff < below_diff {
+                    Some(above)
+                } else {
+                    Some(below)
+                }
+            }
+            (b, a) => b.or(a).map(|(_, record)| record),
+        })
+    }
+}
+
+impl<S, K, A> BPlusTree<S, K, Blob, A>
+where
+    S: Storage<K, Blob>,
+    K: Key,
+    A: Allocator,
+{
+    /// Inserts `bytes` under `key` like [`insert`](Self::insert). Values
+    /// longer than [`INLINE_LIMIT`](crate::blob::INLINE_LIMIT) are written
+    /// to a chain of overflow pages first, and the leaf keeps only where
+    /// the chain starts; the pages of a value it replaces are freed.
+    pub fn insert_bytes(&mut self, key: K, bytes: &[u8]) -> Result<(), BTreeError> {
+        self.check_writable()?;
+        Self::check_key_size(&key)?;
+        if bytes.len() <= INLINE_LIMIT {
+            return self.insert(key, Blob::Inline(bytes.to_vec()));
+        }
+        let chunk = overflow_bytes_for(self.storage.page_capacity());
+        // Written back to front, so each page knows the one after it
+        let mut next = None;
+        let mut chain = Vec::new();
+        for data in bytes.chunks(chunk).rev() {
+            let loc = self.alloc_node();
+            chain.push(loc);
+            let page = OverflowPage {
+                data: data.to_vec(),
+                next,
+            };
+            self.storage.write_node(loc, &Node::Overflow(page))?;
+            next = Some(loc);
+        }
+        let blob = Blob::Overflow {
+            page: chain[chain.len() - 1],
+            len: bytes.len(),
+        };
+        if let Err(err) = self.insert(key, blob) {
+            for loc in chain {
+                self.free_node(loc);
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// The bytes stored under `key`, gathered from its overflow pages if it
+    /// has any.
+    pub fn get_bytes(&mut self, key: &K) -> Result<Option<Vec<u8>>, BTreeError> {
+        match self.get(key)? {
+            Some(blob) => self.read_blob(blob).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the value under `key` like [`delete`](Self::delete),
+    /// freeing its overflow pages, and returns its bytes.
+    pub fn delete_bytes(&mut self, key: &K) -> Result<Option<Vec<u8>>, BTreeError> {
+        self.check_writable()?;
+        let Some(bytes) = self.get_bytes(key)? else {
+            return Ok(None);
+        };
+        self.delete(key)?;
+        Ok(Some(bytes))
+    }
+
+    /// The bytes of `blob`, read from its overflow pages unless inline.
+    fn read_blob(&mut self, blob: Blob) -> Result<Vec<u8>, BTreeError> {
+        let (first, len) = match blob {
+            Blob::Inline(bytes) => return Ok(bytes),
+            Blob::Overflow { page, len } => (page, len),
+        };
+        let mut bytes = Vec::with_capacity(len);
+        let mut next = Some(first);
+        while let Some(loc) = next.filter(|_| bytes.len() < len) {
+            match self.storage.read_node(loc)? {
+                Node::Overflow(page) => {
+                    bytes.extend_from_slice(&page.data);
+                    next = page.next;
+                }
+                _ => {
+                    return Err(BTreeError::Corrupt {
+                        loc,
+                        reason: "overflow chain leads to a page of another kind",
+                    });
+                }
+            }
+        }
+        if bytes.len() != len || next.is_some() {
+            return Err(BTreeError::Corrupt {
+                loc: first,
+                reason: "overflow chain does not match the value's length",
+            });
+        }
+        Ok(bytes)
+    }
 }
 
-impl<S> BPlusTree<S>
-where
-    S: Storage,
-{
-    pub fn open(mut storage: S) -> Self {
-        let header: HeaderNode = if storage.total_nodes() == 0 {
-            let header = HeaderNode { root: 1 };
-            storage.write_node(0, &Node::Header(header.clone()));
-            storage.write_node(1, &Node::Leaf(LeafNode::new()));
-            header
-        } else {
-            match storage.read_node(0).unwrap() {
-                Node::Header(n) => n,
-                _ => panic!("first node must be header"),
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::codec;
+    use crate::config::{MAX_KEYS, PAGE_SIZE};
+    use crate::diff::Diff;
+    use crate::file_storage::{Durability, FileStorage};
+    use crate::key::{CaselessString, Collation, Descending};
+    use crate::memory_storage::InMemoryStorage;
+    use crate::sim_storage::SimRng;
+
+    /// Checks every lookup, the full scan and the length against `model`.
+    fn assert_matches(tree: &mut BPlusTree<InMemoryStorage>, model: &BTreeMap<i32, Record>) {
+        assert_eq!(tree.len(), model.len());
+        let entries: Vec<_> = tree.iter().collect::<Result<_, _>>().unwrap();
+        let expected: Vec<_> = model.iter().map(|(&key, &record)| (key, record)).collect();
+        assert_eq!(entries, expected);
+        for (key, record) in model {
+            assert_eq!(tree.get(key).unwrap(), Some(*record));
+        }
+    }
+
+    #[test]
+    fn range_rev_returns_every_duplicate_in_a_multimap() {
+        let mut tree: BPlusTree<InMemoryStorage> =
+            BPlusTree::open_multimap(InMemoryStorage::new()).unwrap();
+        // Enough duplicates to span several leaves
+        let dups = tree.max_keys as i32 * 3;
+        for i in 0..dups {
+            tree.insert(5, [5, i, 0, 0, 0, 0, 0]).unwrap();
+        }
+        for i in 0..20 {
+            tree.insert(i * 10, [i * 10; 7]).unwrap();
+        }
+        let mut expected = tree.range(&5, &5).unwrap();
+        assert_eq!(expected.len(), dups as usize);
+        expected.reverse();
+        assert_eq!(tree.range_rev(&5, &5).unwrap(), expected);
+
+        let mut expected = tree.range(&0, &100).unwrap();
+        expected.reverse();
+        assert_eq!(tree.range_rev(&100, &0).unwrap(), expected);
+    }
+
+    #[test]
+    fn hinted_inserts_read_only_the_leaf_and_keep_counts() {
+        // Half-full leaves leave room for the hinted inserts
+        let entries = (0..100).map(|key| (key * 10, [key * 10; 7]));
+        let mut tree: BPlusTree<InMemoryStorage> =
+            BPlusTree::bulk_load_with_fill(InMemoryStorage::new(), entries, 0.5).unwrap();
+        assert!(tree.header.height > 1);
+
+        let mut hint = InsertHint::default();
+        tree.insert_with_hint(1, [1; 7], &mut hint).unwrap();
+        let reads = tree.storage.page_reads;
+        tree.insert_with_hint(2, [2; 7], &mut hint).unwrap();
+        assert_eq!(tree.storage.page_reads - reads, 1);
+
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 102);
+        assert_eq!(tree.rank(&10).unwrap(), 3);
+        assert_eq!(tree.kth(2).unwrap(), Some((2, [2; 7])));
+    }
+
+    #[test]
+    fn hint_is_dropped_when_its_leafs_separator_moves() {
+        let entries = (0..100).map(|key| (key * 10, [key * 10; 7]));
+        let mut tree: BPlusTree<InMemoryStorage> =
+            BPlusTree::bulk_load_with_fill(InMemoryStorage::new(), entries, 0.5).unwrap();
+        let first = tree.leftmost_leaf().unwrap();
+        let second = tree.read_leaf(first).unwrap().next.unwrap();
+        let first_key = tree.read_leaf(second).unwrap().keys[0];
+
+        // Prime a hint on the second leaf, then delete its first key, which
+        // is also the separator routing to it
+        let mut hint = InsertHint::default();
+        tree.insert_with_hint(first_key + 1, [0; 7], &mut hint)
+            .unwrap();
+        tree.delete(&first_key).unwrap();
+
+        // Now routed to the first leaf, so it must not land in the second
+        tree.insert_with_hint(first_key, [1; 7], &mut hint).unwrap();
+        assert_eq!(tree.get(&first_key).unwrap(), Some([1; 7]));
+        tree.validate().unwrap();
+    }
+
+    #[test]
+    fn stats_size_pages_by_the_storage_page_size() {
+        let path = std::env::temp_dir().join(format!("btree-stats-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let mut tree: BPlusTree<FileStorage> =
+            BPlusTree::open(FileStorage::new(path).unwrap()).unwrap();
+        tree.insert(1, [1; 7]).unwrap();
+        let stats = tree.stats().unwrap();
+        assert_eq!(
+            stats.file_size,
+            tree.storage.total_nodes().unwrap() * PAGE_SIZE
+        );
+        drop(tree);
+        std::fs::remove_file(path).unwrap();
+
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        tree.insert(1, [1; 7]).unwrap();
+        assert_eq!(tree.stats().unwrap().file_size, 0);
+    }
+
+    #[test]
+    fn a_file_page_holds_as_many_keys_as_fit_it() {
+        crate::config::TEST_MAX_KEYS.set(usize::MAX);
+        for name in [codec::SLOTTED, codec::FIXED_LAYOUT, codec::BINCODE] {
+            let path = std::env::temp_dir().join(format!(
+                "btree-fanout-{}-{}.db",
+                name,
+                std::process::id()
+            ));
+            let path = path.to_str().unwrap();
+            let _ = std::fs::remove_file(path);
+            let open = || {
+                let mut storage = match name {
+                    codec::SLOTTED => FileStorage::new(path).unwrap(),
+                    codec::FIXED_LAYOUT => {
+                        FileStorage::with_codec(path, codec::FixedLayout).unwrap()
+                    }
+                    _ => FileStorage::with_codec(path, codec::Bincode).unwrap(),
+                };
+                storage.set_durability(Durability::NoSync);
+                BPlusTree::<FileStorage>::open(storage).unwrap()
+            };
+            let capacity = max_keys_for::<i32, Record>(crate::file_storage::PAYLOAD_SIZE);
+            assert!(capacity > MAX_KEYS);
+            let mut tree = open();
+            assert_eq!(tree.max_keys, capacity);
+
+            // Inserts into full leaves split them, and the halves still fit
+            let keys = capacity as i32 * 3;
+            tree.load_sorted((0..keys).map(|key| (key * 2, [key; 7])))
+                .unwrap();
+            assert_eq!(tree.header.height, 2);
+            for key in 0..10 {
+                tree.insert(key * 100 + 1, [-key; 7]).unwrap();
             }
+            drop(tree);
+            let mut tree = open();
+            tree.validate().unwrap();
+            assert_eq!(tree.header.height, 2);
+            assert_eq!(tree.len(), keys as usize + 10);
+            assert_eq!(tree.get(&(keys * 2 - 2)).unwrap(), Some([keys - 1; 7]));
+            assert_eq!(tree.get(&901).unwrap(), Some([-9; 7]));
+            drop(tree);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn find_nearest_compares_keys_rather_than_record_fields() {
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        // The first field runs against the keys
+        for key in (0..100).map(|key| key * 10) {
+            tree.insert(key, [1000 - key, key, 0, 0, 0, 0, 0]).unwrap();
+        }
+        let nearest = |tree: &mut BPlusTree<InMemoryStorage>, key| {
+            tree.find_nearest(key).unwrap().map(|record| record[1])
         };
-        BPlusTree {
-            storage,
-            header: header,
+        assert_eq!(nearest(&mut tree, 12), Some(10));
+        assert_eq!(nearest(&mut tree, 18), Some(20));
+        // Ties go to the smaller key
+        assert_eq!(nearest(&mut tree, 15), Some(10));
+        assert_eq!(nearest(&mut tree, -50), Some(0));
+        assert_eq!(nearest(&mut tree, 5000), Some(990));
+        // Across leaves, from either side
+        for key in (0..99).map(|key| key * 10) {
+            assert_eq!(nearest(&mut tree, key + 4), Some(key));
+            assert_eq!(nearest(&mut tree, key + 6), Some(key + 10));
         }
     }
 
-    pub fn calculate_depth(&mut self) -> usize {
-        let mut depth = 0;
-        let mut loc = self.header.root;
+    #[test]
+    fn a_pinned_root_keeps_its_page_through_every_rebuild() {
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let mut model = BTreeMap::new();
+        for key in 0..300 {
+            tree.insert(key, [key; 7]).unwrap();
+            model.insert(key, [key; 7]);
+        }
+        // Pinned where unpinned splits left it, away from the first page
+        let root = tree.header.root;
+        assert_ne!(root, 1);
+        tree.set_root_pinned(true).unwrap();
+        tree.set_indexed_field(2).unwrap();
 
-        loop {
-            match self.storage.read_node(loc) {
-                Some(Node::Internal(internal)) => {
-                    depth += 1;
-                    loc = internal.children[0];
+        // Rebuilt from the few records left, the root lies past the new tree
+        let removed = tree.delete_range(10..).unwrap();
+        assert_eq!(removed.len(), 290);
+        model.retain(|&key, _| key < 10);
+        for (rebuilt, step) in ["delete_range", "compact", "load_sorted"]
+            .iter()
+            .enumerate()
+        {
+            match rebuilt {
+                1 => {
+                    tree.compact().unwrap();
                 }
-                Some(Node::Leaf(_)) => {
-                    return depth + 1;
+                2 => {
+                    let entries: Vec<_> = (0..500).map(|key| (key, [key; 7])).collect();
+                    model = entries.iter().copied().collect();
+                    tree.load_sorted(entries).unwrap();
                 }
-                _ => panic!("Corrupt tree"),
+                _ => {}
             }
+            assert_eq!(tree.header.root, root, "after {}", step);
+            assert!(tree.is_root_pinned(), "after {}", step);
+            assert_eq!(tree.indexed_field(), Some(2), "after {}", step);
+            tree.validate().unwrap();
+            assert!(
+                tree.unreachable_pages().unwrap().is_empty(),
+                "after {}",
+                step
+            );
+            assert_matches(&mut tree, &model);
+        }
+
+        // And the tree grows on from there as pinned
+        for key in 500..700 {
+            tree.insert(key, [key; 7]).unwrap();
         }
+        tree.compact().unwrap();
+        assert_eq!(tree.header.root, root);
+        tree.validate().unwrap();
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(tree.storage).unwrap();
+        assert_eq!(tree.header.root, root);
+        assert_eq!(tree.len(), 700);
+        tree.validate().unwrap();
     }
 
-    pub fn count_total_keys(&mut self) -> usize {
-        let mut count = 0;
-        let mut loc = self.leftmost_leaf();
+    #[test]
+    fn a_tree_sharing_its_file_with_buckets_is_reloaded_in_batches() {
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        tree.create_bucket("other").unwrap();
+        tree.use_bucket(Some("other")).unwrap();
+        tree.insert(1, [1; 7]).unwrap();
+        tree.use_bucket(None).unwrap();
+        for key in 0..50 {
+            tree.insert(key, [key; 7]).unwrap();
+        }
 
-        while let Some(Node::Leaf(leaf)) = self.storage.read_node(loc) {
-            count += leaf.keys.len();
-            match leaf.next {
-                Some(next) => loc = next,
-                None => break,
-            }
+        // More than one batch of entries, streamed in
+        let total = (BULK_BATCH * tree.max_keys * 2 + 7) as i32;
+        tree.load_sorted((0..total).map(|key| (key * 2, [key; 7])))
+            .unwrap();
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), total as usize);
+        assert_eq!(tree.get(&10).unwrap(), Some([5; 7]));
+
+        let unsorted = [(3, [3; 7]), (9, [9; 7]), (4, [4; 7])];
+        assert!(matches!(
+            tree.load_sorted(unsorted),
+            Err(BTreeError::Unsorted { index: 2 })
+        ));
+        assert!(tree.is_empty());
+        tree.validate().unwrap();
+        tree.use_bucket(Some("other")).unwrap();
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn unsorted_bulk_input_fails_and_leaves_an_empty_tree() {
+        let mut entries: Vec<(i32, Record)> = (0..100).map(|key| (key, [key; 7])).collect();
+        entries.swap(60, 61);
+        match BPlusTree::<InMemoryStorage>::bulk_load(InMemoryStorage::new(), entries.clone()) {
+            Err(BTreeError::Unsorted { index }) => assert_eq!(index, 61),
+            other => panic!("expected Unsorted, got {:?}", other.map(|tree| tree.len())),
         }
-        count
+
+        let mut tree: BPlusTree<InMemoryStorage> =
+            BPlusTree::bulk_load(InMemoryStorage::new(), (0..100).map(|key| (key, [key; 7])))
+                .unwrap();
+        assert!(matches!(
+            tree.load_sorted(entries),
+            Err(BTreeError::Unsorted { index: 61 })
+        ));
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 0);
+        assert_eq!(tree.get(&10).unwrap(), None);
+        tree.insert(10, [10; 7]).unwrap();
+        assert_eq!(tree.len(), 1);
+
+        // Equal keys only belong in a multimap
+        assert!(matches!(
+            tree.load_sorted([(1, [1; 7]), (1, [2; 7])]),
+            Err(BTreeError::Unsorted { index: 1 })
+        ));
     }
 
-    pub fn find(&mut self, key: i32) -> Option<Record> {
-        let mut current_loc = self.header.root;
-        loop {
-            let node = self.storage.read_node(current_loc)?;
-            match node {
-                Node::Internal(internal) => {
-                    let mut i = 0;
-                    while i < internal.keys.len() && key >= internal.keys[i] {
-                        i += 1;
-                    }
-                    current_loc = internal.children[i];
-                }
-                Node::Leaf(leaf) => {
-                    for (i, k) in leaf.keys.iter().enumerate() {
-                        if *k == key {
-                            return Some(leaf.values[i]);
-                        }
-                    }
-                    return None;
+    #[test]
+    fn random_inserts_and_deletes_match_a_btreemap() {
+        for seed in 0..10 {
+            let mut rng = SimRng(seed);
+            let mut tree: BPlusTree<InMemoryStorage> =
+                BPlusTree::open(InMemoryStorage::new()).unwrap();
+            let mut model = BTreeMap::new();
+            // A narrow key space, so deletes hit and inserts overwrite
+            for i in 0..2000 {
+                let key = rng.below(500) as i32;
+                if rng.chance(0.6) {
+                    let record = [key, i, 0, 0, 0, 0, 0];
+                    tree.insert(key, record).unwrap();
+                    model.insert(key, record);
+                } else {
+                    assert_eq!(tree.delete(&key).unwrap(), model.remove(&key));
                 }
-                Node::Header(_) => {
-                    panic!("What happened?")
+                if let Err(err) = tree.validate() {
+                    panic!("seed {} op {}: {}", seed, i, err);
                 }
+                assert_eq!(tree.len(), model.len());
+            }
+            assert_matches(&mut tree, &model);
+
+            // Draining it merges every leaf back into the root
+            let keys: Vec<_> = model.keys().copied().collect();
+            for key in keys {
+                assert_eq!(tree.delete(&key).unwrap(), model.remove(&key));
+                tree.validate().unwrap();
             }
+            assert!(tree.is_empty());
+            assert_matches(&mut tree, &model);
         }
     }
 
-    fn try_compensate_siblings(
-        &mut self,
-        loc: usize,
-        leaf: LeafNode,
-        path: &mut Vec<(usize, InternalNode)>,
-    ) -> bool {
-        if path.is_empty() {
-            return false;
-        }
+    #[test]
+    fn validate_reports_what_is_broken() {
+        let build = || {
+            let mut tree: BPlusTree<InMemoryStorage> =
+                BPlusTree::open(InMemoryStorage::new()).unwrap();
+            for key in 0..100 {
+                tree.insert(key, [key; 7]).unwrap();
+            }
+            tree.validate().unwrap();
+            let first = tree.leftmost_leaf().unwrap();
+            (tree, first)
+        };
+        let broken = |tree: &mut BPlusTree<InMemoryStorage>| tree.validate().unwrap_err();
 
-        let (parent_loc, parent) = path.last().unwrap();
-        let parent_loc = *parent_loc;
+        let (mut tree, first) = build();
+        let mut leaf = tree.read_leaf(first).unwrap();
+        leaf.keys.swap(0, 1);
+        tree.storage.write_node(first, &Node::Leaf(leaf)).unwrap();
+        assert!(broken(&mut tree).contains("out of order"));
 
-        // Find our position in parent
-        let idx_in_parent = match parent.children.iter().position(|&c| c == loc) {
-            Some(idx) => idx,
-            None => return false,
+        let (mut tree, first) = build();
+        let mut leaf = tree.read_leaf(first).unwrap();
+        leaf.next = None;
+        tree.storage.write_node(first, &Node::Leaf(leaf)).unwrap();
+        assert!(broken(&mut tree).contains("has next None"));
+
+        let (mut tree, _) = build();
+        let root = tree.header.root;
+        let Node::Internal(mut internal) = tree.storage.read_node(root).unwrap() else {
+            panic!("root is a leaf");
         };
+        internal.counts[0] += 1;
+        tree.storage
+            .write_node(root, &Node::Internal(internal))
+            .unwrap();
+        assert!(broken(&mut tree).contains("counts"));
 
-        // Try left sibling first
-        if idx_in_parent > 0 {
-            let left_loc = parent.children[idx_in_parent - 1];
-            if let Some(Node::Leaf(left_sibling)) = self.storage.read_node(left_loc) {
-                if let Some((new_left, new_right, new_parent)) = Self::compensate_leaves(
-                    left_sibling,
-                    leaf.clone(),
-                    parent.clone(),
-                    left_loc,
-                    loc,
-                ) {
-                    self.storage.write_node(left_loc, &Node::Leaf(new_left));
-                    self.storage.write_node(loc, &Node::Leaf(new_right));
+        let (mut tree, _) = build();
+        tree.count += 1;
+        assert!(broken(&mut tree).contains("header counts"));
+    }
 
-                    // Update parent in path and storage
-                    path.last_mut().unwrap().1 = new_parent.clone();
-                    self.storage
-                        .write_node(parent_loc, &Node::Internal(new_parent));
-                    return true;
-                }
-            }
+    #[test]
+    fn a_file_starting_with_a_leaf_is_refused() {
+        let path = std::env::temp_dir().join(format!("btree-leaf-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        {
+            let mut storage: FileStorage = FileStorage::new(path).unwrap();
+            let mut leaf = LeafNode::new();
+            leaf.keys.push(1);
+            leaf.values.push([1; 7]);
+            storage.write_node(0, &Node::Leaf(leaf)).unwrap();
+            storage.flush().unwrap();
         }
+        let opened = BPlusTree::<FileStorage>::open(FileStorage::new(path).unwrap());
+        assert!(matches!(opened, Err(BTreeError::NotAHeader)));
+        std::fs::remove_file(path).unwrap();
+    }
 
-        if idx_in_parent + 1 < parent.children.len() {
-            let right_loc = parent.children[idx_in_parent + 1];
-            if let Some(Node::Leaf(right_sibling)) = self.storage.read_node(right_loc) {
-                if let Some((new_left, new_right, new_parent)) = Self::compensate_leaves(
-                    leaf.clone(),
-                    right_sibling,
-                    parent.clone(),
-                    loc,
-                    right_loc,
-                ) {
-                    self.storage.write_node(loc, &Node::Leaf(new_left));
-                    self.storage.write_node(right_loc, &Node::Leaf(new_right));
-
-                    // Update parent in path and storage
-                    path.last_mut().unwrap().1 = new_parent.clone();
-                    self.storage
-                        .write_node(parent_loc, &Node::Internal(new_parent));
-                    return true;
-                }
+    #[test]
+    fn an_insert_past_the_height_limit_fails_cleanly() {
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        tree.set_max_height(2);
+        let mut inserted = 0;
+        let err = loop {
+            match tree.insert(inserted, [inserted; 7]) {
+                Ok(()) => inserted += 1,
+                Err(err) => break err,
             }
-        }
+        };
+        assert!(matches!(err, BTreeError::HeightLimit { max_height: 2 }));
+        assert_eq!(tree.header.height, 2);
 
-        false
+        // The refused insert changed nothing
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), inserted as usize);
+        assert_eq!(tree.get(&inserted).unwrap(), None);
+        for key in 0..inserted {
+            assert_eq!(tree.get(&key).unwrap(), Some([key; 7]));
+        }
     }
 
-    fn compensate_leaves(
-        mut left: LeafNode,
-        mut right: LeafNode,
-        mut parent: InternalNode,
-        left_loc: usize,
-        right_loc: usize,
-    ) -> Option<(LeafNode, LeafNode, InternalNode)> {
-        let total = left.keys.len() + right.keys.len();
+    #[test]
+    fn a_batch_past_the_height_limit_still_counts_what_it_kept() {
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        tree.set_max_height(2);
+        let err = tree
+            .insert_batch((0..1000).map(|key| (key, [key; 7])))
+            .unwrap_err();
+        assert!(matches!(err, BTreeError::HeightLimit { max_height: 2 }));
+        let kept = tree.len();
+        assert!(kept > 0 && kept < 1000);
+        tree.validate().unwrap();
 
-        if total > MAX_KEYS * 2 {
-            return None;
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(tree.storage).unwrap();
+        assert_eq!(tree.len(), kept);
+        assert_eq!(tree.iter().count(), kept);
+        tree.validate().unwrap();
+    }
+
+    #[test]
+    fn repairing_the_leaf_chain_restores_iteration() {
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        for key in 0..100 {
+            tree.insert(key, [key; 7]).unwrap();
         }
+        let keys = |tree: &mut BPlusTree<InMemoryStorage>| -> Vec<i32> {
+            tree.iter().map(|entry| entry.unwrap().0).collect()
+        };
 
-        let mut all_keys = left.keys;
-        all_keys.append(&mut right.keys);
-        let mut all_values = left.values;
-        all_values.append(&mut right.values);
+        // Point the first leaf past the second, dropping its keys from scans
+        let first = tree.leftmost_leaf().unwrap();
+        let mut leaf = tree.read_leaf(first).unwrap();
+        let second = leaf.next.unwrap();
+        leaf.next = tree.read_leaf(second).unwrap().next;
+        tree.storage.write_node(first, &Node::Leaf(leaf)).unwrap();
+        assert!(keys(&mut tree).len() < 100);
+        assert!(tree.validate().is_err());
 
-        let combined: Vec<_> = all_keys.into_iter().zip(all_values.into_iter()).collect();
-        let mid = combined.len() / 2;
+        assert_eq!(tree.repair_leaf_chain().unwrap(), 1);
+        assert_eq!(keys(&mut tree), (0..100).collect::<Vec<_>>());
+        tree.validate().unwrap();
+        assert_eq!(tree.repair_leaf_chain().unwrap(), 0);
+    }
 
-        left.keys = combined[..mid].iter().map(|(k, _)| *k).collect();
-        left.values = combined[..mid].iter().map(|(_, v)| *v).collect();
+    #[test]
+    fn range_matches_a_btreemap() {
+        let mut rng = SimRng(752);
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let mut model = BTreeMap::new();
+        for _ in 0..500 {
+            let key = rng.below(1000) as i32;
+            tree.insert(key, [key; 7]).unwrap();
+            model.insert(key, [key; 7]);
+        }
+        for _ in 0..300 {
+            // Bounds reach past both ends, and are sometimes reversed
+            let lo = rng.below(1200) as i32 - 100;
+            let hi = rng.below(1200) as i32 - 100;
+            let found = tree.range(&lo, &hi).unwrap();
+            assert!(found.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            let expected: Vec<_> = if lo <= hi {
+                model
+                    .range(lo..=hi)
+                    .map(|(&key, &record)| (key, record))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            assert_eq!(found, expected, "range {}..={}", lo, hi);
+        }
+    }
 
-        right.keys = combined[mid..].iter().map(|(k, _)| *k).collect();
-        right.values = combined[mid..].iter().map(|(_, v)| *v).collect();
+    #[test]
+    fn iter_yields_the_keys_in_sorted_order() {
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        assert_eq!(tree.iter().count(), 0);
 
-        let left_idx = parent.children.iter().position(|&c| c == left_loc)?;
-        let right_idx = parent.children.iter().position(|&c| c == right_loc)?;
+        let mut rng = SimRng(753);
+        let mut model = BTreeMap::new();
+        for _ in 0..1000 {
+            let key = rng.below(400) as i32;
+            if rng.chance(0.25) {
+                tree.delete(&key).unwrap();
+                model.remove(&key);
+            } else {
+                tree.insert(key, [key; 7]).unwrap();
+                model.insert(key, [key; 7]);
+            }
+        }
+        let keys: Vec<_> = tree.iter().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys, model.keys().copied().collect::<Vec<_>>());
+    }
 
-        if right_idx != left_idx + 1 {
-            return None;
+    #[test]
+    fn freed_pages_are_reused_on_regrowth() {
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        for key in 0..500 {
+            tree.insert(key, [key; 7]).unwrap();
+        }
+        let grown = tree.storage.total_nodes().unwrap();
+        for _ in 0..5 {
+            for key in 100..400 {
+                tree.delete(&key).unwrap();
+            }
+            assert!(!tree.free_pages().is_empty());
+            for key in 100..400 {
+                tree.insert(key, [key; 7]).unwrap();
+            }
+            // Reinserting may split a little differently, but without reuse
+            // each round would add as many pages again as the keys took
+            let total = tree.storage.total_nodes().unwrap();
+            assert!(
+                total <= grown + grown / 10,
+                "{} pages, {} at first",
+                total,
+                grown
+            );
         }
+        tree.validate().unwrap();
+    }
 
-        parent.keys[left_idx] = right.keys[0];
+    #[test]
+    fn a_string_keyed_tree_round_trips_in_memory() {
+        type StringTree = BPlusTree<InMemoryStorage<String, Record>, String, Record>;
+        let mut tree: StringTree = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let mut model = BTreeMap::new();
+        for i in 0..200 {
+            let key = format!("key-{}", (i * 37) % 200);
+            tree.insert(key.clone(), [i; 7]).unwrap();
+            model.insert(key, [i; 7]);
+        }
+        for i in (0..200).step_by(3) {
+            let key = format!("key-{}", i);
+            assert_eq!(tree.delete(&key).unwrap(), model.remove(&key));
+        }
 
-        Some((left, right, parent))
+        // Reopened from its pages alone
+        let mut tree: StringTree = BPlusTree::open(tree.storage).unwrap();
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), model.len());
+        let entries: Vec<_> = tree.iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries, model.clone().into_iter().collect::<Vec<_>>());
+        for (key, record) in &model {
+            assert_eq!(tree.get(key).unwrap(), Some(*record));
+        }
+        assert_eq!(tree.get(&"key-0".to_string()).unwrap(), None);
     }
 
-    pub fn insert(&mut self, value: Record) {
-        let key = value[0];
-        let mut path = Vec::new();
-        let mut current_loc = self.header.root;
-        let mut current_node = self.storage.read_node(current_loc).unwrap();
+    #[test]
+    fn bulk_loaded_and_inserted_trees_agree() {
+        let keys: Vec<i32> = (0..700).map(|i| i * 3).collect();
+        let mut loaded: BPlusTree<InMemoryStorage> = BPlusTree::bulk_load(
+            InMemoryStorage::new(),
+            keys.iter().map(|&key| (key, [key; 7])),
+        )
+        .unwrap();
+        let mut inserted: BPlusTree<InMemoryStorage> =
+            BPlusTree::open(InMemoryStorage::new()).unwrap();
+        // Out of order, so its shape differs from the bulk-loaded one
+        for &key in keys.iter().rev() {
+            inserted.insert(key, [key; 7]).unwrap();
+        }
+        loaded.validate().unwrap();
+        inserted.validate().unwrap();
+        assert_eq!(loaded.len(), inserted.len());
 
-        while let Node::Internal(internal) = current_node {
-            path.push((current_loc, internal.clone()));
-            let mut i = 0;
-            while i < internal.keys.len() && key >= internal.keys[i] {
-                i += 1;
-            }
-            current_loc = internal.children[i];
-            current_node = self.storage.read_node(current_loc).unwrap();
+        // Every key, and the gaps and ends around them
+        for key in -1..=keys[keys.len() - 1] + 1 {
+            assert_eq!(
+                loaded.get(&key).unwrap(),
+                inserted.get(&key).unwrap(),
+                "key {}",
+                key
+            );
         }
+    }
 
-        if let Node::Leaf(mut leaf) = current_node {
-            match leaf.keys.binary_search(&key) {
-                Ok(i) => {
-                    leaf.values[i] = value;
-                }
-                Err(i) => {
-                    leaf.keys.insert(i, key);
-                    leaf.values.insert(i, value);
+    #[test]
+    fn len_counts_inserts_overwrites_and_deletes() {
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let mut model = BTreeMap::new();
+        let mut rng = SimRng(760);
+        for i in 0..1500 {
+            let key = rng.below(300) as i32;
+            match rng.below(3) {
+                // Half of these overwrite a key already there
+                0 | 1 => {
+                    tree.insert(key, [i; 7]).unwrap();
+                    model.insert(key, [i; 7]);
                 }
+                // Some miss, and must not change the count
+                _ => assert_eq!(tree.delete(&key).unwrap(), model.remove(&key)),
             }
+            assert_eq!(tree.len(), model.len(), "after op {}", i);
+        }
 
-            if self.try_compensate_siblings(current_loc, leaf.clone(), &mut path) {
-                return;
-            }
+        let tree: BPlusTree<InMemoryStorage> = BPlusTree::open(tree.storage).unwrap();
+        assert_eq!(tree.len(), model.len());
+        assert_eq!(tree.is_empty(), model.is_empty());
+    }
 
-            self.storage
-                .write_node(current_loc, &Node::Leaf(leaf.clone()));
+    #[test]
+    fn ends_match_a_btreemap() {
+        let check = |tree: &mut BPlusTree<InMemoryStorage>, model: &BTreeMap<i32, Record>| {
+            let first = model.first_key_value().map(|(&key, &record)| (key, record));
+            let last = model.last_key_value().map(|(&key, &record)| (key, record));
+            assert_eq!(tree.first_key_value().unwrap(), first);
+            assert_eq!(tree.last_key_value().unwrap(), last);
+            assert_eq!(tree.first().unwrap(), first.map(|(_, record)| record));
+            assert_eq!(tree.last().unwrap(), last.map(|(_, record)| record));
+            assert_eq!(tree.min_key().unwrap(), first.map(|(key, _)| key));
+            assert_eq!(tree.max_key().unwrap(), last.map(|(key, _)| key));
+        };
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let mut model = BTreeMap::new();
+        check(&mut tree, &model);
 
-            if leaf.keys.len() > MAX_KEYS {
-                self.split_leaf(current_loc, leaf, &mut path);
-            }
+        // A single leaf, then several levels, then back down to empty
+        let mut rng = SimRng(764);
+        for i in 0..400 {
+            let key = rng.below(1000) as i32 - 500;
+            tree.insert(key, [i; 7]).unwrap();
+            model.insert(key, [i; 7]);
+            check(&mut tree, &model);
         }
+        assert!(tree.header.height > 2);
+        while let Some((&key, _)) = model.iter().nth(model.len() / 2) {
+            tree.delete(&key).unwrap();
+            model.remove(&key);
+            check(&mut tree, &model);
+        }
+        assert!(tree.is_empty());
     }
 
-    fn split_leaf(&mut self, loc: usize, leaf: LeafNode, path: &mut Vec<(usize, InternalNode)>) {
-        let mid = leaf.keys.len() / 2;
-        let new_leaf = LeafNode {
-            keys: leaf.keys[mid..].to_vec(),
-            values: leaf.values[mid..].to_vec(),
-            next: leaf.next,
-        };
-        let new_leaf_loc = self.storage.total_nodes();
+    /// A tree of keys `0, 2, 4, ...` below `2 * n`, each record filled with
+    /// its key.
+    fn even_keys(n: i32) -> BPlusTree<InMemoryStorage> {
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        for key in (0..n).rev() {
+            tree.insert(key * 2, [key * 2; 7]).unwrap();
+        }
+        tree
+    }
 
-        let original_leaf = LeafNode {
-            keys: leaf.keys[..mid].to_vec(),
-            values: leaf.values[..mid].to_vec(),
-            next: Some(new_leaf_loc),
-        };
+    #[test]
+    fn kth_and_rank_agree_with_key_order() {
+        let mut tree = even_keys(500);
+        assert!(tree.header.height > 2);
+        for n in [0, 1, 57, 250, 499] {
+            let key = n as i32 * 2;
+            assert_eq!(tree.kth(n).unwrap(), Some((key, [key; 7])));
+            assert_eq!(tree.rank(&key).unwrap(), n);
+            // A missing key ranks where it would go
+            assert_eq!(tree.rank(&(key + 1)).unwrap(), n + 1);
+        }
+        assert_eq!(tree.kth(500).unwrap(), None);
+        assert_eq!(tree.rank(&-1).unwrap(), 0);
 
-        self.storage.write_node(loc, &Node::Leaf(original_leaf));
-        self.storage
-            .write_node(new_leaf_loc, &Node::Leaf(new_leaf.clone()));
+        for key in (0..1000).step_by(6) {
+            tree.delete(&key).unwrap();
+        }
+        let keys: Vec<i32> = tree.iter().map(|entry| entry.unwrap().0).collect();
+        for (n, key) in keys.iter().enumerate() {
+            assert_eq!(tree.kth(n).unwrap().map(|(key, _)| key), Some(*key));
+            assert_eq!(tree.rank(key).unwrap(), n);
+        }
+    }
 
-        let new_key = new_leaf.keys[0];
-        if path.is_empty() {
-            // Create a new root
-            let new_root = Node::Internal(InternalNode {
-                keys: vec![new_key],
-                children: vec![loc, new_leaf_loc],
-            });
-            let new_root_loc = self.storage.total_nodes();
-            self.storage.write_node(new_root_loc, &new_root);
-            self.header.root = new_root_loc;
-            self.storage
-                .write_node(0, &Node::Header(self.header.clone()));
-        } else {
-            self.insert_into_parent(new_key, new_leaf_loc, path);
+    #[test]
+    fn an_entry_reads_inserts_and_modifies_in_place() {
+        let mut tree = even_keys(300);
+        let entry = tree.entry(10).unwrap();
+        assert_eq!(entry.get(), Some(&[10; 7]));
+        let entry = entry.and_modify(|record| record[1] = -1).unwrap();
+        assert_eq!(
+            entry.or_insert([0; 7]).unwrap(),
+            [10, -1, 10, 10, 10, 10, 10]
+        );
+        assert_eq!(tree.get(&10).unwrap(), Some([10, -1, 10, 10, 10, 10, 10]));
+
+        let entry = tree.entry(11).unwrap();
+        assert_eq!(entry.get(), None);
+        let entry = entry.and_modify(|_| panic!("the key is vacant")).unwrap();
+        assert_eq!(entry.or_insert_with(|| [11; 7]).unwrap(), [11; 7]);
+        assert_eq!(tree.entry(13).unwrap().or_default().unwrap(), [0; 7]);
+        // Enough new keys through entries to split leaves
+        for key in (1..600).step_by(2) {
+            tree.entry(key).unwrap().or_insert([key; 7]).unwrap();
         }
+        assert_eq!(tree.len(), 600);
+        assert_eq!(tree.get(&13).unwrap(), Some([0; 7]));
+        assert_eq!(tree.get(&599).unwrap(), Some([599; 7]));
+        tree.validate().unwrap();
     }
 
-    fn insert_into_parent(
-        &mut self,
-        key: i32,
-        new_child_loc: usize,
-        path: &mut Vec<(usize, InternalNode)>,
-    ) {
-        let (parent_loc, mut parent) = path.pop().unwrap();
+    #[test]
+    fn upsert_tells_new_keys_from_replaced_ones() {
+        let mut tree = even_keys(100);
+        assert_eq!(tree.upsert(3, [3; 7]).unwrap(), Upsert::Inserted);
+        assert_eq!(tree.upsert(4, [0; 7]).unwrap(), Upsert::Updated([4; 7]));
+        assert_eq!(tree.len(), 101);
+        assert!(tree.contains_key(&3).unwrap());
+        assert!(!tree.contains_key(&5).unwrap());
 
-        // Insert the new key and child into the parent
-        let mut i = 0;
-        while i < parent.keys.len() && key >= parent.keys[i] {
-            i += 1;
+        let mut multimap: BPlusTree<InMemoryStorage> =
+            BPlusTree::open_multimap(InMemoryStorage::new()).unwrap();
+        assert_eq!(multimap.upsert(1, [1; 7]).unwrap(), Upsert::Inserted);
+        assert_eq!(multimap.upsert(1, [2; 7]).unwrap(), Upsert::Inserted);
+        assert_eq!(multimap.len(), 2);
+    }
+
+    #[test]
+    fn neighbour_lookups_find_the_closest_keys() {
+        let mut tree = even_keys(400);
+        assert_eq!(tree.find_ge(&101).unwrap(), Some((102, [102; 7])));
+        assert_eq!(tree.find_ge(&102).unwrap(), Some((102, [102; 7])));
+        assert_eq!(tree.find_le(&101).unwrap(), Some((100, [100; 7])));
+        assert_eq!(tree.find_le(&-1).unwrap(), None);
+        assert_eq!(tree.find_ge(&799).unwrap(), None);
+
+        let keys = [798, -3, 5, 0, 400, 400, 1000];
+        let expected: Vec<_> = keys
+            .iter()
+            .map(|key| (key % 2 == 0 && (0..800).contains(key)).then_some([*key; 7]))
+            .collect();
+        assert_eq!(tree.find_many(&keys).unwrap(), expected);
+    }
+
+    #[test]
+    fn delete_range_removes_small_and_large_ranges() {
+        let mut tree = even_keys(400);
+        // A few keys, deleted one at a time
+        let removed = tree.delete_range(100..110).unwrap();
+        assert_eq!(
+            removed.iter().map(|(key, _)| *key).collect::<Vec<_>>(),
+            [100, 102, 104, 106, 108]
+        );
+        assert_eq!(tree.len(), 395);
+        // Most of the tree, which is rebuilt from the rest
+        let removed = tree.delete_range(..=700).unwrap();
+        assert_eq!(removed.len(), 346);
+        assert_eq!(tree.len(), 49);
+        assert_eq!(tree.first_key_value().unwrap(), Some((702, [702; 7])));
+        assert!(tree.delete_range(0..700).unwrap().is_empty());
+        tree.validate().unwrap();
+    }
+
+    #[test]
+    fn range_queries_filter_project_and_sum() {
+        let mut tree = even_keys(300);
+        assert_eq!(tree.count_range(10..20).unwrap(), 5);
+        assert_eq!(tree.count_range(..).unwrap(), 300);
+        assert_eq!(tree.count_range(1000..).unwrap(), 0);
+
+        let multiples = tree
+            .scan_filter(0..=60, |record| record[1] % 3 == 0)
+            .unwrap();
+        assert_eq!(
+            multiples.iter().map(|(key, _)| *key).collect::<Vec<_>>(),
+            [0, 6, 12, 18, 24, 30, 36, 42, 48, 54, 60]
+        );
+        let halves = tree
+            .select(..10, |key, record| (key % 4 == 0).then_some(record[2] / 2))
+            .unwrap();
+        assert_eq!(halves, [0, 2, 4]);
+        assert_eq!(tree.select_fields(2..=4, [0, 1]).unwrap(), [[2, 2], [4, 4]]);
+
+        let aggregate = tree.aggregate(0..10, 3).unwrap();
+        assert_eq!(
+            (aggregate.count, aggregate.sum, aggregate.min, aggregate.max),
+            (5, 20, Some(0), Some(8))
+        );
+        assert_eq!(tree.sum_field(0..10, 3).unwrap(), 20);
+        assert_eq!(tree.aggregate(1000.., 3).unwrap(), Aggregate::default());
+    }
+
+    #[test]
+    fn a_histogram_covers_every_record_in_key_order() {
+        let mut tree = even_keys(2000);
+        let buckets = tree.histogram(8).unwrap();
+        assert!(buckets.len() > 1 && buckets.len() <= 8);
+        assert_eq!(buckets[0].low, None);
+        assert_eq!(
+            buckets.iter().map(|bucket| bucket.records).sum::<usize>(),
+            2000
+        );
+        // Each bucket holds exactly the records from its low to the next one
+        for pair in buckets.windows(2) {
+            let (low, high) = (pair[0].low.unwrap_or(i32::MIN), pair[1].low.unwrap());
+            assert!(low < high);
+            assert_eq!(tree.count_range(low..high).unwrap(), pair[0].records);
         }
-        parent.keys.insert(i, key);
-        parent.children.insert(i + 1, new_child_loc);
+        assert!(tree.histogram(0).unwrap().is_empty());
+    }
 
-        self.storage
-            .write_node(parent_loc, &Node::Internal(parent.clone()));
+    #[test]
+    fn explain_follows_the_lookup_down_to_the_leaf() {
+        let mut tree = even_keys(500);
+        let explain = tree.explain(&250).unwrap();
+        assert_eq!(explain.value, Some([250; 7]));
+        assert_eq!(explain.steps.len(), tree.header.height);
+        assert_eq!(explain.steps[0].loc, tree.header.root);
+        let (leaf, path) = explain.steps.split_last().unwrap();
+        assert_eq!(leaf.kind, NodeKind::Leaf);
+        assert!(leaf.child.is_none());
+        for (step, below) in path.iter().zip(&explain.steps[1..]) {
+            assert_eq!(step.kind, NodeKind::Internal);
+            assert_eq!(step.child.map(|(_, loc)| loc), Some(below.loc));
+        }
+        assert!(!explain.bloom_ruled_out);
 
-        if parent.keys.len() > MAX_KEYS {
-            self.split_internal(parent_loc, parent, path);
+        tree.build_bloom_filter().unwrap();
+        assert!(tree.has_bloom_filter());
+        let misses = (1..200).step_by(2);
+        let ruled_out = misses
+            .clone()
+            .filter(|key| tree.explain(key).unwrap().bloom_ruled_out)
+            .count();
+        assert!(ruled_out > misses.len() / 2);
+        for key in misses {
+            assert_eq!(tree.get(&key).unwrap(), None);
         }
+        for key in (0..1000).step_by(2) {
+            assert!(!tree.explain(&key).unwrap().bloom_ruled_out);
+        }
+        tree.drop_bloom_filter().unwrap();
+        assert!(!tree.explain(&1).unwrap().bloom_ruled_out);
     }
 
-    fn split_internal(
-        &mut self,
-        loc: usize,
-        internal: InternalNode,
-        path: &mut Vec<(usize, InternalNode)>,
-    ) {
-        let mid = internal.keys.len() / 2;
-        let new_internal = InternalNode {
-            keys: internal.keys[mid + 1..].to_vec(),
-            children: internal.children[mid + 1..].to_vec(),
-        };
-        let new_internal_loc = self.storage.total_nodes();
+    #[test]
+    fn to_dot_draws_every_page_and_the_leaf_chain() {
+        let mut tree = even_keys(300);
+        let dot = tree.to_dot().unwrap();
+        assert!(dot.starts_with("digraph btree {"));
+        assert!(dot.trim_end().ends_with('}'));
+        let stats = tree.stats().unwrap();
+        let labelled = dot.lines().filter(|line| line.contains("[label=")).count();
+        assert_eq!(labelled, stats.internal_nodes + stats.leaf_nodes);
+        let chained = dot.lines().filter(|line| line.contains("dashed")).count();
+        assert_eq!(chained, stats.leaf_nodes - 1);
+    }
 
-        let original_internal = InternalNode {
-            keys: internal.keys[..mid].to_vec(),
-            children: internal.children[..mid + 1].to_vec(),
-        };
+    #[test]
+    fn a_read_only_tree_refuses_every_write() {
+        let path = std::env::temp_dir().join(format!("btree-ro-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let mut tree: BPlusTree<FileStorage> =
+            BPlusTree::open(FileStorage::new(path).unwrap()).unwrap();
+        tree.insert(1, [1; 7]).unwrap();
+        drop(tree);
 
-        self.storage
-            .write_node(loc, &Node::Internal(original_internal));
-        self.storage
-            .write_node(new_internal_loc, &Node::Internal(new_internal));
+        let mut tree: BPlusTree<FileStorage> =
+            BPlusTree::open_read_only(FileStorage::open_read_only(path).unwrap()).unwrap();
+        assert!(tree.is_read_only());
+        assert_eq!(tree.get(&1).unwrap(), Some([1; 7]));
+        assert!(matches!(tree.insert(2, [2; 7]), Err(BTreeError::ReadOnly)));
+        assert!(matches!(tree.delete(&1), Err(BTreeError::ReadOnly)));
+        assert!(matches!(tree.delete_range(..), Err(BTreeError::ReadOnly)));
+        let entry = tree.entry(1).unwrap();
+        assert!(matches!(
+            entry.and_modify(|record| record[1] = 0),
+            Err(BTreeError::ReadOnly)
+        ));
+        assert!(matches!(tree.compact(), Err(BTreeError::ReadOnly)));
+        assert!(matches!(
+            tree.build_bloom_filter(),
+            Err(BTreeError::ReadOnly)
+        ));
+        assert_eq!(tree.get(&1).unwrap(), Some([1; 7]));
+        drop(tree);
+        std::fs::remove_file(path).unwrap();
+    }
 
-        let new_key = internal.keys[mid];
-        if path.is_empty() {
-            // Create a new root
-            let new_root = Node::Internal(InternalNode {
-                keys: vec![new_key],
-                children: vec![loc, new_internal_loc],
-            });
-            let new_root_loc = self.storage.total_nodes();
-            self.storage.write_node(new_root_loc, &new_root);
-            self.header.root = new_root_loc;
-            self.storage
-                .write_node(0, &Node::Header(self.header.clone()));
-        } else {
-            self.insert_into_parent(new_key, new_internal_loc, path);
+    #[test]
+    fn descending_keys_iterate_from_the_largest() {
+        let mut tree: BPlusTree<InMemoryStorage<Descending<i32>>, Descending<i32>> =
+            BPlusTree::open(InMemoryStorage::new()).unwrap();
+        for key in 0..300 {
+            tree.insert(Descending(key), [key; 7]).unwrap();
         }
+        let keys: Vec<i32> = tree.iter().map(|entry| entry.unwrap().0.0).collect();
+        assert_eq!(keys, (0..300).rev().collect::<Vec<_>>());
+        assert_eq!(
+            tree.first_key_value().unwrap().map(|(key, _)| key.0),
+            Some(299)
+        );
+        assert_eq!(
+            tree.find_ge(&Descending(100))
+                .unwrap()
+                .map(|(key, _)| key.0),
+            Some(100)
+        );
+        tree.validate().unwrap();
     }
 
-    pub fn dump_tree(&mut self) {
-        self.dump_node(self.header.root, 0);
+    #[test]
+    fn a_file_is_refused_under_another_key_order() {
+        let path = std::env::temp_dir().join(format!("btree-order-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let mut tree: BPlusTree<FileStorage<String>, String> =
+            BPlusTree::open(FileStorage::new(path).unwrap()).unwrap();
+        tree.insert("Apple".into(), [1; 7]).unwrap();
+        drop(tree);
+
+        let caseless = BPlusTree::<FileStorage<CaselessString>, CaselessString>::open(
+            FileStorage::new(path).unwrap(),
+        );
+        assert!(matches!(
+            caseless,
+            Err(BTreeError::CollationMismatch {
+                found: Some(Collation::Bytewise),
+                expected: Some(Collation::CaseInsensitive),
+            })
+        ));
+        let descending = BPlusTree::<FileStorage<Descending<String>>, Descending<String>>::open(
+            FileStorage::new(path).unwrap(),
+        );
+        assert!(matches!(descending, Err(BTreeError::OrderMismatch { .. })));
+        std::fs::remove_file(path).unwrap();
     }
 
-    fn dump_node(&mut self, loc: usize, depth: usize) {
-        let indent = "  ".repeat(depth);
+    #[test]
+    fn caseless_keys_are_one_key_whatever_the_case() {
+        let mut tree: BPlusTree<InMemoryStorage<CaselessString>, CaselessString> =
+            BPlusTree::open(InMemoryStorage::new()).unwrap();
+        tree.insert("Apple".into(), [1; 7]).unwrap();
+        assert_eq!(
+            tree.upsert("APPLE".into(), [2; 7]).unwrap(),
+            Upsert::Updated([1; 7])
+        );
+        tree.insert("banana".into(), [3; 7]).unwrap();
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.get(&"apple".into()).unwrap(), Some([2; 7]));
+        let keys: Vec<String> = tree
+            .iter()
+            .map(|entry| entry.unwrap().0.as_str().to_owned())
+            .collect();
+        assert_eq!(keys, ["Apple", "banana"]);
+    }
 
-        match self.storage.read_node(loc) {
-            Some(Node::Leaf(leaf)) => {
-                println!("{}Leaf [{}] keys={:?}", indent, loc, leaf.keys);
-            }
-            Some(Node::Internal(internal)) => {
-                println!("{}Internal [{}] keys={:?}", indent, loc, internal.keys);
+    #[test]
+    fn long_values_go_to_overflow_pages_and_come_back() {
+        let mut tree: BPlusTree<InMemoryStorage<i32, Blob>, i32, Blob> =
+            BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let long: Vec<u8> = (0..3 * PAGE_SIZE + 123).map(|i| (i % 251) as u8).collect();
+        tree.insert_bytes(1, b"short").unwrap();
+        tree.insert_bytes(2, &long).unwrap();
+        let pages = tree.storage.total_nodes().unwrap();
+        assert!(pages > 3);
+        assert_eq!(tree.get_bytes(&1).unwrap().as_deref(), Some(&b"short"[..]));
+        assert_eq!(tree.get_bytes(&2).unwrap(), Some(long.clone()));
 
-                for &child in &internal.children {
-                    self.dump_node(child, depth + 1);
-                }
-            }
-            _ => {
-                println!("{}<invalid node @{}>", indent, loc);
-            }
+        // Replacing and deleting the long value frees its chain for reuse
+        tree.insert_bytes(2, b"tiny").unwrap();
+        let freed = tree.free_pages().len();
+        assert!(freed > 3);
+        tree.insert_bytes(3, &long).unwrap();
+        assert_eq!(tree.storage.total_nodes().unwrap(), pages);
+        assert_eq!(tree.delete_bytes(&3).unwrap(), Some(long));
+        assert_eq!(tree.free_pages().len(), freed);
+        assert_eq!(tree.get_bytes(&3).unwrap(), None);
+        tree.validate().unwrap();
+    }
+
+    #[test]
+    fn a_cursor_walks_both_ways_from_where_it_was_put() {
+        let mut tree = even_keys(200);
+        let mut cursor = tree.cursor();
+        cursor.seek(&101).unwrap();
+        assert_eq!(cursor.next().unwrap().map(|(key, _)| key), Some(102));
+        assert_eq!(cursor.next().unwrap().map(|(key, _)| key), Some(104));
+        // Turning around returns the entry just stepped over
+        assert_eq!(cursor.prev().unwrap().map(|(key, _)| key), Some(104));
+        assert_eq!(cursor.prev().unwrap().map(|(key, _)| key), Some(102));
+        assert_eq!(cursor.prev().unwrap().map(|(key, _)| key), Some(100));
+
+        let mut forwards = Vec::new();
+        cursor.seek_first().unwrap();
+        while let Some((key, _)) = cursor.next().unwrap() {
+            forwards.push(key);
         }
+        assert_eq!(forwards, (0..400).step_by(2).collect::<Vec<_>>());
+        assert_eq!(cursor.next().unwrap(), None);
+
+        let mut backwards = Vec::new();
+        cursor.seek_last().unwrap();
+        while let Some((key, _)) = cursor.prev().unwrap() {
+            backwards.push(key);
+        }
+        forwards.reverse();
+        assert_eq!(backwards, forwards);
+
+        // An unpositioned cursor starts from either end
+        assert_eq!(tree.cursor().next().unwrap().map(|(key, _)| key), Some(0));
+        assert_eq!(tree.cursor().prev().unwrap().map(|(key, _)| key), Some(398));
     }
 
-    pub fn dump_records(&mut self) {
-        let mut loc = self.leftmost_leaf();
+    #[test]
+    fn diff_reports_additions_removals_and_changes_in_key_order() {
+        let mut old = even_keys(100);
+        let mut new = even_keys(100);
+        new.delete(&10).unwrap();
+        new.insert(11, [11; 7]).unwrap();
+        new.insert(50, [0; 7]).unwrap();
+        new.insert(500, [500; 7]).unwrap();
+        let diffs: Vec<_> = old.diff(&mut new).collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            diffs,
+            [
+                Diff::Removed(10, [10; 7]),
+                Diff::Added(11, [11; 7]),
+                Diff::Changed {
+                    key: 50,
+                    old: [50; 7],
+                    new: [0; 7]
+                },
+                Diff::Added(500, [500; 7]),
+            ]
+        );
+        assert_eq!(old.diff(&mut even_keys(100)).count(), 0);
+    }
 
-        while let Some(Node::Leaf(leaf)) = self.storage.read_node(loc) {
-            for record in &leaf.values {
-                println!("{:?}", record);
-            }
+    #[test]
+    fn compact_reclaims_freed_pages_and_keeps_every_tree() {
+        let mut tree = even_keys(600);
+        tree.create_bucket("side").unwrap();
+        tree.use_bucket(Some("side")).unwrap();
+        for key in 0..100 {
+            tree.insert(key, [-key; 7]).unwrap();
+        }
+        tree.use_bucket(None).unwrap();
+        tree.build_bloom_filter().unwrap();
+        let deleted: Vec<i32> = (0..1200).step_by(2).filter(|key| key % 8 != 0).collect();
+        for key in &deleted {
+            tree.delete(key).unwrap();
+        }
+        let before = tree.storage.total_nodes().unwrap();
+        assert!(!tree.free_pages().is_empty());
 
-            match leaf.next {
-                Some(next) => loc = next,
-                None => break,
-            }
+        tree.compact().unwrap();
+        assert!(tree.storage.total_nodes().unwrap() < before);
+        assert!(tree.free_pages().is_empty());
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 150);
+        assert_eq!(tree.get(&8).unwrap(), Some([8; 7]));
+        assert_eq!(tree.get(&2).unwrap(), None);
+        // The rebuilt filter has forgotten the deleted keys
+        assert!(tree.has_bloom_filter());
+        let ruled_out = deleted
+            .iter()
+            .filter(|key| tree.explain(key).unwrap().bloom_ruled_out)
+            .count();
+        assert!(ruled_out > deleted.len() / 2);
+        tree.use_bucket(Some("side")).unwrap();
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 100);
+        assert_eq!(tree.get(&99).unwrap(), Some([-99; 7]));
+    }
+
+    #[test]
+    fn buckets_keep_their_records_apart() {
+        let path = std::env::temp_dir().join(format!("btree-buckets-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let mut tree: BPlusTree<FileStorage> =
+            BPlusTree::open(FileStorage::new(path).unwrap()).unwrap();
+        tree.insert(1, [1; 7]).unwrap();
+        tree.create_bucket("a").unwrap();
+        tree.create_bucket("b").unwrap();
+        assert!(matches!(
+            tree.create_bucket("a"),
+            Err(BTreeError::BucketExists { .. })
+        ));
+        assert!(matches!(
+            tree.create_bucket(""),
+            Err(BTreeError::BadBucketName { .. })
+        ));
+        assert!(matches!(
+            tree.use_bucket(Some("c")),
+            Err(BTreeError::NoSuchBucket { .. })
+        ));
+        tree.use_bucket(Some("a")).unwrap();
+        assert_eq!(tree.bucket(), Some("a"));
+        assert!(tree.is_empty());
+        for key in 0..50 {
+            tree.insert(key, [key + 100; 7]).unwrap();
         }
+        assert!(matches!(
+            tree.drop_bucket("a"),
+            Err(BTreeError::BucketInUse { .. })
+        ));
+        tree.use_bucket(Some("b")).unwrap();
+        tree.insert(1, [-1; 7]).unwrap();
+        drop(tree);
+
+        let mut tree: BPlusTree<FileStorage> =
+            BPlusTree::open(FileStorage::new(path).unwrap()).unwrap();
+        let names: Vec<String> = tree.buckets().into_iter().map(|b| b.name).collect();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!((tree.len(), tree.get(&1).unwrap()), (1, Some([1; 7])));
+        tree.use_bucket(Some("a")).unwrap();
+        assert_eq!((tree.len(), tree.get(&1).unwrap()), (50, Some([101; 7])));
+        tree.use_bucket(Some("b")).unwrap();
+        assert_eq!((tree.len(), tree.get(&1).unwrap()), (1, Some([-1; 7])));
+
+        tree.drop_bucket("a").unwrap();
+        assert!(!tree.free_pages().is_empty());
+        tree.use_bucket(None).unwrap();
+        assert_eq!(tree.buckets().len(), 1);
+        tree.validate().unwrap();
+        drop(tree);
+        std::fs::remove_file(path).unwrap();
     }
 
-    fn leftmost_leaf(&mut self) -> usize {
-        let mut loc = self.header.root;
+    #[test]
+    fn a_header_from_another_format_is_refused() {
+        let mut tree = even_keys(10);
+        let Node::Header(header) = tree.storage.read_node(0).unwrap() else {
+            panic!("page 0 holds the header");
+        };
+        let mut storage = tree.storage;
+        let bad_magic = HeaderNode {
+            magic: 0x1234,
+            ..header.clone()
+        };
+        storage.write_node(0, &Node::Header(bad_magic)).unwrap();
+        let opened = BPlusTree::<InMemoryStorage>::open(storage.clone());
+        assert!(matches!(
+            opened,
+            Err(BTreeError::BadMagic { found: 0x1234 })
+        ));
 
-        loop {
-            match self.storage.read_node(loc) {
-                Some(Node::Internal(internal)) => {
-                    loc = internal.children[0];
-                }
-                Some(Node::Leaf(_)) => return loc,
-                _ => panic!("Corrupt tree"),
-            }
+        let old_version = HeaderNode {
+            version: FORMAT_VERSION - 1,
+            ..header
+        };
+        storage.write_node(0, &Node::Header(old_version)).unwrap();
+        let opened = BPlusTree::<InMemoryStorage>::open(storage);
+        assert!(matches!(
+            opened,
+            Err(BTreeError::UnsupportedVersion { found, supported })
+                if found == FORMAT_VERSION - 1 && supported == FORMAT_VERSION
+        ));
+    }
+
+    #[test]
+    fn a_full_leaf_hands_keys_to_a_sibling_before_splitting() {
+        let mut tree = even_keys(200);
+        // Room in most leaves
+        for key in (0..400).step_by(8) {
+            tree.delete(&key).unwrap();
+        }
+        let before = tree.stats().unwrap();
+        for key in (1..400).step_by(4) {
+            tree.insert(key, [key; 7]).unwrap();
         }
+        let after = tree.stats().unwrap();
+        assert!(after.leaf_redistributions > before.leaf_redistributions);
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 250);
     }
 }
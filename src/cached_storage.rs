@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::error::BTreeError;
+use crate::lru::LruCache;
+use crate::node::{LeafKeys, Node};
+use crate::record::Record;
+use crate::storage::Storage;
+
+/// Buffer pool over another storage. Recently used pages are kept in memory
+/// so repeated reads of the upper levels stay off the underlying storage,
+/// and writes are held back as dirty pages until the next flush, which
+/// hands them and the header to the underlying storage in one
+/// [`write_atomic`](Storage::write_atomic). Over a storage whose atomic
+/// writes are crash-safe, a crash then leaves every page of a flush or
+/// none of them.
+pub struct CachedStorage<S, K = i32, V = Record> {
+    inner: S,
+    /// Pages the same as their copy in `inner`.
+    pages: LruCache<Node<K, V>>,
+    /// Pages newer than their copy in `inner`. They stay in memory until
+    /// the flush whatever the capacity, as writing one back early would
+    /// leave it on the storage without the rest of its flush.
+    dirty: BTreeMap<usize, Node<K, V>>,
+    /// Header write held back until [`flush`](Storage::flush), which writes
+    /// it after every dirty page.
+    pending_header: Option<(usize, Node<K, V>)>,
+    pub cache_hits: usize,
+    pub cache_misses: usize,
+}
+
+impl<S, K, V> CachedStorage<S, K, V>
+where
+    S: Storage<K, V>,
+    K: Clone,
+    V: Clone,
+{
+    /// Wraps `inner`, keeping up to `capacity` clean pages in memory besides
+    /// the dirty ones. With a capacity of zero only dirty pages are kept.
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            pages: LruCache::new(capacity),
+            dirty: BTreeMap::new(),
+            pending_header: None,
+            cache_hits: 0,
+            cache_misses: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.pages.capacity()
+    }
+
+    /// Changes the capacity, dropping clean pages that no longer fit.
+    pub fn set_capacity(&mut self, capacity: usize) -> Result<(), BTreeError> {
+        self.pages.resize(capacity);
+        Ok(())
+    }
+
+    /// Number of pages written to the pool but not yet to `inner`.
+    pub fn dirty_pages(&self) -> usize {
+        self.dirty.len()
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// The wrapped storage. Pages still dirty in the pool are not visible
+    /// through it until the next flush.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Flushes the pool and returns the wrapped storage.
+    pub fn into_inner(mut self) -> Result<S, BTreeError> {
+        self.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Writes every dirty page to `inner` in location order, then the
+    /// header, as one atomic write, and keeps the pages as clean ones.
+    fn write_back(&mut self) -> Result<(), BTreeError> {
+        if self.dirty.is_empty() && self.pending_header.is_none() {
+            return Ok(());
+        }
+        let mut pages: Vec<(usize, Node<K, V>)> =
+            std::mem::take(&mut self.dirty).into_iter().collect();
+        let dirty = pages.len();
+        pages.extend(self.pending_header.take());
+        if let Err(err) = self.inner.write_atomic(&pages) {
+            // Still to be written by the next flush
+            if pages.len() > dirty {
+                self.pending_header = pages.pop();
+            }
+            self.dirty = pages.into_iter().collect();
+            return Err(err);
+        }
+        for (loc, node) in pages.into_iter().take(dirty) {
+            self.pages.put(loc, node);
+        }
+        Ok(())
+    }
+
+    /// Whether `loc` is in the pool, dirty or clean.
+    fn pooled(&self, loc: usize) -> bool {
+        self.pages.contains(loc)
+            || self.dirty.contains_key(&loc)
+            || self
+                .pending_header
+                .as_ref()
+                .is_some_and(|(pending, _)| *pending == loc)
+    }
+}
+
+impl<S, K, V> Storage<K, V> for CachedStorage<S, K, V>
+where
+    S: Storage<K, V>,
+    K: Clone,
+    V: Clone,
+{
+    fn read_node(&mut self, loc: usize) -> Result<Node<K, V>, BTreeError> {
+        if let Some((pending, header)) = &self.pending_header
+            && *pending == loc
+        {
+            return Ok(header.clone());
+        }
+        if let Some(node) = self.dirty.get(&loc).or_else(|| self.pages.get(loc)) {
+            self.cache_hits += 1;
+            return Ok(node.clone());
+        }
+
+        self.cache_misses += 1;
+        let node = self.inner.read_node(loc)?;
+        self.pages.put(loc, node.clone());
+        Ok(node)
+    }
+
+    /// Serves pooled leaves from the pool and reads the keys of the others
+    /// from `inner`, which leaves them out of the pool.
+    fn read_keys(&mut self, loc: usize) -> Result<LeafKeys<K>, BTreeError> {
+        if let Some(Node::Leaf(leaf)) = self.dirty.get(&loc).or_else(|| self.pages.get(loc)) {
+            self.cache_hits += 1;
+            return Ok(LeafKeys {
+                keys: leaf.keys.clone(),
+                next: leaf.next,
+            });
+        }
+        if self.pooled(loc) {
+            return LeafKeys::of(loc, self.read_node(loc)?);
+        }
+        self.cache_misses += 1;
+        self.inner.read_keys(loc)
+    }
+
+    /// Serves pooled pages from the pool and fetches the span holding the
+    /// rest from `inner` in one request.
+    fn read_nodes(&mut self, locs: Range<usize>) -> Result<Vec<Node<K, V>>, BTreeError> {
+        // Decided up front, as caching the fetched pages may evict others
+        let pooled: Vec<bool> = locs.clone().map(|loc| self.pooled(loc)).collect();
+        let (Some(first), Some(last)) = (
+            pooled.iter().position(|&p| !p),
+            pooled.iter().rposition(|&p| !p),
+        ) else {
+            return locs.map(|loc| self.read_node(loc)).collect();
+        };
+        let span = locs.start + first..locs.start + last + 1;
+        let mut fetched = self.inner.read_nodes(span.clone())?.into_iter();
+
+        let mut nodes = Vec::with_capacity(locs.len());
+        for (loc, pooled) in locs.zip(pooled) {
+            let node = if span.contains(&loc) {
+                fetched.next()
+            } else {
+                None
+            };
+            match node {
+                Some(node) if !pooled => {
+                    self.cache_misses += 1;
+                    self.pages.put(loc, node.clone());
+                    nodes.push(node);
+                }
+                // The copy in `inner` may be stale
+                _ => nodes.push(self.read_node(loc)?),
+            }
+        }
+        Ok(nodes)
+    }
+
+    fn write_node(&mut self, loc: usize, node: &Node<K, V>) -> Result<(), BTreeError> {
+        self.pages.remove(loc);
+        if let Node::Header(_) = node {
+            self.dirty.remove(&loc);
+            self.pending_header = Some((loc, node.clone()));
+            return Ok(());
+        }
+        if self
+            .pending_header
+            .as_ref()
+            .is_some_and(|(pending, _)| *pending == loc)
+        {
+            self.pending_header = None;
+        }
+        self.dirty.insert(loc, node.clone());
+        Ok(())
+    }
+
+    /// Writes every dirty page in location order, then the header, in one
+    /// atomic write, then flushes `inner`.
+    fn flush(&mut self) -> Result<(), BTreeError> {
+        self.write_back()?;
+        self.inner.flush()
+    }
+
+    /// Writes back like [`flush`](Storage::flush), then syncs `inner`.
+    fn sync(&mut self) -> Result<(), BTreeError> {
+        self.write_back()?;
+        self.inner.sync()
+    }
+
+    /// Flushes the pool, hands the pages to `inner` to write atomically,
+    /// and keeps them cached.
+    fn write_atomic(&mut self, pages: &[(usize, Node<K, V>)]) -> Result<(), BTreeError> {
+        self.flush()?;
+        self.inner.write_atomic(pages)?;
+        for (loc, node) in pages {
+            match node {
+                Node::Header(_) => {
+                    self.pages.remove(*loc);
+                }
+                _ => {
+                    self.pages.put(*loc, node.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts pages that so far only exist in the pool.
+    fn total_nodes(&self) -> Result<usize, BTreeError> {
+        let buffered = self
+            .dirty
+            .keys()
+            .chain(self.pending_header.as_ref().map(|(loc, _)| loc))
+            .max()
+            .map_or(0, |loc| loc + 1);
+        Ok(self.inner.total_nodes()?.max(buffered))
+    }
+
+    /// Flushes the pool and forgets the pages past the new end before
+    /// truncating `inner`.
+    fn truncate(&mut self, pages: usize) -> Result<(), BTreeError> {
+        self.flush()?;
+        for loc in pages..self.inner.total_nodes()? {
+            self.pages.remove(loc);
+        }
+        self.inner.truncate(pages)
+    }
+
+    fn page_capacity(&self) -> usize {
+        self.inner.page_capacity()
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn codec(&self) -> &'static str {
+        self.inner.codec()
+    }
+
+    fn io_counters(&self) -> (usize, usize) {
+        self.inner.io_counters()
+    }
+
+    fn trailing_bytes(&self) -> Result<usize, BTreeError> {
+        self.inner.trailing_bytes()
+    }
+
+    fn file_path(&self) -> Option<&Path> {
+        self.inner.file_path()
+    }
+
+    fn create_copy(&self, path: &str) -> Result<Self, BTreeError> {
+        Ok(Self::new(self.inner.create_copy(path)?, self.capacity()))
+    }
+}
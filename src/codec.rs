@@ -0,0 +1,672 @@
+//! How nodes are laid out in a page's payload. [`FileStorage`] encodes
+//! every page through one codec, chosen when it is opened, and the tree
+//! records the codec's name in the header so a file is never read with
+//! another one.
+//!
+//! [`FileStorage`]: crate::FileStorage
+
+use bincode::error::DecodeError;
+
+use crate::error::SerializeError;
+use crate::file_storage::PAYLOAD_SIZE;
+use crate::key::Key;
+use crate::node::{InternalNode, LeafKeys, LeafNode, Node};
+use crate::record::Record;
+use crate::value::Value;
+
+/// Name of [`Bincode`], the codec of storages that do not choose one.
+pub const BINCODE: &str = "bincode";
+/// Name of [`FixedLayout`].
+pub const FIXED_LAYOUT: &str = "fixed";
+/// Name of [`Slotted`], the codec of [`FileStorage`](crate::FileStorage)
+/// unless it is given another.
+pub const SLOTTED: &str = "slotted";
+
+/// Encodes nodes into page payloads and back.
+pub trait PageCodec<K, V>: Send + Sync {
+    /// Name recorded in the header of files written with this codec.
+    fn name(&self) -> &'static str;
+    /// Another codec like this one, for a storage created alike.
+    fn clone_box(&self) -> Box<dyn PageCodec<K, V>>;
+    fn encode(&self, node: &Node<K, V>) -> Result<Vec<u8>, SerializeError>;
+    /// Decodes the node at the start of `payload`, with the number of bytes
+    /// it took, or `None` if it does not decode.
+    fn decode(&self, payload: &[u8]) -> Option<(Node<K, V>, usize)>;
+    /// Decodes only the keys of the leaf at the start of `payload`, with
+    /// the number of bytes the leaf takes, or `None` if it is not a leaf or
+    /// does not decode. The default decodes the whole node.
+    fn decode_keys(&self, payload: &[u8]) -> Option<(LeafKeys<K>, usize)> {
+        match self.decode(payload)? {
+            (Node::Leaf(leaf), len) => Some((
+                LeafKeys {
+                    keys: leaf.keys,
+                    next: leaf.next,
+                },
+                len,
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// bincode's standard encoding: compact varints and length-prefixed
+/// vectors, so nodes take only the space their entries need.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl<K: Key, V: Value> PageCodec<K, V> for Bincode {
+    fn name(&self) -> &'static str {
+        BINCODE
+    }
+
+    fn clone_box(&self) -> Box<dyn PageCodec<K, V>> {
+        Box::new(*self)
+    }
+
+    fn encode(&self, node: &Node<K, V>) -> Result<Vec<u8>, SerializeError> {
+        Ok(bincode::encode_to_vec(node, bincode::config::standard())?)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Option<(Node<K, V>, usize)> {
+        let result: Result<(Node<K, V>, usize), DecodeError> =
+            bincode::decode_from_slice(payload, bincode::config::standard());
+        result.ok()
+    }
+}
+
+/// A type stored in a fixed number of bytes, as [`FixedLayout`] needs.
+pub trait FixedWidth: Sized {
+    const WIDTH: usize;
+    /// Writes the value into `out`, which is exactly `WIDTH` bytes.
+    fn put(&self, out: &mut [u8]);
+    /// Reads a value back from exactly `WIDTH` bytes.
+    fn get(bytes: &[u8]) -> Self;
+}
+
+macro_rules! fixed_width_int {
+    ($($int:ty),*) => {$(
+        impl FixedWidth for $int {
+            const WIDTH: usize = size_of::<$int>();
+
+            fn put(&self, out: &mut [u8]) {
+                out.copy_from_slice(&self.to_le_bytes());
+            }
+
+            fn get(bytes: &[u8]) -> Self {
+                <$int>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    )*};
+}
+
+fixed_width_int!(i32, u32, i64, u64);
+
+impl FixedWidth for Record {
+    const WIDTH: usize = 7 * i32::WIDTH;
+
+    fn put(&self, out: &mut [u8]) {
+        for (field, out) in self.iter().zip(out.chunks_exact_mut(i32::WIDTH)) {
+            field.put(out);
+        }
+    }
+
+    fn get(bytes: &[u8]) -> Self {
+        let mut record = [0; 7];
+        for (field, bytes) in record.iter_mut().zip(bytes.chunks_exact(i32::WIDTH)) {
+            *field = i32::get(bytes);
+        }
+        record
+    }
+}
+
+/// Tag and entry count, then a leaf's `next` and `prev` pointers or an
+/// internal node's child count.
+const FIXED_OVERHEAD: usize = 1 + 2 + 2 * 8;
+/// Marks a missing `next` or `prev` pointer.
+const NO_PAGE: u64 = u64::MAX;
+
+/// First bytes of the fixed-layout node kinds. bincode starts a node with
+/// its variant index, which is never this large, so the other kinds are
+/// stored as bincode unchanged and the header reads the same under either
+/// codec.
+const TAG_LEAF: u8 = 0xf0;
+const TAG_INTERNAL: u8 = 0xf1;
+
+/// Leaves and internal nodes at fixed offsets, each slot sized for the
+/// widest key and value, so an entry is found without decoding the ones
+/// before it. Every node has as many slots as fill a page, whatever it
+/// holds, so the number is known at compile time. Headers and the other
+/// page kinds are stored as bincode.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixedLayout;
+
+impl FixedLayout {
+    /// Entries a leaf of keys of `K` and values of `V` has room for, as
+    /// many as fill a page's payload.
+    pub const fn leaf_slots<K: FixedWidth, V: FixedWidth>() -> usize {
+        PAYLOAD_SIZE.saturating_sub(FIXED_OVERHEAD) / (K::WIDTH + V::WIDTH)
+    }
+
+    /// Separators an internal node with keys of `K` has room for, each with
+    /// the location and record count of the child after it, besides those
+    /// of its first child.
+    pub const fn internal_slots<K: FixedWidth>() -> usize {
+        PAYLOAD_SIZE.saturating_sub(FIXED_OVERHEAD + 16) / (K::WIDTH + 16)
+    }
+
+    /// Bytes of a leaf holding keys of `K` and values of `V`.
+    pub const fn leaf_size<K: FixedWidth, V: FixedWidth>() -> usize {
+        FIXED_OVERHEAD + Self::leaf_slots::<K, V>() * (K::WIDTH + V::WIDTH)
+    }
+
+    /// Bytes of an internal node with keys of `K`: its separators, then the
+    /// location and then the record count of each child.
+    pub const fn internal_size<K: FixedWidth>() -> usize {
+        let slots = Self::internal_slots::<K>();
+        FIXED_OVERHEAD + slots * K::WIDTH + 2 * (slots + 1) * 8
+    }
+
+    /// Whether both node kinds fit a page's payload.
+    pub const fn fits<K: FixedWidth, V: FixedWidth>() -> bool {
+        Self::leaf_size::<K, V>() <= PAYLOAD_SIZE && Self::internal_size::<K>() <= PAYLOAD_SIZE
+    }
+
+    /// A node of `entries` slots of `width` bytes, which only has room for
+    /// `slots`.
+    fn too_large(entries: usize, slots: usize, width: usize) -> SerializeError {
+        SerializeError::NodeTooLarge {
+            size: FIXED_OVERHEAD + entries * width,
+            capacity: FIXED_OVERHEAD + slots * width,
+        }
+    }
+}
+
+fn put_loc(out: &mut [u8], loc: Option<usize>) {
+    loc.map_or(NO_PAGE, |loc| loc as u64).put(out);
+}
+
+fn get_loc(bytes: &[u8]) -> Option<usize> {
+    Some(u64::get(bytes))
+        .filter(|&loc| loc != NO_PAGE)
+        .map(|loc| loc as usize)
+}
+
+impl<K, V> PageCodec<K, V> for FixedLayout
+where
+    K: Key + FixedWidth,
+    V: Value + FixedWidth,
+{
+    fn name(&self) -> &'static str {
+        FIXED_LAYOUT
+    }
+
+    fn clone_box(&self) -> Box<dyn PageCodec<K, V>> {
+        Box::new(*self)
+    }
+
+    fn encode(&self, node: &Node<K, V>) -> Result<Vec<u8>, SerializeError> {
+        const {
+            assert!(
+                FixedLayout::fits::<K, V>(),
+                "fixed-layout nodes overflow a page"
+            )
+        };
+        match node {
+            Node::Leaf(leaf) => {
+                let slots = Self::leaf_slots::<K, V>();
+                if leaf.keys.len() > slots || leaf.values.len() != leaf.keys.len() {
+                    let entries = leaf.keys.len().max(leaf.values.len());
+                    return Err(Self::too_large(entries, slots, K::WIDTH + V::WIDTH));
+                }
+                let mut out = vec![0u8; Self::leaf_size::<K, V>()];
+                out[0] = TAG_LEAF;
+                out[1..3].copy_from_slice(&(leaf.keys.len() as u16).to_le_bytes());
+                put_loc(&mut out[3..11], leaf.next);
+                put_loc(&mut out[11..19], leaf.prev);
+                let (keys, values) = out[FIXED_OVERHEAD..].split_at_mut(slots * K::WIDTH);
+                for (key, out) in leaf.keys.iter().zip(keys.chunks_exact_mut(K::WIDTH)) {
+                    key.put(out);
+                }
+                for (value, out) in leaf.values.iter().zip(values.chunks_exact_mut(V::WIDTH)) {
+                    value.put(out);
+                }
+                Ok(out)
+            }
+            Node::Internal(internal) => {
+                let slots = Self::internal_slots::<K>();
+                if internal.keys.len() > slots || internal.children.len() > slots + 1 {
+                    return Err(Self::too_large(internal.keys.len(), slots, K::WIDTH + 16));
+                }
+                let mut out = vec![0u8; Self::internal_size::<K>()];
+                out[0] = TAG_INTERNAL;
+                out[1..3].copy_from_slice(&(internal.keys.len() as u16).to_le_bytes());
+                out[3..5].copy_from_slice(&(internal.children.len() as u16).to_le_bytes());
+                let (keys, children) = out[FIXED_OVERHEAD..].split_at_mut(slots * K::WIDTH);
+                for (key, out) in internal.keys.iter().zip(keys.chunks_exact_mut(K::WIDTH)) {
+                    key.put(out);
+                }
+                let (children, counts) = children.split_at_mut((slots + 1) * 8);
+                for (&child, out) in internal.children.iter().zip(children.chunks_exact_mut(8)) {
+                    put_loc(out, Some(child));
+                }
+                for (&count, out) in internal.counts.iter().zip(counts.chunks_exact_mut(8)) {
+                    (count as u64).put(out);
+                }
+                Ok(out)
+            }
+            other => Bincode.encode(other),
+        }
+    }
+
+    fn decode(&self, payload: &[u8]) -> Option<(Node<K, V>, usize)> {
+        let count = |at: usize| {
+            Some(u16::from_le_bytes(
+                payload.get(at..at + 2)?.try_into().ok()?,
+            ))
+        };
+        match *payload.first()? {
+            TAG_LEAF => {
+                let size = Self::leaf_size::<K, V>();
+                let bytes = payload.get(..size)?;
+                let (len, slots) = (count(1)? as usize, Self::leaf_slots::<K, V>());
+                if len > slots {
+                    return None;
+                }
+                let (keys, values) = bytes[FIXED_OVERHEAD..].split_at(slots * K::WIDTH);
+                let leaf = LeafNode {
+                    keys: keys.chunks_exact(K::WIDTH).take(len).map(K::get).collect(),
+                    values: values
+                        .chunks_exact(V::WIDTH)
+                        .take(len)
+                        .map(V::get)
+                        .collect(),
+                    next: get_loc(&bytes[3..11]),
+                    prev: get_loc(&bytes[11..19]),
+                };
+                Some((Node::Leaf(leaf), size))
+            }
+            TAG_INTERNAL => {
+                let size = Self::internal_size::<K>();
+                let bytes = payload.get(..size)?;
+                let (len, children) = (count(1)? as usize, count(3)? as usize);
+                let slots = Self::internal_slots::<K>();
+                if len > slots || children > slots + 1 {
+                    return None;
+                }
+                let (keys, locs) = bytes[FIXED_OVERHEAD..].split_at(slots * K::WIDTH);
+                let (locs, counts) = locs.split_at((slots + 1) * 8);
+                let internal = InternalNode {
+                    keys: keys.chunks_exact(K::WIDTH).take(len).map(K::get).collect(),
+                    children: locs
+                        .chunks_exact(8)
+                        .take(children)
+                        .map(|loc| u64::get(loc) as usize)
+                        .collect(),
+                    counts: counts
+                        .chunks_exact(8)
+                        .take(children)
+                        .map(|count| u64::get(count) as usize)
+                        .collect(),
+                };
+                Some((Node::Internal(internal), size))
+            }
+            _ => Bincode.decode(payload),
+        }
+    }
+
+    fn decode_keys(&self, payload: &[u8]) -> Option<(LeafKeys<K>, usize)> {
+        if payload.first() != Some(&TAG_LEAF) {
+            return None;
+        }
+        let size = Self::leaf_size::<K, V>();
+        let bytes = payload.get(..size)?;
+        let len = u16::from_le_bytes([bytes[1], bytes[2]]) as usize;
+        let slots = Self::leaf_slots::<K, V>();
+        if len > slots {
+            return None;
+        }
+        let keys = bytes[FIXED_OVERHEAD..FIXED_OVERHEAD + slots * K::WIDTH]
+            .chunks_exact(K::WIDTH)
+            .take(len)
+            .map(K::get)
+            .collect();
+        let next = get_loc(&bytes[3..11]);
+        Some((LeafKeys { keys, next }, size))
+    }
+}
+
+/// Tag, slot count, start of the cell area, and the `next` and `prev`
+/// pointers.
+const SLOTTED_OVERHEAD: usize = 1 + 2 + 2 + 2 * 8;
+/// Offset and length of a cell.
+pub(crate) const SLOT_SIZE: usize = 4;
+/// First byte of a slotted leaf, distinct from bincode's variant indexes
+/// like [`FixedLayout`]'s tags.
+const TAG_SLOTTED_LEAF: u8 = 0xf2;
+
+/// A leaf page image: a fixed header, then a directory of slots in key
+/// order growing forwards, and the cells they point to growing backwards
+/// from the end of the page, with the free space in between. Each cell is
+/// one encoded record of any length, and inserting or removing one moves
+/// only slots, never the other cells.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlottedPage {
+    bytes: Vec<u8>,
+}
+
+impl SlottedPage {
+    /// An empty leaf page of `size` bytes, at most 64 KiB.
+    pub fn new(size: usize, next: Option<usize>, prev: Option<usize>) -> Self {
+        assert!(
+            (SLOTTED_OVERHEAD..=u16::MAX as usize + 1).contains(&size),
+            "slotted pages are between {} bytes and 64 KiB",
+            SLOTTED_OVERHEAD
+        );
+        let mut page = Self {
+            bytes: vec![0; size],
+        };
+        page.bytes[0] = TAG_SLOTTED_LEAF;
+        page.set_cell_start(size);
+        page.set_next(next);
+        page.set_prev(prev);
+        page
+    }
+
+    /// Takes a page image back, or `None` if its header or slots point
+    /// outside it.
+    pub fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        if bytes.len() < SLOTTED_OVERHEAD
+            || bytes.len() > u16::MAX as usize + 1
+            || bytes[0] != TAG_SLOTTED_LEAF
+        {
+            return None;
+        }
+        let page = Self { bytes };
+        let directory_end = page.directory_end();
+        if directory_end > page.cell_start() || page.cell_start() > page.bytes.len() {
+            return None;
+        }
+        let cells_valid = (0..page.len()).all(|i| {
+            let (offset, len) = page.slot(i);
+            offset >= page.cell_start() && offset + len <= page.bytes.len()
+        });
+        cells_valid.then_some(page)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Number of cells.
+    pub fn len(&self) -> usize {
+        self.u16_at(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn next(&self) -> Option<usize> {
+        get_loc(&self.bytes[5..13])
+    }
+
+    pub fn prev(&self) -> Option<usize> {
+        get_loc(&self.bytes[13..21])
+    }
+
+    pub fn set_next(&mut self, next: Option<usize>) {
+        put_loc(&mut self.bytes[5..13], next);
+    }
+
+    pub fn set_prev(&mut self, prev: Option<usize>) {
+        put_loc(&mut self.bytes[13..21], prev);
+    }
+
+    /// The cell in slot `index`.
+    pub fn cell(&self, index: usize) -> &[u8] {
+        assert!(index < self.len(), "slot {} out of range", index);
+        let (offset, len) = self.slot(index);
+        &self.bytes[offset..offset + len]
+    }
+
+    /// Bytes still available for cells and their slots, counting space
+    /// left behind by removed cells.
+    pub fn free_space(&self) -> usize {
+        let used: usize = (0..self.len()).map(|i| self.slot(i).1).sum();
+        self.bytes.len() - self.directory_end() - used
+    }
+
+    /// Puts `cell` in slot `index`, shifting the later slots along, and
+    /// returns whether it fit. Space left by removed cells is reclaimed
+    /// only when the free space between slots and cells runs out.
+    pub fn insert(&mut self, index: usize, cell: &[u8]) -> bool {
+        let len = self.len();
+        assert!(index <= len, "slot {} out of range", index);
+        if cell.len() + SLOT_SIZE > self.free_space() {
+            return false;
+        }
+        if self.directory_end() + SLOT_SIZE + cell.len() > self.cell_start() {
+            self.defragment();
+        }
+        let offset = self.cell_start() - cell.len();
+        self.bytes[offset..offset + cell.len()].copy_from_slice(cell);
+        self.set_cell_start(offset);
+
+        let at = SLOTTED_OVERHEAD + index * SLOT_SIZE;
+        let end = self.directory_end();
+        self.bytes.copy_within(at..end, at + SLOT_SIZE);
+        self.set_len(len + 1);
+        self.set_slot(index, offset, cell.len());
+        true
+    }
+
+    /// Drops the cell in slot `index`, shifting the later slots back. Its
+    /// bytes stay where they are until the space is needed.
+    pub fn remove(&mut self, index: usize) {
+        let len = self.len();
+        assert!(index < len, "slot {} out of range", index);
+        let (offset, cell_len) = self.slot(index);
+        let at = SLOTTED_OVERHEAD + index * SLOT_SIZE;
+        let end = self.directory_end();
+        self.bytes.copy_within(at + SLOT_SIZE..end, at);
+        self.set_len(len - 1);
+        if offset == self.cell_start() {
+            self.set_cell_start(offset + cell_len);
+        }
+    }
+
+    /// Packs the cells against the end of the page, so all free space is
+    /// between them and the slots.
+    pub fn defragment(&mut self) {
+        let cells: Vec<Vec<u8>> = (0..self.len()).map(|i| self.cell(i).to_vec()).collect();
+        let mut start = self.bytes.len();
+        for (i, cell) in cells.iter().enumerate() {
+            start -= cell.len();
+            self.bytes[start..start + cell.len()].copy_from_slice(cell);
+            self.set_slot(i, start, cell.len());
+        }
+        self.set_cell_start(start);
+    }
+
+    fn u16_at(&self, at: usize) -> usize {
+        u16::from_le_bytes([self.bytes[at], self.bytes[at + 1]]) as usize
+    }
+
+    /// Stores `value`, where a full 64 KiB page's cell start wraps to 0.
+    fn set_u16_at(&mut self, at: usize, value: usize) {
+        self.bytes[at..at + 2].copy_from_slice(&(value as u16).to_le_bytes());
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.set_u16_at(1, len);
+    }
+
+    fn cell_start(&self) -> usize {
+        match self.u16_at(3) {
+            0 => self.bytes.len(),
+            start => start,
+        }
+    }
+
+    fn set_cell_start(&mut self, start: usize) {
+        self.set_u16_at(3, start);
+    }
+
+    fn directory_end(&self) -> usize {
+        SLOTTED_OVERHEAD + self.len() * SLOT_SIZE
+    }
+
+    fn slot(&self, index: usize) -> (usize, usize) {
+        let at = SLOTTED_OVERHEAD + index * SLOT_SIZE;
+        (self.u16_at(at), self.u16_at(at + 2))
+    }
+
+    fn set_slot(&mut self, index: usize, offset: usize, len: usize) {
+        let at = SLOTTED_OVERHEAD + index * SLOT_SIZE;
+        self.set_u16_at(at, offset);
+        self.set_u16_at(at + 2, len);
+    }
+}
+
+/// Leaves as [`SlottedPage`]s filling the page, each record a cell of its
+/// bincode encoding, so records of different lengths share a leaf without
+/// each taking the longest one's space. Internal nodes and the other page
+/// kinds are stored as bincode.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Slotted;
+
+impl<K: Key, V: Value> PageCodec<K, V> for Slotted {
+    fn name(&self) -> &'static str {
+        SLOTTED
+    }
+
+    fn clone_box(&self) -> Box<dyn PageCodec<K, V>> {
+        Box::new(*self)
+    }
+
+    fn encode(&self, node: &Node<K, V>) -> Result<Vec<u8>, SerializeError> {
+        let Node::Leaf(leaf) = node else {
+            return Bincode.encode(node);
+        };
+        let mut page = SlottedPage::new(PAYLOAD_SIZE, leaf.next, leaf.prev);
+        let mut size = SLOTTED_OVERHEAD;
+        let mut fits = true;
+        for (i, entry) in leaf.keys.iter().zip(&leaf.values).enumerate() {
+            let cell = bincode::encode_to_vec(entry, bincode::config::standard())?;
+            size += cell.len() + SLOT_SIZE;
+            fits = fits && page.insert(i, &cell);
+        }
+        if !fits {
+            return Err(SerializeError::NodeTooLarge {
+                size,
+                capacity: PAYLOAD_SIZE,
+            });
+        }
+        Ok(page.into_bytes())
+    }
+
+    fn decode(&self, payload: &[u8]) -> Option<(Node<K, V>, usize)> {
+        if payload.first() != Some(&TAG_SLOTTED_LEAF) {
+            return Bincode.decode(payload);
+        }
+        let page = SlottedPage::from_bytes(payload.get(..PAYLOAD_SIZE)?.to_vec())?;
+        let mut leaf = LeafNode {
+            keys: Vec::with_capacity(page.len()),
+            values: Vec::with_capacity(page.len()),
+            next: page.next(),
+            prev: page.prev(),
+        };
+        for i in 0..page.len() {
+            let result: Result<((K, V), usize), DecodeError> =
+                bincode::decode_from_slice(page.cell(i), bincode::config::standard());
+            let ((key, value), _) = result.ok()?;
+            leaf.keys.push(key);
+            leaf.values.push(value);
+        }
+        Some((Node::Leaf(leaf), PAYLOAD_SIZE))
+    }
+
+    /// Decodes the key at the start of each cell, skipping the value after
+    /// it.
+    fn decode_keys(&self, payload: &[u8]) -> Option<(LeafKeys<K>, usize)> {
+        if payload.first() != Some(&TAG_SLOTTED_LEAF) {
+            return None;
+        }
+        let page = SlottedPage::from_bytes(payload.get(..PAYLOAD_SIZE)?.to_vec())?;
+        let mut keys = Vec::with_capacity(page.len());
+        for i in 0..page.len() {
+            let result: Result<(K, usize), DecodeError> =
+                bincode::decode_from_slice(page.cell(i), bincode::config::standard());
+            keys.push(result.ok()?.0);
+        }
+        let next = page.next();
+        Some((LeafKeys { keys, next }, PAYLOAD_SIZE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf() -> Node<i32, Record> {
+        Node::Leaf(LeafNode {
+            keys: vec![-7, 0, 300_000],
+            values: vec![[1; 7], [i32::MIN; 7], [i32::MAX; 7]],
+            next: Some(12),
+            prev: None,
+        })
+    }
+
+    fn internal() -> Node<i32, Record> {
+        Node::Internal(InternalNode {
+            keys: vec![10, 20],
+            children: vec![3, 4, 5],
+            counts: vec![5, 1, 9],
+        })
+    }
+
+    #[test]
+    fn every_codec_decodes_what_it_encodes() {
+        let codecs: [Box<dyn PageCodec<i32, Record>>; 3] =
+            [Box::new(Bincode), Box::new(FixedLayout), Box::new(Slotted)];
+        for codec in codecs {
+            for node in [leaf(), internal()] {
+                let bytes = codec.encode(&node).unwrap();
+                let (decoded, len) = codec.decode(&bytes).unwrap();
+                assert_eq!(decoded, node, "{}", codec.name());
+                assert!(len <= bytes.len());
+            }
+            let (keys, _) = codec.decode_keys(&codec.encode(&leaf()).unwrap()).unwrap();
+            assert_eq!((keys.keys, keys.next), (vec![-7, 0, 300_000], Some(12)));
+            assert!(
+                codec
+                    .decode_keys(&codec.encode(&internal()).unwrap())
+                    .is_none()
+            );
+        }
+    }
+
+    #[test]
+    fn a_slotted_page_reuses_the_space_of_removed_cells() {
+        let mut page = SlottedPage::new(256, Some(1), Some(2));
+        let cell = [7u8; 20];
+        let mut cells = 0;
+        while page.insert(cells, &cell) {
+            cells += 1;
+        }
+        assert_eq!(page.len(), cells);
+        assert!(page.free_space() < cell.len() + SLOT_SIZE);
+
+        // Freed in the middle, so only defragmenting makes room
+        page.remove(1);
+        page.remove(1);
+        assert!(page.insert(0, &[1; 30]));
+        assert_eq!(page.cell(0), [1; 30]);
+        assert_eq!(page.cell(1), cell);
+        assert_eq!(page.len(), cells - 1);
+
+        let page = SlottedPage::from_bytes(page.into_bytes()).unwrap();
+        assert_eq!((page.next(), page.prev()), (Some(1), Some(2)));
+        assert!(SlottedPage::from_bytes(vec![0; 256]).is_none());
+    }
+}
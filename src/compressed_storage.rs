@@ -0,0 +1,460 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use crate::error::{BTreeError, SerializeError};
+use crate::file_storage::PAYLOAD_SIZE;
+use crate::key::Key;
+use crate::node::Node;
+use crate::record::Record;
+use crate::storage::{self, Storage};
+use crate::value::Value;
+
+/// First bytes of a compressed log, telling it from a [`FileStorage`]
+/// file.
+///
+/// [`FileStorage`]: crate::FileStorage
+const LOG_MAGIC: u32 = u32::from_le_bytes(*b"BPLZ");
+/// Every record starts with its kind, a page location or count, the
+/// payload length and a CRC32 of all of it.
+const RECORD_HEADER_SIZE: usize = 1 + 8 + 4 + 4;
+/// A page: its location and its node, LZ4-compressed.
+const PAGE_RECORD: u8 = 1;
+/// The end of a flush: the page count, and no payload.
+const COMMIT_RECORD: u8 = 2;
+/// Longest payload of a record: a full node, compressed, with its length
+/// prefix.
+const MAX_PAYLOAD_SIZE: usize = 4 + PAYLOAD_SIZE + PAYLOAD_SIZE / 255 + 16;
+/// Logs smaller than this are never rewritten, however much of them is
+/// superseded.
+const MIN_REWRITE_SIZE: u64 = 1 << 20;
+
+/// Where the latest record of a page starts in the log, and its length.
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    offset: u64,
+    len: usize,
+}
+
+/// Storage keeping each page LZ4-compressed in an append-only log, so a
+/// page takes the size of its compressed node on disk rather than a whole
+/// [`PAGE_SIZE`](crate::config::PAGE_SIZE) block. Writes append the page,
+/// and a flush appends a commit record and syncs. Opening replays the log
+/// up to its last commit, dropping what was written after it, so every
+/// flush and [`write_atomic`](Storage::write_atomic) is all-or-nothing.
+/// Once superseded records make up most of the log, a flush rewrites it
+/// with only the latest copy of each page.
+///
+/// The file format is its own: neither this nor
+/// [`FileStorage`](crate::FileStorage) opens the other's files.
+#[derive(Debug)]
+pub struct CompressedStorage<K = i32, V = Record> {
+    file: File,
+    path: PathBuf,
+    /// Latest record of each page, `None` for pages never written.
+    slots: Vec<Option<Slot>>,
+    /// End of the log, where the next record goes.
+    end: u64,
+    /// Bytes of the records in `slots`, the ones a rewrite would keep.
+    live: u64,
+    /// Pages read from and written to the log.
+    pub page_reads: usize,
+    pub page_writes: usize,
+    marker: PhantomData<(K, V)>,
+}
+
+impl<K: Key, V: Value> CompressedStorage<K, V> {
+    /// Opens or creates the log at `path`, replaying it up to the last
+    /// commit and cutting off anything after it.
+    pub fn new(path: &str) -> Result<Self, BTreeError> {
+        let path = PathBuf::from(path);
+        // Left by a crash partway through a rewrite, which the log it was
+        // to replace outlives
+        match fs::remove_file(Self::rewrite_path(&path)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut storage = Self {
+            file,
+            path,
+            slots: Vec::new(),
+            end: 0,
+            live: 0,
+            page_reads: 0,
+            page_writes: 0,
+            marker: PhantomData,
+        };
+        if storage.file.metadata()?.len() == 0 {
+            storage.file.write_all(&LOG_MAGIC.to_le_bytes())?;
+            storage.file.sync_all()?;
+            storage.end = 4;
+        } else {
+            storage.replay()?;
+        }
+        Ok(storage)
+    }
+
+    /// Bytes the log takes on disk.
+    pub fn log_size(&self) -> u64 {
+        self.end
+    }
+
+    fn rewrite_path(path: &Path) -> PathBuf {
+        let mut rewrite = path.as_os_str().to_owned();
+        rewrite.push(".rewrite");
+        PathBuf::from(rewrite)
+    }
+
+    /// Rebuilds the page table from the log, applying each commit's pages
+    /// once the commit is read, and truncates the log after the last one.
+    fn replay(&mut self) -> Result<(), BTreeError> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(self.file.try_clone()?);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        let magic = u32::from_le_bytes(magic);
+        if magic != LOG_MAGIC {
+            return Err(BTreeError::BadMagic { found: magic });
+        }
+
+        let mut offset = 4;
+        let mut committed = offset;
+        let mut pending = Vec::new();
+        let mut payload = Vec::new();
+        loop {
+            let mut header = [0; RECORD_HEADER_SIZE];
+            match reader.read_exact(&mut header) {
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                result => result?,
+            }
+            let (kind, arg, len) = Self::parse_header(&header);
+            // A length no page could have means the header itself is torn
+            if len > MAX_PAYLOAD_SIZE {
+                break;
+            }
+            payload.resize(len, 0);
+            match reader.read_exact(&mut payload) {
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                result => result?,
+            }
+            if Self::checksum(&header, &payload) != Self::stored_checksum(&header) {
+                break;
+            }
+            let record_len = (RECORD_HEADER_SIZE + len) as u64;
+            match kind {
+                PAGE_RECORD => pending.push((
+                    arg as usize,
+                    Slot {
+                        offset,
+                        len: record_len as usize,
+                    },
+                )),
+                COMMIT_RECORD => {
+                    for (loc, slot) in pending.drain(..) {
+                        self.set_slot(loc, slot);
+                    }
+                    self.slots.resize(arg as usize, None);
+                    committed = offset + record_len;
+                }
+                _ => break,
+            }
+            offset += record_len;
+        }
+        drop(reader);
+
+        self.end = committed;
+        self.file.set_len(committed)?;
+        self.live = self
+            .slots
+            .iter()
+            .flatten()
+            .map(|slot| slot.len as u64)
+            .sum();
+        Ok(())
+    }
+
+    fn set_slot(&mut self, loc: usize, slot: Slot) {
+        if loc >= self.slots.len() {
+            self.slots.resize(loc + 1, None);
+        }
+        self.slots[loc] = Some(slot);
+    }
+
+    fn parse_header(header: &[u8; RECORD_HEADER_SIZE]) -> (u8, u64, usize) {
+        let arg = u64::from_le_bytes(header[1..9].try_into().unwrap());
+        let len = u32::from_le_bytes(header[9..13].try_into().unwrap());
+        (header[0], arg, len as usize)
+    }
+
+    fn stored_checksum(header: &[u8; RECORD_HEADER_SIZE]) -> u32 {
+        u32::from_le_bytes(header[13..].try_into().unwrap())
+    }
+
+    /// CRC32 of a record's header, up to the checksum, and its payload.
+    fn checksum(header: &[u8], payload: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[..13]);
+        hasher.update(payload);
+        hasher.finalize()
+    }
+
+    fn encode_record(kind: u8, arg: u64, payload: &[u8]) -> Vec<u8> {
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        record.push(kind);
+        record.extend_from_slice(&arg.to_le_bytes());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        let checksum = Self::checksum(&record, payload);
+        record.extend_from_slice(&checksum.to_le_bytes());
+        record.extend_from_slice(payload);
+        record
+    }
+
+    /// Appends a record to the log, returning where it starts.
+    fn append(&mut self, record: &[u8]) -> Result<u64, BTreeError> {
+        let offset = self.end;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(record)?;
+        self.end += record.len() as u64;
+        Ok(offset)
+    }
+
+    /// Appends a commit record and syncs, then rewrites the log if it has
+    /// grown mostly superseded.
+    fn commit(&mut self) -> Result<(), BTreeError> {
+        let record = Self::encode_record(COMMIT_RECORD, self.slots.len() as u64, &[]);
+        self.append(&record)?;
+        self.file.sync_data()?;
+        if self.end > MIN_REWRITE_SIZE && self.end > 2 * self.live {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    /// Copies the latest record of each page and a commit to a new log,
+    /// which then replaces this one.
+    fn rewrite(&mut self) -> Result<(), BTreeError> {
+        let path = Self::rewrite_path(&self.path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let mut writer = BufWriter::new(&file);
+        writer.write_all(&LOG_MAGIC.to_le_bytes())?;
+        let mut end = 4;
+        let mut slots = Vec::with_capacity(self.slots.len());
+        for slot in self.slots.clone() {
+            slots.push(match slot {
+                Some(slot) => {
+                    writer.write_all(&self.read_record(slot)?)?;
+                    let moved = Slot {
+                        offset: end,
+                        ..slot
+                    };
+                    end += slot.len as u64;
+                    Some(moved)
+                }
+                None => None,
+            });
+        }
+        let record = Self::encode_record(COMMIT_RECORD, slots.len() as u64, &[]);
+        writer.write_all(&record)?;
+        writer.flush()?;
+        drop(writer);
+        file.sync_all()?;
+        fs::rename(&path, &self.path)?;
+
+        self.file = file;
+        self.slots = slots;
+        self.end = end + record.len() as u64;
+        Ok(())
+    }
+
+    fn read_record(&mut self, slot: Slot) -> io::Result<Vec<u8>> {
+        let mut record = vec![0; slot.len];
+        self.file.seek(SeekFrom::Start(slot.offset))?;
+        self.file.read_exact(&mut record)?;
+        Ok(record)
+    }
+}
+
+impl<K: Key, V: Value> Storage<K, V> for CompressedStorage<K, V> {
+    fn read_node(&mut self, loc: usize) -> Result<Node<K, V>, BTreeError> {
+        let corrupt = |reason| BTreeError::Corrupt { loc, reason };
+        let slot = self
+            .slots
+            .get(loc)
+            .copied()
+            .flatten()
+            .ok_or(corrupt("page was never written"))?;
+        let record = self.read_record(slot)?;
+        let (header, payload) = record.split_at(RECORD_HEADER_SIZE);
+        let header: &[u8; RECORD_HEADER_SIZE] = header.try_into().unwrap();
+        if Self::checksum(header, payload) != Self::stored_checksum(header) {
+            return Err(corrupt("checksum mismatch"));
+        }
+        let encoded = lz4_flex::decompress_size_prepended(payload)
+            .map_err(|_| corrupt("page does not decompress"))?;
+        let (node, _) = bincode::decode_from_slice(&encoded, bincode::config::standard())
+            .map_err(|_| corrupt("node does not decode"))?;
+        self.page_reads += 1;
+        Ok(node)
+    }
+
+    fn write_node(&mut self, loc: usize, node: &Node<K, V>) -> Result<(), BTreeError> {
+        let encoded = bincode::encode_to_vec(node, bincode::config::standard())
+            .map_err(SerializeError::from)?;
+        if encoded.len() > PAYLOAD_SIZE {
+            return Err(SerializeError::NodeTooLarge {
+                size: encoded.len(),
+                capacity: PAYLOAD_SIZE,
+            }
+            .into());
+        }
+        let compressed = lz4_flex::compress_prepend_size(&encoded);
+        let record = Self::encode_record(PAGE_RECORD, loc as u64, &compressed);
+        let offset = self.append(&record)?;
+        let superseded = self
+            .slots
+            .get(loc)
+            .copied()
+            .flatten()
+            .map_or(0, |slot| slot.len as u64);
+        self.live = self.live - superseded + record.len() as u64;
+        self.set_slot(
+            loc,
+            Slot {
+                offset,
+                len: record.len(),
+            },
+        );
+        self.page_writes += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BTreeError> {
+        self.commit()
+    }
+
+    fn total_nodes(&self) -> Result<usize, BTreeError> {
+        Ok(self.slots.len())
+    }
+
+    /// Commits the shorter page count right away, as a flush does.
+    fn truncate(&mut self, pages: usize) -> Result<(), BTreeError> {
+        for slot in self.slots.drain(pages.min(self.slots.len())..).flatten() {
+            self.live -= slot.len as u64;
+        }
+        self.commit()
+    }
+
+    fn page_capacity(&self) -> usize {
+        PAYLOAD_SIZE
+    }
+
+    /// The average size of the latest record of each page.
+    fn page_size(&self) -> usize {
+        let pages = self.slots.iter().flatten().count();
+        (self.live as usize).checked_div(pages).unwrap_or(0)
+    }
+
+    fn io_counters(&self) -> (usize, usize) {
+        (self.page_reads, self.page_writes)
+    }
+
+    fn file_path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    fn create_copy(&self, path: &str) -> Result<Self, BTreeError> {
+        storage::clear_for_copy(&self.path, path)?;
+        Self::new(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::BPlusTree;
+    use crate::config::PAGE_SIZE;
+    use crate::file_storage::FileStorage;
+    use crate::node::LeafNode;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("btree-{}-{}.db", name, std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn a_tree_takes_less_room_than_whole_pages() {
+        let path = temp_path("lz-size");
+        let mut tree: BPlusTree<CompressedStorage> =
+            BPlusTree::open(CompressedStorage::new(&path).unwrap()).unwrap();
+        for key in 0..2000 {
+            tree.insert(key, [0; 7]).unwrap();
+        }
+        tree.compact().unwrap();
+        let pages = tree.storage.total_nodes().unwrap() as u64;
+        assert!(tree.storage.log_size() < pages * PAGE_SIZE as u64 / 2);
+        drop(tree);
+
+        let mut tree: BPlusTree<CompressedStorage> =
+            BPlusTree::open(CompressedStorage::new(&path).unwrap()).unwrap();
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 2000);
+        assert_eq!(tree.get(&1234).unwrap(), Some([0; 7]));
+        drop(tree);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pages_written_after_the_last_commit_are_dropped() {
+        let path = temp_path("lz-commit");
+        let mut tree: BPlusTree<CompressedStorage> =
+            BPlusTree::open(CompressedStorage::new(&path).unwrap()).unwrap();
+        for key in 0..300 {
+            tree.insert(key, [key; 7]).unwrap();
+        }
+        let committed = tree.storage.log_size();
+        let mut leaf = LeafNode::new();
+        leaf.keys.push(-1);
+        leaf.values.push([0; 7]);
+        tree.storage.write_node(1, &Node::Leaf(leaf)).unwrap();
+        assert!(tree.storage.log_size() > committed);
+        drop(tree);
+
+        let mut tree: BPlusTree<CompressedStorage> =
+            BPlusTree::open(CompressedStorage::new(&path).unwrap()).unwrap();
+        assert_eq!(tree.storage.log_size(), committed);
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 300);
+        assert_eq!(tree.get(&-1).unwrap(), None);
+        drop(tree);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_page_file_is_not_taken_for_a_log() {
+        let path = temp_path("lz-magic");
+        let mut tree: BPlusTree<FileStorage> =
+            BPlusTree::open(FileStorage::new(&path).unwrap()).unwrap();
+        tree.insert(1, [1; 7]).unwrap();
+        drop(tree);
+
+        assert!(matches!(
+            CompressedStorage::<i32, Record>::new(&path),
+            Err(BTreeError::BadMagic { .. })
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,3 +1,13 @@
 pub const DEGREE: usize = 2;
+/// Keys per node in the crate's own tests, so a few records are enough to
+/// split and merge nodes. Elsewhere nodes hold as many as fit a page.
 pub const MAX_KEYS: usize = DEGREE * 2 + 1;
 pub const PAGE_SIZE: usize = 4 * 16384;
+pub const DEFAULT_MAX_HEIGHT: usize = 64;
+
+#[cfg(test)]
+thread_local! {
+    /// Cap on keys per node for trees opened on this test thread,
+    /// [`MAX_KEYS`] unless a test lifts it.
+    pub(crate) static TEST_MAX_KEYS: std::cell::Cell<usize> = const { std::cell::Cell::new(MAX_KEYS) };
+}
@@ -0,0 +1,187 @@
+use std::io::{BufRead, Lines, Seek, Write};
+use std::iter::Enumerate;
+
+use crate::allocator::Allocator;
+use crate::btree::BPlusTree;
+use crate::error::BTreeError;
+use crate::record::{Record, RecordText};
+use crate::storage::Storage;
+
+/// Records taken at a time when the input cannot be bulk-loaded, which
+/// bounds how many are held in memory during an import.
+pub const BATCH: usize = 4096;
+
+/// Records parsed from CSV text one line at a time, skipping blank lines
+/// and noting the lines that did not parse. Iteration stops at the first
+/// read error, which [`finish`](Records::finish) returns.
+pub struct Records<R> {
+    lines: Enumerate<Lines<R>>,
+    loaded: usize,
+    errors: Vec<(usize, String)>,
+    failed: Option<std::io::Error>,
+}
+
+impl<R: BufRead> Records<R> {
+    /// Records returned so far.
+    pub fn loaded(&self) -> usize {
+        self.loaded
+    }
+
+    /// The 1-based line number and reason for every line skipped so far,
+    /// or the error that stopped the reading.
+    pub fn finish(self) -> Result<Vec<(usize, String)>, BTreeError> {
+        match self.failed {
+            Some(err) => Err(err.into()),
+            None => Ok(self.errors),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Records<R> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        if self.failed.is_some() {
+            return None;
+        }
+        for (i, line) in self.lines.by_ref() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    self.failed = Some(err);
+                    return None;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Record::from_text(&line) {
+                Ok(record) => {
+                    self.loaded += 1;
+                    return Some(record);
+                }
+                Err(err) => self.errors.push((i + 1, err)),
+            }
+        }
+        None
+    }
+}
+
+/// Parses one record per line as it is read.
+pub fn records<R: BufRead>(reader: R) -> Records<R> {
+    Records {
+        lines: reader.lines().enumerate(),
+        loaded: 0,
+        errors: Vec::new(),
+        failed: None,
+    }
+}
+
+/// Whether the records read from `reader` are in strictly ascending key
+/// order, so they can be bulk-loaded. Reads to the end without keeping
+/// them.
+pub fn is_sorted<R: BufRead>(reader: R) -> Result<bool, BTreeError> {
+    let mut records = records(reader);
+    let mut sorted = true;
+    let mut last = None;
+    for record in records.by_ref() {
+        sorted &= last.is_none_or(|last| last < record[0]);
+        last = Some(record[0]);
+    }
+    records.finish()?;
+    Ok(sorted)
+}
+
+/// Adds the records read from `reader` to `tree`, returning how many were
+/// loaded and the lines that were skipped. An empty tree given sorted input
+/// is bulk-loaded, after a first pass that checks the order; otherwise
+/// records are inserted [`BATCH`] at a time.
+pub fn import<S, A, R>(
+    tree: &mut BPlusTree<S, i32, Record, A>,
+    mut reader: R,
+) -> Result<(usize, Vec<(usize, String)>), BTreeError>
+where
+    S: Storage,
+    A: Allocator,
+    R: BufRead + Seek,
+{
+    let bulk = tree.is_empty() && is_sorted(&mut reader)?;
+    reader.rewind()?;
+    let mut records = records(reader);
+    if bulk {
+        tree.load_sorted(records.by_ref().map(|record| (record[0], record)))?;
+    } else {
+        loop {
+            let batch: Vec<_> = records.by_ref().take(BATCH).collect();
+            if batch.is_empty() {
+                break;
+            }
+            tree.insert_batch(batch.into_iter().map(|record| (record[0], record)))?;
+        }
+    }
+    let loaded = records.loaded();
+    Ok((loaded, records.finish()?))
+}
+
+/// Writes every record of `tree` in key order, one line each. Returns how
+/// many were written.
+pub fn export<S, A, W>(
+    tree: &mut BPlusTree<S, i32, Record, A>,
+    mut writer: W,
+) -> Result<usize, BTreeError>
+where
+    S: Storage,
+    A: Allocator,
+    W: Write,
+{
+    let mut exported = 0;
+    for entry in tree.iter() {
+        let (_, record) = entry?;
+        writeln!(writer, "{}", record.to_text())?;
+        exported += 1;
+    }
+    writer.flush()?;
+    Ok(exported)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::memory_storage::InMemoryStorage;
+
+    #[test]
+    fn an_export_imports_back_in_either_order() {
+        let mut tree: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        for key in 0..300 {
+            tree.insert(key, [key, -key, 1, 2, 3, 4, 5]).unwrap();
+        }
+        let mut text = Vec::new();
+        assert_eq!(export(&mut tree, &mut text).unwrap(), 300);
+        let text = String::from_utf8(text).unwrap();
+
+        // Sorted into an empty tree: bulk-loaded
+        let mut sorted: BPlusTree<InMemoryStorage> =
+            BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let (loaded, skipped) = import(&mut sorted, Cursor::new(&text)).unwrap();
+        assert_eq!((loaded, skipped), (300, Vec::new()));
+        assert!(sorted.diff(&mut tree).next().is_none());
+
+        // Reversed, with a blank and a bad line: inserted in batches
+        let mut lines: Vec<&str> = text.lines().rev().collect();
+        lines.insert(1, "");
+        lines.insert(3, "not a record");
+        let reversed = lines.join("\n");
+        let mut unsorted: BPlusTree<InMemoryStorage> =
+            BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let (loaded, skipped) = import(&mut unsorted, Cursor::new(&reversed)).unwrap();
+        assert_eq!(loaded, 300);
+        assert_eq!(
+            skipped.iter().map(|(line, _)| *line).collect::<Vec<_>>(),
+            [4]
+        );
+        unsorted.validate().unwrap();
+        assert!(unsorted.diff(&mut tree).next().is_none());
+    }
+}
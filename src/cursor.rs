@@ -0,0 +1,120 @@
+use crate::allocator::{Allocator, FreeListAllocator};
+use crate::btree::BPlusTree;
+use crate::error::BTreeError;
+use crate::key::Key;
+use crate::node::LeafNode;
+use crate::record::Record;
+use crate::storage::Storage;
+use crate::value::Value;
+
+/// A position between two entries of the tree that can move in both
+/// directions, so a scan can be paused and resumed, or turned around,
+/// without descending from the root again.
+///
+/// [`next`](Cursor::next) returns the entry after the position and steps
+/// past it; [`prev`](Cursor::prev) does the same backwards. A new cursor is
+/// unpositioned: its first `next` starts at the smallest key and its first
+/// `prev` at the largest.
+pub struct Cursor<'a, S, K = i32, V = Record, A = FreeListAllocator> {
+    tree: &'a mut BPlusTree<S, K, V, A>,
+    /// Location and contents of the current leaf.
+    leaf: Option<(usize, LeafNode<K, V>)>,
+    /// Index into `leaf` of the entry just after the position.
+    index: usize,
+}
+
+impl<'a, S, K, V, A> Cursor<'a, S, K, V, A>
+where
+    S: Storage<K, V>,
+    K: Key,
+    V: Value,
+    A: Allocator,
+{
+    pub(crate) fn new(tree: &'a mut BPlusTree<S, K, V, A>) -> Self {
+        Cursor {
+            tree,
+            leaf: None,
+            index: 0,
+        }
+    }
+
+    /// Moves to just before the first entry with a key `>= key`.
+    pub fn seek(&mut self, key: &K) -> Result<(), BTreeError> {
+        let loc = self.tree.find_leaf(key)?;
+        let leaf = self.tree.read_leaf(loc)?;
+        self.index = leaf.keys.partition_point(|k| k < key);
+        self.leaf = Some((loc, leaf));
+        Ok(())
+    }
+
+    /// Moves to just before the smallest key.
+    pub fn seek_first(&mut self) -> Result<(), BTreeError> {
+        let loc = self.tree.leftmost_leaf()?;
+        self.leaf = Some((loc, self.tree.read_leaf(loc)?));
+        self.index = 0;
+        Ok(())
+    }
+
+    /// Moves to just after the largest key.
+    pub fn seek_last(&mut self) -> Result<(), BTreeError> {
+        let loc = self.tree.rightmost_leaf()?;
+        let leaf = self.tree.read_leaf(loc)?;
+        self.index = leaf.keys.len();
+        self.leaf = Some((loc, leaf));
+        Ok(())
+    }
+
+    /// Returns the entry after the position and steps past it, or `None`
+    /// at the end of the tree.
+    // Not an `Iterator`: it can fail, and pairs with `prev`
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<(K, V)>, BTreeError> {
+        if self.leaf.is_none() {
+            self.seek_first()?;
+        }
+        loop {
+            let Some((_, leaf)) = &self.leaf else {
+                return Ok(None);
+            };
+            if self.index < leaf.keys.len() {
+                let entry = (
+                    leaf.keys[self.index].clone(),
+                    leaf.values[self.index].clone(),
+                );
+                self.index += 1;
+                return Ok(Some(entry));
+            }
+
+            let Some(next) = leaf.next else {
+                return Ok(None);
+            };
+            self.leaf = Some((next, self.tree.read_leaf(next)?));
+            self.index = 0;
+        }
+    }
+
+    /// Returns the entry before the position and steps back over it, or
+    /// `None` at the start of the tree.
+    pub fn prev(&mut self) -> Result<Option<(K, V)>, BTreeError> {
+        if self.leaf.is_none() {
+            self.seek_last()?;
+        }
+        loop {
+            let Some((_, leaf)) = &self.leaf else {
+                return Ok(None);
+            };
+            if self.index > 0 {
+                self.index -= 1;
+                let i = self.index;
+                return Ok(Some((leaf.keys[i].clone(), leaf.values[i].clone())));
+            }
+
+            let Some(prev) = leaf.prev else {
+                return Ok(None);
+            };
+            let leaf = self.tree.read_leaf(prev)?;
+            self.index = leaf.keys.len();
+            self.leaf = Some((prev, leaf));
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::cmp::Ordering;
+use std::iter::Peekable;
+
+use crate::error::BTreeError;
+use crate::iter::Records;
+use crate::key::Key;
+use crate::record::Record;
+use crate::storage::Storage;
+use crate::value::Value;
+
+/// One difference between two trees, seen from the first towards the second.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diff<K = i32, V = Record> {
+    Added(K, V),
+    Removed(K, V),
+    Changed { key: K, old: V, new: V },
+}
+
+/// Merge-walks the leaf chains of two trees, yielding their differences in
+/// key order. A read error on either side is yielded as soon as it is hit.
+pub struct DiffIter<'a, S1, S2, K = i32, V = Record>
+where
+    S1: Storage<K, V>,
+    S2: Storage<K, V>,
+    K: Key,
+    V: Value,
+{
+    old: Peekable<Records<'a, S1, K, V>>,
+    new: Peekable<Records<'a, S2, K, V>>,
+}
+
+impl<'a, S1, S2, K, V> DiffIter<'a, S1, S2, K, V>
+where
+    S1: Storage<K, V>,
+    S2: Storage<K, V>,
+    K: Key,
+    V: Value,
+{
+    pub(crate) fn new(old: Records<'a, S1, K, V>, new: Records<'a, S2, K, V>) -> Self {
+        DiffIter {
+            old: old.peekable(),
+            new: new.peekable(),
+        }
+    }
+}
+
+impl<S1, S2, K, V> Iterator for DiffIter<'_, S1, S2, K, V>
+where
+    S1: Storage<K, V>,
+    S2: Storage<K, V>,
+    K: Key,
+    V: Value,
+{
+    type Item = Result<Diff<K, V>, BTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Errors sort first so they are taken, and reported, right away
+            let order = match (self.old.peek(), self.new.peek()) {
+                (None, None) => return None,
+                (Some(Err(_)), _) | (Some(_), None) => Ordering::Less,
+                (_, Some(Err(_))) | (None, Some(_)) => Ordering::Greater,
+                (Some(Ok((old, _))), Some(Ok((new, _)))) => old.cmp(new),
+            };
+
+            match order {
+                Ordering::Less => {
+                    return self
+                        .old
+                        .next()
+                        .map(|entry| entry.map(|(key, old)| Diff::Removed(key, old)));
+                }
+                Ordering::Greater => {
+                    return self
+                        .new
+                        .next()
+                        .map(|entry| entry.map(|(key, new)| Diff::Added(key, new)));
+                }
+                Ordering::Equal => {
+                    if let (Some(Ok((key, old))), Some(Ok((_, new)))) =
+                        (self.old.next(), self.new.next())
+                        && old != new
+                    {
+                        return Some(Ok(Diff::Changed { key, old, new }));
+                    }
+                }
+            }
+        }
+    }
+}
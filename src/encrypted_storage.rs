@@ -0,0 +1,494 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+
+use crate::config::PAGE_SIZE;
+use crate::error::{BTreeError, SerializeError};
+use crate::key::Key;
+use crate::node::Node;
+use crate::record::Record;
+use crate::storage::{self, Storage};
+use crate::value::Value;
+
+/// First bytes of an encrypted file, telling it from a [`FileStorage`]
+/// file.
+///
+/// [`FileStorage`]: crate::FileStorage
+const FILE_MAGIC: u32 = u32::from_le_bytes(*b"BPEN");
+/// The preamble before the first page: the magic number, a tag proving the
+/// key, and the counter reservation, padded so pages stay block-aligned.
+const PREAMBLE_SIZE: u64 = 4096;
+const KEY_CHECK_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// Every page starts with its nonce and the length of its ciphertext.
+const PAGE_HEADER_SIZE: usize = NONCE_SIZE + 4;
+/// Bytes of a page left for the encoded node.
+const PAYLOAD_SIZE: usize = PAGE_SIZE - PAGE_HEADER_SIZE - TAG_SIZE;
+/// Counters reserved in the preamble at a time, so it is only rewritten
+/// once every this many page writes.
+const COUNTER_BLOCK: u64 = 1 << 16;
+
+/// A 256-bit AES key.
+pub type EncryptionKey = [u8; 32];
+
+/// Storage encrypting each page with AES-256-GCM under a key supplied at
+/// open time, so a database file can sit on a disk others can read. Pages
+/// keep their fixed slots, each holding its nonce and ciphertext; the page
+/// location is authenticated along with it, so pages cannot be swapped
+/// around without reads failing.
+///
+/// A nonce is a write counter followed by the page number. The counter
+/// never repeats under one key: the file reserves counters in blocks and
+/// stores the end of the block in use, so a crash skips the rest of it
+/// rather than reusing it. [`rekey`](Self::rekey) moves a file to a new key.
+///
+/// Storages hold decoded nodes rather than bytes, so this works on its own
+/// file instead of wrapping another storage, and neither this nor
+/// [`FileStorage`](crate::FileStorage) opens the other's files.
+/// [`write_atomic`](Storage::write_atomic) writes pages one by one, so a
+/// crash partway through a commit can leave part of it behind.
+pub struct EncryptedStorage<K = i32, V = Record> {
+    file: File,
+    path: PathBuf,
+    cipher: Aes256Gcm,
+    /// Next counter a page write uses.
+    counter: u64,
+    /// End of the counters reserved in the preamble.
+    reserved: u64,
+    /// Header write held back until [`flush`](Storage::flush), so it only
+    /// reaches the disk after the pages it points to.
+    pending_header: Option<(usize, Node<K, V>)>,
+    /// Pages read from and written to the file.
+    pub page_reads: usize,
+    pub page_writes: usize,
+    marker: PhantomData<(K, V)>,
+}
+
+impl<K, V> fmt::Debug for EncryptedStorage<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedStorage")
+            .field("file", &self.file)
+            .field("counter", &self.counter)
+            .field("reserved", &self.reserved)
+            .field("page_reads", &self.page_reads)
+            .field("page_writes", &self.page_writes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K: Key, V: Value> EncryptedStorage<K, V> {
+    /// Opens or creates the file at `path` under `key`, failing with
+    /// [`BTreeError::WrongKey`] if the file was encrypted with another.
+    pub fn new(path: &str, key: &EncryptionKey) -> Result<Self, BTreeError> {
+        Self::with_cipher(path, Aes256Gcm::new(key.into()))
+    }
+
+    fn with_cipher(path: &str, cipher: Aes256Gcm) -> Result<Self, BTreeError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut storage = Self {
+            file,
+            path: PathBuf::from(path),
+            cipher,
+            counter: 1,
+            reserved: 1,
+            pending_header: None,
+            page_reads: 0,
+            page_writes: 0,
+            marker: PhantomData,
+        };
+        if storage.file.metadata()?.len() == 0 {
+            storage.file.set_len(PREAMBLE_SIZE)?;
+        } else {
+            storage.read_preamble()?;
+        }
+        storage.reserve()?;
+        Ok(storage)
+    }
+
+    /// Re-encrypts the file at `path` from `old` to `new`, by writing every
+    /// page to a new file that then replaces it. The file is left as it was
+    /// if anything fails, including a page that does not decrypt.
+    pub fn rekey(path: &str, old: &EncryptionKey, new: &EncryptionKey) -> Result<(), BTreeError> {
+        let mut source = Self::new(path, old)?;
+        let rekeyed = Self::rekey_path(Path::new(path));
+        let _ = fs::remove_file(&rekeyed);
+        let mut target = Self::new(rekeyed.to_str().expect("built from a valid path"), new)?;
+        for loc in 0..source.total_nodes()? {
+            if let Some(plaintext) = source.read_plaintext(loc)? {
+                target.write_plaintext(loc, &plaintext)?;
+            }
+        }
+        target.file.sync_all()?;
+        drop(target);
+        fs::rename(&rekeyed, path)?;
+        Ok(())
+    }
+
+    fn rekey_path(path: &Path) -> PathBuf {
+        let mut rekeyed = path.as_os_str().to_owned();
+        rekeyed.push(".rekey");
+        PathBuf::from(rekeyed)
+    }
+
+    /// Checks the magic number and key, and picks up the counter where the
+    /// last reservation ended.
+    fn read_preamble(&mut self) -> Result<(), BTreeError> {
+        let mut preamble = [0; 4 + KEY_CHECK_SIZE + 8];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_exact(&mut preamble)?;
+        let magic = u32::from_le_bytes(preamble[..4].try_into().unwrap());
+        if magic != FILE_MAGIC {
+            return Err(BTreeError::BadMagic { found: magic });
+        }
+        if preamble[4..4 + KEY_CHECK_SIZE] != self.key_check() {
+            return Err(BTreeError::WrongKey);
+        }
+        self.reserved = u64::from_le_bytes(preamble[4 + KEY_CHECK_SIZE..].try_into().unwrap());
+        self.counter = self.reserved;
+        Ok(())
+    }
+
+    /// Reserves the next block of counters, syncing the preamble before any
+    /// of them is used.
+    fn reserve(&mut self) -> Result<(), BTreeError> {
+        let reserved = self.counter + COUNTER_BLOCK;
+        let mut preamble = Vec::with_capacity(4 + KEY_CHECK_SIZE + 8);
+        preamble.extend_from_slice(&FILE_MAGIC.to_le_bytes());
+        preamble.extend_from_slice(&self.key_check());
+        preamble.extend_from_slice(&reserved.to_le_bytes());
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&preamble)?;
+        self.file.sync_data()?;
+        self.reserved = reserved;
+        Ok(())
+    }
+
+    /// The tag of an empty message under counter 0, which no page uses, so
+    /// a wrong key is caught on open rather than on the first read.
+    fn key_check(&self) -> Vec<u8> {
+        self.cipher
+            .encrypt(Nonce::from_slice(&Self::nonce(0, u32::MAX)), &b""[..])
+            .expect("an empty message encrypts")
+    }
+
+    fn nonce(counter: u64, page: u32) -> [u8; NONCE_SIZE] {
+        let mut nonce = [0; NONCE_SIZE];
+        nonce[..8].copy_from_slice(&counter.to_le_bytes());
+        nonce[8..].copy_from_slice(&page.to_le_bytes());
+        nonce
+    }
+
+    fn offset(loc: usize) -> u64 {
+        PREAMBLE_SIZE + (loc * PAGE_SIZE) as u64
+    }
+
+    /// The decrypted page at `loc`, or `None` for a page never written.
+    fn read_plaintext(&mut self, loc: usize) -> Result<Option<Vec<u8>>, BTreeError> {
+        let corrupt = |reason| BTreeError::Corrupt { loc, reason };
+        let mut page = vec![0; PAGE_SIZE];
+        self.file.seek(SeekFrom::Start(Self::offset(loc)))?;
+        match self.file.read_exact(&mut page) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                return Err(corrupt("page was never written"));
+            }
+            result => result?,
+        }
+        if page.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes(page[NONCE_SIZE..PAGE_HEADER_SIZE].try_into().unwrap());
+        let ciphertext = page
+            .get(PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + len as usize)
+            .ok_or(corrupt("ciphertext length out of range"))?;
+        let aad = (loc as u64).to_le_bytes();
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&page[..NONCE_SIZE]),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| corrupt("page fails authentication"))?;
+        self.page_reads += 1;
+        Ok(Some(plaintext))
+    }
+
+    fn write_plaintext(&mut self, loc: usize, plaintext: &[u8]) -> Result<(), BTreeError> {
+        if self.counter == self.reserved {
+            self.reserve()?;
+        }
+        let nonce = Self::nonce(self.counter, loc as u32);
+        self.counter += 1;
+        let aad = (loc as u64).to_le_bytes();
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .expect("a page-sized message encrypts");
+        let mut page = vec![0; PAGE_SIZE];
+        page[..NONCE_SIZE].copy_from_slice(&nonce);
+        page[NONCE_SIZE..PAGE_HEADER_SIZE]
+            .copy_from_slice(&(ciphertext.len() as u32).to_le_bytes());
+        page[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + ciphertext.len()].copy_from_slice(&ciphertext);
+        self.file.seek(SeekFrom::Start(Self::offset(loc)))?;
+        self.file.write_all(&page)?;
+        self.page_writes += 1;
+        Ok(())
+    }
+
+    fn write_pending_header(&mut self) -> Result<(), BTreeError> {
+        if let Some((loc, header)) = self.pending_header.take() {
+            self.file.sync_data()?;
+            let encoded = Self::encode(&header)?;
+            self.write_plaintext(loc, &encoded)?;
+        }
+        Ok(())
+    }
+
+    fn encode(node: &Node<K, V>) -> Result<Vec<u8>, BTreeError> {
+        let encoded = bincode::encode_to_vec(node, bincode::config::standard())
+            .map_err(SerializeError::from)?;
+        if encoded.len() > PAYLOAD_SIZE {
+            return Err(SerializeError::NodeTooLarge {
+                size: encoded.len(),
+                capacity: PAYLOAD_SIZE,
+            }
+            .into());
+        }
+        Ok(encoded)
+    }
+}
+
+impl<K: Key, V: Value> Storage<K, V> for EncryptedStorage<K, V> {
+    fn read_node(&mut self, loc: usize) -> Result<Node<K, V>, BTreeError> {
+        if let Some((pending, header)) = &self.pending_header
+            && *pending == loc
+        {
+            return Ok(header.clone());
+        }
+        let plaintext = self.read_plaintext(loc)?.ok_or(BTreeError::Corrupt {
+            loc,
+            reason: "page was never written",
+        })?;
+        let (node, _) = bincode::decode_from_slice(&plaintext, bincode::config::standard())
+            .map_err(|_| BTreeError::Corrupt {
+                loc,
+                reason: "node does not decode",
+            })?;
+        Ok(node)
+    }
+
+    fn write_node(&mut self, loc: usize, node: &Node<K, V>) -> Result<(), BTreeError> {
+        if matches!(node, Node::Header(_)) {
+            // Checked now, so the error comes from the write that caused it
+            Self::encode(node)?;
+            self.pending_header = Some((loc, node.clone()));
+            return Ok(());
+        }
+        let encoded = Self::encode(node)?;
+        self.write_plaintext(loc, &encoded)
+    }
+
+    /// Syncs the pages written so far, then writes the held-back header and
+    /// syncs again.
+    fn flush(&mut self) -> Result<(), BTreeError> {
+        self.write_pending_header()?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn total_nodes(&self) -> Result<usize, BTreeError> {
+        let len = self.file.metadata()?.len().saturating_sub(PREAMBLE_SIZE);
+        let pages = (len / PAGE_SIZE as u64) as usize;
+        Ok(match &self.pending_header {
+            Some((loc, _)) => pages.max(loc + 1),
+            None => pages,
+        })
+    }
+
+    fn truncate(&mut self, pages: usize) -> Result<(), BTreeError> {
+        if self
+            .pending_header
+            .as_ref()
+            .is_some_and(|(loc, _)| *loc >= pages)
+        {
+            self.pending_header = None;
+        }
+        self.file.set_len(Self::offset(pages))?;
+        Ok(())
+    }
+
+    fn page_capacity(&self) -> usize {
+        PAYLOAD_SIZE
+    }
+
+    fn io_counters(&self) -> (usize, usize) {
+        (self.page_reads, self.page_writes)
+    }
+
+    fn trailing_bytes(&self) -> Result<usize, BTreeError> {
+        let len = self.file.metadata()?.len().saturating_sub(PREAMBLE_SIZE);
+        Ok((len % PAGE_SIZE as u64) as usize)
+    }
+
+    fn file_path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    /// Encrypts the copy under the same key.
+    fn create_copy(&self, path: &str) -> Result<Self, BTreeError> {
+        storage::clear_for_copy(&self.path, path)?;
+        Self::with_cipher(path, self.cipher.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::BPlusTree;
+    use crate::file_storage::FileStorage;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("btree-{}-{}.db", name, std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn a_backup_stays_encrypted_under_the_same_key() {
+        let (path, copy) = (temp_path("enc-source"), temp_path("enc-backup"));
+        let key = [7; 32];
+        let mut tree: BPlusTree<EncryptedStorage> =
+            BPlusTree::open(EncryptedStorage::new(&path, &key).unwrap()).unwrap();
+        for key in 0..200 {
+            tree.insert(key, [key; 7]).unwrap();
+        }
+        assert!(matches!(
+            tree.backup(&path),
+            Err(BTreeError::BackupOverSource { .. })
+        ));
+        tree.backup(&copy).unwrap();
+        drop(tree);
+
+        // No record reaches the file in the clear
+        let bytes = fs::read(&copy).unwrap();
+        assert_eq!(bytes[..4], FILE_MAGIC.to_le_bytes());
+        let plain = FileStorage::open_read_only(&copy).unwrap();
+        assert!(BPlusTree::<FileStorage>::open_read_only(plain).is_err());
+        assert!(matches!(
+            EncryptedStorage::<i32, Record>::new(&copy, &[8; 32]),
+            Err(BTreeError::WrongKey)
+        ));
+
+        let mut backup: BPlusTree<EncryptedStorage> =
+            BPlusTree::open(EncryptedStorage::new(&copy, &key).unwrap()).unwrap();
+        backup.validate().unwrap();
+        assert_eq!(backup.len(), 200);
+        for key in 0..200 {
+            assert_eq!(backup.get(&key).unwrap(), Some([key; 7]));
+        }
+        drop(backup);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&copy).unwrap();
+    }
+
+    #[test]
+    fn a_page_moved_to_another_slot_fails_authentication() {
+        let path = temp_path("enc-moved");
+        let key = [3; 32];
+        let mut tree: BPlusTree<EncryptedStorage> =
+            BPlusTree::open(EncryptedStorage::new(&path, &key).unwrap()).unwrap();
+        for key in 0..100 {
+            tree.insert(key, [key; 7]).unwrap();
+        }
+        drop(tree);
+
+        // Page 1 copied over page 2 decrypts under its own location only
+        let mut bytes = fs::read(&path).unwrap();
+        let page = |loc: usize| EncryptedStorage::<i32, Record>::offset(loc) as usize;
+        let moved = bytes[page(1)..page(2)].to_vec();
+        bytes[page(2)..page(3)].copy_from_slice(&moved);
+        fs::write(&path, &bytes).unwrap();
+
+        let mut storage: EncryptedStorage = EncryptedStorage::new(&path, &key).unwrap();
+        storage.read_node(1).unwrap();
+        assert!(matches!(
+            storage.read_node(2),
+            Err(BTreeError::Corrupt {
+                loc: 2,
+                reason: "page fails authentication"
+            })
+        ));
+        drop(storage);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_rekeyed_file_opens_only_under_the_new_key() {
+        let path = temp_path("enc-rekey");
+        let (old, new) = ([1; 32], [2; 32]);
+        let mut tree: BPlusTree<EncryptedStorage> =
+            BPlusTree::open(EncryptedStorage::new(&path, &old).unwrap()).unwrap();
+        for key in 0..150 {
+            tree.insert(key, [key; 7]).unwrap();
+        }
+        drop(tree);
+
+        assert!(matches!(
+            EncryptedStorage::<i32, Record>::rekey(&path, &new, &old),
+            Err(BTreeError::WrongKey)
+        ));
+        EncryptedStorage::<i32, Record>::rekey(&path, &old, &new).unwrap();
+        assert!(matches!(
+            EncryptedStorage::<i32, Record>::new(&path, &old),
+            Err(BTreeError::WrongKey)
+        ));
+        let mut tree: BPlusTree<EncryptedStorage> =
+            BPlusTree::open(EncryptedStorage::new(&path, &new).unwrap()).unwrap();
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 150);
+        assert_eq!(tree.get(&77).unwrap(), Some([77; 7]));
+        drop(tree);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_reopened_file_never_reuses_a_counter() {
+        let path = temp_path("enc-counter");
+        let key = [5; 32];
+        let mut storage: EncryptedStorage = EncryptedStorage::new(&path, &key).unwrap();
+        let mut leaf = crate::node::LeafNode::new();
+        leaf.keys.push(1);
+        leaf.values.push([1; 7]);
+        for loc in 0..10 {
+            storage.write_node(loc, &Node::Leaf(leaf.clone())).unwrap();
+        }
+        let used = storage.counter;
+        // As if the process crashed: nothing records the counters used
+        std::mem::forget(storage);
+
+        let storage: EncryptedStorage = EncryptedStorage::new(&path, &key).unwrap();
+        assert!(storage.counter >= used);
+        drop(storage);
+        fs::remove_file(&path).unwrap();
+    }
+}
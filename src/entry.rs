@@ -0,0 +1,107 @@
+use crate::allocator::{Allocator, FreeListAllocator};
+use crate::btree::BPlusTree;
+use crate::error::BTreeError;
+use crate::key::Key;
+use crate::node::{InternalNode, LeafNode};
+use crate::record::Record;
+use crate::storage::Storage;
+use crate::value::Value;
+
+/// A key and the leaf it belongs in, from [`BPlusTree::entry`], so a value
+/// can be read and then inserted or modified without descending again.
+///
+/// In a multimap every entry is vacant, as inserts never overwrite.
+pub struct Entry<'a, S, K = i32, V = Record, A = FreeListAllocator> {
+    tree: &'a mut BPlusTree<S, K, V, A>,
+    key: K,
+    /// Location and contents of the leaf, and the internal nodes above it.
+    loc: usize,
+    leaf: LeafNode<K, V>,
+    path: Vec<(usize, InternalNode<K>)>,
+    /// `Ok(i)` if the key is entry `i` of the leaf, `Err(i)` if it would be
+    /// inserted at `i`.
+    slot: Result<usize, usize>,
+}
+
+impl<'a, S, K, V, A> Entry<'a, S, K, V, A>
+where
+    S: Storage<K, V>,
+    K: Key,
+    V: Value,
+    A: Allocator,
+{
+    pub(crate) fn new(
+        tree: &'a mut BPlusTree<S, K, V, A>,
+        key: K,
+        loc: usize,
+        leaf: LeafNode<K, V>,
+        path: Vec<(usize, InternalNode<K>)>,
+        slot: Result<usize, usize>,
+    ) -> Self {
+        Entry {
+            tree,
+            key,
+            loc,
+            leaf,
+            path,
+            slot,
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// The value stored under the key, if any.
+    pub fn get(&self) -> Option<&V> {
+        self.slot.ok().map(|i| &self.leaf.values[i])
+    }
+
+    /// Applies `f` to the stored value and writes the leaf back, if the key
+    /// is present. A vacant entry is returned unchanged.
+    pub fn and_modify(mut self, f: impl FnOnce(&mut V)) -> Result<Self, BTreeError> {
+        if let Ok(i) = self.slot {
+            self.tree.check_writable()?;
+            let old = self.leaf.values[i].clone();
+            f(&mut self.leaf.values[i]);
+            BPlusTree::<S, K, V, A>::check_value_size(&self.leaf.values[i])?;
+            if self.leaf.values[i].overflow() != old.overflow() {
+                self.tree.free_overflow(&old)?;
+            }
+            self.tree
+                .commit_leaf(self.loc, self.leaf.clone(), &mut self.path, false)?;
+        }
+        Ok(self)
+    }
+
+    /// Returns the stored value, first inserting the one made by `f` if the
+    /// key is absent.
+    pub fn or_insert_with(mut self, f: impl FnOnce() -> V) -> Result<V, BTreeError> {
+        match self.slot {
+            Ok(i) => Ok(self.leaf.values[i].clone()),
+            Err(i) => {
+                self.tree.check_writable()?;
+                BPlusTree::<S, K, V, A>::check_key_size(&self.key)?;
+                let value = f();
+                BPlusTree::<S, K, V, A>::check_value_size(&value)?;
+                self.tree.bloom_add(&self.key)?;
+                self.leaf.keys.insert(i, self.key);
+                self.leaf.values.insert(i, value.clone());
+                self.tree
+                    .commit_leaf(self.loc, self.leaf, &mut self.path, true)?;
+                Ok(value)
+            }
+        }
+    }
+
+    pub fn or_insert(self, value: V) -> Result<V, BTreeError> {
+        self.or_insert_with(|| value)
+    }
+
+    pub fn or_default(self) -> Result<V, BTreeError>
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+}
@@ -0,0 +1,256 @@
+use bincode::error::EncodeError;
+use std::fmt;
+use std::io;
+
+use crate::key::Collation;
+
+#[derive(Debug)]
+pub enum BTreeError {
+    Io(io::Error),
+    /// A page is missing, fails its checksum, or holds the wrong kind of
+    /// node for where it was reached from.
+    Corrupt {
+        loc: usize,
+        reason: &'static str,
+    },
+    NotAHeader,
+    /// Page 0 holds a header without the magic number, so the file is not
+    /// a tree.
+    BadMagic {
+        found: u32,
+    },
+    /// The file was written in an on-disk format this code cannot read.
+    UnsupportedVersion {
+        found: u32,
+        supported: u32,
+    },
+    /// The file was created with pages of a different size.
+    PageSizeMismatch {
+        found: usize,
+        expected: usize,
+    },
+    /// The file was created with string keys of another collation, or
+    /// with keys of another kind.
+    CollationMismatch {
+        found: Option<Collation>,
+        expected: Option<Collation>,
+    },
+    /// The file was created with keys sorted in another order.
+    OrderMismatch {
+        found: String,
+        expected: String,
+    },
+    /// The file's pages were encoded with another codec than the storage's.
+    CodecMismatch {
+        found: String,
+        expected: String,
+    },
+    /// The secondary index was built on another field of the records.
+    IndexFieldMismatch {
+        found: usize,
+        expected: usize,
+    },
+    PartialPage {
+        trailing: usize,
+    },
+    /// The file was encrypted under another key.
+    WrongKey,
+    /// A write to a tree or storage opened read-only.
+    ReadOnly,
+    /// [`open_multimap`](crate::BPlusTree::open_multimap) on a tree created
+    /// with unique keys.
+    NotMultimap,
+    HeightLimit {
+        max_height: usize,
+    },
+    /// No bucket of this name exists in the file.
+    NoSuchBucket {
+        name: String,
+    },
+    BucketExists {
+        name: String,
+    },
+    /// A bucket name that is empty or longer than
+    /// [`MAX_BUCKET_NAME`](crate::node::MAX_BUCKET_NAME) bytes.
+    BadBucketName {
+        name: String,
+    },
+    TooManyBuckets {
+        max: usize,
+    },
+    /// An attempt to drop the bucket the tree is using.
+    BucketInUse {
+        name: String,
+    },
+    /// A backup aimed at the file the tree itself is kept in.
+    BackupOverSource {
+        path: String,
+    },
+    /// A backup of a storage that cannot create another like itself.
+    BackupUnsupported,
+    /// Input to a bulk load out of ascending key order, first at entry
+    /// `index`.
+    Unsorted {
+        index: usize,
+    },
+    Serialize(SerializeError),
+}
+
+#[derive(Debug)]
+pub enum SerializeError {
+    Encode(EncodeError),
+    NodeTooLarge {
+        size: usize,
+        capacity: usize,
+    },
+    /// A key longer than nodes are sized for, refused before any page is
+    /// written.
+    KeyTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// A value longer than leaves are sized for, refused before any page is
+    /// written.
+    ValueTooLarge {
+        size: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for BTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BTreeError::Io(err) => write!(f, "I/O error: {}", err),
+            BTreeError::Corrupt { loc, reason } => write!(f, "page {} is corrupt: {}", loc, reason),
+            BTreeError::NotAHeader => write!(f, "page 0 is not a tree header"),
+            BTreeError::BadMagic { found } => {
+                write!(f, "not a tree file: bad magic number {:#010x}", found)
+            }
+            BTreeError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "file format version {} is not supported, expected {}",
+                found, supported
+            ),
+            BTreeError::PageSizeMismatch { found, expected } => write!(
+                f,
+                "file was created with {}-byte pages, expected {}",
+                found, expected
+            ),
+            BTreeError::CollationMismatch { found, expected } => write!(
+                f,
+                "file was created with {} keys, expected {}",
+                describe_collation(*found),
+                describe_collation(*expected)
+            ),
+            BTreeError::OrderMismatch { found, expected } => write!(
+                f,
+                "file was created with keys in {} order, expected {}",
+                found, expected
+            ),
+            BTreeError::CodecMismatch { found, expected } => write!(
+                f,
+                "file was written with the {} page codec, expected {}",
+                found, expected
+            ),
+            BTreeError::IndexFieldMismatch { found, expected } => write!(
+                f,
+                "index was built on record field {}, expected {}",
+                found, expected
+            ),
+            BTreeError::Unsorted { index } => {
+                write!(f, "entry {} of the sorted input is out of order", index)
+            }
+            BTreeError::PartialPage { trailing } => {
+                write!(f, "file ends with a partial page of {} bytes", trailing)
+            }
+            BTreeError::WrongKey => write!(f, "the file was encrypted with another key"),
+            BTreeError::ReadOnly => write!(f, "the database is open read-only"),
+            BTreeError::NotMultimap => write!(f, "the tree was created with unique keys"),
+            BTreeError::HeightLimit { max_height } => {
+                write!(f, "insert would grow the tree past {} levels", max_height)
+            }
+            BTreeError::NoSuchBucket { name } => write!(f, "no bucket named {:?}", name),
+            BTreeError::BucketExists { name } => write!(f, "bucket {:?} already exists", name),
+            BTreeError::BadBucketName { name } => write!(f, "invalid bucket name {:?}", name),
+            BTreeError::TooManyBuckets { max } => {
+                write!(f, "a file holds at most {} buckets", max)
+            }
+            BTreeError::BucketInUse { name } => write!(f, "bucket {:?} is in use", name),
+            BTreeError::BackupOverSource { path } => {
+                write!(f, "cannot back up onto {}, the database's own file", path)
+            }
+            BTreeError::BackupUnsupported => write!(f, "this storage cannot be backed up"),
+            BTreeError::Serialize(err) => write!(f, "cannot serialize node: {}", err),
+        }
+    }
+}
+
+fn describe_collation(collation: Option<Collation>) -> &'static str {
+    match collation {
+        Some(Collation::Bytewise) => "bytewise string",
+        Some(Collation::CaseInsensitive) => "case-insensitive string",
+        None => "non-string",
+    }
+}
+
+impl std::error::Error for BTreeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BTreeError::Io(err) => Some(err),
+            BTreeError::Serialize(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerializeError::Encode(err) => write!(f, "encoding failed: {}", err),
+            SerializeError::NodeTooLarge { size, capacity } => {
+                write!(f, "node needs {} bytes but a page holds {}", size, capacity)
+            }
+            SerializeError::KeyTooLarge { size, max } => {
+                write!(
+                    f,
+                    "key needs {} bytes but at most {} are allowed",
+                    size, max
+                )
+            }
+            SerializeError::ValueTooLarge { size, max } => {
+                write!(
+                    f,
+                    "value needs {} bytes but at most {} are allowed",
+                    size, max
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SerializeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SerializeError::Encode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BTreeError {
+    fn from(err: io::Error) -> Self {
+        BTreeError::Io(err)
+    }
+}
+
+impl From<SerializeError> for BTreeError {
+    fn from(err: SerializeError) -> Self {
+        BTreeError::Serialize(err)
+    }
+}
+
+impl From<EncodeError> for SerializeError {
+    fn from(err: EncodeError) -> Self {
+        SerializeError::Encode(err)
+    }
+}
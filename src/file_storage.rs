@@ -1,100 +1,1369 @@
-use bincode::error::DecodeError;
-
-use crate::storage::Storage;
+use crate::codec::{PageCodec, Slotted};
+use crate::error::{BTreeError, SerializeError};
+use crate::key::Key;
+use crate::lru::LruCache;
+use crate::node::{LeafKeys, NodeKind};
+use crate::record::Record;
+use crate::storage::{self, SharedStorage, Storage};
+use crate::value::Value;
 use crate::{config::PAGE_SIZE, node::Node};
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// Every page starts with a version stamp that is odd while a write is in
+/// flight. Readers sharing the file with a writer re-check it after reading
+/// and retry on a change, so a torn page is never decoded.
+pub(crate) const STAMP_SIZE: usize = 8;
+/// The last bytes of every page hold a CRC32 of the encoded node, so
+/// flipped bits are caught instead of decoding into a wrong node.
+const CHECKSUM_SIZE: usize = 4;
+/// Bytes of a page available to the encoded node.
+pub(crate) const PAYLOAD_SIZE: usize = PAGE_SIZE - STAMP_SIZE - CHECKSUM_SIZE;
+pub(crate) const MAX_READ_RETRIES: usize = 1000;
+/// A journal entry is the page location followed by the full page.
+const JOURNAL_ENTRY_SIZE: usize = 8 + PAGE_SIZE;
+/// The journal ends with the entry count and a CRC32 of everything before
+/// it, so a journal cut short by a crash is recognised and ignored.
+const JOURNAL_TRAILER_SIZE: usize = 8 + CHECKSUM_SIZE;
+/// Pages held in memory before they move to the journal of the next flush,
+/// bounding the memory a long run of writes without one can take.
+const MAX_DIRTY_PAGES: usize = 256;
+
+/// A node waiting to be written, with the page it encodes to.
+type HeldPage<K, V> = (Node<K, V>, Box<[u8; PAGE_SIZE]>);
+
+/// The journal of the next flush while it is being written. Pages past
+/// [`MAX_DIRTY_PAGES`] go here rather than to the file, and the flush adds
+/// the rest, writes the trailer and only then updates the file.
+struct OpenJournal {
+    file: File,
+    /// Offset of the latest entry for each page but the header.
+    pages: BTreeMap<usize, u64>,
+    /// Location and offset of the header's entry, which is applied last.
+    header: Option<(usize, u64)>,
+    entries: u64,
+    len: u64,
+    checksum: crc32fast::Hasher,
+    /// Set once the trailer is written. The entries must then reach the
+    /// file before another journal replaces this one.
+    committed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// How eagerly a [`FileStorage`] syncs the file to disk.
+///
+/// Every flush goes through the redo journal whatever the mode, so the
+/// process crashing leaves the file as of one flush or the next once it is
+/// reopened. Surviving power loss as well takes the syncs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Never syncs, not even the journal of a flush, so pages reach the
+    /// disk whenever the OS writes them back, and power loss can tear a
+    /// flush. [`sync`](Storage::sync) still syncs.
+    NoSync,
+    /// Syncs the journal of every flush, which each insert, delete and
+    /// commit ends with, before updating the file, and the file before
+    /// removing the journal.
+    #[default]
+    SyncOnCommit,
+    /// Like `SyncOnCommit`, and also syncs after every page write.
+    SyncEveryWrite,
+}
 
-#[derive(Debug)]
-pub struct FileStorage {
+/// Called on every successful page access with the page location and node kind.
+pub type Instrument = Box<dyn FnMut(Access, usize, NodeKind) + Send + Sync>;
+
+pub struct FileStorage<K = i32, V = Record> {
     pub file: File,
+    /// Pages read from disk; reads served by the cache are not counted.
     pub page_reads: usize,
     pub page_writes: usize,
+    /// Page writes replaced by a later write to the same page before they
+    /// reached the file, so not counted in `page_writes`.
+    pub coalesced_writes: usize,
+    pub cache_hits: usize,
+    /// Decoded pages kept in memory. Leave it empty when other processes
+    /// write to the same file, as cached pages are not revalidated.
+    cache: LruCache<Node<K, V>>,
+    /// Pages written since the last [`flush`](Storage::flush), by location.
+    /// A page written again replaces its entry, and the flush journals them
+    /// in location order.
+    dirty: BTreeMap<usize, HeldPage<K, V>>,
+    /// Header write held back until [`flush`](Storage::flush), so it only
+    /// reaches the file along with the pages it points to.
+    pending_header: Option<(usize, HeldPage<K, V>)>,
+    /// Journal of the next flush, once pages have spilled into it.
+    open_journal: Option<OpenJournal>,
+    path: PathBuf,
+    /// Redo journal every flush goes through, next to the database file.
+    /// It only exists while pages are spilled or a flush is being applied.
+    journal: PathBuf,
+    /// Set by [`open_read_only`](Self::open_read_only); writes are refused.
+    read_only: bool,
+    durability: Durability,
+    /// Flushes per sync; see [`set_group_commit`](Self::set_group_commit).
+    group_commit: usize,
+    /// Flushes since the last sync.
+    unsynced_flushes: usize,
+    instrument: Option<Instrument>,
+    /// How nodes are encoded into pages; see [`with_codec`](Self::with_codec).
+    codec: Box<dyn PageCodec<K, V>>,
+}
+
+impl<K, V> fmt::Debug for FileStorage<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileStorage")
+            .field("file", &self.file)
+            .field("page_reads", &self.page_reads)
+            .field("page_writes", &self.page_writes)
+            .field("coalesced_writes", &self.coalesced_writes)
+            .field("cache_hits", &self.cache_hits)
+            .field("cache_capacity", &self.cache.capacity())
+            .field("dirty_pages", &self.dirty.len())
+            .field("path", &self.path)
+            .field("journal", &self.journal)
+            .field("read_only", &self.read_only)
+            .field("durability", &self.durability)
+            .field("group_commit", &self.group_commit)
+            .field("instrument", &self.instrument.is_some())
+            .field("codec", &self.codec.name())
+            .finish()
+    }
 }
 
-impl FileStorage {
-    pub fn new(path: &str) -> Self {
+impl<K: Key, V: Value> FileStorage<K, V> {
+    pub fn new(path: &str) -> Result<Self, BTreeError> {
+        Self::with_cache_capacity(path, 0)
+    }
+
+    /// Opens `path`, keeping up to `capacity` recently used pages in memory.
+    /// A complete journal left by an interrupted atomic write is replayed
+    /// first.
+    pub fn with_cache_capacity(path: &str, capacity: usize) -> Result<Self, BTreeError> {
+        Self::open_with(path, capacity, Box::new(Slotted))
+    }
+
+    /// Opens `path` like [`new`](Self::new), encoding pages with `codec`
+    /// instead of the [`Slotted`] layout. The tree records the codec in the header and
+    /// refuses to open the file with any other.
+    pub fn with_codec(
+        path: &str,
+        codec: impl PageCodec<K, V> + 'static,
+    ) -> Result<Self, BTreeError> {
+        Self::open_with(path, 0, Box::new(codec))
+    }
+
+    fn open_with(
+        path: &str,
+        capacity: usize,
+        codec: Box<dyn PageCodec<K, V>>,
+    ) -> Result<Self, BTreeError> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(path)
-            .unwrap();
+            .truncate(false)
+            .open(path)?;
+
+        let mut storage = Self::from_file(file, path, capacity, false, codec);
+        storage.recover()?;
+        Ok(storage)
+    }
+
+    /// Opens an existing `path` without write permission, so databases
+    /// owned by another user or on read-only media can be inspected. Every
+    /// write fails with [`BTreeError::ReadOnly`].
+    pub fn open_read_only(path: &str) -> Result<Self, BTreeError> {
+        Self::open_read_only_with_codec(path, Slotted)
+    }
+
+    /// Opens `path` like [`open_read_only`](Self::open_read_only), for a
+    /// file written with `codec`.
+    pub fn open_read_only_with_codec(
+        path: &str,
+        codec: impl PageCodec<K, V> + 'static,
+    ) -> Result<Self, BTreeError> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let storage = Self::from_file(file, path, 0, true, Box::new(codec));
+        if storage.journal.exists() {
+            return Err(BTreeError::Io(io::Error::other(
+                "an interrupted commit must be replayed by opening the file for writing",
+            )));
+        }
+        Ok(storage)
+    }
 
+    fn from_file(
+        file: File,
+        path: &str,
+        capacity: usize,
+        read_only: bool,
+        codec: Box<dyn PageCodec<K, V>>,
+    ) -> Self {
         Self {
             file,
             page_reads: 0,
             page_writes: 0,
+            coalesced_writes: 0,
+            cache_hits: 0,
+            cache: LruCache::new(capacity),
+            dirty: BTreeMap::new(),
+            pending_header: None,
+            open_journal: None,
+            path: PathBuf::from(path),
+            journal: PathBuf::from(format!("{}.journal", path)),
+            read_only,
+            durability: Durability::default(),
+            group_commit: 1,
+            unsynced_flushes: 0,
+            instrument: None,
+            codec,
+        }
+    }
+
+    /// Opens `path` like [`new`](Self::new), syncing as `durability` says.
+    pub fn with_durability(path: &str, durability: Durability) -> Result<Self, BTreeError> {
+        let mut storage = Self::new(path)?;
+        storage.durability = durability;
+        Ok(storage)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Groups `flushes` consecutive flushes into one journaled write and
+    /// sync, for throughput. Pages stay held back until the last of them,
+    /// so a crash in between loses the updates of up to `flushes - 1`
+    /// operations but leaves the tree as of an earlier flush;
+    /// [`sync`](Storage::sync) ends the group early. Has no effect under
+    /// [`Durability::NoSync`].
+    pub fn set_group_commit(&mut self, flushes: usize) {
+        self.group_commit = flushes.max(1);
+    }
+
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache.resize(capacity);
+    }
+
+    pub fn cache_capacity(&self) -> usize {
+        self.cache.capacity()
+    }
+
+    pub fn set_instrument(&mut self, instrument: Option<Instrument>) {
+        self.instrument = instrument;
+    }
+
+    /// Number of pages written but held back until the next flush, in
+    /// memory or in its journal.
+    pub fn dirty_pages(&self) -> usize {
+        let spilled = self
+            .open_journal
+            .iter()
+            .flat_map(|journal| journal.pages.keys());
+        spilled.filter(|loc| !self.dirty.contains_key(loc)).count() + self.dirty.len()
+    }
+
+    /// Reads every page from the file, bypassing the cache, and returns the
+    /// ones that fail to decode or whose checksum does not match, with the
+    /// reason. Pages still held back for the next flush are not seen.
+    pub fn verify_pages(&self) -> Result<Vec<(usize, &'static str)>, BTreeError> {
+        let mut damaged = Vec::new();
+        for loc in 0..self.file_pages()? {
+            if let Err(reason) = Self::deserialize_node(&*self.codec, self.read_block(loc)?) {
+                damaged.push((loc, reason));
+            }
         }
+        Ok(damaged)
     }
 
-    pub fn dump_pages(&mut self) {
-        let total = self.total_nodes();
+    pub fn dump_pages(&mut self) -> Result<(), BTreeError> {
+        let total = self.total_nodes()?;
+        let instrument = self.instrument.take();
+        let (reads, hits) = (self.page_reads, self.cache_hits);
         for loc in 0..total {
             print!("Page {}: ", loc);
 
             match self.read_node(loc) {
-                Some(Node::Header(header)) => {
-                    println!("Header, root: {:?}", header.root)
+                Ok(Node::Header(header)) => {
+                    println!(
+                        "Header v{}, root: {:?} height={} count={} multimap={} free={:?} buckets={:?} bloom={:?}",
+                        header.version,
+                        header.root,
+                        header.height,
+                        header.count,
+                        header.multimap,
+                        header.free_list,
+                        header
+                            .buckets
+                            .iter()
+                            .map(|bucket| (&bucket.name, bucket.root))
+                            .collect::<Vec<_>>(),
+                        header.bloom.map(|bloom| bloom.pages)
+                    )
                 }
-                Some(Node::Leaf(leaf)) => {
+                Ok(Node::Leaf(leaf)) => {
                     println!(
-                        "Leaf keys={:?} values={} next={:?}",
+                        "Leaf keys={:?} values={} next={:?} prev={:?}",
                         leaf.keys,
                         leaf.values.len(),
-                        leaf.next
+                        leaf.next,
+                        leaf.prev
                     );
                 }
-                Some(Node::Internal(internal)) => {
+                Ok(Node::Internal(internal)) => {
                     println!(
-                        "Internal keys={:?} children={:?}",
-                        internal.keys, internal.children
+                        "Internal keys={:?} children={:?} counts={:?}",
+                        internal.keys, internal.children, internal.counts
                     );
                 }
-                None => {
-                    println!("<empty or invalid>");
+                Ok(Node::Bloom(page)) => {
+                    let set: u32 = page.bits.iter().map(|byte| byte.count_ones()).sum();
+                    println!("Bloom filter bits={} set={}", page.bits.len() * 8, set);
+                }
+                Ok(Node::Overflow(page)) => {
+                    println!("Overflow bytes={} next={:?}", page.data.len(), page.next);
+                }
+                Err(err) => {
+                    println!("<{}>", err);
                 }
             }
+        }
+        self.page_reads = reads;
+        self.cache_hits = hits;
+        self.instrument = instrument;
+        Ok(())
+    }
+}
 
-            self.page_reads -= 1;
+/// Reads through a shared reference without the cache, which needs `&mut`,
+/// so neither the cache nor the counters and instrument see these reads.
+/// Pages still waiting for a flush are returned as written.
+impl<K: Key, V: Value> SharedStorage<K, V> for FileStorage<K, V> {
+    fn read_node_shared(&self, loc: usize) -> Result<Node<K, V>, BTreeError> {
+        if let Some(node) = self.held(loc) {
+            return Ok(node.clone());
         }
+        let block = match self.spilled_block(loc)? {
+            Some(block) => block,
+            None => self.read_block(loc)?,
+        };
+        Self::deserialize_node(&*self.codec, block)
+            .map_err(|reason| BTreeError::Corrupt { loc, reason })
     }
 }
 
-impl Storage for FileStorage {
-    fn read_node(&mut self, loc: usize) -> Option<Node> {
+impl<K: Key, V: Value> Storage<K, V> for FileStorage<K, V> {
+    fn read_node(&mut self, loc: usize) -> Result<Node<K, V>, BTreeError> {
+        if let Some((pending, (header, _))) = &self.pending_header
+            && *pending == loc
+        {
+            return Ok(header.clone());
+        }
+        if let Some(node) = self
+            .dirty
+            .get(&loc)
+            .map(|(node, _)| node)
+            .or_else(|| self.cache.get(loc))
+        {
+            let node = node.clone();
+            self.cache_hits += 1;
+            if let Some(instrument) = self.instrument.as_mut() {
+                instrument(Access::Read, loc, node.kind());
+            }
+            return Ok(node);
+        }
+
+        let block = match self.spilled_block(loc)? {
+            Some(block) => block,
+            None => self.read_block(loc)?,
+        };
+        self.page_reads += 1;
+        let node = Self::deserialize_node(&*self.codec, block)
+            .map_err(|reason| BTreeError::Corrupt { loc, reason })?;
+        self.loaded(loc, &node);
+        Ok(node)
+    }
+
+    /// Decodes only the keys of a leaf read from disk, which is not cached
+    /// as it is incomplete. Pages the codec cannot read that way, and ones
+    /// that fail it, go through [`read_node`](Storage::read_node)'s decoding
+    /// for its error.
+    fn read_keys(&mut self, loc: usize) -> Result<LeafKeys<K>, BTreeError> {
+        if self.held(loc).is_some() || self.spilled(loc) {
+            return LeafKeys::of(loc, self.read_node(loc)?);
+        }
+        if let Some(node) = self.cache.get(loc) {
+            let keys = match node {
+                Node::Leaf(leaf) => LeafKeys {
+                    keys: leaf.keys.clone(),
+                    next: leaf.next,
+                },
+                _ => return LeafKeys::of(loc, self.read_node(loc)?),
+            };
+            self.cache_hits += 1;
+            if let Some(instrument) = self.instrument.as_mut() {
+                instrument(Access::Read, loc, NodeKind::Leaf);
+            }
+            return Ok(keys);
+        }
+
+        let block = self.read_block(loc)?;
+        self.page_reads += 1;
+        let payload = &block[STAMP_SIZE..PAGE_SIZE - CHECKSUM_SIZE];
+        let keys = match self.codec.decode_keys(payload) {
+            Some((keys, len)) if Self::checksum_matches(&block, len) => keys,
+            _ => {
+                let node = Self::deserialize_node(&*self.codec, block)
+                    .map_err(|reason| BTreeError::Corrupt { loc, reason })?;
+                LeafKeys::of(loc, node)?
+            }
+        };
+        if let Some(instrument) = self.instrument.as_mut() {
+            instrument(Access::Read, loc, NodeKind::Leaf);
+        }
+        Ok(keys)
+    }
+
+    /// Fetches the pages from disk with a single read, decoding each as
+    /// [`read_node`](Storage::read_node) would. Pages in the cache or
+    /// waiting for a flush are taken from there or the journal, and a page
+    /// caught mid-write or not yet in the file is read again on its own.
+    fn read_nodes(&mut self, locs: Range<usize>) -> Result<Vec<Node<K, V>>, BTreeError> {
+        let stored = locs
+            .len()
+            .min(self.file_pages()?.saturating_sub(locs.start));
+        let mut buffer = vec![0u8; stored * PAGE_SIZE];
+        let offset = (locs.start * PAGE_SIZE) as u64;
+        read_at(&self.file, &mut buffer, offset)?;
+
+        let mut nodes = Vec::with_capacity(locs.len());
+        for (i, loc) in locs.enumerate() {
+            let Some(block) = buffer.chunks_exact(PAGE_SIZE).nth(i) else {
+                nodes.push(self.read_node(loc)?);
+                continue;
+            };
+            let held = self.cache.contains(loc) || self.held(loc).is_some() || self.spilled(loc);
+            let block: [u8; PAGE_SIZE] = block.try_into().unwrap();
+            let node = match Self::deserialize_node(&*self.codec, block) {
+                Ok(node) if !held && Self::stamp_of(&block) % 2 == 0 => {
+                    self.page_reads += 1;
+                    self.loaded(loc, &node);
+                    node
+                }
+                _ => self.read_node(loc)?,
+            };
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+
+    /// Holds nodes back until the next [`flush`](Storage::flush), which
+    /// journals each page once however often it was written since. Past
+    /// [`MAX_DIRTY_PAGES`] the held pages move to the journal early. The
+    /// node is encoded here, so an oversized one fails now rather than in
+    /// the flush.
+    fn write_node(&mut self, loc: usize, input: &Node<K, V>) -> Result<(), BTreeError> {
+        self.check_writable()?;
+        let held = (
+            input.clone(),
+            Box::new(Self::serialize_node(&*self.codec, input)?),
+        );
+        self.cache.remove(loc);
+        if let Node::Header(_) = input {
+            self.pending_header = Some((loc, held));
+            return Ok(());
+        }
+        if self
+            .pending_header
+            .as_ref()
+            .is_some_and(|(pending, _)| *pending == loc)
+        {
+            self.pending_header = None;
+        }
+        if self.dirty.insert(loc, held).is_some() {
+            self.coalesced_writes += 1;
+        }
+        if self.dirty.len() >= MAX_DIRTY_PAGES {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Serializes every node and appends them to the journal of the next
+    /// flush in a single write, dropping held-back writes to the same pages.
+    /// A header among the nodes is held back as usual, and the run goes
+    /// page by page.
+    fn write_nodes(&mut self, start: usize, nodes: &[Node<K, V>]) -> Result<(), BTreeError> {
+        self.check_writable()?;
+        if nodes.iter().any(|node| matches!(node, Node::Header(_))) {
+            for (i, node) in nodes.iter().enumerate() {
+                self.write_node(start + i, node)?;
+            }
+            return Ok(());
+        }
+        let blocks = nodes
+            .iter()
+            .map(|node| Self::serialize_node(&*self.codec, node))
+            .collect::<Result<Vec<_>, _>>()?;
+        for i in 0..nodes.len() {
+            self.cache.remove(start + i);
+            self.dirty.remove(&(start + i));
+        }
+        if self
+            .pending_header
+            .as_ref()
+            .is_some_and(|(loc, _)| (start..start + nodes.len()).contains(loc))
+        {
+            self.pending_header = None;
+        }
+        let pages: Vec<_> = blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (start + i, block))
+            .collect();
+        self.append_journal(&pages, None)?;
+
+        for (i, node) in nodes.iter().enumerate() {
+            self.written(start + i, node.clone());
+        }
+        Ok(())
+    }
+
+    /// Writes the held-back pages and the pending header to the journal,
+    /// then to the file, and removes the journal, so a crash leaves either
+    /// all of them or none once the file is reopened. The syncs in between
+    /// are up to the [`Durability`]. Under group commit only every few
+    /// flushes do this; see [`set_group_commit`](Self::set_group_commit).
+    fn flush(&mut self) -> Result<(), BTreeError> {
+        // Nothing can have been written
+        if self.read_only {
+            return Ok(());
+        }
+        if self.durability == Durability::NoSync {
+            return self.write_pending(false);
+        }
+        self.unsynced_flushes += 1;
+        if self.unsynced_flushes < self.group_commit {
+            return Ok(());
+        }
+        self.write_pending(true)
+    }
+
+    /// Flushes with syncs whatever the durability mode or group commit.
+    fn sync(&mut self) -> Result<(), BTreeError> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.write_pending(true)
+    }
+
+    /// Holds the pages back with any others and flushes them all through
+    /// the journal at once, ending a group commit early.
+    fn write_atomic(&mut self, pages: &[(usize, Node<K, V>)]) -> Result<(), BTreeError> {
+        self.check_writable()?;
+        for (loc, node) in pages {
+            self.write_node(*loc, node)?;
+        }
+        self.write_pending(self.durability != Durability::NoSync)
+    }
+
+    /// Counts pages that so far are only held back for the next flush.
+    fn total_nodes(&self) -> Result<usize, BTreeError> {
+        let spilled = self.open_journal.iter().flat_map(|journal| {
+            let header = journal.header.map(|(loc, _)| loc);
+            journal
+                .pages
+                .last_key_value()
+                .map(|(&loc, _)| loc)
+                .into_iter()
+                .chain(header)
+        });
+        let held = self
+            .dirty
+            .last_key_value()
+            .map(|(&loc, _)| loc)
+            .into_iter()
+            .chain(self.pending_header.as_ref().map(|(loc, _)| *loc))
+            .chain(spilled)
+            .max()
+            .map_or(0, |loc| loc + 1);
+        Ok(self.file_pages()?.max(held))
+    }
+
+    /// Writes out held-back pages and header first, so they cannot land
+    /// past the new end.
+    fn truncate(&mut self, pages: usize) -> Result<(), BTreeError> {
+        self.check_writable()?;
+        let sync = self.durability != Durability::NoSync;
+        self.write_pending(sync)?;
+        for loc in pages..Storage::<K, V>::total_nodes(self)? {
+            self.cache.remove(loc);
+        }
+        self.file.set_len((pages * PAGE_SIZE) as u64)?;
+        if sync {
+            self.file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn codec(&self) -> &'static str {
+        self.codec.name()
+    }
+
+    fn page_capacity(&self) -> usize {
+        PAYLOAD_SIZE
+    }
+
+    fn io_counters(&self) -> (usize, usize) {
+        (self.page_reads, self.page_writes)
+    }
+
+    fn trailing_bytes(&self) -> Result<usize, BTreeError> {
+        Ok(self.file.metadata()?.len() as usize % PAGE_SIZE)
+    }
+
+    fn file_path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    /// Opens the copy with this storage's codec, cache capacity and
+    /// durability. A journal left at `path` belongs to the file replaced.
+    fn create_copy(&self, path: &str) -> Result<Self, BTreeError> {
+        storage::clear_for_copy(&self.path, path)?;
+        match fs::remove_file(format!("{}.journal", path)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        let mut copy = Self::open_with(path, self.cache.capacity(), self.codec.clone_box())?;
+        copy.durability = self.durability;
+        copy.group_commit = self.group_commit;
+        Ok(copy)
+    }
+}
+
+impl<K: Key, V: Value> FileStorage<K, V> {
+    fn check_writable(&self) -> Result<(), BTreeError> {
+        if self.read_only {
+            return Err(BTreeError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// A node waiting for the next flush at `loc`, the header included.
+    fn held(&self, loc: usize) -> Option<&Node<K, V>> {
+        match &self.pending_header {
+            Some((pending, (header, _))) if *pending == loc => Some(header),
+            _ => self.dirty.get(&loc).map(|(node, _)| node),
+        }
+    }
+
+    /// Replays the journal if one was completely written, then removes it.
+    /// A partial journal means the database file was never touched by its
+    /// flush.
+    fn recover(&mut self) -> Result<(), BTreeError> {
+        let journal = match fs::read(&self.journal) {
+            Ok(journal) => journal,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some(entries) = Self::journal_entries(&journal) {
+            for entry in entries.chunks_exact(JOURNAL_ENTRY_SIZE) {
+                let loc = u64::from_le_bytes(entry[..8].try_into().unwrap()) as usize;
+                self.write_block(loc, entry[8..].try_into().unwrap())?;
+            }
+            self.file.sync_all()?;
+        }
+        fs::remove_file(&self.journal)?;
+        Ok(())
+    }
+
+    /// The entries of a complete journal, or `None` if its trailer is
+    /// missing or does not match.
+    fn journal_entries(journal: &[u8]) -> Option<&[u8]> {
+        let (body, checksum) = journal.split_at(journal.len().checked_sub(CHECKSUM_SIZE)?);
+        if crc32fast::hash(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return None;
+        }
+        let (entries, count) = body.split_at(body.len().checked_sub(8)?);
+        let count = u64::from_le_bytes(count.try_into().unwrap()) as usize;
+        (entries.len() == count * JOURNAL_ENTRY_SIZE).then_some(entries)
+    }
+
+    /// Reads the page at `loc`, retrying while a writer has it half written.
+    fn read_block(&self, loc: usize) -> Result<[u8; PAGE_SIZE], BTreeError> {
         let offset = (loc * PAGE_SIZE) as u64;
-        self.file.seek(SeekFrom::Start(offset)).ok()?;
         let mut block = [0u8; PAGE_SIZE];
-        self.file.read_exact(&mut block).ok()?;
-        self.page_reads += 1;
-        FileStorage::deserialize_node(block)
+
+        for _ in 0..MAX_READ_RETRIES {
+            if let Err(err) = read_at(&self.file, &mut block, offset) {
+                return Err(match err.kind() {
+                    ErrorKind::UnexpectedEof => BTreeError::Corrupt {
+                        loc,
+                        reason: "page is past the end of the file",
+                    },
+                    _ => err.into(),
+                });
+            }
+
+            let before = Self::stamp_of(&block);
+            if before % 2 == 1 || self.read_stamp(offset)? != before {
+                thread::yield_now();
+                continue;
+            }
+            return Ok(block);
+        }
+        Err(BTreeError::Io(io::Error::other(format!(
+            "page {} kept changing while being read",
+            loc
+        ))))
+    }
+
+    /// Caches a node just read from disk and reports the read.
+    fn loaded(&mut self, loc: usize, node: &Node<K, V>) {
+        self.cache.put(loc, node.clone());
+        if let Some(instrument) = self.instrument.as_mut() {
+            instrument(Access::Read, loc, node.kind());
+        }
+    }
+
+    pub(crate) fn stamp_of(block: &[u8; PAGE_SIZE]) -> u64 {
+        u64::from_le_bytes(block[..STAMP_SIZE].try_into().unwrap())
+    }
+
+    /// Encodes `input` after the stamp and checksums it, refusing nodes that
+    /// overflow the page.
+    pub(crate) fn serialize_node(
+        codec: &dyn PageCodec<K, V>,
+        input: &Node<K, V>,
+    ) -> Result<[u8; PAGE_SIZE], SerializeError> {
+        let encoded = codec.encode(input)?;
+        if encoded.len() > PAYLOAD_SIZE {
+            return Err(SerializeError::NodeTooLarge {
+                size: encoded.len(),
+                capacity: PAYLOAD_SIZE,
+            });
+        }
+
+        let mut slice = [0u8; PAGE_SIZE];
+        slice[STAMP_SIZE..STAMP_SIZE + encoded.len()].copy_from_slice(&encoded);
+        let checksum = crc32fast::hash(&encoded);
+        slice[PAGE_SIZE - CHECKSUM_SIZE..].copy_from_slice(&checksum.to_le_bytes());
+        Ok(slice)
+    }
+    /// Decodes the node in `input`, describing what is wrong if it does not
+    /// decode or its checksum does not match.
+    pub(crate) fn deserialize_node(
+        codec: &dyn PageCodec<K, V>,
+        input: [u8; PAGE_SIZE],
+    ) -> Result<Node<K, V>, &'static str> {
+        let payload = &input[STAMP_SIZE..PAGE_SIZE - CHECKSUM_SIZE];
+        let (node, len) = codec.decode(payload).ok_or("node does not decode")?;
+        if !Self::checksum_matches(&input, len) {
+            return Err("checksum mismatch");
+        }
+        Ok(node)
+    }
+
+    /// Whether the checksum of `block` matches the `len` bytes of its
+    /// encoded node.
+    fn checksum_matches(block: &[u8; PAGE_SIZE], len: usize) -> bool {
+        let stored = u32::from_le_bytes(block[PAGE_SIZE - CHECKSUM_SIZE..].try_into().unwrap());
+        crc32fast::hash(&block[STAMP_SIZE..STAMP_SIZE + len]) == stored
+    }
+}
+
+/// Writing out held-back pages needs neither the key nor the value type, as
+/// they are encoded when written, so it can also run on drop.
+impl<K, V> FileStorage<K, V> {
+    /// Journals the held-back pages and header, writes the trailer, and
+    /// applies the journal to the file. With `sync` the journal is synced
+    /// before the file is touched, and the file before the journal goes.
+    fn write_pending(&mut self, sync: bool) -> Result<(), BTreeError> {
+        self.unsynced_flushes = 0;
+        self.spill()?;
+        if let Some((loc, (header, block))) = self.pending_header.take() {
+            if let Err(err) = self.append_journal(&[(loc, &block)], Some(loc)) {
+                self.pending_header = Some((loc, (header, block)));
+                return Err(err);
+            }
+            self.written(loc, header);
+        }
+        if self.open_journal.is_some() {
+            return self.apply_journal(sync);
+        }
+        if sync {
+            self.file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Moves the held-back pages, but not the header, to the journal of the
+    /// next flush.
+    fn spill(&mut self) -> Result<(), BTreeError> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        let dirty = std::mem::take(&mut self.dirty);
+        let pages: Vec<_> = dirty
+            .iter()
+            .map(|(&loc, (_, block))| (loc, &**block))
+            .collect();
+        if let Err(err) = self.append_journal(&pages, None) {
+            self.dirty = dirty;
+            return Err(err);
+        }
+        for (loc, (node, _)) in dirty {
+            self.written(loc, node);
+        }
+        Ok(())
     }
 
-    fn write_node(&mut self, loc: usize, input: &Node) {
+    /// Appends pages to the journal of the next flush in one write, starting
+    /// it if needed. `header` marks the location of the header among them.
+    fn append_journal(
+        &mut self,
+        pages: &[(usize, &[u8; PAGE_SIZE])],
+        header: Option<usize>,
+    ) -> Result<(), BTreeError> {
+        // A journal committed by a flush that failed to apply it still
+        // holds the only complete copy of its pages.
+        if self
+            .open_journal
+            .as_ref()
+            .is_some_and(|journal| journal.committed)
+        {
+            self.apply_journal(self.durability != Durability::NoSync)?;
+        }
+        if self.open_journal.is_none() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&self.journal)?;
+            self.open_journal = Some(OpenJournal {
+                file,
+                pages: BTreeMap::new(),
+                header: None,
+                entries: 0,
+                len: 0,
+                checksum: crc32fast::Hasher::new(),
+                committed: false,
+            });
+        }
+        let journal = self.open_journal.as_mut().unwrap();
+
+        let mut buffer = Vec::with_capacity(pages.len() * JOURNAL_ENTRY_SIZE);
+        for (loc, block) in pages {
+            buffer.extend_from_slice(&(*loc as u64).to_le_bytes());
+            buffer.extend_from_slice(*block);
+        }
+        journal.file.write_all(&buffer)?;
+        journal.checksum.update(&buffer);
+
+        for (i, (loc, _)) in pages.iter().enumerate() {
+            let offset = journal.len + (i * JOURNAL_ENTRY_SIZE) as u64;
+            if header == Some(*loc) {
+                journal.pages.remove(loc);
+                journal.header = Some((*loc, offset));
+            } else {
+                journal.pages.insert(*loc, offset);
+                if journal.header.is_some_and(|(header, _)| header == *loc) {
+                    journal.header = None;
+                }
+            }
+        }
+        journal.len += buffer.len() as u64;
+        journal.entries += pages.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the trailer of the open journal, unless a failed earlier
+    /// attempt already did, then copies its pages to the file in location
+    /// order, the header last, and removes it.
+    fn apply_journal(&mut self, sync: bool) -> Result<(), BTreeError> {
+        let Some(journal) = self.open_journal.as_mut() else {
+            return Ok(());
+        };
+        if !journal.committed {
+            let mut trailer = Vec::with_capacity(JOURNAL_TRAILER_SIZE);
+            trailer.extend_from_slice(&journal.entries.to_le_bytes());
+            journal.checksum.update(&trailer);
+            trailer.extend_from_slice(&journal.checksum.clone().finalize().to_le_bytes());
+            journal.file.write_all(&trailer)?;
+            if sync {
+                journal.file.sync_all()?;
+            }
+            journal.committed = true;
+        }
+
+        let order: Vec<(usize, u64)> = journal
+            .pages
+            .iter()
+            .map(|(&loc, &offset)| (loc, offset))
+            .chain(journal.header)
+            .collect();
+        for (loc, offset) in order {
+            let mut block = [0u8; PAGE_SIZE];
+            let file = &self.open_journal.as_ref().unwrap().file;
+            read_at(file, &mut block, offset + 8)?;
+            self.write_block(loc, block)?;
+        }
+        if sync {
+            self.file.sync_all()?;
+        }
+        self.open_journal = None;
+        fs::remove_file(&self.journal)?;
+        Ok(())
+    }
+
+    /// Whether the latest write of `loc` is in the open journal.
+    fn spilled(&self, loc: usize) -> bool {
+        self.open_journal.as_ref().is_some_and(|journal| {
+            journal.pages.contains_key(&loc)
+                || journal.header.is_some_and(|(header, _)| header == loc)
+        })
+    }
+
+    /// The page at `loc` if its latest write is in the open journal.
+    fn spilled_block(&self, loc: usize) -> Result<Option<[u8; PAGE_SIZE]>, BTreeError> {
+        let Some(journal) = &self.open_journal else {
+            return Ok(None);
+        };
+        let offset = match journal.header {
+            Some((header, offset)) if header == loc => offset,
+            _ => match journal.pages.get(&loc) {
+                Some(&offset) => offset,
+                None => return Ok(None),
+            },
+        };
+        let mut block = [0u8; PAGE_SIZE];
+        read_at(&journal.file, &mut block, offset + 8)?;
+        Ok(Some(block))
+    }
+
+    /// Caches a node just written to disk and reports the write.
+    fn written(&mut self, loc: usize, node: Node<K, V>) {
+        if let Some(instrument) = self.instrument.as_mut() {
+            instrument(Access::Write, loc, node.kind());
+        }
+        self.cache.put(loc, node);
+    }
+
+    /// Writes an encoded page under an odd stamp, then makes the stamp even.
+    fn write_block(&mut self, loc: usize, mut block: [u8; PAGE_SIZE]) -> Result<(), BTreeError> {
         let offset = (loc * PAGE_SIZE) as u64;
-        let block = FileStorage::serialize_node(input);
-        self.file.seek(SeekFrom::Start(offset)).unwrap();
-        self.file.write_all(&block).unwrap();
+        // Pages past the end of the file start from version 0.
+        let version = (self.read_stamp(offset).unwrap_or(0) + 1) | 1;
+        block[..STAMP_SIZE].copy_from_slice(&version.to_le_bytes());
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&block)?;
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&(version + 1).to_le_bytes())?;
+        if self.durability == Durability::SyncEveryWrite {
+            self.file.sync_data()?;
+        }
+
         self.page_writes += 1;
+        Ok(())
+    }
+
+    fn read_stamp(&self, offset: u64) -> io::Result<u64> {
+        let mut stamp = [0u8; STAMP_SIZE];
+        read_at(&self.file, &mut stamp, offset)?;
+        Ok(u64::from_le_bytes(stamp))
     }
-    fn total_nodes(&self) -> usize {
-        self.file.metadata().unwrap().len() as usize / PAGE_SIZE
+
+    /// Whole pages in the file, leaving out ones only held back so far.
+    fn file_pages(&self) -> Result<usize, BTreeError> {
+        Ok(self.file.metadata()?.len() as usize / PAGE_SIZE)
     }
 }
 
-impl FileStorage {
-    fn serialize_node(input: &Node) -> [u8; PAGE_SIZE] {
-        let mut slice = [0u8; PAGE_SIZE];
-        let _ = bincode::encode_into_slice(input, &mut slice, bincode::config::standard());
-        return slice;
+/// Writes out pages still held back, as a flush would, so a storage dropped
+/// without one still leaves its writes in the file. Errors are lost here;
+/// flush first to see them.
+impl<K, V> Drop for FileStorage<K, V> {
+    fn drop(&mut self) {
+        if !self.read_only {
+            let _ = self.write_pending(self.durability != Durability::NoSync);
+        }
     }
-    fn deserialize_node(input: [u8; PAGE_SIZE]) -> Option<Node> {
-        let result: Result<(Node, usize), DecodeError> =
-            bincode::decode_from_slice(&input, bincode::config::standard());
-        match result {
-            Ok(value) => Some(value.0),
-            _ => None,
+}
+
+/// Reads exactly `buf.len()` bytes at `offset` without going through the
+/// shared file cursor, so concurrent readers do not disturb each other.
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
         }
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::btree::BPlusTree;
+    use crate::node::LeafNode;
+
+    /// A fresh path in the temporary directory, named for the test.
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("btree-{}-{}.db", name, std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.journal", path));
+        path
+    }
+
+    #[test]
+    fn a_flipped_byte_fails_the_checksum() {
+        let path = temp_path("checksum");
+        let loc = {
+            let mut tree: BPlusTree<FileStorage> =
+                BPlusTree::open(FileStorage::new(&path).unwrap()).unwrap();
+            tree.insert(1, [1; 7]).unwrap();
+            tree.insert(2, [2; 7]).unwrap();
+            tree.leftmost_leaf().unwrap()
+        };
+
+        // The node still decodes, but no longer matches its checksum
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[(loc + 1) * PAGE_SIZE - 1] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut storage: FileStorage = FileStorage::new(&path).unwrap();
+        match Storage::read_node(&mut storage, loc) {
+            Err(BTreeError::Corrupt { loc: at, reason }) => {
+                assert_eq!((at, reason), (loc, "checksum mismatch"));
+            }
+            other => panic!("expected a checksum mismatch, got {:?}", other.map(|_| ())),
+        }
+        let mut tree: BPlusTree<FileStorage> = BPlusTree::open(storage).unwrap();
+        assert!(matches!(
+            tree.get(&2),
+            Err(BTreeError::Corrupt {
+                reason: "checksum mismatch",
+                ..
+            })
+        ));
+        drop(tree);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_repeated_lookup_is_served_by_the_cache() {
+        let path = temp_path("cache");
+        {
+            let mut tree: BPlusTree<FileStorage> =
+                BPlusTree::open(FileStorage::new(&path).unwrap()).unwrap();
+            for key in 0..100 {
+                tree.insert(key, [key; 7]).unwrap();
+            }
+        }
+
+        let storage = FileStorage::with_cache_capacity(&path, 16).unwrap();
+        let mut tree: BPlusTree<FileStorage> = BPlusTree::open(storage).unwrap();
+        assert_eq!(tree.get(&42).unwrap(), Some([42; 7]));
+        let reads = tree.storage.page_reads;
+        assert!(reads > 0);
+        assert_eq!(tree.get(&42).unwrap(), Some([42; 7]));
+        assert_eq!(tree.storage.page_reads, reads);
+        drop(tree);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_split_writes_the_header_after_its_nodes() {
+        let path = temp_path("ordering");
+        let mut tree: BPlusTree<FileStorage> =
+            BPlusTree::open(FileStorage::new(&path).unwrap()).unwrap();
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&writes);
+        tree.storage
+            .set_instrument(Some(Box::new(move |access, loc, kind| {
+                if access == Access::Write {
+                    log.lock().unwrap().push((loc, kind));
+                }
+            })));
+        // Keeps the writes of the insert that splits the root leaf
+        let mut key = 0;
+        while tree.stats().unwrap().height == 1 {
+            writes.lock().unwrap().clear();
+            tree.insert(key, [key; 7]).unwrap();
+            key += 1;
+        }
+
+        let writes = writes.lock().unwrap();
+        // Both halves of the leaf and the new root, then the header
+        assert!(writes.len() >= 4, "{:?}", writes);
+        assert_eq!(writes.last(), Some(&(0, NodeKind::Header)));
+        assert_eq!(writes.iter().filter(|(loc, _)| *loc == 0).count(), 1);
+        drop(writes);
+        drop(tree);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn whole_pages_and_trailing_bytes_are_counted_apart() {
+        let path = temp_path("trailing");
+        for (len, pages, trailing) in [(0, 0, 0), (PAGE_SIZE, 1, 0), (PAGE_SIZE + 100, 1, 100)] {
+            fs::write(&path, vec![0u8; len]).unwrap();
+            let storage: FileStorage = FileStorage::new(&path).unwrap();
+            assert_eq!(
+                Storage::total_nodes(&storage).unwrap(),
+                pages,
+                "{} bytes",
+                len
+            );
+            assert_eq!(
+                Storage::trailing_bytes(&storage).unwrap(),
+                trailing,
+                "{} bytes",
+                len
+            );
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn an_overfull_leaf_is_too_large_to_write() {
+        let mut leaf = LeafNode::new();
+        for key in 0..10_000 {
+            leaf.keys.push(key);
+            leaf.values.push([i32::MAX - key; 7]);
+        }
+        let node = Node::Leaf(leaf);
+        match FileStorage::<i32, Record>::serialize_node(&Slotted, &node) {
+            Err(SerializeError::NodeTooLarge { size, capacity }) => {
+                assert!(size > capacity);
+                assert_eq!(capacity, PAYLOAD_SIZE);
+            }
+            other => panic!("expected NodeTooLarge, got {:?}", other.map(|_| ())),
+        }
+
+        // Refused when written, rather than when flushed
+        let path = temp_path("overfull");
+        let mut storage: FileStorage = FileStorage::new(&path).unwrap();
+        assert!(matches!(
+            storage.write_node(1, &node),
+            Err(BTreeError::Serialize(SerializeError::NodeTooLarge { .. }))
+        ));
+        assert_eq!(storage.dirty_pages(), 0);
+        drop(storage);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pages_spilled_before_a_crash_never_reach_the_file() {
+        let leaf = |tag: i32| {
+            let mut leaf = LeafNode::new();
+            leaf.keys.push(tag);
+            leaf.values.push([tag; 7]);
+            Node::Leaf(leaf)
+        };
+        let pages = MAX_DIRTY_PAGES + 50;
+        let path = temp_path("spill");
+        let mut storage: FileStorage = FileStorage::new(&path).unwrap();
+        for loc in 0..pages {
+            storage.write_node(loc, &leaf(1)).unwrap();
+        }
+        storage.flush().unwrap();
+
+        // Past the limit the pages go to the journal, still readable there
+        for loc in 0..pages {
+            storage.write_node(loc, &leaf(2)).unwrap();
+        }
+        assert!(storage.dirty.len() < MAX_DIRTY_PAGES);
+        assert_eq!(storage.dirty_pages(), pages);
+        assert!(storage.journal.exists());
+        assert_eq!(storage.read_node_shared(3).unwrap(), leaf(2));
+        // A crash before the flush leaves a journal without its trailer
+        std::mem::forget(storage);
+
+        let mut storage: FileStorage = FileStorage::new(&path).unwrap();
+        assert!(!storage.journal.exists());
+        for loc in 0..pages {
+            assert_eq!(storage.read_node(loc).unwrap(), leaf(1), "page {}", loc);
+        }
+        drop(storage);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_group_commit_holds_pages_until_its_last_flush() {
+        let path = temp_path("group");
+        let mut tree: BPlusTree<FileStorage> =
+            BPlusTree::open(FileStorage::new(&path).unwrap()).unwrap();
+        tree.insert(0, [0; 7]).unwrap();
+        tree.storage.set_group_commit(3);
+        tree.insert(1, [1; 7]).unwrap();
+        tree.insert(2, [2; 7]).unwrap();
+        assert!(tree.storage.dirty_pages() > 0);
+        tree.insert(3, [3; 7]).unwrap();
+        assert_eq!(tree.storage.dirty_pages(), 0);
+
+        // A sync ends the group early
+        tree.insert(4, [4; 7]).unwrap();
+        tree.storage.sync().unwrap();
+        assert_eq!(tree.storage.dirty_pages(), 0);
+
+        // A crash loses the flushes of the open group and nothing else
+        tree.insert(5, [5; 7]).unwrap();
+        tree.insert(6, [6; 7]).unwrap();
+        std::mem::forget(tree.storage);
+        let mut tree: BPlusTree<FileStorage> =
+            BPlusTree::open(FileStorage::new(&path).unwrap()).unwrap();
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 5);
+        assert_eq!(tree.get(&4).unwrap(), Some([4; 7]));
+        assert_eq!(tree.get(&5).unwrap(), None);
+
+        // Without syncs there is nothing to group, so every flush writes
+        tree.storage.set_durability(Durability::NoSync);
+        tree.storage.set_group_commit(3);
+        tree.insert(5, [5; 7]).unwrap();
+        assert_eq!(tree.storage.dirty_pages(), 0);
+        drop(tree);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_page_written_twice_before_a_flush_is_written_once() {
+        let leaf = |tag: i32| {
+            let mut leaf = LeafNode::new();
+            leaf.keys.push(tag);
+            leaf.values.push([tag; 7]);
+            Node::Leaf(leaf)
+        };
+        let path = temp_path("coalesce");
+        let mut storage: FileStorage = FileStorage::new(&path).unwrap();
+        for tag in 0..4 {
+            storage.write_node(1, &leaf(tag)).unwrap();
+        }
+        storage.write_node(2, &leaf(9)).unwrap();
+        assert_eq!(storage.dirty_pages(), 2);
+        storage.flush().unwrap();
+        assert_eq!(storage.coalesced_writes, 3);
+        assert_eq!(storage.page_writes, 2);
+        assert_eq!(storage.read_node(1).unwrap(), leaf(3));
+        drop(storage);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn backing_up_onto_the_own_file_is_refused() {
+        let path = temp_path("backup-own");
+        let mut tree: BPlusTree<FileStorage> =
+            BPlusTree::open(FileStorage::new(&path).unwrap()).unwrap();
+        for key in 0..50 {
+            tree.insert(key, [key; 7]).unwrap();
+        }
+        tree.flush().unwrap();
+
+        // Also when named another way
+        let other = format!(
+            "{}/./{}",
+            std::env::temp_dir().display(),
+            Path::new(&path).file_name().unwrap().to_str().unwrap()
+        );
+        for target in [path.as_str(), other.as_str()] {
+            assert!(matches!(
+                tree.backup(target),
+                Err(BTreeError::BackupOverSource { .. })
+            ));
+        }
+        drop(tree);
+
+        let mut tree: BPlusTree<FileStorage> =
+            BPlusTree::open(FileStorage::new(&path).unwrap()).unwrap();
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 50);
+        assert_eq!(tree.get(&49).unwrap(), Some([49; 7]));
+        drop(tree);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn readers_never_see_a_torn_page_while_a_tree_is_reloaded() {
+        let path = temp_path("reload-reader");
+        let mut tree: BPlusTree<FileStorage> = BPlusTree::bulk_load(
+            FileStorage::new(&path).unwrap(),
+            (0..2000).map(|key| (key, [key; 7])),
+        )
+        .unwrap();
+        tree.flush().unwrap();
+        let pages = tree.storage.total_nodes().unwrap();
+
+        let reader = FileStorage::<i32, Record>::open_read_only(&path).unwrap();
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reading = {
+            let done = Arc::clone(&done);
+            std::thread::spawn(move || {
+                let mut reads = 0;
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    for loc in 0..pages {
+                        if let Err(err) = reader.read_node_shared(loc) {
+                            panic!("page {} read back as {}", loc, err);
+                        }
+                        reads += 1;
+                    }
+                }
+                reads
+            })
+        };
+
+        // Each reload writes the same pages over again in place
+        for round in 1..10 {
+            tree.load_sorted((0..2000).map(|key| (key, [key + round; 7])))
+                .unwrap();
+            tree.flush().unwrap();
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert!(reading.join().unwrap() > 0);
+
+        assert_eq!(tree.storage.total_nodes().unwrap(), pages);
+        tree.validate().unwrap();
+        assert_eq!(tree.get(&1999).unwrap(), Some([1999 + 9; 7]));
+        drop(tree);
+        fs::remove_file(&path).unwrap();
+    }
 }
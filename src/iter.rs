@@ -0,0 +1,281 @@
+use std::marker::PhantomData;
+
+use crate::error::BTreeError;
+use crate::key::Key;
+use crate::node::{LeafNode, Node};
+use crate::record::Record;
+use crate::storage::Storage;
+use crate::value::Value;
+
+/// Most pages fetched in one request while a scan runs through consecutive
+/// pages.
+const MAX_READAHEAD: usize = 16;
+
+/// Yields entries in key order by following the leaf chain, reading one
+/// leaf at a time. A leaf that cannot be read is yielded as an error and
+/// ends the iteration.
+pub struct Records<'a, S, K = i32, V = Record> {
+    storage: &'a mut S,
+    readahead: Readahead<K, V>,
+    leaf: Option<LeafNode<K, V>>,
+    index: usize,
+    /// Leaf to read once `leaf` is exhausted, or the error that stopped the
+    /// descent to the first one.
+    next: Option<Result<usize, BTreeError>>,
+    marker: PhantomData<(K, V)>,
+}
+
+impl<'a, S, K, V> Records<'a, S, K, V>
+where
+    S: Storage<K, V>,
+    K: Key,
+    V: Value,
+{
+    pub(crate) fn new(storage: &'a mut S, start: Result<usize, BTreeError>) -> Self {
+        Records {
+            storage,
+            readahead: Readahead::new(),
+            leaf: None,
+            index: 0,
+            next: Some(start),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<S, K, V> Iterator for Records<'_, S, K, V>
+where
+    S: Storage<K, V>,
+    K: Key,
+    V: Value,
+{
+    type Item = Result<(K, V), BTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(leaf) = &self.leaf
+                && let Some(record) = leaf.values.get(self.index)
+            {
+                let key = leaf.keys[self.index].clone();
+                self.index += 1;
+                return Some(Ok((key, record.clone())));
+            }
+
+            self.leaf = None;
+            let next = self.next.take()?;
+            let leaf = match self.readahead.read_leaf(self.storage, next, false) {
+                Ok(leaf) => leaf,
+                Err(err) => return Some(Err(err)),
+            };
+            self.next = leaf.next.map(Ok);
+            self.leaf = Some(leaf);
+            self.index = 0;
+        }
+    }
+}
+
+/// Yields keys in order by following the leaf chain, reading only the keys
+/// of each leaf where the storage can. A leaf that cannot be read is
+/// yielded as an error and ends the iteration.
+pub struct Keys<'a, S, K = i32, V = Record> {
+    storage: &'a mut S,
+    keys: std::vec::IntoIter<K>,
+    /// Leaf to read once `keys` is exhausted, or the error that stopped
+    /// the descent to the first one.
+    next: Option<Result<usize, BTreeError>>,
+    marker: PhantomData<V>,
+}
+
+impl<'a, S, K, V> Keys<'a, S, K, V>
+where
+    S: Storage<K, V>,
+    K: Key,
+{
+    pub(crate) fn new(storage: &'a mut S, start: Result<usize, BTreeError>) -> Self {
+        Keys {
+            storage,
+            keys: Vec::new().into_iter(),
+            next: Some(start),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<S, K, V> Iterator for Keys<'_, S, K, V>
+where
+    S: Storage<K, V>,
+    K: Key,
+{
+    type Item = Result<K, BTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(key) = self.keys.next() {
+                return Some(Ok(key));
+            }
+            let leaf = match self.next.take()? {
+                Ok(loc) => self.storage.read_keys(loc),
+                Err(err) => Err(err),
+            };
+            match leaf {
+                Ok(leaf) => {
+                    self.next = leaf.next.map(Ok);
+                    self.keys = leaf.keys.into_iter();
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// Yields entries in descending key order by following the `prev` pointers,
+/// starting from the end of the leaf at `start`.
+pub struct RevRecords<'a, S, K = i32, V = Record> {
+    storage: &'a mut S,
+    readahead: Readahead<K, V>,
+    leaf: Option<LeafNode<K, V>>,
+    /// Entries of `leaf` not yet yielded.
+    remaining: usize,
+    /// Leaf to read once `leaf` is exhausted, or the error that stopped the
+    /// descent to the last one.
+    prev: Option<Result<usize, BTreeError>>,
+    marker: PhantomData<(K, V)>,
+}
+
+impl<'a, S, K, V> RevRecords<'a, S, K, V>
+where
+    S: Storage<K, V>,
+    K: Key,
+    V: Value,
+{
+    pub(crate) fn new(storage: &'a mut S, start: Result<usize, BTreeError>) -> Self {
+        RevRecords {
+            storage,
+            readahead: Readahead::new(),
+            leaf: None,
+            remaining: 0,
+            prev: Some(start),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<S, K, V> Iterator for RevRecords<'_, S, K, V>
+where
+    S: Storage<K, V>,
+    K: Key,
+    V: Value,
+{
+    type Item = Result<(K, V), BTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(leaf) = &self.leaf
+                && self.remaining > 0
+            {
+                self.remaining -= 1;
+                let i = self.remaining;
+                return Some(Ok((leaf.keys[i].clone(), leaf.values[i].clone())));
+            }
+
+            self.leaf = None;
+            let prev = self.prev.take()?;
+            let leaf = match self.readahead.read_leaf(self.storage, prev, true) {
+                Ok(leaf) => leaf,
+                Err(err) => return Some(Err(err)),
+            };
+            self.prev = leaf.prev.map(Ok);
+            self.remaining = leaf.keys.len();
+            self.leaf = Some(leaf);
+        }
+    }
+}
+
+/// Reads leaves for a scan. Once the leaf chain runs through consecutive
+/// pages, as it does after a bulk load, the pages beyond the one asked for
+/// are fetched in the same request; the window doubles on every step that
+/// stays in order and drops back to one page when the chain jumps.
+struct Readahead<K, V> {
+    pages: Vec<(usize, Node<K, V>)>,
+    last: Option<usize>,
+    window: usize,
+}
+
+impl<K, V> Readahead<K, V> {
+    fn new() -> Self {
+        Self {
+            pages: Vec::new(),
+            last: None,
+            window: 1,
+        }
+    }
+
+    /// Reads the leaf at `loc` for a scan moving forward, or towards lower
+    /// pages if `backward`, failing if the page holds anything else.
+    fn read_leaf<S>(
+        &mut self,
+        storage: &mut S,
+        loc: Result<usize, BTreeError>,
+        backward: bool,
+    ) -> Result<LeafNode<K, V>, BTreeError>
+    where
+        S: Storage<K, V>,
+    {
+        let loc = loc?;
+        let node = match self.pages.iter().position(|(page, _)| *page == loc) {
+            Some(i) => self.pages.swap_remove(i).1,
+            None => self.fetch(storage, loc, backward)?,
+        };
+        self.last = Some(loc);
+        match node {
+            Node::Leaf(leaf) => Ok(leaf),
+            _ => Err(BTreeError::Corrupt {
+                loc,
+                reason: "leaf chain points at a non-leaf page",
+            }),
+        }
+    }
+
+    fn fetch<S>(
+        &mut self,
+        storage: &mut S,
+        loc: usize,
+        backward: bool,
+    ) -> Result<Node<K, V>, BTreeError>
+    where
+        S: Storage<K, V>,
+    {
+        let previous = if backward {
+            loc.checked_add(1)
+        } else {
+            loc.checked_sub(1)
+        };
+        self.window = if self.last.is_some() && self.last == previous {
+            (self.window * 2).min(MAX_READAHEAD)
+        } else {
+            1
+        };
+        self.pages.clear();
+        let locs = if backward {
+            loc.saturating_sub(self.window - 1)..loc + 1
+        } else {
+            loc..(loc + self.window).min(storage.total_nodes()?)
+        };
+        if locs.len() < 2 || !locs.contains(&loc) {
+            return storage.read_node(loc);
+        }
+
+        match storage.read_nodes(locs.clone()) {
+            Ok(nodes) => {
+                self.pages = locs.zip(nodes).collect();
+                let i = self.pages.iter().position(|(page, _)| *page == loc);
+                Ok(self
+                    .pages
+                    .swap_remove(i.expect("the page asked for is read"))
+                    .1)
+            }
+            // A damaged page nearby must not fail the scan
+            Err(_) => storage.read_node(loc),
+        }
+    }
+}
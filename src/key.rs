@@ -0,0 +1,244 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+
+use bincode::{Decode, Encode};
+
+use crate::record::{MAX_I32_SIZE, SerializedSize};
+
+/// Largest varint encoding of a 64-bit integer or a length prefix.
+const MAX_U64_SIZE: usize = 9;
+/// Strings have no fixed bound, so nodes are sized assuming keys of at most
+/// this many bytes, and longer keys are refused.
+pub const MAX_STRING_KEY_SIZE: usize = 256;
+/// Names of the natural order of keys and of its reverse.
+pub(crate) const ASCENDING: &str = "ascending";
+const DESCENDING: &str = "descending";
+
+/// Types a tree can be keyed by. Keys are compared with `Ord` and stored in
+/// nodes with bincode. Other types become keys with an empty `impl Key`,
+/// taking the defaults below.
+pub trait Key: Ord + Clone + Debug + Encode + Decode<()> + SerializedSize {
+    /// How keys of this type are ordered, recorded in the header of trees
+    /// keyed by strings. `None` for other keys.
+    fn collation() -> Option<Collation> {
+        None
+    }
+
+    /// A key to tell the node holding `left` from the one starting at
+    /// `right` in their parent: at most `right` and, when `left < right`,
+    /// above `left`. String keys shorten it to keep internal nodes small.
+    fn separator(_left: &Self, right: &Self) -> Self {
+        right.clone()
+    }
+
+    /// The form shared by all keys equal to this one, which is what the
+    /// Bloom filter hashes.
+    fn canonical(&self) -> Self {
+        self.clone()
+    }
+
+    /// Name of the order `Ord` sorts keys of this type in, recorded in the
+    /// header so the file is only reopened with keys sorted the same way.
+    /// A type sorting by a derived order of its own should name it, in at
+    /// most [`MAX_ORDER_NAME`](crate::node::MAX_ORDER_NAME) bytes.
+    fn order() -> String {
+        ASCENDING.to_owned()
+    }
+}
+
+impl Key for i32 {}
+
+impl Key for u32 {}
+
+impl Key for i64 {}
+
+impl Key for u64 {}
+
+/// A key sorted in reverse, so a tree of `Descending` keys iterates from
+/// the largest key down.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Descending<K>(pub K);
+
+impl<K: Ord> Ord for Descending<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.cmp(&self.0)
+    }
+}
+
+impl<K: Ord> PartialOrd for Descending<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: SerializedSize> SerializedSize for Descending<K> {
+    fn max_serialized_size() -> usize {
+        K::max_serialized_size()
+    }
+
+    fn serialized_size(&self) -> usize {
+        self.0.serialized_size()
+    }
+}
+
+impl<K: Key> Key for Descending<K> {
+    fn collation() -> Option<Collation> {
+        K::collation()
+    }
+
+    fn canonical(&self) -> Self {
+        Descending(self.0.canonical())
+    }
+
+    fn order() -> String {
+        match K::order().as_str() {
+            ASCENDING => DESCENDING.to_owned(),
+            DESCENDING => ASCENDING.to_owned(),
+            order => format!("reversed {}", order),
+        }
+    }
+}
+
+/// Orders of string keys, fixed when a tree is created and checked when it
+/// is opened, since a tree searched in another order than it was built in
+/// loses keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum Collation {
+    /// By UTF-8 bytes, as `String` keys compare.
+    Bytewise,
+    /// By lowercase form, as [`CaselessString`] keys compare.
+    CaseInsensitive,
+}
+
+impl Key for String {
+    fn collation() -> Option<Collation> {
+        Some(Collation::Bytewise)
+    }
+
+    fn separator(left: &Self, right: &Self) -> Self {
+        shortest_prefix(right, |prefix| left.as_str() < prefix).to_owned()
+    }
+}
+
+/// A string key compared case-insensitively: `"Apple"` and `"apple"` are
+/// the same key, and the spelling it was first inserted with is kept.
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct CaselessString(pub String);
+
+impl CaselessString {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for CaselessString {
+    fn from(text: &str) -> Self {
+        CaselessString(text.to_owned())
+    }
+}
+
+impl From<String> for CaselessString {
+    fn from(text: String) -> Self {
+        CaselessString(text)
+    }
+}
+
+impl Ord for CaselessString {
+    fn cmp(&self, other: &Self) -> Ordering {
+        caseless_cmp(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for CaselessString {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for CaselessString {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for CaselessString {}
+
+impl SerializedSize for CaselessString {
+    fn max_serialized_size() -> usize {
+        String::max_serialized_size()
+    }
+
+    fn serialized_size(&self) -> usize {
+        self.0.serialized_size()
+    }
+}
+
+impl Key for CaselessString {
+    fn collation() -> Option<Collation> {
+        Some(Collation::CaseInsensitive)
+    }
+
+    fn separator(left: &Self, right: &Self) -> Self {
+        let prefix = shortest_prefix(&right.0, |prefix| {
+            caseless_cmp(&left.0, prefix) == Ordering::Less
+        });
+        CaselessString::from(prefix)
+    }
+
+    fn canonical(&self) -> Self {
+        CaselessString(fold(&self.0).collect())
+    }
+}
+
+/// The characters of `text` lowercased one by one, unlike
+/// `str::to_lowercase`, which also looks at the letters around a sigma.
+fn fold(text: &str) -> impl Iterator<Item = char> + '_ {
+    text.chars().flat_map(char::to_lowercase)
+}
+
+fn caseless_cmp(a: &str, b: &str) -> Ordering {
+    fold(a).cmp(fold(b))
+}
+
+/// The shortest prefix of `text`, ending on a character boundary, that
+/// `above` accepts, or all of `text` if none does.
+fn shortest_prefix(text: &str, above: impl Fn(&str) -> bool) -> &str {
+    text.char_indices()
+        .map(|(i, c)| &text[..i + c.len_utf8()])
+        .find(|prefix| above(prefix))
+        .unwrap_or(text)
+}
+
+impl SerializedSize for i32 {
+    fn max_serialized_size() -> usize {
+        MAX_I32_SIZE
+    }
+}
+
+impl SerializedSize for u32 {
+    fn max_serialized_size() -> usize {
+        MAX_I32_SIZE
+    }
+}
+
+impl SerializedSize for i64 {
+    fn max_serialized_size() -> usize {
+        MAX_U64_SIZE
+    }
+}
+
+impl SerializedSize for u64 {
+    fn max_serialized_size() -> usize {
+        MAX_U64_SIZE
+    }
+}
+
+impl SerializedSize for String {
+    fn max_serialized_size() -> usize {
+        MAX_U64_SIZE + MAX_STRING_KEY_SIZE
+    }
+
+    fn serialized_size(&self) -> usize {
+        MAX_U64_SIZE + self.len()
+    }
+}
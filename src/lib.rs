@@ -0,0 +1,72 @@
+//! A persistent B+ tree storing keys and values in pages, with pluggable
+//! storage backends and page allocators.
+
+pub mod allocator;
+#[cfg(feature = "async")]
+pub mod async_storage;
+#[cfg(feature = "async")]
+pub mod async_tree;
+pub mod blob;
+mod bloom;
+pub mod btree;
+pub mod cached_storage;
+pub mod codec;
+#[cfg(feature = "compression")]
+pub mod compressed_storage;
+pub mod config;
+pub mod csv;
+pub mod cursor;
+pub mod diff;
+#[cfg(feature = "encryption")]
+pub mod encrypted_storage;
+pub mod entry;
+pub mod error;
+pub mod file_storage;
+pub mod iter;
+pub mod key;
+mod lru;
+pub mod memory_storage;
+pub mod node;
+pub mod record;
+pub mod secondary_index;
+pub mod shared;
+pub mod sim_storage;
+pub mod storage;
+#[cfg(feature = "async")]
+pub mod tokio_file_storage;
+pub mod txn;
+pub mod value;
+
+#[cfg(feature = "async")]
+pub use crate::async_storage::AsyncStorage;
+#[cfg(feature = "async")]
+pub use crate::async_tree::AsyncBPlusTree;
+pub use crate::blob::Blob;
+pub use crate::btree::{
+    Aggregate, BPlusTree, Explain, ExplainStep, HistogramBucket, I32Tree, InsertHint, TreeStats,
+    Upsert,
+};
+pub use crate::cached_storage::CachedStorage;
+pub use crate::codec::{Bincode, FixedLayout, FixedWidth, PageCodec, Slotted, SlottedPage};
+#[cfg(feature = "compression")]
+pub use crate::compressed_storage::CompressedStorage;
+pub use crate::cursor::Cursor;
+#[cfg(feature = "encryption")]
+pub use crate::encrypted_storage::{EncryptedStorage, EncryptionKey};
+pub use crate::entry::Entry;
+pub use crate::error::{BTreeError, SerializeError};
+pub use crate::file_storage::{Durability, FileStorage};
+pub use crate::key::{CaselessString, Collation, Descending, Key};
+pub use crate::memory_storage::InMemoryStorage;
+pub use crate::node::{
+    BloomHeader, BloomPage, Bucket, HeaderNode, InternalNode, LeafKeys, LeafNode, Node, NodeKind,
+    OverflowPage,
+};
+pub use crate::record::Record;
+pub use crate::shared::SharedBPlusTree;
+pub use crate::sim_storage::{SimConfig, SimFailure, SimRunner, SimStorage};
+pub use crate::storage::{Fault, FaultyStorage, SharedStorage, Storage};
+#[cfg(feature = "async")]
+pub use crate::tokio_file_storage::TokioFileStorage;
+pub use crate::txn::Txn;
+pub use crate::value::Value;
@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Fixed-capacity map from page locations to values that evicts the least
+/// recently used entry when full.
+pub struct LruCache<V> {
+    capacity: usize,
+    entries: HashMap<usize, (V, u64)>,
+    /// Last-use tick of every entry, oldest first.
+    order: BTreeMap<u64, usize>,
+    tick: u64,
+}
+
+impl<V> LruCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Whether `loc` is cached, without counting as a use.
+    pub fn contains(&self, loc: usize) -> bool {
+        self.entries.contains_key(&loc)
+    }
+
+    pub fn get(&mut self, loc: usize) -> Option<&V> {
+        let tick = self.next_tick();
+        let (value, last_used) = self.entries.get_mut(&loc)?;
+        self.order.remove(last_used);
+        self.order.insert(tick, loc);
+        *last_used = tick;
+        Some(value)
+    }
+
+    /// Inserts or replaces the entry for `loc`, returning the entry evicted
+    /// to make room, if any.
+    pub fn put(&mut self, loc: usize, value: V) -> Option<(usize, V)> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let tick = self.next_tick();
+        if let Some((_, last_used)) = self.entries.insert(loc, (value, tick)) {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(tick, loc);
+
+        if self.entries.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first()?;
+            let (value, _) = self.entries.remove(&oldest)?;
+            return Some((oldest, value));
+        }
+        None
+    }
+
+    pub fn remove(&mut self, loc: usize) -> Option<V> {
+        let (value, last_used) = self.entries.remove(&loc)?;
+        self.order.remove(&last_used);
+        Some(value)
+    }
+
+    /// Changes the capacity, evicting least recently used entries as needed
+    /// and returning them oldest first.
+    pub fn resize(&mut self, capacity: usize) -> Vec<(usize, V)> {
+        self.capacity = capacity;
+        let mut evicted = Vec::new();
+        while self.entries.len() > capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((value, _)) = self.entries.remove(&oldest) {
+                evicted.push((oldest, value));
+            }
+        }
+        evicted
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_least_recently_used_entry_is_evicted_first() {
+        let mut cache = LruCache::new(3);
+        for loc in 0..3 {
+            assert_eq!(cache.put(loc, loc * 10), None);
+        }
+        // A lookup counts as a use, a containment check does not
+        assert_eq!(cache.get(0), Some(&0));
+        assert!(cache.contains(1));
+        assert_eq!(cache.put(3, 30), Some((1, 10)));
+        // Replacing an entry uses it without evicting anything
+        assert_eq!(cache.put(2, 21), None);
+        assert_eq!(cache.put(4, 40), Some((0, 0)));
+        assert_eq!(cache.remove(2), Some(21));
+        assert_eq!(cache.get(2), None);
+
+        assert_eq!(cache.resize(1), [(3, 30)]);
+        assert_eq!(cache.get(4), Some(&40));
+        assert_eq!(cache.resize(0), [(4, 40)]);
+        assert_eq!(cache.put(5, 50), None);
+        assert!(!cache.contains(5));
+    }
+}
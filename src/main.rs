@@ -1,48 +1,418 @@
-mod btree;
-mod config;
-mod file_storage;
-mod node;
-mod record;
-mod storage;
-
-use crate::{btree::BPlusTree, file_storage::FileStorage};
+use btree::{
+    BPlusTree, FileStorage, I32Tree, NodeKind, Record, Storage, Upsert, csv, diff::Diff,
+    file_storage::Access, secondary_index::SecondaryIndex, storage,
+};
 use std::{
+    cmp::Ordering,
+    collections::HashMap,
     env,
-    io::{self, Write},
+    fmt::Display,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal},
+    ops::Bound,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+mod server;
+
+use rustyline::{
+    CompletionType, Config, Context, Editor, Helper, completion::Completer, error::ReadlineError,
+    highlight::Highlighter, hint::Hinter, history::FileHistory, validate::Validator,
 };
 
-fn main() {
-    let filename = match env::args().nth(1) {
-        Some(f) => f,
-        None => {
-            eprintln!("Usage: btree <database_file>");
+/// Per-page access counts collected through the storage instrument.
+type HeatMap = Arc<Mutex<HashMap<usize, (NodeKind, usize, usize)>>>;
+
+/// Port `btree serve` listens on unless given `--port`.
+const DEFAULT_PORT: u16 = 4000;
+
+/// Pages the database file's cache starts out with; `cache <n>` changes it.
+const CACHE_PAGES: usize = 256;
+
+/// Slices of the key space `stats` shows unless given a number.
+const STATS_BUCKETS: usize = 4;
+
+/// Command names offered by tab completion.
+const COMMANDS: &[&str] = &[
+    "abort",
+    "agg",
+    "all",
+    "backup",
+    "begin",
+    "bloom",
+    "buckets",
+    "cache",
+    "check",
+    "commit",
+    "contains",
+    "delete",
+    "depth",
+    "diff",
+    "dot",
+    "drop-bucket",
+    "exit",
+    "explain",
+    "export",
+    "find",
+    "find_by_x",
+    "findx",
+    "first",
+    "ge",
+    "heat",
+    "import",
+    "insert",
+    "keys",
+    "last",
+    "le",
+    "maxheight",
+    "nearest",
+    "nodes",
+    "pop-first",
+    "pop-last",
+    "quit",
+    "range",
+    "repair",
+    "rev",
+    "rrange",
+    "select",
+    "stats",
+    "timing",
+    "tree",
+    "use",
+    "vacuum",
+    "validate",
+    "verify-pages",
+];
+
+/// Completes the command name at the start of the line.
+struct CommandHelper;
+
+impl Completer for CommandHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let word = &line[..pos];
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let matches = COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(word))
+            .map(|command| command.to_string())
+            .collect();
+        Ok((0, matches))
+    }
+}
+
+impl Hinter for CommandHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CommandHelper {}
+
+impl Validator for CommandHelper {}
+
+impl Helper for CommandHelper {}
+
+/// Where commands come from: the line editor, which keeps its history in a
+/// dot-file next to the database, or plain lines from a script or a pipe.
+enum Source {
+    Editor(Box<Editor<CommandHelper, FileHistory>>, PathBuf),
+    Lines(Box<dyn BufRead>),
+}
+
+/// Reads commands and keeps track of whether any of them failed.
+struct Input {
+    source: Source,
+    stop_on_error: bool,
+    failed: bool,
+}
+
+impl Input {
+    fn editor(database: &str) -> rustyline::Result<Self> {
+        let config = Config::builder()
+            .completion_type(CompletionType::List)
+            .build();
+        let mut editor = Editor::with_config(config)?;
+        editor.set_helper(Some(CommandHelper));
+
+        let path = Path::new(database);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let history = path.with_file_name(format!(".{}_history", name));
+        // A missing history file just means a fresh start.
+        let _ = editor.load_history(&history);
+        Ok(Self::new(Source::Editor(Box::new(editor), history)))
+    }
+
+    /// Reads one command per line without prompting. Lines starting with `#`
+    /// are comments.
+    fn lines(reader: impl BufRead + 'static) -> Self {
+        Self::new(Source::Lines(Box::new(reader)))
+    }
+
+    fn new(source: Source) -> Self {
+        Self {
+            source,
+            stop_on_error: false,
+            failed: false,
+        }
+    }
+
+    /// The next command, trimmed, or `None` once the input ends or, with
+    /// `stop_on_error`, a command has failed.
+    fn next_line(&mut self, prompt: &str) -> Option<String> {
+        loop {
+            if self.failed && self.stop_on_error {
+                return None;
+            }
+            let line = match &mut self.source {
+                Source::Editor(editor, _) => match editor.readline(prompt) {
+                    Ok(line) => line,
+                    Err(ReadlineError::Interrupted) => continue,
+                    Err(ReadlineError::Eof) => return None,
+                    Err(err) => {
+                        println!("Failed to read input: {}", err);
+                        return None;
+                    }
+                },
+                Source::Lines(reader) => {
+                    let mut line = String::new();
+                    match reader.read_line(&mut line) {
+                        Ok(0) => return None,
+                        Ok(_) if line.trim_start().starts_with('#') => continue,
+                        Ok(_) => line,
+                        Err(err) => {
+                            println!("Failed to read input: {}", err);
+                            return None;
+                        }
+                    }
+                }
+            };
+
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Source::Editor(editor, _) = &mut self.source {
+                let _ = editor.add_history_entry(line);
+            }
+            return Some(line.to_string());
+        }
+    }
+
+    /// Prints the reason a command failed and remembers that one did.
+    fn fail(&mut self, message: impl Display) {
+        println!("{}", message);
+        self.failed = true;
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        if let Source::Editor(editor, history) = &mut self.source
+            && let Err(err) = editor.save_history(history)
+        {
+            eprintln!("Cannot save {}: {}", history.display(), err);
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: btree [--read-only] [--script <file>] [--stop-on-error] [--paranoid] [--heat] <database_file>"
+    );
+    eprintln!("       btree serve <database_file> [--port <port>] [--resp | --http]");
+    std::process::exit(1);
+}
+
+/// `btree serve`: parses its arguments and runs the TCP server.
+fn serve(mut args: impl Iterator<Item = String>) -> ExitCode {
+    let mut port = DEFAULT_PORT;
+    let mut protocol = server::Protocol::Lines;
+    let mut filename = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--resp" => protocol = server::Protocol::Resp,
+            #[cfg(feature = "http")]
+            "--http" => protocol = server::Protocol::Http,
+            "--port" => {
+                port = args
+                    .next()
+                    .and_then(|port| port.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            _ if filename.is_none() && !arg.starts_with("--") => filename = Some(arg),
+            _ => usage(),
+        }
+    }
+    let Some(filename) = filename else { usage() };
+
+    match server::serve(&filename, port, protocol) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let mut read_only = false;
+    let mut stop_on_error = false;
+    let mut paranoid = false;
+    let mut heat_map = false;
+    let mut script = None;
+    let mut filename = None;
+    let mut args = env::args().skip(1).peekable();
+    if args.next_if_eq("serve").is_some() {
+        return serve(args);
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--read-only" => read_only = true,
+            "--stop-on-error" => stop_on_error = true,
+            "--paranoid" => paranoid = true,
+            "--heat" => heat_map = true,
+            "--script" => script = Some(args.next().unwrap_or_else(|| usage())),
+            _ if filename.is_none() && !arg.starts_with("--") => filename = Some(arg),
+            _ => usage(),
+        }
+    }
+    let Some(filename) = filename else { usage() };
+
+    // The file's own cache is the only one, so every page access goes
+    // through its counters and the heat map
+    let storage = if read_only {
+        FileStorage::open_read_only(&filename).map(|mut storage| {
+            storage.set_cache_capacity(CACHE_PAGES);
+            storage
+        })
+    } else {
+        FileStorage::with_cache_capacity(&filename, CACHE_PAGES)
+    };
+    let mut storage = match storage {
+        Ok(storage) => storage,
+        Err(err) => {
+            eprintln!("Cannot open {}: {}", filename, err);
+            std::process::exit(1);
+        }
+    };
+    let mut heat = heat_map.then(|| track_heat(&mut storage));
+    let tree = if read_only {
+        BPlusTree::open_read_only(storage)
+    } else {
+        BPlusTree::open(storage)
+    };
+    let mut tree = match tree {
+        Ok(tree) => tree,
+        Err(err) => {
+            eprintln!("Cannot open {}: {}", filename, err);
             std::process::exit(1);
         }
     };
 
-    let storage = FileStorage::new(&filename);
-    let mut tree = BPlusTree::open(storage);
+    let index_filename = format!("{}.xidx", filename);
+    let index = if read_only {
+        FileStorage::open_read_only(&index_filename).and_then(SecondaryIndex::open_read_only)
+    } else {
+        FileStorage::new(&index_filename).and_then(SecondaryIndex::open)
+    };
+    let mut index = match index {
+        Ok(index) => index,
+        Err(err) => {
+            eprintln!("Cannot open {}: {}", index_filename, err);
+            std::process::exit(1);
+        }
+    };
+    if !read_only
+        && index.is_empty()
+        && !tree.is_empty()
+        && let Err(err) = index.rebuild(&mut tree)
+    {
+        eprintln!("Cannot build {}: {}", index_filename, err);
+        std::process::exit(1);
+    }
 
-    repl(&mut tree);
+    let mut input = if let Some(script) = script {
+        match File::open(&script) {
+            Ok(file) => Input::lines(BufReader::new(file)),
+            Err(err) => {
+                eprintln!("Cannot open {}: {}", script, err);
+                std::process::exit(1);
+            }
+        }
+    } else if !io::stdin().is_terminal() {
+        Input::lines(io::stdin().lock())
+    } else {
+        match Input::editor(&filename) {
+            Ok(input) => input,
+            Err(err) => {
+                eprintln!("Cannot start the line editor: {}", err);
+                std::process::exit(1);
+            }
+        }
+    };
+    input.stop_on_error = stop_on_error;
+
+    repl(&mut input, &mut tree, &mut index, &mut heat, paranoid);
+    if input.failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
 }
 
-fn repl(tree: &mut BPlusTree<FileStorage>) {
+fn repl(
+    lines: &mut Input,
+    tree: &mut I32Tree<FileStorage>,
+    index: &mut SecondaryIndex<FileStorage>,
+    heat: &mut Option<HeatMap>,
+    paranoid: bool,
+) {
+    let mut timing = false;
+    // When the command being timed started; reported before the next prompt
+    let mut started: Option<Instant> = None;
     loop {
-        io::stdout().flush().unwrap();
-
-        let mut input = String::new();
-        if io::stdin().read_line(&mut input).is_err() {
-            println!("Failed to read input");
-            continue;
+        if let Some(started) = started.take() {
+            report_timing(tree, started);
+        }
+        let prompt = match tree.bucket() {
+            Some(bucket) => format!("{}> ", bucket),
+            None => "> ".to_string(),
+        };
+        let Some(line) = lines.next_line(&prompt) else {
+            break;
+        };
+        let input = line.as_str();
+        if input == "exit" || input == "quit" {
+            break;
         }
 
-        let input = input.trim();
-        if input.is_empty() {
+        if let Some(setting) = input.strip_prefix("timing") {
+            match setting.trim() {
+                "on" => timing = true,
+                "off" => timing = false,
+                "" => {}
+                _ => {
+                    lines.fail("Usage: timing [on|off]");
+                    continue;
+                }
+            }
+            println!("Timing is {}", if timing { "on" } else { "off" });
             continue;
         }
 
-        if input == "exit" || input == "quit" {
-            break;
+        if timing {
+            reset_counters(tree);
+            started = Some(Instant::now());
         }
 
         if input == "tree" {
@@ -50,55 +420,412 @@ fn repl(tree: &mut BPlusTree<FileStorage>) {
             continue;
         }
 
-        if input == "all" {
-            tree.dump_records();
+        if input == "nodes" {
+            if let Err(err) = tree.storage.dump_pages() {
+                lines.fail(format!("Error: {}", err));
+            }
             continue;
         }
 
-        if input == "nodes" {
-            tree.storage.dump_pages();
+        if input == "verify-pages" {
+            if let Err(err) = handle_verify_pages(tree) {
+                lines.fail(format!("Error: {}", err));
+            }
             continue;
         }
 
         if input == "depth" {
-            let depth = tree.calculate_depth();
-            println!("Tree depth: {}", depth);
+            match tree.calculate_depth() {
+                Ok(depth) => println!("Tree depth: {}", depth),
+                Err(err) => lines.fail(format!("Error: {}", err)),
+            }
             continue;
         }
 
         if input == "keys" {
-            let count = tree.count_total_keys();
+            let count = tree.len();
             println!("total keys: {}", count);
+            match (tree.min_key(), tree.max_key()) {
+                (Ok(Some(min)), Ok(Some(max))) => println!("key range: {}..={}", min, max),
+                (Err(err), _) | (_, Err(err)) => lines.fail(format!("Error: {}", err)),
+                _ => {}
+            }
+            continue;
+        }
+
+        if let Some(arg) = input.strip_prefix("stats") {
+            let buckets = match arg.trim() {
+                "" => STATS_BUCKETS,
+                n => match n.parse::<usize>() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        lines.fail("Usage: stats [buckets]");
+                        continue;
+                    }
+                },
+            };
+            let stats = match tree.stats() {
+                Ok(stats) => stats,
+                Err(err) => {
+                    lines.fail(format!("Error: {}", err));
+                    continue;
+                }
+            };
+            println!("Height: {}", stats.height);
+            println!("Internal nodes: {}", stats.internal_nodes);
+            println!("Leaf nodes: {}", stats.leaf_nodes);
+            println!("Records: {}", stats.records);
+            println!("Leaf fill: {:.1}%", stats.leaf_fill * 100.0);
+            println!(
+                "Leaf redistributions: {} (splits: {})",
+                stats.leaf_redistributions, stats.leaf_splits
+            );
+            println!("File size: {} bytes", stats.file_size);
+            println!("Page reads: {}", stats.page_reads);
+            println!("Page writes: {}", stats.page_writes);
+            println!("Coalesced writes: {}", tree.storage.coalesced_writes);
+            println!("Total I/O: {}", stats.page_reads + stats.page_writes);
+            println!("Cache hits: {}", tree.storage.cache_hits);
+            println!("Free pages: {}", tree.free_pages().len());
+            println!(
+                "Bloom filter: {}",
+                if tree.has_bloom_filter() { "on" } else { "off" }
+            );
+            match tree.histogram(buckets) {
+                Ok(histogram) if histogram.is_empty() => {}
+                Ok(histogram) => {
+                    println!("Key distribution (approximate):");
+                    for bucket in histogram {
+                        match bucket.low {
+                            Some(low) => println!("  from {}: {} records", low, bucket.records),
+                            None => println!("  from start: {} records", bucket.records),
+                        }
+                    }
+                }
+                Err(err) => lines.fail(format!("Error: {}", err)),
+            }
+            continue;
+        }
+
+        if input == "first" {
+            match tree.first() {
+                Ok(Some(record)) => println!("First: {:?}", record),
+                Ok(None) => println!("Tree is empty"),
+                Err(err) => lines.fail(format!("Error: {}", err)),
+            }
             continue;
         }
 
-        if input == "stats" {
-            let reads = tree.storage.page_reads;
-            let writes = tree.storage.page_writes;
-            println!("Page reads: {}", reads);
-            println!("Page writes: {}", writes);
-            println!("Total I/O: {}", reads + writes);
+        if input == "last" {
+            match tree.last() {
+                Ok(Some(record)) => println!("Last: {:?}", record),
+                Ok(None) => println!("Tree is empty"),
+                Err(err) => lines.fail(format!("Error: {}", err)),
+            }
             continue;
         }
 
-        match handle_command(tree, input) {
+        if input == "validate" {
+            match tree.validate() {
+                Ok(()) => println!("Tree is valid"),
+                Err(err) => lines.fail(format!("Invalid tree: {}", err)),
+            }
+            continue;
+        }
+
+        if input == "check" {
+            if let Err(err) = tree.validate() {
+                lines.fail(format!("Invalid tree: {}", err));
+                continue;
+            }
+            match tree.unreachable_pages() {
+                Ok(pages) if pages.is_empty() => println!("Tree is valid, no leaked pages"),
+                Ok(pages) => println!("Tree is valid, {} leaked pages: {:?}", pages.len(), pages),
+                Err(err) => lines.fail(format!("Error: {}", err)),
+            }
+            continue;
+        }
+
+        if input == "repair" {
+            match tree.repair_leaf_chain() {
+                Ok(fixed) => println!("Fixed {} leaf pointers", fixed),
+                Err(err) => lines.fail(format!("Error: {}", err)),
+            }
+            continue;
+        }
+
+        if input == "heat" {
+            match heat {
+                Some(heat) => dump_heat(heat),
+                None => {
+                    *heat = Some(track_heat(&mut tree.storage));
+                    println!("Counting page accesses from now on; run heat again to see them");
+                }
+            }
+            continue;
+        }
+
+        if let Some(pages) = input.strip_prefix("cache ") {
+            if let Err(err) = handle_cache(tree, pages.trim()) {
+                lines.fail(format!("Error: {}", err));
+            }
+            continue;
+        }
+
+        if input == "buckets" {
+            handle_buckets(tree);
+            continue;
+        }
+
+        if input == "use" || input.starts_with("use ") {
+            let name = input["use".len()..].trim();
+            let name = (!name.is_empty()).then_some(name);
+            if let Err(err) = handle_use(tree, index, name) {
+                lines.fail(format!("Error: {}", err));
+            }
+            continue;
+        }
+
+        if let Some(name) = input.strip_prefix("drop-bucket ") {
+            if let Err(err) = handle_drop_bucket(tree, index, name.trim()) {
+                lines.fail(format!("Error: {}", err));
+            }
+            continue;
+        }
+
+        if input == "begin" {
+            if let Err(err) = transaction(lines, tree, index, paranoid) {
+                lines.fail(format!("Error: {}", err));
+            }
+            continue;
+        }
+
+        match handle_command(tree, index, input).and_then(|()| check_after(tree, paranoid)) {
             Ok(()) => {}
-            Err(err) => println!("Error: {}", err),
+            Err(err) => lines.fail(format!("Error: {}", err)),
         }
     }
 }
 
-fn handle_command(tree: &mut BPlusTree<FileStorage>, input: &str) -> Result<(), String> {
+/// Zeroes the page and cache counters of the tree's storage, so the next
+/// report covers a single command.
+fn reset_counters(tree: &mut I32Tree<FileStorage>) {
+    let file = &mut tree.storage;
+    file.page_reads = 0;
+    file.page_writes = 0;
+    file.coalesced_writes = 0;
+    file.cache_hits = 0;
+}
+
+/// Prints the time since `started` and the page traffic counted since the
+/// last [`reset_counters`].
+fn report_timing(tree: &I32Tree<FileStorage>, started: Instant) {
+    let (reads, writes) = tree.storage.io_counters();
+    println!(
+        "Time: {:.3} ms, page reads: {}, page writes: {}, cache hits: {}",
+        started.elapsed().as_secs_f64() * 1000.0,
+        reads,
+        writes,
+        tree.storage.cache_hits
+    );
+}
+
+/// Under `--paranoid`, validates the tree after each command, so a bug in
+/// splitting or rebalancing is reported by the command that caused it
+/// rather than by whatever trips over the damage later.
+fn check_after<S: Storage>(tree: &mut I32Tree<S>, paranoid: bool) -> Result<(), String> {
+    if paranoid {
+        tree.validate()
+            .map_err(|err| format!("Invalid tree: {}", err))?;
+    }
+    Ok(())
+}
+
+/// Runs commands inside a transaction on both trees until `commit` or
+/// `abort`. Each command sees the writes of the ones before it, while the
+/// files stay untouched until the commit.
+fn transaction(
+    lines: &mut Input,
+    tree: &mut I32Tree<FileStorage>,
+    index: &mut SecondaryIndex<FileStorage>,
+    paranoid: bool,
+) -> Result<(), String> {
+    let mut txn = tree.begin().map_err(|err| err.to_string())?;
+    let mut index_txn = index.begin().map_err(|err| err.to_string())?;
+    println!("Transaction started");
+
+    while let Some(line) = lines.next_line("txn> ") {
+        let input = line.as_str();
+        if input == "commit" {
+            // The two files cannot commit as one. The index goes first, so a
+            // failed primary commit leaves index entries for missing keys,
+            // which lookups skip, rather than keys the index does not know.
+            let pages = txn.dirty_pages() + index_txn.dirty_pages();
+            index_txn.commit().map_err(|err| err.to_string())?;
+            txn.commit().map_err(|err| err.to_string())?;
+            println!("Committed {} pages", pages);
+            return Ok(());
+        }
+
+        if input == "abort" {
+            txn.rollback();
+            index_txn.rollback();
+            println!("Transaction aborted");
+            return Ok(());
+        }
+
+        let result = txn.tree().and_then(|mut tree| {
+            SecondaryIndex::open(index_txn.storage()).map(|mut index| {
+                handle_command(&mut tree, &mut index, input)
+                    .and_then(|()| check_after(&mut tree, paranoid))
+            })
+        });
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => lines.fail(format!("Error: {}", err)),
+            Err(err) => lines.fail(format!("Error: {}", err)),
+        }
+    }
+
+    // The input ended without a commit.
+    txn.rollback();
+    index_txn.rollback();
+    println!("Transaction aborted");
+    Ok(())
+}
+
+/// Lists the main tree and every bucket, marking the one in use.
+fn handle_buckets(tree: &I32Tree<FileStorage>) {
+    let current = tree.bucket();
+    let marker = if current.is_none() { "*" } else { " " };
+    println!("{} (main)", marker);
+    for bucket in tree.buckets() {
+        let marker = if current == Some(&bucket.name) {
+            "*"
+        } else {
+            " "
+        };
+        println!("{} {}: {} records", marker, bucket.name, bucket.count);
+    }
+}
+
+/// Switches both trees to the bucket `name`, or back to their main trees,
+/// creating the bucket first if it is new.
+fn handle_use(
+    tree: &mut I32Tree<FileStorage>,
+    index: &mut SecondaryIndex<FileStorage>,
+    name: Option<&str>,
+) -> Result<(), String> {
+    if let Some(name) = name
+        && !tree.buckets().iter().any(|bucket| bucket.name == name)
+    {
+        tree.create_bucket(name).map_err(|err| err.to_string())?;
+        println!("Created bucket {}", name);
+    }
+
+    let previous = tree.bucket().map(str::to_owned);
+    tree.use_bucket(name).map_err(|err| err.to_string())?;
+    if let Err(err) = index.use_bucket(name) {
+        let _ = tree.use_bucket(previous.as_deref());
+        return Err(err.to_string());
+    }
+    if index.is_empty() && !tree.is_empty() {
+        index.rebuild(tree).map_err(|err| err.to_string())?;
+    }
+
+    match name {
+        Some(name) => println!("Using bucket {}", name),
+        None => println!("Using the main tree"),
+    }
+    Ok(())
+}
+
+fn handle_drop_bucket(
+    tree: &mut I32Tree<FileStorage>,
+    index: &mut SecondaryIndex<FileStorage>,
+    name: &str,
+) -> Result<(), String> {
+    tree.drop_bucket(name).map_err(|err| err.to_string())?;
+    index.drop_bucket(name).map_err(|err| err.to_string())?;
+    println!("Dropped bucket {}", name);
+    Ok(())
+}
+
+/// Installs the instrument that counts page accesses into a new heat map.
+/// Left out unless asked for, as it takes a lock on every page access.
+fn track_heat(storage: &mut FileStorage) -> HeatMap {
+    let heat: HeatMap = Arc::default();
+    let counter = Arc::clone(&heat);
+    storage.set_instrument(Some(Box::new(move |access, loc, kind| {
+        let mut counter = counter.lock().unwrap();
+        let entry = counter.entry(loc).or_insert((kind, 0, 0));
+        entry.0 = kind;
+        match access {
+            Access::Read => entry.1 += 1,
+            Access::Write => entry.2 += 1,
+        }
+    })));
+    heat
+}
+
+fn dump_heat(heat: &HeatMap) {
+    let heat = heat.lock().unwrap();
+    let mut pages: Vec<_> = heat.iter().collect();
+    pages.sort_by_key(|(loc, (_, reads, writes))| (std::cmp::Reverse(reads + writes), **loc));
+
+    for (loc, (kind, reads, writes)) in pages.iter().take(20) {
+        println!(
+            "Page {} ({:?}): reads={} writes={}",
+            loc, kind, reads, writes
+        );
+    }
+}
+
+fn handle_command<S: Storage, T: Storage>(
+    tree: &mut I32Tree<S>,
+    index: &mut SecondaryIndex<T>,
+    input: &str,
+) -> Result<(), String> {
     let parts: Vec<&str> = input.split_whitespace().collect();
 
     match parts.as_slice() {
-        ["insert", rest @ ..] => handle_insert(tree, rest),
+        ["insert", rest @ ..] => handle_insert(tree, index, rest),
+        ["findx" | "find_by_x", x] => handle_find_by_x(tree, index, x),
+        ["delete", key] => handle_delete(tree, index, key),
+        ["delete", from, to] => handle_delete_range(tree, index, from, to),
+        ["pop-first"] => handle_pop(tree, index, true),
+        ["pop-last"] => handle_pop(tree, index, false),
+        ["all", rest @ ..] => handle_all(tree, rest),
+        ["rev", args @ ..] => handle_rev(tree, args),
+        ["range", lo, hi, rest @ ..] => handle_range(tree, lo, hi, rest),
+        ["rrange", hi, lo] => handle_range_rev(tree, hi, lo),
+        ["select", fields, "from", rest @ ..] => handle_select(tree, fields, rest),
+        ["diff", path] => handle_diff(tree, path),
+        ["import", path] => handle_import(tree, index, path),
+        ["export", path] => handle_export(tree, path),
+        ["dot", path] => handle_dot(tree, path),
         ["find", key] => handle_find(tree, key),
+        ["explain", "find", key] => handle_explain(tree, key),
+        ["find", keys @ ..] if keys.len() > 1 => handle_find_many(tree, keys),
+        ["nearest", key] => handle_nearest(tree, key),
+        ["ge", key] => handle_find_ge(tree, key),
+        ["le", key] => handle_find_le(tree, key),
+        ["contains", key] => handle_contains(tree, key),
+        ["agg", field] => handle_aggregate(tree, field, None),
+        ["agg", field, lo, hi] => handle_aggregate(tree, field, Some((lo, hi))),
+        ["maxheight", height] => handle_max_height(tree, height),
+        ["vacuum"] => handle_vacuum(tree, index),
+        ["backup", path] => handle_backup(tree, index, path),
+        ["bloom", setting @ ("on" | "off")] => handle_bloom(tree, setting),
         _ => Err("Unknown command".into()),
     }
 }
 
-fn handle_insert(tree: &mut BPlusTree<FileStorage>, args: &[&str]) -> Result<(), String> {
+fn handle_insert<S: Storage, T: Storage>(
+    tree: &mut I32Tree<S>,
+    index: &mut SecondaryIndex<T>,
+    args: &[&str],
+) -> Result<(), String> {
     if args.len() != 7 {
         return Err("Usage: insert k x a_1 a_2 a_3 a_4 a_5".into());
     }
@@ -110,20 +837,618 @@ fn handle_insert(tree: &mut BPlusTree<FileStorage>, args: &[&str]) -> Result<(),
             .map_err(|_| format!("Invalid integer: {}", arg))?;
     }
 
-    tree.insert(record);
-    println!("Inserted: {:?}", record);
+    match index.insert(tree, record).map_err(|err| err.to_string())? {
+        Upsert::Inserted => println!("Inserted: {:?}", record),
+        Upsert::Updated(old) => println!("Updated: {:?}, was {:?}", record, old),
+    }
     Ok(())
 }
 
-fn handle_find(tree: &mut BPlusTree<FileStorage>, key: &str) -> Result<(), String> {
+fn handle_all<S: Storage>(tree: &mut I32Tree<S>, args: &[&str]) -> Result<(), String> {
+    let (args, filter) = split_where(args)?;
+    let (from, limit) = match args {
+        [] => (None, None),
+        [limit] => (None, Some(*limit)),
+        ["from", key] => (Some(*key), None),
+        ["from", key, limit] => (Some(*key), Some(*limit)),
+        _ => {
+            return Err(
+                "Usage: all [n] | all from <key> [n], then optionally where <condition>".into(),
+            );
+        }
+    };
+
+    let from = from
+        .map(|key| {
+            key.parse::<i32>()
+                .map_err(|_| format!("Invalid key: {}", key))
+        })
+        .transpose()?;
+    let limit = limit
+        .map(|n| {
+            n.parse::<usize>()
+                .map_err(|_| format!("Invalid limit: {}", n))
+        })
+        .transpose()?;
+
+    if tree.is_empty() {
+        println!("Tree is empty");
+    }
+    let Some(conditions) = filter else {
+        return tree
+            .dump_records(from.as_ref(), limit)
+            .map_err(|err| err.to_string());
+    };
+    let start = from.map_or(Bound::Unbounded, Bound::Included);
+    let entries = tree
+        .scan_filter((start, Bound::Unbounded), |record| {
+            conditions.iter().all(|condition| condition.matches(record))
+        })
+        .map_err(|err| err.to_string())?;
+    for (_, record) in entries.iter().take(limit.unwrap_or(usize::MAX)) {
+        println!("{:?}", record);
+    }
+    Ok(())
+}
+
+fn handle_delete<S: Storage, T: Storage>(
+    tree: &mut I32Tree<S>,
+    index: &mut SecondaryIndex<T>,
+    key: &str,
+) -> Result<(), String> {
     let key = key
         .parse::<i32>()
         .map_err(|_| format!("Invalid key: {}", key))?;
 
-    match tree.find(key) {
+    match index.delete(tree, key).map_err(|err| err.to_string())? {
+        Some(record) => println!("Deleted: {:?}", record),
+        None => println!("Key not found"),
+    }
+
+    Ok(())
+}
+
+fn handle_pop<S: Storage, T: Storage>(
+    tree: &mut I32Tree<S>,
+    index: &mut SecondaryIndex<T>,
+    first: bool,
+) -> Result<(), String> {
+    let popped = if first {
+        index.pop_first(tree)
+    } else {
+        index.pop_last(tree)
+    };
+    match popped.map_err(|err| err.to_string())? {
+        Some(record) => println!("Popped: {:?}", record),
+        None => println!("Tree is empty"),
+    }
+
+    Ok(())
+}
+
+fn handle_delete_range<S: Storage, T: Storage>(
+    tree: &mut I32Tree<S>,
+    index: &mut SecondaryIndex<T>,
+    from: &str,
+    to: &str,
+) -> Result<(), String> {
+    let from = from
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid key: {}", from))?;
+    let to = to
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid key: {}", to))?;
+
+    let removed = index
+        .delete_range(tree, from..=to)
+        .map_err(|err| err.to_string())?;
+    println!("Deleted {} records", removed.len());
+
+    Ok(())
+}
+
+fn handle_range<S: Storage>(
+    tree: &mut I32Tree<S>,
+    lo: &str,
+    hi: &str,
+    rest: &[&str],
+) -> Result<(), String> {
+    let lo = lo
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid key: {}", lo))?;
+    let hi = hi
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid key: {}", hi))?;
+    let (extra, filter) = split_where(rest)?;
+    if !extra.is_empty() {
+        return Err("Usage: range <lo> <hi> [where <condition>]".into());
+    }
+
+    let entries = match filter {
+        None => tree.range(&lo, &hi),
+        Some(conditions) => tree.scan_filter(lo..=hi, |record| {
+            conditions.iter().all(|condition| condition.matches(record))
+        }),
+    }
+    .map_err(|err| err.to_string())?;
+    for (_, record) in &entries {
+        println!("{:?}", record);
+    }
+    println!("{} records", entries.len());
+
+    Ok(())
+}
+
+fn handle_rev<S: Storage>(tree: &mut I32Tree<S>, args: &[&str]) -> Result<(), String> {
+    let limit = match args {
+        [] => usize::MAX,
+        [limit] => limit
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid limit: {}", limit))?,
+        _ => return Err("Usage: rev [n]".into()),
+    };
+
+    for entry in tree.rev_iter().take(limit) {
+        let (_, record) = entry.map_err(|err| err.to_string())?;
+        println!("{:?}", record);
+    }
+    Ok(())
+}
+
+fn handle_range_rev<S: Storage>(tree: &mut I32Tree<S>, hi: &str, lo: &str) -> Result<(), String> {
+    let hi = hi
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid key: {}", hi))?;
+    let lo = lo
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid key: {}", lo))?;
+
+    let entries = tree.range_rev(&hi, &lo).map_err(|err| err.to_string())?;
+    for (_, record) in &entries {
+        println!("{:?}", record);
+    }
+    println!("{} records", entries.len());
+
+    Ok(())
+}
+
+fn handle_find<S: Storage>(tree: &mut I32Tree<S>, key: &str) -> Result<(), String> {
+    let key = key
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid key: {}", key))?;
+
+    match tree.get(&key).map_err(|err| err.to_string())? {
         Some(record) => println!("Found: {:?}", record),
         None => println!("Key not found"),
     }
 
     Ok(())
 }
+
+fn handle_explain<S: Storage>(tree: &mut I32Tree<S>, key: &str) -> Result<(), String> {
+    let key = key
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid key: {}", key))?;
+
+    let explain = tree.explain(&key).map_err(|err| err.to_string())?;
+    if explain.bloom_ruled_out {
+        println!("Bloom filter rules out {}", key);
+    }
+    for (i, step) in explain.steps.iter().enumerate() {
+        let comparisons: Vec<String> = step
+            .comparisons
+            .iter()
+            .map(|(other, ordering)| {
+                let sign = match ordering {
+                    Ordering::Less => "<",
+                    Ordering::Equal => "=",
+                    Ordering::Greater => ">",
+                };
+                format!("{} {} {}", key, sign, other)
+            })
+            .collect();
+        let outcome = match step.child {
+            Some((child, loc)) => format!("child {} (page {})", child, loc),
+            None if i + 1 < explain.steps.len() => "next leaf".to_string(),
+            None if explain.value.is_some() => "found".to_string(),
+            None => "not found".to_string(),
+        };
+        println!(
+            "Page {} ({:?}, {} keys): {} -> {}",
+            step.loc,
+            step.kind,
+            step.keys,
+            comparisons.join(", "),
+            outcome
+        );
+    }
+    match explain.value {
+        Some(record) => println!("Found: {:?}", record),
+        None => println!("Key not found"),
+    }
+    println!(
+        "Pages visited: {}, page reads: {}",
+        explain.steps.len(),
+        explain.page_reads
+    );
+    Ok(())
+}
+
+fn handle_find_many<S: Storage>(tree: &mut I32Tree<S>, keys: &[&str]) -> Result<(), String> {
+    let keys = keys
+        .iter()
+        .map(|key| {
+            key.parse::<i32>()
+                .map_err(|_| format!("Invalid key: {}", key))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let records = tree.find_many(&keys).map_err(|err| err.to_string())?;
+    for (key, record) in keys.iter().zip(records) {
+        match record {
+            Some(record) => println!("Found: {:?}", record),
+            None => println!("Key not found: {}", key),
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_nearest<S: Storage>(tree: &mut I32Tree<S>, key: &str) -> Result<(), String> {
+    let key = key
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid key: {}", key))?;
+
+    match tree.find_nearest(key).map_err(|err| err.to_string())? {
+        Some(record) => println!("Nearest: {:?}", record),
+        None => println!("Tree is empty"),
+    }
+
+    Ok(())
+}
+
+fn handle_find_ge<S: Storage>(tree: &mut I32Tree<S>, key: &str) -> Result<(), String> {
+    let key = key
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid key: {}", key))?;
+
+    match tree.find_ge(&key).map_err(|err| err.to_string())? {
+        Some((_, record)) => println!("Found: {:?}", record),
+        None => println!("No key >= {}", key),
+    }
+
+    Ok(())
+}
+
+fn handle_find_le<S: Storage>(tree: &mut I32Tree<S>, key: &str) -> Result<(), String> {
+    let key = key
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid key: {}", key))?;
+
+    match tree.find_le(&key).map_err(|err| err.to_string())? {
+        Some((_, record)) => println!("Found: {:?}", record),
+        None => println!("No key <= {}", key),
+    }
+
+    Ok(())
+}
+
+fn handle_contains<S: Storage>(tree: &mut I32Tree<S>, key: &str) -> Result<(), String> {
+    let key = key
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid key: {}", key))?;
+
+    let found = tree.contains_key(&key).map_err(|err| err.to_string())?;
+    println!("{}", found);
+    Ok(())
+}
+
+/// Record fields by name, in order, for `agg`, `select` and `where`.
+const FIELDS: [&str; 7] = ["key", "x", "a1", "a2", "a3", "a4", "a5"];
+
+/// Index of the record field called `name`.
+fn field_index(name: &str) -> Result<usize, String> {
+    FIELDS
+        .iter()
+        .position(|field| *field == name)
+        .ok_or_else(|| {
+            format!(
+                "Unknown field: {} (expected one of {})",
+                name,
+                FIELDS.join(", ")
+            )
+        })
+}
+
+/// One comparison of a `where` clause, such as `x > 10`.
+struct Condition {
+    field: usize,
+    /// How the field may compare to `value` for a record to match.
+    accept: &'static [Ordering],
+    value: i32,
+}
+
+impl Condition {
+    fn parse(field: &str, op: &str, value: &str) -> Result<Self, String> {
+        let accept: &'static [Ordering] = match op {
+            "=" | "==" => &[Ordering::Equal],
+            "!=" => &[Ordering::Less, Ordering::Greater],
+            "<" => &[Ordering::Less],
+            "<=" => &[Ordering::Less, Ordering::Equal],
+            ">" => &[Ordering::Greater],
+            ">=" => &[Ordering::Greater, Ordering::Equal],
+            _ => return Err(format!("Unknown comparison: {}", op)),
+        };
+        Ok(Condition {
+            field: field_index(field)?,
+            accept,
+            value: value
+                .parse::<i32>()
+                .map_err(|_| format!("Invalid integer: {}", value))?,
+        })
+    }
+
+    fn matches(&self, record: &Record) -> bool {
+        self.accept.contains(&record[self.field].cmp(&self.value))
+    }
+}
+
+/// Splits `args` at a `where` clause, parsing the comparisons after it.
+/// Comparisons are `<field> <op> <value>`, joined by `and`.
+fn split_where<'a, 'b>(
+    args: &'a [&'b str],
+) -> Result<(&'a [&'b str], Option<Vec<Condition>>), String> {
+    let Some(at) = args.iter().position(|arg| *arg == "where") else {
+        return Ok((args, None));
+    };
+    let conditions = args[at + 1..]
+        .split(|arg| *arg == "and")
+        .map(|condition| match condition {
+            [field, op, value] => Condition::parse(field, op, value),
+            _ => Err("Usage: where <field> <op> <value> [and ...]".into()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((&args[..at], Some(conditions)))
+}
+
+fn handle_aggregate<S: Storage>(
+    tree: &mut I32Tree<S>,
+    field: &str,
+    bounds: Option<(&str, &str)>,
+) -> Result<(), String> {
+    let index = field_index(field)?;
+    let aggregate = match bounds {
+        None => tree.aggregate(.., index),
+        Some((lo, hi)) => {
+            let lo = lo
+                .parse::<i32>()
+                .map_err(|_| format!("Invalid key: {}", lo))?;
+            let hi = hi
+                .parse::<i32>()
+                .map_err(|_| format!("Invalid key: {}", hi))?;
+            tree.aggregate(lo..=hi, index)
+        }
+    }
+    .map_err(|err| err.to_string())?;
+
+    let show = |value: Option<i32>| value.map_or("-".to_owned(), |value| value.to_string());
+    println!("Records: {}", aggregate.count);
+    println!("Sum of {}: {}", field, aggregate.sum);
+    println!("Min of {}: {}", field, show(aggregate.min));
+    println!("Max of {}: {}", field, show(aggregate.max));
+    Ok(())
+}
+
+fn handle_select<S: Storage>(
+    tree: &mut I32Tree<S>,
+    fields: &str,
+    source: &[&str],
+) -> Result<(), String> {
+    let fields = match fields {
+        "*" => (0..FIELDS.len()).collect(),
+        _ => fields
+            .split(',')
+            .map(field_index)
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    let (source, filter) = split_where(source)?;
+    let range = match source {
+        ["all"] => (Bound::Unbounded, Bound::Unbounded),
+        ["range", lo, hi] => {
+            let lo = lo
+                .parse::<i32>()
+                .map_err(|_| format!("Invalid key: {}", lo))?;
+            let hi = hi
+                .parse::<i32>()
+                .map_err(|_| format!("Invalid key: {}", hi))?;
+            (Bound::Included(lo), Bound::Included(hi))
+        }
+        _ => {
+            return Err(
+                "Usage: select <field,...|*> from all | range <lo> <hi> [where <condition>]".into(),
+            );
+        }
+    };
+
+    let conditions = filter.unwrap_or_default();
+    let rows = tree
+        .select(range, |_, record| {
+            conditions
+                .iter()
+                .all(|condition| condition.matches(record))
+                .then(|| {
+                    fields
+                        .iter()
+                        .map(|&field| record[field])
+                        .collect::<Vec<_>>()
+                })
+        })
+        .map_err(|err| err.to_string())?;
+    for row in &rows {
+        println!("{:?}", row);
+    }
+    println!("{} records", rows.len());
+    Ok(())
+}
+
+fn handle_verify_pages(tree: &mut I32Tree<FileStorage>) -> Result<(), String> {
+    // Pages still held back are checked once they reach the file
+    tree.flush().map_err(|err| err.to_string())?;
+    let storage = &tree.storage;
+    let damaged = storage.verify_pages().map_err(|err| err.to_string())?;
+    for (loc, reason) in &damaged {
+        println!("Page {}: {}", loc, reason);
+    }
+    let total = storage.total_nodes().map_err(|err| err.to_string())?;
+    if damaged.is_empty() {
+        println!("All {} pages are intact", total);
+    } else {
+        println!("{} of {} pages are damaged", damaged.len(), total);
+    }
+
+    Ok(())
+}
+
+fn handle_cache(tree: &mut I32Tree<FileStorage>, pages: &str) -> Result<(), String> {
+    let pages = pages
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid page count: {}", pages))?;
+
+    tree.storage.set_cache_capacity(pages);
+    println!("Cache capacity: {} pages", tree.storage.cache_capacity());
+    Ok(())
+}
+
+fn handle_max_height<S: Storage>(tree: &mut I32Tree<S>, height: &str) -> Result<(), String> {
+    let height = height
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid height: {}", height))?;
+
+    tree.set_max_height(height);
+    println!("Max height: {}", height);
+    Ok(())
+}
+
+fn handle_find_by_x<S: Storage, T: Storage>(
+    tree: &mut I32Tree<S>,
+    index: &mut SecondaryIndex<T>,
+    x: &str,
+) -> Result<(), String> {
+    let x = x.parse::<i32>().map_err(|_| format!("Invalid x: {}", x))?;
+
+    let records = index.find_by_x(tree, x).map_err(|err| err.to_string())?;
+    if records.is_empty() {
+        println!("No records with x = {}", x);
+    }
+    for record in records {
+        println!("Found: {:?}", record);
+    }
+
+    Ok(())
+}
+
+fn handle_diff<S: Storage>(tree: &mut I32Tree<S>, path: &str) -> Result<(), String> {
+    let mut other: I32Tree<FileStorage> = FileStorage::new(path)
+        .and_then(BPlusTree::open)
+        .map_err(|err| err.to_string())?;
+
+    let mut changes = 0;
+    for diff in tree.diff(&mut other) {
+        match diff.map_err(|err| err.to_string())? {
+            Diff::Added(_, record) => println!("+ {:?}", record),
+            Diff::Removed(_, record) => println!("- {:?}", record),
+            Diff::Changed { old, new, .. } => println!("~ {:?} -> {:?}", old, new),
+        }
+        changes += 1;
+    }
+    println!("{} differences", changes);
+
+    Ok(())
+}
+
+fn handle_import<S: Storage, T: Storage>(
+    tree: &mut I32Tree<S>,
+    index: &mut SecondaryIndex<T>,
+    path: &str,
+) -> Result<(), String> {
+    let file = File::open(path).map_err(|err| format!("Cannot read {}: {}", path, err))?;
+    let (reads, writes) = tree.storage.io_counters();
+    let (loaded, errors) =
+        csv::import(tree, BufReader::new(file)).map_err(|err| err.to_string())?;
+    // Caught up in one pass rather than record by record
+    index.rebuild(tree).map_err(|err| err.to_string())?;
+    for (line, err) in &errors {
+        println!("Line {}: {}", line, err);
+    }
+    let skipped = errors.len();
+    let (after_reads, after_writes) = tree.storage.io_counters();
+    println!("Imported {} records, skipped {} lines", loaded, skipped);
+    println!(
+        "Page reads: {}, page writes: {}",
+        after_reads - reads,
+        after_writes - writes
+    );
+
+    Ok(())
+}
+
+fn handle_vacuum<S: Storage, T: Storage>(
+    tree: &mut I32Tree<S>,
+    index: &mut SecondaryIndex<T>,
+) -> Result<(), String> {
+    let reclaimed = tree.compact().map_err(|err| err.to_string())?;
+    let index_reclaimed = index.compact().map_err(|err| err.to_string())?;
+    println!(
+        "Reclaimed {} bytes, {} from the index",
+        reclaimed + index_reclaimed,
+        index_reclaimed
+    );
+
+    Ok(())
+}
+
+fn handle_backup<S: Storage, T: Storage>(
+    tree: &mut I32Tree<S>,
+    index: &SecondaryIndex<T>,
+    path: &str,
+) -> Result<(), String> {
+    // The tree refuses its own file, but not the index's
+    if let Some(own) = index.storage().file_path()
+        && storage::same_file(own, Path::new(path))
+    {
+        return Err(format!(
+            "Cannot back up onto {}, the index's own file",
+            path
+        ));
+    }
+    let pages = tree.backup(path).map_err(|err| err.to_string())?;
+    println!("Backed up {} pages to {}", pages, path);
+    Ok(())
+}
+
+fn handle_bloom<S: Storage>(tree: &mut I32Tree<S>, setting: &str) -> Result<(), String> {
+    if setting == "on" {
+        tree.build_bloom_filter().map_err(|err| err.to_string())?;
+        println!("Bloom filter built");
+    } else {
+        tree.drop_bloom_filter().map_err(|err| err.to_string())?;
+        println!("Bloom filter dropped");
+    }
+    Ok(())
+}
+
+fn handle_export<S: Storage>(tree: &mut I32Tree<S>, path: &str) -> Result<(), String> {
+    let file = File::create(path).map_err(|err| format!("Cannot create {}: {}", path, err))?;
+    let exported = csv::export(tree, BufWriter::new(file)).map_err(|err| err.to_string())?;
+    println!("Exported {} records", exported);
+
+    Ok(())
+}
+
+fn handle_dot<S: Storage>(tree: &mut I32Tree<S>, path: &str) -> Result<(), String> {
+    let dot = tree.to_dot().map_err(|err| err.to_string())?;
+    fs::write(path, dot).map_err(|err| format!("Cannot write {}: {}", path, err))?;
+    println!("Wrote {}", path);
+
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use crate::error::BTreeError;
+use crate::node::Node;
+use crate::record::Record;
+use crate::storage::{SharedStorage, Storage};
+
+/// Keeps every page in memory; useful for tests and throwaway trees.
+#[derive(Debug, Clone)]
+pub struct InMemoryStorage<K = i32, V = Record> {
+    pages: Vec<Option<Node<K, V>>>,
+    /// Pages read and written through `&mut self`; shared reads are not
+    /// counted.
+    pub page_reads: usize,
+    pub page_writes: usize,
+}
+
+impl<K, V> InMemoryStorage<K, V> {
+    pub fn new() -> Self {
+        Self {
+            pages: Vec::new(),
+            page_reads: 0,
+            page_writes: 0,
+        }
+    }
+}
+
+impl<K, V> Default for InMemoryStorage<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Clone, V: Clone> Storage<K, V> for InMemoryStorage<K, V> {
+    fn read_node(&mut self, loc: usize) -> Result<Node<K, V>, BTreeError> {
+        let node = self.read_node_shared(loc)?;
+        self.page_reads += 1;
+        Ok(node)
+    }
+
+    fn write_node(&mut self, loc: usize, node: &Node<K, V>) -> Result<(), BTreeError> {
+        if loc >= self.pages.len() {
+            self.pages.resize(loc + 1, None);
+        }
+        self.pages[loc] = Some(node.clone());
+        self.page_writes += 1;
+        Ok(())
+    }
+
+    fn total_nodes(&self) -> Result<usize, BTreeError> {
+        Ok(self.pages.len())
+    }
+
+    fn truncate(&mut self, pages: usize) -> Result<(), BTreeError> {
+        self.pages.truncate(pages);
+        Ok(())
+    }
+
+    fn page_capacity(&self) -> usize {
+        usize::MAX
+    }
+
+    fn page_size(&self) -> usize {
+        0
+    }
+
+    fn io_counters(&self) -> (usize, usize) {
+        (self.page_reads, self.page_writes)
+    }
+}
+
+impl<K: Clone, V: Clone> SharedStorage<K, V> for InMemoryStorage<K, V> {
+    fn read_node_shared(&self, loc: usize) -> Result<Node<K, V>, BTreeError> {
+        self.pages
+            .get(loc)
+            .cloned()
+            .flatten()
+            .ok_or(BTreeError::Corrupt {
+                loc,
+                reason: "page was never written",
+            })
+    }
+}
@@ -1,39 +1,357 @@
 use bincode::{Decode, Encode};
 
-use crate::config::DEGREE;
-use crate::record::Record;
+use crate::codec::{BINCODE, SLOT_SIZE};
+use crate::config::{DEGREE, PAGE_SIZE};
+use crate::error::BTreeError;
+use crate::key::{ASCENDING, Collation};
+use crate::record::{Record, SerializedSize};
+
+/// Bound on everything in an encoded node besides its entries: the variant
+/// tag, vector lengths, and the optional `next` and `prev` pointers.
+const NODE_OVERHEAD: usize = 48;
+/// Largest varint encoding of a page location.
+const MAX_LOC_SIZE: usize = 9;
+/// Bound on everything in an encoded header besides its free list entries
+/// and bucket catalog.
+const HEADER_OVERHEAD: usize = 112;
+/// Longest name of a key order, in bytes.
+pub const MAX_ORDER_NAME: usize = 64;
+/// Longest name of a page codec, in bytes.
+pub const MAX_CODEC_NAME: usize = 32;
+/// Most buckets a file can hold besides its main tree.
+pub const MAX_BUCKETS: usize = 32;
+/// Longest bucket name, in bytes.
+pub const MAX_BUCKET_NAME: usize = 64;
+/// Bound on an encoded bucket catalog: each entry is a name with its
+/// length prefix and three locations or counts.
+const CATALOG_SIZE: usize = MAX_BUCKETS * (MAX_BUCKET_NAME + 4 * MAX_LOC_SIZE);
+/// Most pages a Bloom filter may span.
+pub const MAX_BLOOM_PAGES: usize = 16;
+/// Bound on the header's Bloom filter entry: its page list, with length
+/// prefix, and hash count.
+const BLOOM_ENTRY_SIZE: usize = (MAX_BLOOM_PAGES + 3) * MAX_LOC_SIZE;
+/// Bound on everything in an encoded Bloom filter page besides its bits.
+const BLOOM_PAGE_OVERHEAD: usize = 16;
+/// Bound on everything in an encoded overflow page besides its bytes.
+const OVERFLOW_PAGE_OVERHEAD: usize = 32;
+
+/// First field of every header, marking page 0 as the start of a tree.
+pub const MAGIC: u32 = u32::from_le_bytes(*b"BPTR");
+/// Version of the on-disk format written by this code. Files written in
+/// any other version are refused.
+pub const FORMAT_VERSION: u32 = 11;
 
 #[derive(Debug, Clone, Encode, Decode, PartialEq, Eq)]
-pub enum Node {
-    Leaf(LeafNode),
-    Internal(InternalNode),
+pub enum Node<K = i32, V = Record> {
+    Leaf(LeafNode<K, V>),
+    Internal(InternalNode<K>),
     Header(HeaderNode),
+    Bloom(BloomPage),
+    Overflow(OverflowPage),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Leaf,
+    Internal,
+    Header,
+    Bloom,
+    Overflow,
+}
+
+impl<K, V> Node<K, V> {
+    pub fn kind(&self) -> NodeKind {
+        match self {
+            Node::Leaf(_) => NodeKind::Leaf,
+            Node::Internal(_) => NodeKind::Internal,
+            Node::Header(_) => NodeKind::Header,
+            Node::Bloom(_) => NodeKind::Bloom,
+            Node::Overflow(_) => NodeKind::Overflow,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Encode, Decode, PartialEq, Eq)]
 pub struct HeaderNode {
+    /// Always [`MAGIC`].
+    pub magic: u32,
+    /// [`FORMAT_VERSION`] of the code that created the file.
+    pub version: u32,
+    /// Bytes of each page available to a node, which node capacity is
+    /// derived from.
+    pub page_size: usize,
     pub root: usize,
+    /// Levels from the root down to the leaves, counting both.
+    pub height: usize,
+    /// Pages released by deletes, reused before the file grows.
+    pub free_list: Vec<usize>,
+    /// Number of records in the tree.
+    pub count: usize,
+    /// Whether equal keys coexist rather than overwriting each other.
+    pub multimap: bool,
+    /// Whether each tree's root stays on its page as the tree grows and
+    /// shrinks; see [`set_root_pinned`](crate::BPlusTree::set_root_pinned).
+    pub pinned_root: bool,
+    /// How the keys compare, for trees keyed by strings.
+    pub collation: Option<Collation>,
+    /// Name of the order the keys are sorted in, from
+    /// [`Key::order`](crate::Key::order).
+    pub order: String,
+    /// Name of the [`PageCodec`](crate::codec::PageCodec) the pages were
+    /// encoded with.
+    pub codec: String,
+    /// Named trees sharing the file with the main one, which is described
+    /// by `root`, `height` and `count` above.
+    pub buckets: Vec<Bucket>,
+    /// Bloom filter over the main tree's keys, if one was built.
+    pub bloom: Option<BloomHeader>,
+    /// For the tree of a [`SecondaryIndex`](crate::secondary_index::SecondaryIndex),
+    /// the position in the record of the field it indexes.
+    pub indexed_field: Option<usize>,
 }
 
+/// A named tree in the header's catalog, kept in the same pages and free
+/// list as the main tree.
 #[derive(Debug, Clone, Encode, Decode, PartialEq, Eq)]
-pub struct LeafNode {
-    pub keys: Vec<i32>,
-    pub values: Vec<Record>,
+pub struct Bucket {
+    pub name: String,
+    pub root: usize,
+    /// Levels from the root down to the leaves, counting both.
+    pub height: usize,
+    /// Number of records in the bucket.
+    pub count: usize,
+}
+
+/// Where a Bloom filter's bits are kept: one block per page, in order.
+#[derive(Debug, Clone, Encode, Decode, PartialEq, Eq)]
+pub struct BloomHeader {
+    pub pages: Vec<usize>,
+    /// Bits set for each key.
+    pub hashes: u32,
+}
+
+/// One block of a Bloom filter's bits.
+#[derive(Debug, Clone, Encode, Decode, PartialEq, Eq)]
+pub struct BloomPage {
+    pub bits: Vec<u8>,
+}
+
+/// A piece of a value too large for its leaf, with the page holding the
+/// next piece.
+#[derive(Debug, Clone, Encode, Decode, PartialEq, Eq)]
+pub struct OverflowPage {
+    pub data: Vec<u8>,
+    pub next: Option<usize>,
+}
+
+impl HeaderNode {
+    /// Header of a tree rooted at `root` on pages of `page_size` bytes.
+    pub fn new(root: usize, height: usize, page_size: usize) -> Self {
+        Self {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            page_size,
+            root,
+            height,
+            free_list: Vec::new(),
+            count: 0,
+            multimap: false,
+            pinned_root: false,
+            collation: None,
+            order: ASCENDING.to_owned(),
+            codec: BINCODE.to_owned(),
+            buckets: Vec::new(),
+            bloom: None,
+            indexed_field: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Encode, Decode, PartialEq, Eq)]
+pub struct LeafNode<K = i32, V = Record> {
+    pub keys: Vec<K>,
+    pub values: Vec<V>,
+    pub next: Option<usize>,
+    pub prev: Option<usize>,
+}
+
+/// The keys of a leaf and its `next` pointer, read without its values by
+/// [`Storage::read_keys`](crate::Storage::read_keys).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeafKeys<K = i32> {
+    pub keys: Vec<K>,
     pub next: Option<usize>,
 }
 
-impl LeafNode {
+impl<K> LeafKeys<K> {
+    /// The keys of `node`, read from `loc`, failing if it is not a leaf.
+    pub fn of<V>(loc: usize, node: Node<K, V>) -> Result<Self, BTreeError> {
+        match node {
+            Node::Leaf(leaf) => Ok(LeafKeys {
+                keys: leaf.keys,
+                next: leaf.next,
+            }),
+            _ => Err(BTreeError::Corrupt {
+                loc,
+                reason: "expected a leaf",
+            }),
+        }
+    }
+}
+
+/// Most keys a node may hold so that both node kinds, briefly overfilled by
+/// one entry before splitting, still encode into `capacity` bytes.
+pub fn max_keys_for<K: SerializedSize, V: SerializedSize>(capacity: usize) -> usize {
+    // A record, and its slot if the leaf is slotted
+    let leaf_entry = K::max_serialized_size() + V::max_serialized_size() + SLOT_SIZE;
+    // A separator, and the location and record count of the child after it
+    let internal_entry = K::max_serialized_size() + 2 * MAX_LOC_SIZE;
+    let available = capacity.saturating_sub(NODE_OVERHEAD + 2 * MAX_LOC_SIZE);
+    (available / leaf_entry.max(internal_entry)).saturating_sub(1)
+}
+
+/// Most free pages the header can list while still fitting in `capacity` bytes
+/// alongside a full bucket catalog and Bloom filter entry.
+pub fn max_free_list_for(capacity: usize) -> usize {
+    capacity.saturating_sub(
+        HEADER_OVERHEAD + MAX_ORDER_NAME + MAX_CODEC_NAME + CATALOG_SIZE + BLOOM_ENTRY_SIZE,
+    ) / MAX_LOC_SIZE
+}
+
+/// Bytes of Bloom filter bits a page of `capacity` bytes holds. Storages
+/// without a real bound on pages, like memory, get blocks of a file page.
+pub fn bloom_bytes_for(capacity: usize) -> usize {
+    capacity.min(PAGE_SIZE).saturating_sub(BLOOM_PAGE_OVERHEAD)
+}
+
+/// Bytes of a spilled value an overflow page of `capacity` bytes holds,
+/// bounded like [`bloom_bytes_for`].
+pub fn overflow_bytes_for(capacity: usize) -> usize {
+    capacity
+        .min(PAGE_SIZE)
+        .saturating_sub(OVERFLOW_PAGE_OVERHEAD)
+}
+
+impl<K, V> LeafNode<K, V> {
     pub fn new() -> Self {
         Self {
             keys: Vec::with_capacity(DEGREE * 2),
             values: Vec::with_capacity(DEGREE * 2),
             next: None,
+            prev: None,
         }
     }
 }
 
+impl<K, V> Default for LeafNode<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug, Clone, Encode, Decode, PartialEq, Eq)]
-pub struct InternalNode {
-    pub keys: Vec<i32>,
+pub struct InternalNode<K = i32> {
+    pub keys: Vec<K>,
     pub children: Vec<usize>,
+    /// Records under each child, in step with `children`.
+    pub counts: Vec<usize>,
+}
+
+impl<K: Ord> InternalNode<K> {
+    /// Index of the child `key` descends into. Keys equal to a separator go
+    /// to its right, so this is the number of separators `<= key`.
+    pub fn child_index(&self, key: &K) -> usize {
+        self.keys.partition_point(|separator| separator <= key)
+    }
+
+    /// Index of the first child that may hold `key` when equal keys can run
+    /// across several children, as in a multimap: the number of separators
+    /// `< key`.
+    pub fn first_child_index(&self, key: &K) -> usize {
+        self.keys.partition_point(|separator| separator < key)
+    }
+}
+
+impl<K> InternalNode<K> {
+    /// Records in the subtree under this node.
+    pub fn count(&self) -> usize {
+        self.counts.iter().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Bincode, PageCodec, Slotted};
+    use crate::file_storage::PAYLOAD_SIZE;
+    use crate::key::{Key, MAX_STRING_KEY_SIZE};
+    use crate::value::Value;
+
+    /// A leaf and an internal node each overfilled by one entry past
+    /// `max_keys_for(capacity)`, with every key, value and location at its
+    /// longest encoding.
+    fn fullest<K: Key + SerializedSize>(key: K, capacity: usize) -> [Node<K, Record>; 2] {
+        let keys = max_keys_for::<K, Record>(capacity) + 1;
+        let leaf = LeafNode {
+            keys: vec![key.clone(); keys],
+            values: vec![[i32::MIN; 7]; keys],
+            next: Some(usize::MAX),
+            prev: Some(usize::MAX),
+        };
+        let internal = InternalNode {
+            keys: vec![key; keys],
+            children: vec![usize::MAX; keys + 1],
+            counts: vec![usize::MAX; keys + 1],
+        };
+        [Node::Leaf(leaf), Node::Internal(internal)]
+    }
+
+    /// Slotted leaves always fill a file page, so they are only checked
+    /// against a file page's capacity.
+    fn assert_fits<K: Key + SerializedSize, V: Value>(nodes: &[Node<K, V>], capacity: usize) {
+        let slotted: &dyn PageCodec<K, V> = &Slotted;
+        let codecs: &[&dyn PageCodec<K, V>] = if capacity == PAYLOAD_SIZE {
+            &[&Bincode, slotted]
+        } else {
+            &[&Bincode]
+        };
+        for codec in codecs {
+            for node in nodes {
+                let size = codec.encode(node).unwrap().len();
+                assert!(
+                    size <= capacity,
+                    "{} {:?} takes {} of {} bytes",
+                    codec.name(),
+                    node.kind(),
+                    size,
+                    capacity
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn max_keys_for_leaves_room_for_one_more_entry() {
+        let leaf_entry = i32::max_serialized_size() + Record::max_serialized_size() + SLOT_SIZE;
+        let fixed = NODE_OVERHEAD + 2 * MAX_LOC_SIZE;
+        assert_eq!(max_keys_for::<i32, Record>(0), 0);
+        assert_eq!(max_keys_for::<i32, Record>(fixed), 0);
+        // One key, and the entry it is briefly overfilled by
+        assert_eq!(max_keys_for::<i32, Record>(fixed + 2 * leaf_entry - 1), 0);
+        assert_eq!(max_keys_for::<i32, Record>(fixed + 2 * leaf_entry), 1);
+        assert_eq!(max_keys_for::<i32, Record>(fixed + 11 * leaf_entry), 10);
+        assert_eq!(max_keys_for::<i32, Record>(fixed + 12 * leaf_entry - 1), 10);
+    }
+
+    #[test]
+    fn the_fullest_nodes_encode_within_capacity() {
+        for capacity in [256, 1024, 4096, PAYLOAD_SIZE] {
+            assert_fits(&fullest(i32::MIN, capacity), capacity);
+        }
+        let key = "k".repeat(MAX_STRING_KEY_SIZE);
+        for capacity in [4096, PAYLOAD_SIZE] {
+            assert!(max_keys_for::<String, Record>(capacity) > 0);
+            assert_fits(&fullest(key.clone(), capacity), capacity);
+        }
+    }
 }
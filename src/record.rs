@@ -1,2 +1,59 @@
 // 0 - key, 1 - x, 2 ..=6 - a
 pub type Record = [i32; 7];
+
+/// Upper bound on the encoded size of a value, used to size nodes to a page.
+pub trait SerializedSize {
+    fn max_serialized_size() -> usize;
+
+    /// Encoded size of this value, for types whose values vary in size.
+    fn serialized_size(&self) -> usize {
+        Self::max_serialized_size()
+    }
+
+    /// First page of the overflow chain holding the part of this value kept
+    /// outside the leaf, for types whose values can spill.
+    fn overflow(&self) -> Option<usize> {
+        None
+    }
+
+    /// Points the value at its overflow chain's new first page, after
+    /// compaction moved the chain.
+    fn set_overflow(&mut self, _loc: usize) {}
+}
+
+/// Largest varint encoding of an `i32` under bincode's standard config.
+pub const MAX_I32_SIZE: usize = 5;
+
+impl SerializedSize for Record {
+    fn max_serialized_size() -> usize {
+        7 * MAX_I32_SIZE
+    }
+}
+
+/// Comma-separated text form of a record, one line per record, as used by
+/// the REPL's CSV import and export.
+pub trait RecordText: Sized {
+    fn to_text(&self) -> String;
+    fn from_text(line: &str) -> Result<Self, String>;
+}
+
+impl RecordText for Record {
+    fn to_text(&self) -> String {
+        self.map(|field| field.to_string()).join(",")
+    }
+
+    fn from_text(line: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if fields.len() != 7 {
+            return Err(format!("expected 7 fields, found {}", fields.len()));
+        }
+
+        let mut record = [0i32; 7];
+        for (i, field) in fields.iter().enumerate() {
+            record[i] = field
+                .parse::<i32>()
+                .map_err(|_| format!("invalid integer: {}", field))?;
+        }
+        Ok(record)
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+
+use crate::btree::{BPlusTree, I32Tree, Upsert};
+use crate::error::BTreeError;
+use crate::record::Record;
+use crate::storage::Storage;
+use crate::txn::Txn;
+
+/// Position of `x` in a record, the field indexed by default.
+pub const X_FIELD: usize = 1;
+
+/// Secondary index on one field of the records, `x` unless opened with
+/// [`open_on`](SecondaryIndex::open_on), kept as a second tree: a multimap
+/// with one posting per record, keyed by the field's value, whose record
+/// starts with the primary key holding that value. However many records
+/// share a value, looking it up reads only their postings.
+pub struct SecondaryIndex<S> {
+    tree: I32Tree<S>,
+    /// Position in the record of the indexed field.
+    field: usize,
+}
+
+impl<S> SecondaryIndex<S>
+where
+    S: Storage,
+{
+    /// Opens the index held by `storage` on the field it was built for, or
+    /// a new one on `x`.
+    pub fn open(storage: S) -> Result<Self, BTreeError> {
+        let mut tree = BPlusTree::open_multimap(storage)?;
+        let field = match tree.indexed_field() {
+            Some(field) => field,
+            None => {
+                tree.set_indexed_field(X_FIELD)?;
+                X_FIELD
+            }
+        };
+        Ok(SecondaryIndex { tree, field })
+    }
+
+    /// Opens an index on `record[field]`, one of `x` or `a_1..=a_5`. The
+    /// field is recorded in the index, and opening it on another fails with
+    /// [`BTreeError::IndexFieldMismatch`].
+    pub fn open_on(storage: S, field: usize) -> Result<Self, BTreeError> {
+        assert!((1..7).contains(&field), "field {} is not x or a_i", field);
+        let mut tree = BPlusTree::open_multimap(storage)?;
+        match tree.indexed_field() {
+            Some(found) if found != field => {
+                return Err(BTreeError::IndexFieldMismatch {
+                    found,
+                    expected: field,
+                });
+            }
+            Some(_) => {}
+            None => tree.set_indexed_field(field)?,
+        }
+        Ok(SecondaryIndex { tree, field })
+    }
+
+    /// Position in the record of the indexed field.
+    pub fn field(&self) -> usize {
+        self.field
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.tree.storage
+    }
+
+    /// Opens an existing index for lookups only, on the field it was built
+    /// for; see [`BPlusTree::open_read_only`].
+    pub fn open_read_only(storage: S) -> Result<Self, BTreeError> {
+        let tree = BPlusTree::open_read_only(storage)?;
+        // Only an index never opened for writing lacks one, and it is empty
+        let field = tree.indexed_field().unwrap_or(X_FIELD);
+        Ok(SecondaryIndex { tree, field })
+    }
+
+    /// Starts a transaction on the index tree. Open the index inside it
+    /// from [`Txn::storage`].
+    pub fn begin(&mut self) -> Result<Txn<'_, S>, BTreeError> {
+        self.tree.begin()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Points the index at its bucket named `name`, or back at its main
+    /// tree for `None`, creating the bucket if needed, so it can follow the
+    /// primary tree across buckets; see [`BPlusTree::use_bucket`].
+    pub fn use_bucket(&mut self, name: Option<&str>) -> Result<(), BTreeError> {
+        if let Some(name) = name
+            && !self.tree.buckets().iter().any(|bucket| bucket.name == name)
+        {
+            self.tree.create_bucket(name)?;
+        }
+        self.tree.use_bucket(name)
+    }
+
+    /// Drops the index's bucket named `name`, if it has one.
+    pub fn drop_bucket(&mut self, name: &str) -> Result<(), BTreeError> {
+        match self.tree.drop_bucket(name) {
+            Err(BTreeError::NoSuchBucket { .. }) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Compacts the index tree; see [`BPlusTree::compact`].
+    pub fn compact(&mut self) -> Result<usize, BTreeError> {
+        self.tree.compact()
+    }
+
+    /// Replaces the postings with one for every record of `primary`,
+    /// bulk-loaded in a single pass, so it both populates a fresh index and
+    /// catches one up after `primary` changed behind its back.
+    pub fn rebuild<P: Storage>(&mut self, primary: &mut I32Tree<P>) -> Result<(), BTreeError> {
+        let mut postings = primary
+            .iter()
+            .map(|entry| entry.map(|(key, record)| (record[self.field], key)))
+            .collect::<Result<Vec<_>, _>>()?;
+        postings.sort_unstable();
+        self.tree.load_sorted(
+            postings
+                .into_iter()
+                .map(|(value, key)| (value, Self::posting(key))),
+        )
+    }
+
+    /// Inserts `record` into `primary` and updates the index to match,
+    /// returning the record it replaced, if any.
+    pub fn insert<P: Storage>(
+        &mut self,
+        primary: &mut I32Tree<P>,
+        record: Record,
+    ) -> Result<Upsert, BTreeError> {
+        let (key, value) = (record[0], record[self.field]);
+        let upsert = primary.upsert(key, record)?;
+
+        match &upsert {
+            Upsert::Updated(old) if old[self.field] == value => {}
+            Upsert::Updated(old) => {
+                self.remove_entry(old[self.field], key)?;
+                self.add_entry(value, key)?;
+            }
+            Upsert::Inserted => self.add_entry(value, key)?,
+        }
+        Ok(upsert)
+    }
+
+    /// Inserts `records` into `primary` with
+    /// [`insert_batch`](BPlusTree::insert_batch) and updates the index to
+    /// match. When a key repeats, the last record wins.
+    pub fn insert_batch<P: Storage>(
+        &mut self,
+        primary: &mut I32Tree<P>,
+        records: impl IntoIterator<Item = Record>,
+    ) -> Result<(), BTreeError> {
+        let latest: BTreeMap<i32, Record> = records
+            .into_iter()
+            .map(|record| (record[0], record))
+            .collect();
+        let old = latest
+            .keys()
+            .map(|key| primary.get(key))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        primary.insert_batch(latest.iter().map(|(&key, &record)| (key, record)))?;
+
+        for ((&key, record), old) in latest.iter().zip(old) {
+            let value = record[self.field];
+            if old.is_none_or(|old| old[self.field] != value) {
+                if let Some(old) = old {
+                    self.remove_entry(old[self.field], key)?;
+                }
+                self.add_entry(value, key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes `key` from `primary` and drops it from the index.
+    pub fn delete<P: Storage>(
+        &mut self,
+        primary: &mut I32Tree<P>,
+        key: i32,
+    ) -> Result<Option<Record>, BTreeError> {
+        let old = primary.delete(&key)?;
+        if let Some(old) = old {
+            self.remove_entry(old[self.field], key)?;
+        }
+        Ok(old)
+    }
+
+    /// Removes the record with the smallest key from `primary` and drops
+    /// it from the index.
+    pub fn pop_first<P: Storage>(
+        &mut self,
+        primary: &mut I32Tree<P>,
+    ) -> Result<Option<Record>, BTreeError> {
+        let popped = primary.pop_first()?;
+        if let Some((key, old)) = popped {
+            self.remove_entry(old[self.field], key)?;
+        }
+        Ok(popped.map(|(_, old)| old))
+    }
+
+    /// Removes the record with the largest key from `primary` and drops it
+    /// from the index.
+    pub fn pop_last<P: Storage>(
+        &mut self,
+        primary: &mut I32Tree<P>,
+    ) -> Result<Option<Record>, BTreeError> {
+        let popped = primary.pop_last()?;
+        if let Some((key, old)) = popped {
+            self.remove_entry(old[self.field], key)?;
+        }
+        Ok(popped.map(|(_, old)| old))
+    }
+
+    /// Deletes every key of `primary` in `range` and drops them from the
+    /// index, returning the deleted records in key order.
+    pub fn delete_range<P: Storage, R: RangeBounds<i32>>(
+        &mut self,
+        primary: &mut I32Tree<P>,
+        range: R,
+    ) -> Result<Vec<Record>, BTreeError> {
+        let removed = primary.delete_range(range)?;
+        for (key, record) in &removed {
+            self.remove_entry(record[self.field], *key)?;
+        }
+        Ok(removed.into_iter().map(|(_, record)| record).collect())
+    }
+
+    /// Returns every record of `primary` whose indexed field equals `value`,
+    /// in primary key order.
+    pub fn find<P: Storage>(
+        &mut self,
+        primary: &mut I32Tree<P>,
+        value: i32,
+    ) -> Result<Vec<Record>, BTreeError> {
+        let mut keys: Vec<i32> = self
+            .tree
+            .range(&value, &value)?
+            .into_iter()
+            .map(|(_, posting)| posting[0])
+            .collect();
+        keys.sort_unstable();
+        keys.iter()
+            .filter_map(|key| primary.get(key).transpose())
+            .collect()
+    }
+
+    /// Name from when only `x` could be indexed; see [`find`](Self::find).
+    pub fn find_by_x<P: Storage>(
+        &mut self,
+        primary: &mut I32Tree<P>,
+        x: i32,
+    ) -> Result<Vec<Record>, BTreeError> {
+        self.find(primary, x)
+    }
+
+    fn posting(key: i32) -> Record {
+        [key, 0, 0, 0, 0, 0, 0]
+    }
+
+    fn add_entry(&mut self, value: i32, key: i32) -> Result<(), BTreeError> {
+        self.tree.insert(value, Self::posting(key))
+    }
+
+    fn remove_entry(&mut self, value: i32, key: i32) -> Result<(), BTreeError> {
+        self.tree.delete_entry(&value, &Self::posting(key))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_storage::FileStorage;
+    use crate::memory_storage::InMemoryStorage;
+
+    #[test]
+    fn a_value_shared_by_many_records_keeps_a_posting_for_each() {
+        let mut primary: I32Tree<InMemoryStorage> =
+            BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let mut index = SecondaryIndex::open(InMemoryStorage::new()).unwrap();
+        for key in 0..300 {
+            index
+                .insert(&mut primary, [key, key % 3, 0, 0, 0, 0, 0])
+                .unwrap();
+        }
+        // Moves a record to another value, and drops a few
+        index.insert(&mut primary, [7, 2, 1, 0, 0, 0, 0]).unwrap();
+        for key in [0, 3, 150] {
+            index.delete(&mut primary, key).unwrap();
+        }
+
+        let expected: Vec<Record> = primary
+            .iter()
+            .map(|entry| entry.unwrap().1)
+            .filter(|record| record[X_FIELD] == 0)
+            .collect();
+        assert_eq!(expected.len(), 97);
+        assert_eq!(index.find(&mut primary, 0).unwrap(), expected);
+        // A posting for every record, however many share its value
+        assert_eq!(index.tree.len(), primary.len());
+
+        let found = index.find(&mut primary, 2).unwrap();
+        assert_eq!(found.len(), 101);
+        assert!(found.contains(&[7, 2, 1, 0, 0, 0, 0]));
+        assert!(index.find(&mut primary, 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_rebuild_catches_up_with_records_added_behind_its_back() {
+        let mut primary: I32Tree<InMemoryStorage> =
+            BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let mut index = SecondaryIndex::open(InMemoryStorage::new()).unwrap();
+        for key in 0..100 {
+            index
+                .insert(&mut primary, [key, key % 10, 0, 0, 0, 0, 0])
+                .unwrap();
+        }
+        // Moved and added without the index, as an import does
+        primary
+            .insert_batch((50..200).map(|key| (key, [key, key % 7, 0, 0, 0, 0, 0])))
+            .unwrap();
+
+        index.rebuild(&mut primary).unwrap();
+        assert_eq!(index.tree.len(), primary.len());
+        for value in 0..10 {
+            let expected: Vec<Record> = primary
+                .iter()
+                .map(|entry| entry.unwrap().1)
+                .filter(|record| record[X_FIELD] == value)
+                .collect();
+            assert_eq!(index.find(&mut primary, value).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn an_index_reopens_on_the_field_it_was_built_for() {
+        let path =
+            std::env::temp_dir().join(format!("btree-index-field-{}.xidx", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let mut primary: I32Tree<InMemoryStorage> =
+            BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let mut index = SecondaryIndex::open_on(FileStorage::new(path).unwrap(), 3).unwrap();
+        for key in 0..20 {
+            index
+                .insert(&mut primary, [key, 0, 0, key % 4, 0, 0, 0])
+                .unwrap();
+        }
+        drop(index);
+
+        let mut index = SecondaryIndex::open(FileStorage::new(path).unwrap()).unwrap();
+        assert_eq!(index.field(), 3);
+        assert_eq!(index.find(&mut primary, 1).unwrap().len(), 5);
+        drop(index);
+        let index =
+            SecondaryIndex::open_read_only(FileStorage::open_read_only(path).unwrap()).unwrap();
+        assert_eq!(index.field(), 3);
+        drop(index);
+        assert!(matches!(
+            SecondaryIndex::open_on(FileStorage::new(path).unwrap(), X_FIELD),
+            Err(BTreeError::IndexFieldMismatch {
+                found: 3,
+                expected: X_FIELD
+            })
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
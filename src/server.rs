@@ -0,0 +1,272 @@
+//! `btree serve`: the tree over TCP, one line-based command per request.
+//!
+//! Each client gets its own thread. Lookups take the lock shared and run
+//! side by side; inserts and deletes take it exclusively, so writes are
+//! serialized through the tree and its index. With `--resp` clients speak
+//! the Redis protocol instead; see [`resp`]. Built with the `http` feature,
+//! `--http` serves a small JSON API over HTTP instead.
+
+#[cfg(feature = "http")]
+mod http;
+mod resp;
+
+use btree::secondary_index::SecondaryIndex;
+use btree::{BPlusTree, FileStorage, I32Tree, Record, Upsert};
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    thread,
+};
+
+/// The database as served: the tree and its secondary index, which every
+/// write keeps in step.
+struct Database {
+    tree: I32Tree<FileStorage>,
+    index: SecondaryIndex<FileStorage>,
+}
+
+type Shared = Arc<RwLock<Database>>;
+
+/// The wire protocol clients speak.
+#[derive(Clone, Copy)]
+pub enum Protocol {
+    /// One command per line, answered with one or more lines.
+    Lines,
+    /// The Redis serialization protocol.
+    Resp,
+    /// JSON over HTTP.
+    #[cfg(feature = "http")]
+    Http,
+}
+
+/// Opens `filename` and its index, then serves clients on `port` of the
+/// loopback interface until the process is killed.
+pub fn serve(filename: &str, port: u16, protocol: Protocol) -> Result<(), String> {
+    let tree = FileStorage::new(filename)
+        .and_then(BPlusTree::open)
+        .map_err(|err| format!("Cannot open {}: {}", filename, err))?;
+    let index_filename = format!("{}.xidx", filename);
+    let index = FileStorage::new(&index_filename)
+        .and_then(SecondaryIndex::open)
+        .map_err(|err| format!("Cannot open {}: {}", index_filename, err))?;
+    let mut database = Database { tree, index };
+    if database.index.is_empty() && !database.tree.is_empty() {
+        database
+            .index
+            .rebuild(&mut database.tree)
+            .map_err(|err| format!("Cannot build {}: {}", index_filename, err))?;
+    }
+
+    let database: Shared = Arc::new(RwLock::new(database));
+    match protocol {
+        Protocol::Lines => serve_tcp(filename, port, database, handle_client),
+        Protocol::Resp => serve_tcp(filename, port, database, resp::handle_client),
+        #[cfg(feature = "http")]
+        Protocol::Http => http::serve(filename, port, database),
+    }
+}
+
+/// Accepts connections on `port`, running `handle` for each on a thread
+/// of its own.
+fn serve_tcp(
+    filename: &str,
+    port: u16,
+    database: Shared,
+    handle: fn(TcpStream, &Shared) -> io::Result<()>,
+) -> Result<(), String> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .map_err(|err| format!("Cannot listen on port {}: {}", port, err))?;
+    println!("Serving {} on 127.0.0.1:{}", filename, port);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Cannot accept a connection: {}", err);
+                continue;
+            }
+        };
+        let database = Arc::clone(&database);
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            if let Err(err) = handle(stream, &database) {
+                match peer {
+                    Ok(peer) => eprintln!("Connection from {} failed: {}", peer, err),
+                    Err(_) => eprintln!("Connection failed: {}", err),
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Answers commands from one client until it sends `QUIT` or hangs up.
+fn handle_client(stream: TcpStream, database: &Shared) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    for line in reader.lines() {
+        let line = line?;
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let Some((command, args)) = parts.split_first() else {
+            continue;
+        };
+        let command = command.to_ascii_uppercase();
+        if command == "QUIT" {
+            writeln!(writer, "BYE")?;
+            break;
+        }
+
+        match run(database, &command, args) {
+            Ok(reply) => {
+                for line in reply {
+                    writeln!(writer, "{}", line)?;
+                }
+            }
+            Err(err) => writeln!(writer, "ERROR {}", err)?,
+        }
+        writer.flush()?;
+    }
+    writer.flush()
+}
+
+/// Runs one command, returning the lines of its reply.
+fn run(database: &Shared, command: &str, args: &[&str]) -> Result<Vec<String>, String> {
+    match (command, args) {
+        ("INSERT", record) => {
+            let record: Record = parse_ints(record)?
+                .try_into()
+                .map_err(|_| "usage: INSERT k x a_1 a_2 a_3 a_4 a_5".to_string())?;
+            let mut database = write(database);
+            let Database { tree, index } = &mut *database;
+            Ok(vec![
+                match index.insert(tree, record).map_err(|err| err.to_string())? {
+                    Upsert::Inserted => "INSERTED".to_string(),
+                    Upsert::Updated(old) => format!("UPDATED {}", format_record(&old)),
+                },
+            ])
+        }
+        ("DELETE", [key]) => {
+            let key = parse_int(key)?;
+            let mut database = write(database);
+            let Database { tree, index } = &mut *database;
+            Ok(vec![
+                match index.delete(tree, key).map_err(|err| err.to_string())? {
+                    Some(old) => format!("DELETED {}", format_record(&old)),
+                    None => "NOT_FOUND".to_string(),
+                },
+            ])
+        }
+        ("FIND", [key]) => {
+            let key = parse_int(key)?;
+            let record = read(database)
+                .tree
+                .get_shared(&key)
+                .map_err(|err| err.to_string())?;
+            Ok(vec![match record {
+                Some(record) => format!("FOUND {}", format_record(&record)),
+                None => "NOT_FOUND".to_string(),
+            }])
+        }
+        ("RANGE", [lo, hi]) => {
+            let (lo, hi) = (parse_int(lo)?, parse_int(hi)?);
+            let entries = read(database)
+                .tree
+                .range_shared(&lo, &hi)
+                .map_err(|err| err.to_string())?;
+            let mut reply: Vec<String> = entries
+                .iter()
+                .map(|(_, record)| format!("FOUND {}", format_record(record)))
+                .collect();
+            reply.push("END".to_string());
+            Ok(reply)
+        }
+        ("DELETE" | "FIND", _) => Err(format!("usage: {} k", command)),
+        ("RANGE", _) => Err("usage: RANGE lo hi".into()),
+        _ => Err(format!("unknown command {}", command)),
+    }
+}
+
+fn read(database: &Shared) -> RwLockReadGuard<'_, Database> {
+    database
+        .read()
+        .expect("a writer panicked while holding the database")
+}
+
+fn write(database: &Shared) -> RwLockWriteGuard<'_, Database> {
+    database
+        .write()
+        .expect("a writer panicked while holding the database")
+}
+
+fn parse_int(arg: &str) -> Result<i32, String> {
+    arg.parse().map_err(|_| format!("invalid integer {}", arg))
+}
+
+fn parse_ints(args: &[&str]) -> Result<Vec<i32>, String> {
+    args.iter().map(|arg| parse_int(arg)).collect()
+}
+
+fn format_record(record: &Record) -> String {
+    record
+        .iter()
+        .map(i32::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// An empty database in a fresh file, and the path to remove it by.
+    pub(super) fn temp_database(name: &str) -> (Shared, String) {
+        let path = std::env::temp_dir().join(format!("btree-{}-{}.db", name, std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.xidx", path));
+        let database = Database {
+            tree: BPlusTree::open(FileStorage::new(&path).unwrap()).unwrap(),
+            index: SecondaryIndex::open(FileStorage::new(&format!("{}.xidx", path)).unwrap())
+                .unwrap(),
+        };
+        (Arc::new(RwLock::new(database)), path)
+    }
+
+    pub(super) fn remove_database(database: Shared, path: &str) {
+        drop(database);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(format!("{}.xidx", path)).unwrap();
+    }
+
+    #[test]
+    fn commands_answer_in_lines() {
+        let (database, path) = temp_database("serve-lines");
+        let run = |line: &str| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            run(&database, parts[0], &parts[1..])
+        };
+        assert_eq!(run("INSERT 1 2 3 4 5 6 7"), Ok(vec!["INSERTED".into()]));
+        assert_eq!(
+            run("INSERT 1 9 3 4 5 6 7"),
+            Ok(vec!["UPDATED 1 2 3 4 5 6 7".into()])
+        );
+        run("INSERT 2 0 0 0 0 0 0").unwrap();
+        assert_eq!(run("FIND 1"), Ok(vec!["FOUND 1 9 3 4 5 6 7".into()]));
+        assert_eq!(
+            run("RANGE 0 5"),
+            Ok(vec![
+                "FOUND 1 9 3 4 5 6 7".into(),
+                "FOUND 2 0 0 0 0 0 0".into(),
+                "END".into()
+            ])
+        );
+        assert_eq!(run("DELETE 2"), Ok(vec!["DELETED 2 0 0 0 0 0 0".into()]));
+        assert_eq!(run("FIND 2"), Ok(vec!["NOT_FOUND".into()]));
+        assert_eq!(run("FIND x"), Err("invalid integer x".into()));
+        assert_eq!(run("RANGE 1"), Err("usage: RANGE lo hi".into()));
+        assert_eq!(run("FROB"), Err("unknown command FROB".into()));
+        remove_database(database, &path);
+    }
+}
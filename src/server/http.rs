@@ -0,0 +1,222 @@
+//! `btree serve --http`: a small JSON API over HTTP, for scripts and
+//! browsers.
+//!
+//! - `GET /records/{key}` - the record, or 404.
+//! - `GET /records?from=&to=` - records with keys in the range, in key
+//!   order; either bound may be left out.
+//! - `PUT /records` - inserts or replaces the record in the body, answering
+//!   201 if it is new and 200 along with the record it replaced otherwise.
+//!
+//! Records are objects such as `{"key": 1, "x": 2, "a": [3, 4, 5, 6, 7]}`
+//! and failures are `{"error": message}`. Each request runs on a thread of
+//! its own, under the same locking as the line protocol.
+
+use super::{Database, Shared, read, write};
+use btree::{Record, Upsert};
+use serde_json::{Value, json};
+use std::{io::Read, sync::Arc, thread};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Largest request body read, well above any single record.
+const MAX_BODY_LEN: u64 = 64 * 1024;
+
+/// A response status and JSON body.
+type Reply = (u16, Value);
+
+pub(super) fn serve(filename: &str, port: u16, database: Shared) -> Result<(), String> {
+    let server = Server::http(("127.0.0.1", port))
+        .map_err(|err| format!("Cannot listen on port {}: {}", port, err))?;
+    println!("Serving {} over HTTP on 127.0.0.1:{}", filename, port);
+
+    for request in server.incoming_requests() {
+        let database = Arc::clone(&database);
+        thread::spawn(move || handle_request(request, &database));
+    }
+    Ok(())
+}
+
+fn handle_request(mut request: Request, database: &Shared) {
+    let (status, body) = route(&mut request, database).unwrap_or_else(|err| err);
+    let content_type =
+        Header::from_bytes("Content-Type", "application/json").expect("a valid header");
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type);
+    let url = request.url().to_string();
+    if let Err(err) = request.respond(response) {
+        eprintln!("Cannot answer {}: {}", url, err);
+    }
+}
+
+/// Dispatches on the method and path, answering errors as `Err` so they
+/// can be returned with `?`.
+fn route(request: &mut Request, database: &Shared) -> Result<Reply, Reply> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match (request.method(), segments.as_slice()) {
+        (Method::Get, ["records", key]) => {
+            let key = parse_int("key", key)?;
+            let record = read(database)
+                .tree
+                .get_shared(&key)
+                .map_err(internal_error)?;
+            match record {
+                Some(record) => Ok((200, record_json(&record))),
+                None => Err(error(404, format!("no record with key {}", key))),
+            }
+        }
+        (Method::Get, ["records"]) => {
+            let (mut lo, mut hi) = (i32::MIN, i32::MAX);
+            for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+                match name {
+                    "from" => lo = parse_int(name, value)?,
+                    "to" => hi = parse_int(name, value)?,
+                    _ => return Err(error(400, format!("unknown parameter {}", name))),
+                }
+            }
+            let entries = read(database)
+                .tree
+                .range_shared(&lo, &hi)
+                .map_err(internal_error)?;
+            let records = entries
+                .iter()
+                .map(|(_, record)| record_json(record))
+                .collect();
+            Ok((200, Value::Array(records)))
+        }
+        (Method::Put, ["records"]) => {
+            let mut body = String::new();
+            request
+                .as_reader()
+                .take(MAX_BODY_LEN)
+                .read_to_string(&mut body)
+                .map_err(|err| error(400, format!("cannot read the body: {}", err)))?;
+            let record = parse_record(&body)?;
+            let mut database = write(database);
+            let Database { tree, index } = &mut *database;
+            match index.insert(tree, record).map_err(internal_error)? {
+                Upsert::Inserted => Ok((201, record_json(&record))),
+                Upsert::Updated(old) => Ok((
+                    200,
+                    json!({ "record": record_json(&record), "replaced": record_json(&old) }),
+                )),
+            }
+        }
+        (_, ["records"] | ["records", _]) => Err(error(405, "method not allowed")),
+        _ => Err(error(404, format!("no such path {}", path))),
+    }
+}
+
+fn record_json(record: &Record) -> Value {
+    json!({ "key": record[0], "x": record[1], "a": &record[2..] })
+}
+
+/// The record described by a JSON body, as written by [`record_json`].
+fn parse_record(body: &str) -> Result<Record, Reply> {
+    let bad = || {
+        error(
+            400,
+            r#"expected {"key": k, "x": x, "a": [a1, a2, a3, a4, a5]}"#,
+        )
+    };
+    let value: Value = serde_json::from_str(body).map_err(|_| bad())?;
+    let field = |value: &Value| value.as_i64().and_then(|n| i32::try_from(n).ok());
+    let mut record = [0; 7];
+    record[0] = value.get("key").and_then(field).ok_or_else(bad)?;
+    record[1] = value.get("x").and_then(field).ok_or_else(bad)?;
+    let a = value.get("a").and_then(Value::as_array).ok_or_else(bad)?;
+    if a.len() != 5 {
+        return Err(bad());
+    }
+    for (slot, value) in record[2..].iter_mut().zip(a) {
+        *slot = field(value).ok_or_else(bad)?;
+    }
+    Ok(record)
+}
+
+fn parse_int(name: &str, value: &str) -> Result<i32, Reply> {
+    value
+        .parse()
+        .map_err(|_| error(400, format!("{} must be an integer, got {}", name, value)))
+}
+
+fn error(status: u16, message: impl Into<String>) -> Reply {
+    (status, json!({ "error": message.into() }))
+}
+
+fn internal_error(err: impl ToString) -> Reply {
+    error(500, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{remove_database, temp_database};
+    use super::*;
+    use tiny_http::TestRequest;
+
+    fn call(database: &Shared, method: Method, path: &str, body: &'static str) -> Reply {
+        let mut request: Request = TestRequest::new()
+            .with_method(method)
+            .with_path(path)
+            .with_body(body)
+            .into();
+        route(&mut request, database).unwrap_or_else(|err| err)
+    }
+
+    #[test]
+    fn records_are_put_and_got_as_json() {
+        let (database, path) = temp_database("serve-http");
+        let record = r#"{"key": 1, "x": 2, "a": [3, 4, 5, 6, 7]}"#;
+        let (status, body) = call(&database, Method::Put, "/records", record);
+        assert_eq!((status, &body), (201, &record_json(&[1, 2, 3, 4, 5, 6, 7])));
+        let (status, body) = call(
+            &database,
+            Method::Put,
+            "/records",
+            r#"{"key": 1, "x": 9, "a": [3, 4, 5, 6, 7]}"#,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body["replaced"], record_json(&[1, 2, 3, 4, 5, 6, 7]));
+        call(
+            &database,
+            Method::Put,
+            "/records",
+            r#"{"key": 5, "x": 0, "a": [0, 0, 0, 0, 0]}"#,
+        );
+
+        let (status, body) = call(&database, Method::Get, "/records/1", "");
+        assert_eq!((status, body["x"].as_i64()), (200, Some(9)));
+        assert_eq!(call(&database, Method::Get, "/records/2", "").0, 404);
+        let (status, body) = call(&database, Method::Get, "/records?from=2", "");
+        assert_eq!(
+            (status, body),
+            (200, json!([record_json(&[5, 0, 0, 0, 0, 0, 0])]))
+        );
+        remove_database(database, &path);
+    }
+
+    #[test]
+    fn bad_requests_are_refused() {
+        let (database, path) = temp_database("serve-http-bad");
+        let refused = [
+            (
+                Method::Put,
+                "/records",
+                r#"{"key": 1, "x": 2, "a": [3]}"#,
+                400,
+            ),
+            (Method::Put, "/records", "not json", 400),
+            (Method::Get, "/records/x", "", 400),
+            (Method::Get, "/records?limit=3", "", 400),
+            (Method::Delete, "/records/1", "", 405),
+            (Method::Get, "/tables", "", 404),
+        ];
+        for (method, url, body, expected) in refused {
+            let (status, body) = call(&database, method, url, body);
+            assert_eq!(status, expected, "{}", url);
+            assert!(body["error"].is_string());
+        }
+        remove_database(database, &path);
+    }
+}
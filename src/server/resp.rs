@@ -0,0 +1,380 @@
+//! `btree serve --resp`: the same database spoken to in the Redis
+//! serialization protocol, so `redis-cli` and Redis client libraries can
+//! be pointed at it.
+//!
+//! Keys are integers and values are the other six fields of a record as
+//! space-separated integers: `SET 7 "1 2 3 4 5 6"` stores the record
+//! `7 1 2 3 4 5 6`, and `GET 7` answers `"1 2 3 4 5 6"`. `SCAN` cursors
+//! encode the key to resume from, so a scan sees every key present for
+//! its whole duration.
+
+use super::{Database, Shared, read, write};
+use btree::Record;
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::TcpStream,
+};
+
+/// Keys `SCAN` returns per call unless given `COUNT`, as in Redis.
+const SCAN_COUNT: usize = 10;
+
+/// Limits on a request, so a bad length cannot make us allocate without
+/// bound.
+const MAX_ARGS: usize = 1024;
+const MAX_BULK_LEN: usize = 1 << 20;
+
+/// A reply, in the RESP2 types Redis clients expect.
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+/// Answers requests from one client until it sends `QUIT` or hangs up.
+pub(super) fn handle_client(stream: TcpStream, database: &Shared) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let args = match read_request(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                // Like Redis, give up on a client whose framing is lost.
+                write_reply(
+                    &mut writer,
+                    &Reply::Error(format!("ERR Protocol error: {}", err)),
+                )?;
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        let Some((command, args)) = args.split_first() else {
+            continue;
+        };
+        let command = command.to_ascii_uppercase();
+        if command == "QUIT" {
+            write_reply(&mut writer, &Reply::Status("OK"))?;
+            break;
+        }
+        write_reply(&mut writer, &run(database, &command, args))?;
+        writer.flush()?;
+    }
+    writer.flush()
+}
+
+/// Reads one request: an array of bulk strings, or an inline command on a
+/// line of its own as typed into telnet. `None` once the client hangs up.
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Vec<String>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix('*') else {
+        return Ok(Some(line.split_whitespace().map(String::from).collect()));
+    };
+    let count = parse_len(count, MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| invalid("unexpected end of request"))?;
+        let len = line
+            .strip_prefix('$')
+            .ok_or_else(|| invalid(format!("expected '$', got '{}'", line)))?;
+        let len = parse_len(len, MAX_BULK_LEN)?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(String::from_utf8(arg).map_err(|_| invalid("argument is not UTF-8"))?);
+    }
+    Ok(Some(args))
+}
+
+/// One line without its line ending, or `None` at the end of the stream.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    Ok(Some(line))
+}
+
+fn parse_len(len: &str, max: usize) -> io::Result<usize> {
+    match len.parse() {
+        Ok(len) if len <= max => Ok(len),
+        _ => Err(invalid(format!("invalid length '{}'", len))),
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn write_reply(writer: &mut impl Write, reply: &Reply) -> io::Result<()> {
+    match reply {
+        Reply::Status(status) => write!(writer, "+{}\r\n", status),
+        Reply::Error(message) => write!(writer, "-{}\r\n", message),
+        Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+        Reply::Bulk(None) => write!(writer, "$-1\r\n"),
+        Reply::Bulk(Some(value)) => write!(writer, "${}\r\n{}\r\n", value.len(), value),
+        Reply::Array(items) => {
+            write!(writer, "*{}\r\n", items.len())?;
+            items.iter().try_for_each(|item| write_reply(writer, item))
+        }
+    }
+}
+
+/// Runs one command, turning failures into error replies.
+fn run(database: &Shared, command: &str, args: &[String]) -> Reply {
+    execute(database, command, args).unwrap_or_else(Reply::Error)
+}
+
+fn execute(database: &Shared, command: &str, args: &[String]) -> Result<Reply, String> {
+    match (command, args) {
+        ("PING", []) => Ok(Reply::Status("PONG")),
+        ("PING", [message]) => Ok(Reply::Bulk(Some(message.clone()))),
+        // Clients ask for the command table on connect; an empty one makes
+        // them fall back to sending commands as typed.
+        ("COMMAND", _) => Ok(Reply::Array(Vec::new())),
+        ("GET", [key]) => {
+            let key = parse_key(key)?;
+            let record = read(database)
+                .tree
+                .get_shared(&key)
+                .map_err(|err| format!("ERR {}", err))?;
+            Ok(Reply::Bulk(record.map(|record| format_value(&record))))
+        }
+        ("SET", [key, value]) => {
+            let key = parse_key(key)?;
+            let record = parse_value(key, value)?;
+            let mut database = write(database);
+            let Database { tree, index } = &mut *database;
+            index
+                .insert(tree, record)
+                .map_err(|err| format!("ERR {}", err))?;
+            Ok(Reply::Status("OK"))
+        }
+        ("SET", [_, _, ..]) => Err("ERR SET options are not supported".into()),
+        ("DEL", [_, ..]) => {
+            let mut database = write(database);
+            let Database { tree, index } = &mut *database;
+            let mut deleted = 0;
+            // A key that is not an integer cannot be stored, so it is
+            // simply not there to delete.
+            for key in args.iter().filter_map(|key| key.parse().ok()) {
+                if index
+                    .delete(tree, key)
+                    .map_err(|err| format!("ERR {}", err))?
+                    .is_some()
+                {
+                    deleted += 1;
+                }
+            }
+            Ok(Reply::Integer(deleted))
+        }
+        ("EXISTS", [_, ..]) => {
+            let database = read(database);
+            let mut found = 0;
+            for key in args.iter().filter_map(|key| key.parse().ok()) {
+                if database
+                    .tree
+                    .get_shared(&key)
+                    .map_err(|err| format!("ERR {}", err))?
+                    .is_some()
+                {
+                    found += 1;
+                }
+            }
+            Ok(Reply::Integer(found))
+        }
+        ("DBSIZE", []) => Ok(Reply::Integer(read(database).tree.len() as i64)),
+        ("SCAN", [cursor, options @ ..]) => scan(database, cursor, options),
+        ("PING" | "GET" | "SET" | "DEL" | "EXISTS" | "DBSIZE" | "SCAN", _) => Err(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command.to_ascii_lowercase()
+        )),
+        _ => Err(format!("ERR unknown command '{}'", command)),
+    }
+}
+
+/// `SCAN cursor [MATCH *] [COUNT n]`: up to `n` keys from the cursor on,
+/// along with the cursor to continue from, which is 0 once the scan is
+/// done. Cursor `c` stands for key `i32::MIN + c - 1`, so 0 starts over.
+fn scan(database: &Shared, cursor: &str, options: &[String]) -> Result<Reply, String> {
+    let cursor: u64 = cursor
+        .parse()
+        .ok()
+        .filter(|&cursor| cursor <= 1 << 32)
+        .ok_or("ERR invalid cursor")?;
+    let from = (i64::from(i32::MIN) + cursor.saturating_sub(1) as i64) as i32;
+
+    let mut count = SCAN_COUNT;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or("ERR syntax error")?;
+        match option.to_ascii_uppercase().as_str() {
+            "COUNT" => {
+                count = value
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or("ERR value is out of range, must be positive")?
+            }
+            "MATCH" if value == "*" => {}
+            "MATCH" => return Err("ERR only MATCH * is supported".into()),
+            _ => return Err("ERR syntax error".into()),
+        }
+    }
+
+    let entries = read(database)
+        .tree
+        .range_from_shared(&from, count)
+        .map_err(|err| format!("ERR {}", err))?;
+    let next = match entries.last() {
+        Some(&(last, _)) if entries.len() == count && last < i32::MAX => {
+            (i64::from(last) - i64::from(i32::MIN) + 2).to_string()
+        }
+        _ => "0".to_string(),
+    };
+    let keys = entries
+        .iter()
+        .map(|(key, _)| Reply::Bulk(Some(key.to_string())))
+        .collect();
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next)),
+        Reply::Array(keys),
+    ]))
+}
+
+fn parse_key(key: &str) -> Result<i32, String> {
+    key.parse()
+        .map_err(|_| "ERR key is not an integer or out of range".to_string())
+}
+
+/// The record stored by `SET key value`, from the six integers of `value`.
+fn parse_value(key: i32, value: &str) -> Result<Record, String> {
+    let fields: Vec<i32> = std::iter::once(Ok(key))
+        .chain(value.split_whitespace().map(str::parse))
+        .collect::<Result<_, _>>()
+        .map_err(|_| "ERR value must be six integers".to_string())?;
+    fields
+        .try_into()
+        .map_err(|_| "ERR value must be six integers".to_string())
+}
+
+/// The value `GET` answers: the record without its key.
+fn format_value(record: &Record) -> String {
+    record[1..]
+        .iter()
+        .map(i32::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{remove_database, temp_database};
+    use super::*;
+
+    fn reply_bytes(reply: &Reply) -> String {
+        let mut out = Vec::new();
+        write_reply(&mut out, reply).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn command(database: &Shared, args: &[&str]) -> String {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        reply_bytes(&run(database, &args[0].to_ascii_uppercase(), &args[1..]))
+    }
+
+    #[test]
+    fn requests_are_read_framed_or_inline() {
+        let mut input = io::Cursor::new(
+            "*3\r\n$3\r\nSET\r\n$1\r\n7\r\n$11\r\n1 2 3 4 5 6\r\nGET 7\r\n".as_bytes(),
+        );
+        assert_eq!(
+            read_request(&mut input).unwrap(),
+            Some(vec!["SET".into(), "7".into(), "1 2 3 4 5 6".into()])
+        );
+        assert_eq!(
+            read_request(&mut input).unwrap(),
+            Some(vec!["GET".into(), "7".into()])
+        );
+        assert_eq!(read_request(&mut input).unwrap(), None);
+
+        for bad in ["*1\r\n+GET\r\n", "*1\r\n$3\r\nGETX\r\n", "*9999999\r\n"] {
+            let err = read_request(&mut io::Cursor::new(bad.as_bytes())).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn replies_are_encoded_as_resp2() {
+        let reply = Reply::Array(vec![
+            Reply::Status("OK"),
+            Reply::Error("ERR no".into()),
+            Reply::Integer(-3),
+            Reply::Bulk(None),
+            Reply::Bulk(Some("1 2".into())),
+        ]);
+        assert_eq!(
+            reply_bytes(&reply),
+            "*5\r\n+OK\r\n-ERR no\r\n:-3\r\n$-1\r\n$3\r\n1 2\r\n"
+        );
+    }
+
+    #[test]
+    fn commands_read_and_write_the_database() {
+        let (database, path) = temp_database("serve-resp");
+        assert_eq!(command(&database, &["ping"]), "+PONG\r\n");
+        assert_eq!(command(&database, &["SET", "7", "1 2 3 4 5 6"]), "+OK\r\n");
+        assert_eq!(command(&database, &["GET", "7"]), "$11\r\n1 2 3 4 5 6\r\n");
+        assert_eq!(command(&database, &["GET", "8"]), "$-1\r\n");
+        assert_eq!(
+            command(&database, &["SET", "8", "1 2"]),
+            "-ERR value must be six integers\r\n"
+        );
+        assert_eq!(command(&database, &["EXISTS", "7", "8", "x"]), ":1\r\n");
+        assert_eq!(command(&database, &["DBSIZE"]), ":1\r\n");
+        assert_eq!(command(&database, &["DEL", "7", "7", "x"]), ":1\r\n");
+        assert_eq!(
+            command(&database, &["GET"]),
+            "-ERR wrong number of arguments for 'get' command\r\n"
+        );
+        remove_database(database, &path);
+    }
+
+    #[test]
+    fn a_scan_visits_every_key_once() {
+        let (database, path) = temp_database("serve-scan");
+        for key in [i32::MIN, -5, 0, 3, 9, i32::MAX] {
+            command(&database, &["SET", &key.to_string(), "0 0 0 0 0 0"]);
+        }
+        let mut cursor = "0".to_string();
+        let mut keys = Vec::new();
+        loop {
+            let options = ["COUNT".to_string(), "4".to_string()];
+            let Reply::Array(reply) = scan(&database, &cursor, &options).unwrap() else {
+                panic!("SCAN answers an array");
+            };
+            let [Reply::Bulk(Some(next)), Reply::Array(page)] = reply.as_slice() else {
+                panic!("SCAN answers a cursor and keys");
+            };
+            keys.extend(page.iter().map(|key| match key {
+                Reply::Bulk(Some(key)) => key.parse::<i32>().unwrap(),
+                _ => panic!("keys are bulk strings"),
+            }));
+            cursor = next.clone();
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(keys, [i32::MIN, -5, 0, 3, 9, i32::MAX]);
+        assert!(scan(&database, "0", &["MATCH".into(), "a*".into()]).is_err());
+        remove_database(database, &path);
+    }
+}
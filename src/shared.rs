@@ -0,0 +1,175 @@
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::allocator::{Allocator, FreeListAllocator};
+use crate::btree::BPlusTree;
+use crate::error::BTreeError;
+use crate::key::Key;
+use crate::record::Record;
+use crate::storage::SharedStorage;
+use crate::value::Value;
+
+/// A tree that can be shared between threads. Lookups take the lock shared
+/// and run side by side; inserts and deletes take it exclusively, so
+/// writers are serialized and never overlap a read.
+pub struct SharedBPlusTree<S, K = i32, V = Record, A = FreeListAllocator> {
+    tree: RwLock<BPlusTree<S, K, V, A>>,
+}
+
+impl<S, K, V, A> SharedBPlusTree<S, K, V, A>
+where
+    S: SharedStorage<K, V>,
+    K: Key,
+    V: Value,
+    A: Allocator,
+{
+    pub fn new(tree: BPlusTree<S, K, V, A>) -> Self {
+        Self {
+            tree: RwLock::new(tree),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, BTreeError> {
+        self.read().get_shared(key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool, BTreeError> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Entries with keys between `lo` and `hi` inclusive, in key order.
+    pub fn range(&self, lo: &K, hi: &K) -> Result<Vec<(K, V)>, BTreeError> {
+        self.read().range_shared(lo, hi)
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    /// Copies the tree to a new file at `path` while holding the lock
+    /// shared, so lookups carry on and writers wait until the copy is done;
+    /// see [`BPlusTree::backup`].
+    pub fn backup(&self, path: &str) -> Result<usize, BTreeError> {
+        self.read().backup_shared(path)
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Result<(), BTreeError> {
+        self.write().insert(key, value)
+    }
+
+    pub fn delete(&self, key: &K) -> Result<Option<V>, BTreeError> {
+        self.write().delete(key)
+    }
+
+    /// Exclusive access to the tree, for everything without a shared
+    /// counterpart here. Readers wait until the guard is dropped.
+    pub fn write(&self) -> RwLockWriteGuard<'_, BPlusTree<S, K, V, A>> {
+        self.tree
+            .write()
+            .expect("a writer panicked while holding the tree")
+    }
+
+    pub fn into_inner(self) -> BPlusTree<S, K, V, A> {
+        self.tree
+            .into_inner()
+            .expect("a writer panicked while holding the tree")
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, BPlusTree<S, K, V, A>> {
+        self.tree
+            .read()
+            .expect("a writer panicked while holding the tree")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use super::*;
+    use crate::file_storage::FileStorage;
+    use crate::memory_storage::InMemoryStorage;
+
+    fn assert_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn readers_see_a_consistent_tree_while_a_writer_inserts() {
+        assert_sync::<SharedBPlusTree<InMemoryStorage>>();
+
+        let tree: SharedBPlusTree<InMemoryStorage> =
+            SharedBPlusTree::new(BPlusTree::open(InMemoryStorage::new()).unwrap());
+        let done = AtomicBool::new(false);
+        let keys = 2000;
+        thread::scope(|scope| {
+            for reader in 0..4 {
+                let (tree, done) = (&tree, &done);
+                scope.spawn(move || {
+                    let mut len = 0;
+                    let mut key = reader;
+                    while !done.load(Ordering::Acquire) {
+                        // Keys go in ascending, so the tree always holds a
+                        // prefix of them, and never fewer than last time
+                        let now = tree.len();
+                        assert!(now >= len);
+                        len = now;
+                        let prefix = tree.range(&0, &keys).unwrap();
+                        assert!(prefix.len() >= len);
+                        for (i, (key, record)) in prefix.into_iter().enumerate() {
+                            assert_eq!((key, record), (i as i32, [key; 7]));
+                        }
+                        key = (key + 37) % keys;
+                        if let Some(record) = tree.get(&key).unwrap() {
+                            assert_eq!(record, [key; 7]);
+                        }
+                    }
+                });
+            }
+            scope.spawn(|| {
+                for key in 0..keys {
+                    tree.insert(key, [key; 7]).unwrap();
+                }
+                done.store(true, Ordering::Release);
+            });
+        });
+
+        let mut tree = tree.into_inner();
+        assert_eq!(tree.len(), keys as usize);
+        tree.validate().unwrap();
+    }
+
+    #[test]
+    fn a_backup_runs_alongside_lookups() {
+        let path = std::env::temp_dir().join(format!("btree-shared-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let copy = format!("{}.bak", path);
+        let _ = std::fs::remove_file(path);
+        let tree: SharedBPlusTree<FileStorage> =
+            SharedBPlusTree::new(BPlusTree::open(FileStorage::new(path).unwrap()).unwrap());
+        for key in 0..500 {
+            tree.insert(key, [key; 7]).unwrap();
+        }
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for key in 0..500 {
+                    assert_eq!(tree.get(&key).unwrap(), Some([key; 7]));
+                }
+            });
+            assert!(tree.backup(&copy).unwrap() > 0);
+        });
+        tree.insert(500, [500; 7]).unwrap();
+        drop(tree);
+
+        let mut backup: BPlusTree<FileStorage> =
+            BPlusTree::open(FileStorage::new(&copy).unwrap()).unwrap();
+        backup.validate().unwrap();
+        assert_eq!(backup.len(), 500);
+        assert_eq!(backup.get(&499).unwrap(), Some([499; 7]));
+        drop(backup);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(&copy).unwrap();
+    }
+}
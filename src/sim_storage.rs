@@ -0,0 +1,492 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::BTreeError;
+use crate::node::{LeafKeys, Node};
+use crate::record::Record;
+use crate::storage::Storage;
+
+/// Environment variable naming a single seed for [`SimRunner`] to run.
+pub const SEED_VAR: &str = "BTREE_SIM_SEED";
+
+/// How a [`SimStorage`] behaves, all of it drawn from its seed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimConfig {
+    /// Time each page read, page write and flush takes, before jitter.
+    pub read_latency: Duration,
+    pub write_latency: Duration,
+    pub sync_latency: Duration,
+    /// Most extra time added to each latency, picked at random.
+    pub jitter: Duration,
+    /// Whether latencies are slept through rather than only added to
+    /// [`elapsed`](SimStorage::elapsed).
+    pub sleep: bool,
+    /// Chance of crashing at each write, flush or truncate, from 0 to 1.
+    pub crash_chance: f64,
+    /// Whether buffered writes reach the disk in a random order rather than
+    /// the order they were made in, so a crash can keep a later write and
+    /// lose an earlier one.
+    pub reorder: bool,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            read_latency: Duration::ZERO,
+            write_latency: Duration::ZERO,
+            sync_latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            sleep: false,
+            crash_chance: 0.0,
+            reorder: true,
+        }
+    }
+}
+
+/// Wraps a storage, standing for the disk, with a write buffer standing for
+/// the OS page cache, driven by a seeded random generator so a run can be
+/// repeated exactly. Writes stay in the buffer until a flush hands them to
+/// the disk. At each write there is a chance of a crash, after which a
+/// random subset of the buffered writes has reached the disk and every
+/// call fails; [`into_inner`](Self::into_inner) then gives the disk to
+/// reopen, as recovery after a power loss would find it.
+///
+/// A [`write_atomic`](Storage::write_atomic) goes to the disk's own, which
+/// a crash lets happen entirely or not at all.
+pub struct SimStorage<S, K = i32, V = Record> {
+    inner: S,
+    config: SimConfig,
+    rng: SimRng,
+    /// Writes not yet handed to the disk, the latest for each page.
+    buffered: BTreeMap<usize, Node<K, V>>,
+    /// Pages written since the buffer was last drained, in order.
+    order: Vec<usize>,
+    elapsed: Duration,
+    crashed: bool,
+    /// Writes, flushes and truncates made, for telling where a crash hit.
+    operations: usize,
+}
+
+impl<S, K, V> fmt::Debug for SimStorage<S, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimStorage")
+            .field("config", &self.config)
+            .field("buffered", &self.buffered.len())
+            .field("elapsed", &self.elapsed)
+            .field("crashed", &self.crashed)
+            .field("operations", &self.operations)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, K, V> SimStorage<S, K, V>
+where
+    S: Storage<K, V>,
+    K: Clone,
+    V: Clone,
+{
+    pub fn new(inner: S, seed: u64, config: SimConfig) -> Self {
+        Self {
+            inner,
+            config,
+            rng: SimRng(seed),
+            buffered: BTreeMap::new(),
+            order: Vec::new(),
+            elapsed: Duration::ZERO,
+            crashed: false,
+            operations: 0,
+        }
+    }
+
+    /// Simulated time spent in the storage so far.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.crashed
+    }
+
+    /// Writes, flushes and truncates made so far, the last of them the one
+    /// that crashed if [`crashed`](Self::crashed).
+    pub fn operations(&self) -> usize {
+        self.operations
+    }
+
+    /// Crashes now, handing a random subset of the buffered writes to the
+    /// disk.
+    pub fn crash(&mut self) -> Result<(), BTreeError> {
+        self.crashed = true;
+        let pages = self.drain_order();
+        let kept = self.rng.below(pages.len() + 1);
+        for loc in &pages[..kept] {
+            self.inner.write_node(*loc, &self.buffered[loc])?;
+        }
+        self.buffered.clear();
+        Ok(())
+    }
+
+    /// The disk, holding whatever reached it.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// The buffered pages in the order they reach the disk, each once.
+    fn drain_order(&mut self) -> Vec<usize> {
+        let mut seen = BTreeSet::new();
+        let mut pages = Vec::with_capacity(self.buffered.len());
+        for loc in std::mem::take(&mut self.order) {
+            if self.buffered.contains_key(&loc) && seen.insert(loc) {
+                pages.push(loc);
+            }
+        }
+        if self.config.reorder {
+            for i in (1..pages.len()).rev() {
+                pages.swap(i, self.rng.below(i + 1));
+            }
+        }
+        pages
+    }
+
+    fn check_alive(&self) -> Result<(), BTreeError> {
+        if self.crashed {
+            return Err(BTreeError::Io(io::Error::other("simulated crash")));
+        }
+        Ok(())
+    }
+
+    /// Counts a mutating operation, crashing before it with the configured
+    /// chance.
+    fn step(&mut self, latency: Duration) -> Result<(), BTreeError> {
+        self.check_alive()?;
+        self.operations += 1;
+        self.wait(latency);
+        if self.rng.chance(self.config.crash_chance) {
+            self.crash()?;
+            self.check_alive()?;
+        }
+        Ok(())
+    }
+
+    fn wait(&mut self, latency: Duration) {
+        let jitter = self
+            .config
+            .jitter
+            .mul_f64(self.rng.below(1 << 20) as f64 / (1 << 20) as f64);
+        let latency = latency + jitter;
+        self.elapsed += latency;
+        if self.config.sleep && !latency.is_zero() {
+            thread::sleep(latency);
+        }
+    }
+
+    fn drain(&mut self) -> Result<(), BTreeError> {
+        for loc in self.drain_order() {
+            self.inner.write_node(loc, &self.buffered[&loc])?;
+        }
+        self.buffered.clear();
+        Ok(())
+    }
+}
+
+impl<S, K, V> Storage<K, V> for SimStorage<S, K, V>
+where
+    S: Storage<K, V>,
+    K: Clone,
+    V: Clone,
+{
+    fn read_node(&mut self, loc: usize) -> Result<Node<K, V>, BTreeError> {
+        self.check_alive()?;
+        self.wait(self.config.read_latency);
+        match self.buffered.get(&loc) {
+            Some(node) => Ok(node.clone()),
+            None => self.inner.read_node(loc),
+        }
+    }
+
+    fn read_keys(&mut self, loc: usize) -> Result<LeafKeys<K>, BTreeError> {
+        self.check_alive()?;
+        self.wait(self.config.read_latency);
+        match self.buffered.get(&loc) {
+            Some(node) => LeafKeys::of(loc, node.clone()),
+            None => self.inner.read_keys(loc),
+        }
+    }
+
+    fn write_node(&mut self, loc: usize, node: &Node<K, V>) -> Result<(), BTreeError> {
+        self.step(self.config.write_latency)?;
+        self.buffered.insert(loc, node.clone());
+        self.order.push(loc);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BTreeError> {
+        self.step(self.config.sync_latency)?;
+        self.drain()?;
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<(), BTreeError> {
+        self.step(self.config.sync_latency)?;
+        self.drain()?;
+        self.inner.sync()
+    }
+
+    /// Drains the buffer, then applies `pages` through the disk, crashing
+    /// either before or after them.
+    fn write_atomic(&mut self, pages: &[(usize, Node<K, V>)]) -> Result<(), BTreeError> {
+        self.step(self.config.sync_latency)?;
+        self.drain()?;
+        self.inner.write_atomic(pages)?;
+        self.step(self.config.sync_latency)
+    }
+
+    fn total_nodes(&self) -> Result<usize, BTreeError> {
+        let buffered = self.buffered.keys().next_back().map_or(0, |loc| loc + 1);
+        Ok(self.inner.total_nodes()?.max(buffered))
+    }
+
+    fn truncate(&mut self, pages: usize) -> Result<(), BTreeError> {
+        self.step(self.config.sync_latency)?;
+        self.drain()?;
+        self.inner.truncate(pages)
+    }
+
+    fn page_capacity(&self) -> usize {
+        self.inner.page_capacity()
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn codec(&self) -> &'static str {
+        self.inner.codec()
+    }
+
+    fn io_counters(&self) -> (usize, usize) {
+        self.inner.io_counters()
+    }
+
+    fn trailing_bytes(&self) -> Result<usize, BTreeError> {
+        self.inner.trailing_bytes()
+    }
+
+    fn file_path(&self) -> Option<&Path> {
+        self.inner.file_path()
+    }
+}
+
+/// SplitMix64: small, fast, and the same on every platform, so a seed
+/// replays identically anywhere.
+#[derive(Debug, Clone)]
+pub(crate) struct SimRng(pub(crate) u64);
+
+impl SimRng {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `n`, which must not be 0.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub(crate) fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next() >> 11) as f64) < p * (1u64 << 53) as f64
+    }
+}
+
+/// A seed a [`SimRunner`] found failing, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimFailure {
+    pub seed: u64,
+    pub message: String,
+}
+
+impl fmt::Display for SimFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "seed {} failed: {} (rerun it alone with {}={})",
+            self.seed, self.message, SEED_VAR, self.seed
+        )
+    }
+}
+
+impl std::error::Error for SimFailure {}
+
+/// Runs a simulation over many seeds, remembering the ones that fail in a
+/// file so they are replayed first on every later run until they pass.
+/// Setting [`SEED_VAR`] runs that seed alone.
+#[derive(Debug, Clone)]
+pub struct SimRunner {
+    seeds_file: PathBuf,
+}
+
+impl SimRunner {
+    /// A runner keeping failing seeds in `seeds_file`, one per line.
+    pub fn new(seeds_file: impl Into<PathBuf>) -> Self {
+        Self {
+            seeds_file: seeds_file.into(),
+        }
+    }
+
+    /// Seeds recorded as failing, oldest first.
+    pub fn recorded_seeds(&self) -> Result<Vec<u64>, io::Error> {
+        match fs::read_to_string(&self.seeds_file) {
+            Ok(text) => Ok(text
+                .lines()
+                .filter_map(|line| line.trim().parse().ok())
+                .collect()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Runs `simulate` on the recorded seeds and then on each of `seeds`,
+    /// stopping at the first that returns an error or panics. That seed is
+    /// recorded if it was not already. A recorded seed that now passes is
+    /// kept, as a regression check.
+    pub fn run<F>(
+        &self,
+        seeds: impl IntoIterator<Item = u64>,
+        mut simulate: F,
+    ) -> Result<(), SimFailure>
+    where
+        F: FnMut(u64) -> Result<(), String>,
+    {
+        let mut run_one = |seed: u64| -> Result<(), SimFailure> {
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| simulate(seed)));
+            let message = match outcome {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(message)) => message,
+                Err(panic) => panic
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "panicked".to_string()),
+            };
+            Err(SimFailure { seed, message })
+        };
+
+        if let Ok(seed) = std::env::var(SEED_VAR) {
+            let seed = seed.trim().parse().map_err(|_| SimFailure {
+                seed: 0,
+                message: format!("{} is not a seed: {:?}", SEED_VAR, seed),
+            })?;
+            return run_one(seed);
+        }
+
+        let recorded = self.recorded_seeds().unwrap_or_default();
+        for &seed in &recorded {
+            run_one(seed)?;
+        }
+        for seed in seeds {
+            if let Err(mut failure) = run_one(seed) {
+                if !recorded.contains(&seed)
+                    && let Err(err) = self.record(seed)
+                {
+                    failure.message += &format!(", and it cannot be recorded: {}", err);
+                }
+                return Err(failure);
+            }
+        }
+        Ok(())
+    }
+
+    fn record(&self, seed: u64) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.seeds_file)?;
+        writeln!(file, "{}", seed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::BPlusTree;
+    use crate::memory_storage::InMemoryStorage;
+
+    type Model = BTreeMap<i32, Record>;
+
+    /// Commits random transactions on a crashing storage until it crashes,
+    /// then checks the recovered tree holds the last commit, or the one in
+    /// flight, which a crash after its pages went out lets through.
+    fn crash_and_recover(seed: u64) -> Result<(), String> {
+        let config = SimConfig {
+            crash_chance: 0.02,
+            ..SimConfig::default()
+        };
+        let storage = SimStorage::new(InMemoryStorage::new(), seed, config);
+        let mut tree: BPlusTree<SimStorage<InMemoryStorage>> = match BPlusTree::open(storage) {
+            Ok(tree) => tree,
+            // Crashed while writing the first header, before any commit
+            Err(_) => return Ok(()),
+        };
+        let mut rng = SimRng(seed);
+        let mut committed = Model::new();
+        let mut staged = committed.clone();
+        while !tree.storage.crashed() {
+            staged = committed.clone();
+            let outcome = tree.begin().and_then(|mut txn| {
+                {
+                    let mut inner = txn.tree()?;
+                    for _ in 0..rng.below(20) + 1 {
+                        let key = rng.below(200) as i32;
+                        if rng.chance(0.3) {
+                            inner.delete(&key)?;
+                            staged.remove(&key);
+                        } else {
+                            inner.insert(key, [key; 7])?;
+                            staged.insert(key, [key; 7]);
+                        }
+                    }
+                }
+                txn.commit()
+            });
+            if outcome.is_ok() {
+                committed = staged.clone();
+            }
+        }
+
+        let mut recovered: BPlusTree<InMemoryStorage> =
+            BPlusTree::open(tree.storage.into_inner()).map_err(|err| err.to_string())?;
+        recovered.validate()?;
+        let entries: Model = recovered
+            .iter()
+            .collect::<Result<_, _>>()
+            .map_err(|err| err.to_string())?;
+        if entries != committed && entries != staged {
+            return Err(format!(
+                "recovered {} records, committed {} and in flight {}",
+                entries.len(),
+                committed.len(),
+                staged.len()
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn trees_recover_from_a_crash_under_every_seed() {
+        let seeds = std::env::temp_dir().join(format!("btree-sim-{}.seeds", std::process::id()));
+        let outcome = SimRunner::new(&seeds).run(0..200, crash_and_recover);
+        let _ = fs::remove_file(&seeds);
+        if let Err(failure) = outcome {
+            panic!("{}", failure);
+        }
+    }
+}
@@ -1,7 +1,390 @@
-use crate::node::Node;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+use std::path::Path;
 
-pub trait Storage {
-    fn read_node(&mut self, loc: usize) -> Option<Node>;
-    fn write_node(&mut self, loc: usize, node: &Node);
-    fn total_nodes(&self) -> usize;
+use crate::codec::BINCODE;
+use crate::config::PAGE_SIZE;
+use crate::error::BTreeError;
+use crate::node::{LeafKeys, Node};
+use crate::record::Record;
+
+pub trait Storage<K = i32, V = Record> {
+    /// Reads the node at `loc`, failing with [`BTreeError::Corrupt`] if the
+    /// page does not exist or does not decode.
+    fn read_node(&mut self, loc: usize) -> Result<Node<K, V>, BTreeError>;
+    /// Reads the nodes at every location in `locs`, in order. Storages that
+    /// can fetch consecutive pages in one request override this; the
+    /// default reads them one by one.
+    fn read_nodes(&mut self, locs: Range<usize>) -> Result<Vec<Node<K, V>>, BTreeError> {
+        locs.map(|loc| self.read_node(loc)).collect()
+    }
+    /// Reads the keys of the leaf at `loc`, for lookups that need no
+    /// values. Storages that can decode a leaf's keys alone override this;
+    /// the default reads the whole node.
+    fn read_keys(&mut self, loc: usize) -> Result<LeafKeys<K>, BTreeError> {
+        LeafKeys::of(loc, self.read_node(loc)?)
+    }
+    fn write_node(&mut self, loc: usize, node: &Node<K, V>) -> Result<(), BTreeError>;
+    /// Writes `nodes` to consecutive pages starting at `start`.
+    fn write_nodes(&mut self, start: usize, nodes: &[Node<K, V>]) -> Result<(), BTreeError> {
+        for (i, node) in nodes.iter().enumerate() {
+            self.write_node(start + i, node)?;
+        }
+        Ok(())
+    }
+    /// Makes every write so far durable. Storages that reorder writes must
+    /// put the header last.
+    fn flush(&mut self) -> Result<(), BTreeError> {
+        Ok(())
+    }
+    /// Like [`flush`](Self::flush), for storages whose flush may hold back
+    /// or skip syncs: makes every write so far durable regardless.
+    fn sync(&mut self) -> Result<(), BTreeError> {
+        self.flush()
+    }
+    /// Writes `pages` in order, header last, then flushes, such that a crash
+    /// leaves either all of them or none on the storage. The default writes
+    /// them one by one, which is only all-or-nothing for storages that
+    /// cannot crash partway.
+    fn write_atomic(&mut self, pages: &[(usize, Node<K, V>)]) -> Result<(), BTreeError> {
+        for (loc, node) in pages {
+            self.write_node(*loc, node)?;
+        }
+        self.flush()
+    }
+    /// Number of whole pages; a partial trailing page is not counted.
+    fn total_nodes(&self) -> Result<usize, BTreeError>;
+    /// Drops every page from `pages` on. Storages that cannot shrink keep
+    /// them, which is what the default does.
+    fn truncate(&mut self, pages: usize) -> Result<(), BTreeError> {
+        let _ = pages;
+        Ok(())
+    }
+    /// Bytes of each page available to an encoded node.
+    fn page_capacity(&self) -> usize {
+        PAGE_SIZE
+    }
+    /// Bytes each page takes up on the medium, for reporting sizes.
+    /// Storages whose pages vary in size report their average, and ones
+    /// that keep nodes as they are report 0.
+    fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+    /// Name of the [`PageCodec`](crate::codec::PageCodec) pages are encoded
+    /// with. Storages that keep nodes as they are, or encode them with
+    /// bincode themselves, report bincode.
+    fn codec(&self) -> &'static str {
+        BINCODE
+    }
+    /// Pages read from and written to the underlying medium so far, for
+    /// storages that count them.
+    fn io_counters(&self) -> (usize, usize) {
+        (0, 0)
+    }
+    /// Bytes past the last whole page, left behind by an interrupted write.
+    fn trailing_bytes(&self) -> Result<usize, BTreeError> {
+        Ok(0)
+    }
+    /// The file pages are kept in, for storages kept in one.
+    fn file_path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// A new, empty storage of the same kind at `path`, encoding pages as
+    /// this one does, for [`backup`](crate::BPlusTree::backup) to write a
+    /// copy into. Whatever `path` held is replaced, unless it is this
+    /// storage's own file, which fails with
+    /// [`BTreeError::BackupOverSource`]. Storages without a file of their
+    /// own fail with [`BTreeError::BackupUnsupported`], as the default does.
+    fn create_copy(&self, path: &str) -> Result<Self, BTreeError>
+    where
+        Self: Sized,
+    {
+        let _ = path;
+        Err(BTreeError::BackupUnsupported)
+    }
+}
+
+/// Storage that can also read through a shared reference, so several
+/// threads can read it at once.
+pub trait SharedStorage<K = i32, V = Record>: Storage<K, V> {
+    fn read_node_shared(&self, loc: usize) -> Result<Node<K, V>, BTreeError>;
+}
+
+/// What a [`FaultyStorage`] does with writes once its limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Writes fail with an I/O error, as when the disk fills up.
+    Fail,
+    /// Writes report success but never reach the storage, as if the machine
+    /// lost power at that point.
+    Truncate,
+}
+
+/// Wraps a storage to inject a fault after a set number of writes, for
+/// checking what a crash at any point leaves behind. Each page written
+/// counts as one write, including each page of a
+/// [`write_atomic`](Storage::write_atomic); once `limit` of them went
+/// through, the rest meet `fault`. An atomic write the fault falls within
+/// meets it whole, as its journal never completed. Reads always go
+/// through, so reopening the wrapped storage shows what survived.
+pub struct FaultyStorage<S> {
+    inner: S,
+    limit: usize,
+    fault: Fault,
+    writes: usize,
+}
+
+impl<S> FaultyStorage<S> {
+    pub fn new(inner: S, limit: usize, fault: Fault) -> Self {
+        Self {
+            inner,
+            limit,
+            fault,
+            writes: 0,
+        }
+    }
+
+    /// Writes attempted so far, including the ones that met the fault.
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+
+    /// Whether any write has met the fault yet.
+    pub fn tripped(&self) -> bool {
+        self.writes > self.limit
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Counts a write, returning whether it should reach the storage.
+    fn admit(&mut self) -> Result<bool, BTreeError> {
+        self.admit_pages(1)
+    }
+
+    /// Counts a write of `pages` pages, returning whether it should reach
+    /// the storage.
+    fn admit_pages(&mut self, pages: usize) -> Result<bool, BTreeError> {
+        self.writes += pages;
+        if !self.tripped() {
+            return Ok(true);
+        }
+        match self.fault {
+            Fault::Fail => Err(Self::injected()),
+            Fault::Truncate => Ok(false),
+        }
+    }
+
+    fn injected() -> BTreeError {
+        BTreeError::Io(io::Error::other("injected write fault"))
+    }
+}
+
+impl<S, K, V> Storage<K, V> for FaultyStorage<S>
+where
+    S: Storage<K, V>,
+{
+    fn read_node(&mut self, loc: usize) -> Result<Node<K, V>, BTreeError> {
+        self.inner.read_node(loc)
+    }
+
+    fn read_nodes(&mut self, locs: Range<usize>) -> Result<Vec<Node<K, V>>, BTreeError> {
+        self.inner.read_nodes(locs)
+    }
+
+    fn read_keys(&mut self, loc: usize) -> Result<LeafKeys<K>, BTreeError> {
+        self.inner.read_keys(loc)
+    }
+
+    fn write_node(&mut self, loc: usize, node: &Node<K, V>) -> Result<(), BTreeError> {
+        if self.admit()? {
+            self.inner.write_node(loc, node)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BTreeError> {
+        if self.tripped() {
+            return match self.fault {
+                Fault::Fail => Err(Self::injected()),
+                Fault::Truncate => Ok(()),
+            };
+        }
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<(), BTreeError> {
+        if self.tripped() {
+            return match self.fault {
+                Fault::Fail => Err(Self::injected()),
+                Fault::Truncate => Ok(()),
+            };
+        }
+        self.inner.sync()
+    }
+
+    fn write_atomic(&mut self, pages: &[(usize, Node<K, V>)]) -> Result<(), BTreeError> {
+        if self.admit_pages(pages.len())? {
+            self.inner.write_atomic(pages)?;
+        }
+        Ok(())
+    }
+
+    fn total_nodes(&self) -> Result<usize, BTreeError> {
+        self.inner.total_nodes()
+    }
+
+    fn truncate(&mut self, pages: usize) -> Result<(), BTreeError> {
+        if self.admit()? {
+            self.inner.truncate(pages)?;
+        }
+        Ok(())
+    }
+
+    fn page_capacity(&self) -> usize {
+        self.inner.page_capacity()
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn codec(&self) -> &'static str {
+        self.inner.codec()
+    }
+
+    fn io_counters(&self) -> (usize, usize) {
+        self.inner.io_counters()
+    }
+
+    fn trailing_bytes(&self) -> Result<usize, BTreeError> {
+        self.inner.trailing_bytes()
+    }
+
+    fn file_path(&self) -> Option<&Path> {
+        self.inner.file_path()
+    }
+}
+
+/// Whether `a` and `b` name the same existing file.
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Clears `path` for a copy of the storage kept in `own` to be created
+/// there, refusing if both name the same file.
+pub(crate) fn clear_for_copy(own: &Path, path: &str) -> Result<(), BTreeError> {
+    if same_file(own, Path::new(path)) {
+        return Err(BTreeError::BackupOverSource {
+            path: path.to_owned(),
+        });
+    }
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::btree::BPlusTree;
+    use crate::cached_storage::CachedStorage;
+    use crate::memory_storage::InMemoryStorage;
+
+    #[test]
+    fn an_insert_meeting_a_failed_write_returns_the_error() {
+        let base: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let base = base.storage;
+        for limit in 0..20 {
+            let storage = FaultyStorage::new(base.clone(), limit, Fault::Fail);
+            let mut tree: BPlusTree<FaultyStorage<InMemoryStorage>> =
+                BPlusTree::open(storage).unwrap();
+            let failed = (0..100).find_map(|key| tree.insert(key, [key; 7]).err());
+            match failed {
+                Some(BTreeError::Io(err)) => assert_eq!(err.to_string(), "injected write fault"),
+                other => panic!("write {} failed with {:?}", limit, other),
+            }
+            // The failing write is the last one tried
+            assert_eq!(tree.storage.writes(), limit + 1);
+        }
+    }
+
+    type Model = BTreeMap<i32, Record>;
+    type Pooled = BPlusTree<CachedStorage<FaultyStorage<InMemoryStorage>>>;
+
+    /// Inserts and deletes one key at a time, splitting and merging nodes,
+    /// and notes the contents after each along with the writes made by then.
+    fn churn(
+        tree: &mut Pooled,
+        model: &mut Model,
+        done: &mut Vec<(usize, Model)>,
+    ) -> Result<(), BTreeError> {
+        for i in 0..60 {
+            let key = (i * 37) % 90;
+            if i % 4 == 3 {
+                tree.delete(&key)?;
+                model.remove(&key);
+            } else {
+                tree.insert(key, [key, i, 0, 0, 0, 0, 0])?;
+                model.insert(key, [key, i, 0, 0, 0, 0, 0]);
+            }
+            done.push((tree.storage.inner().writes(), model.clone()));
+        }
+        Ok(())
+    }
+
+    /// Like FileStorage, the pool holds pages back until the flush each
+    /// insert and delete ends with, then writes them all atomically.
+    #[test]
+    fn a_crash_at_any_write_of_a_plain_insert_or_delete_leaves_the_last_one() {
+        let mut base: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let mut model = Model::new();
+        for key in (0..90).step_by(3) {
+            base.insert(key, [key; 7]).unwrap();
+            model.insert(key, [key; 7]);
+        }
+        let base = base.storage;
+        let open = |limit, fault| -> Pooled {
+            let storage = FaultyStorage::new(base.clone(), limit, fault);
+            BPlusTree::open(CachedStorage::new(storage, 256)).unwrap()
+        };
+
+        let mut tree = open(usize::MAX, Fault::Fail);
+        let mut done = vec![(0, model.clone())];
+        churn(&mut tree, &mut model.clone(), &mut done).unwrap();
+        let total = tree.storage.inner().writes();
+
+        for fault in [Fault::Fail, Fault::Truncate] {
+            for limit in 0..total {
+                let mut tree = open(limit, fault);
+                let _ = churn(&mut tree, &mut model.clone(), &mut Vec::new());
+                assert!(tree.storage.inner().tripped());
+
+                // The crash loses whatever the pool still held
+                let crashed = FaultyStorage::new(InMemoryStorage::new(), 0, fault);
+                let storage = std::mem::replace(tree.storage.inner_mut(), crashed);
+                let mut reopened: BPlusTree<InMemoryStorage> =
+                    BPlusTree::open(storage.into_inner()).unwrap();
+                if let Err(err) = reopened.validate() {
+                    panic!("{:?} at write {}: {}", fault, limit, err);
+                }
+                let (_, expected) = done
+                    .iter()
+                    .rev()
+                    .find(|(writes, _)| *writes <= limit)
+                    .unwrap();
+                let entries: Model = reopened.iter().collect::<Result<_, _>>().unwrap();
+                assert_eq!(&entries, expected, "{:?} at write {}", fault, limit);
+            }
+        }
+    }
 }
@@ -0,0 +1,256 @@
+use std::io::{self, ErrorKind, SeekFrom};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::async_storage::AsyncStorage;
+use crate::codec::{SLOTTED, Slotted};
+use crate::config::PAGE_SIZE;
+use crate::error::BTreeError;
+use crate::file_storage::{FileStorage, MAX_READ_RETRIES, PAYLOAD_SIZE, STAMP_SIZE};
+use crate::key::Key;
+use crate::node::Node;
+use crate::record::Record;
+use crate::value::Value;
+
+/// [`AsyncStorage`] over a tokio file, in the same page format as
+/// [`FileStorage`], so either can open a file written by the other.
+#[derive(Debug)]
+pub struct TokioFileStorage<K = i32, V = Record> {
+    file: File,
+    /// Pages read from disk.
+    pub page_reads: usize,
+    pub page_writes: usize,
+    /// Header write held back until [`flush`](AsyncStorage::flush), so it
+    /// only reaches the disk after the pages it points to.
+    pending_header: Option<(usize, Node<K, V>)>,
+    marker: PhantomData<(K, V)>,
+}
+
+impl<K: Key, V: Value> TokioFileStorage<K, V> {
+    /// Opens or creates `path`. A journal left by an interrupted
+    /// [`FileStorage`] commit is refused rather than replayed; open the file
+    /// with [`FileStorage::new`] once to replay it.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self, BTreeError> {
+        let path = path.as_ref();
+        let mut journal = path.as_os_str().to_owned();
+        journal.push(".journal");
+        if tokio::fs::try_exists(&journal).await? {
+            return Err(BTreeError::Io(io::Error::other(
+                "an interrupted commit must be replayed by opening the file with FileStorage",
+            )));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await?;
+        Ok(Self {
+            file,
+            page_reads: 0,
+            page_writes: 0,
+            pending_header: None,
+            marker: PhantomData,
+        })
+    }
+
+    /// Reads the page at `loc`, retrying while a writer has it half written.
+    async fn read_block(&mut self, loc: usize) -> Result<[u8; PAGE_SIZE], BTreeError> {
+        let offset = (loc * PAGE_SIZE) as u64;
+        let mut block = [0u8; PAGE_SIZE];
+
+        for _ in 0..MAX_READ_RETRIES {
+            self.file.seek(SeekFrom::Start(offset)).await?;
+            if let Err(err) = self.file.read_exact(&mut block).await {
+                return Err(match err.kind() {
+                    ErrorKind::UnexpectedEof => BTreeError::Corrupt {
+                        loc,
+                        reason: "page is past the end of the file",
+                    },
+                    _ => err.into(),
+                });
+            }
+
+            let before = FileStorage::<K, V>::stamp_of(&block);
+            if before % 2 == 1 || self.read_stamp(offset).await? != before {
+                tokio::task::yield_now().await;
+                continue;
+            }
+            return Ok(block);
+        }
+        Err(BTreeError::Io(io::Error::other(format!(
+            "page {} kept changing while being read",
+            loc
+        ))))
+    }
+
+    /// Writes an encoded page under an odd stamp, then makes the stamp even.
+    async fn write_page(&mut self, loc: usize, input: &Node<K, V>) -> Result<(), BTreeError> {
+        let mut block = FileStorage::<K, V>::serialize_node(&Slotted, input)?;
+        let offset = (loc * PAGE_SIZE) as u64;
+        // Pages past the end of the file start from version 0.
+        let version = (self.read_stamp(offset).await.unwrap_or(0) + 1) | 1;
+        block[..STAMP_SIZE].copy_from_slice(&version.to_le_bytes());
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        self.file.write_all(&block).await?;
+
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        self.file.write_all(&(version + 1).to_le_bytes()).await?;
+        // Surfaces an error from the write tokio finished in the background.
+        self.file.flush().await?;
+
+        self.page_writes += 1;
+        Ok(())
+    }
+
+    async fn read_stamp(&mut self, offset: u64) -> io::Result<u64> {
+        let mut stamp = [0u8; STAMP_SIZE];
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        self.file.read_exact(&mut stamp).await?;
+        Ok(u64::from_le_bytes(stamp))
+    }
+}
+
+impl<K, V> AsyncStorage<K, V> for TokioFileStorage<K, V>
+where
+    K: Key + Send + Sync,
+    V: Value + Send + Sync,
+{
+    async fn read_node(&mut self, loc: usize) -> Result<Node<K, V>, BTreeError> {
+        if let Some((pending, header)) = &self.pending_header
+            && *pending == loc
+        {
+            return Ok(header.clone());
+        }
+        let block = self.read_block(loc).await?;
+        self.page_reads += 1;
+        FileStorage::<K, V>::deserialize_node(&Slotted, block)
+            .map_err(|reason| BTreeError::Corrupt { loc, reason })
+    }
+
+    /// Writes nodes straight through, except headers, which wait for the
+    /// next [`flush`](AsyncStorage::flush).
+    async fn write_node(&mut self, loc: usize, input: &Node<K, V>) -> Result<(), BTreeError> {
+        if let Node::Header(_) = input {
+            // Serialize now so an oversized header fails here, not in flush
+            FileStorage::<K, V>::serialize_node(&Slotted, input)?;
+            self.pending_header = Some((loc, input.clone()));
+            return Ok(());
+        }
+        self.write_page(loc, input).await
+    }
+
+    /// Syncs the pages written so far, then writes and syncs the pending
+    /// header, so a crash never leaves a header pointing at missing pages.
+    async fn flush(&mut self) -> Result<(), BTreeError> {
+        if let Some((loc, header)) = self.pending_header.take() {
+            self.file.sync_data().await?;
+            if let Err(err) = self.write_page(loc, &header).await {
+                self.pending_header = Some((loc, header));
+                return Err(err);
+            }
+        }
+        self.file.sync_all().await?;
+        Ok(())
+    }
+
+    async fn total_nodes(&self) -> Result<usize, BTreeError> {
+        Ok(self.file.metadata().await?.len() as usize / PAGE_SIZE)
+    }
+
+    async fn truncate(&mut self, pages: usize) -> Result<(), BTreeError> {
+        self.flush().await?;
+        self.file.set_len((pages * PAGE_SIZE) as u64).await?;
+        self.file.sync_all().await?;
+        Ok(())
+    }
+
+    async fn trailing_bytes(&self) -> Result<usize, BTreeError> {
+        Ok(self.file.metadata().await?.len() as usize % PAGE_SIZE)
+    }
+
+    fn codec(&self) -> &'static str {
+        SLOTTED
+    }
+
+    fn page_capacity(&self) -> usize {
+        PAYLOAD_SIZE
+    }
+
+    fn io_counters(&self) -> (usize, usize) {
+        (self.page_reads, self.page_writes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_tree::AsyncBPlusTree;
+    use crate::btree::BPlusTree;
+    use tokio::runtime::{Builder, Runtime};
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("btree-{}-{}.db", name, std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().enable_all().build().unwrap()
+    }
+
+    #[test]
+    fn a_file_written_async_opens_with_file_storage_and_back() {
+        let path = temp_path("tokio-shared");
+        runtime().block_on(async {
+            let tree: AsyncBPlusTree<TokioFileStorage> =
+                AsyncBPlusTree::open(TokioFileStorage::open(&path).await.unwrap())
+                    .await
+                    .unwrap();
+            for key in 0..500 {
+                tree.insert(key, [key; 7]).await.unwrap();
+            }
+            assert_eq!(tree.delete(7).await.unwrap(), Some([7; 7]));
+            tree.flush().await.unwrap();
+        });
+
+        let mut tree: BPlusTree<FileStorage> =
+            BPlusTree::open(FileStorage::new(&path).unwrap()).unwrap();
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 499);
+        assert_eq!(tree.get(&7).unwrap(), None);
+        tree.insert(1000, [1; 7]).unwrap();
+        drop(tree);
+
+        runtime().block_on(async {
+            let tree: AsyncBPlusTree<TokioFileStorage> =
+                AsyncBPlusTree::open(TokioFileStorage::open(&path).await.unwrap())
+                    .await
+                    .unwrap();
+            assert_eq!(tree.validate().await.unwrap(), Ok(()));
+            assert_eq!(tree.len().await.unwrap(), 500);
+            assert_eq!(tree.get(1000).await.unwrap(), Some([1; 7]));
+            assert_eq!(
+                tree.range(10, 12).await.unwrap(),
+                vec![(10, [10; 7]), (11, [11; 7]), (12, [12; 7])]
+            );
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_file_with_an_interrupted_commit_is_refused() {
+        let path = temp_path("tokio-journal");
+        std::fs::write(format!("{}.journal", path), b"partial").unwrap();
+        let opened = runtime().block_on(TokioFileStorage::<i32, Record>::open(&path));
+        assert!(matches!(opened, Err(BTreeError::Io(_))));
+        std::fs::remove_file(format!("{}.journal", path)).unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
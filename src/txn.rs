@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::allocator::{Allocator, FreeListAllocator};
+use crate::btree::BPlusTree;
+use crate::error::BTreeError;
+use crate::key::Key;
+use crate::node::{LeafKeys, Node};
+use crate::record::Record;
+use crate::storage::Storage;
+use crate::value::Value;
+
+/// The tree inside a transaction, as returned by [`Txn::tree`].
+pub type TxnTree<'a, S, K = i32, V = Record, A = FreeListAllocator> =
+    BPlusTree<TxnStorage<'a, S, K, V>, K, V, A>;
+
+/// A set of changes to a tree that is applied all at once or not at all.
+///
+/// Pages written inside the transaction are kept in memory, on top of the
+/// tree's storage, until [`commit`](Txn::commit) hands them to
+/// [`Storage::write_atomic`]. Dropping the transaction, or calling
+/// [`rollback`](Txn::rollback), throws them away and leaves the tree as it
+/// was before [`begin`](BPlusTree::begin).
+pub struct Txn<'a, S, K = i32, V = Record, A = FreeListAllocator> {
+    tree: &'a mut BPlusTree<S, K, V, A>,
+    pages: BTreeMap<usize, Node<K, V>>,
+}
+
+impl<'a, S, K, V, A> Txn<'a, S, K, V, A>
+where
+    S: Storage<K, V>,
+    K: Key,
+    V: Value,
+    A: Allocator,
+{
+    pub(crate) fn new(tree: &'a mut BPlusTree<S, K, V, A>) -> Self {
+        Self {
+            tree,
+            pages: BTreeMap::new(),
+        }
+    }
+
+    /// The tree as seen from inside the transaction, on the same bucket as
+    /// the tree it was begun on. Inserts and deletes on it land in the
+    /// transaction rather than in the storage.
+    pub fn tree(&mut self) -> Result<TxnTree<'_, S, K, V, A>, BTreeError> {
+        let max_height = self.tree.max_height();
+        let bucket = self.tree.bucket().map(str::to_owned);
+        let mut tree = BPlusTree::open(self.storage())?;
+        tree.set_max_height(max_height);
+        tree.use_bucket(bucket.as_deref())?;
+        Ok(tree)
+    }
+
+    /// The storage as seen from inside the transaction, for opening other
+    /// structures kept in the same storage.
+    pub fn storage(&mut self) -> TxnStorage<'_, S, K, V> {
+        TxnStorage {
+            inner: &mut self.tree.storage,
+            pages: &mut self.pages,
+        }
+    }
+
+    /// Number of pages written so far.
+    pub fn dirty_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Writes every page of the transaction atomically, header last, and
+    /// brings the tree up to date with them.
+    pub fn commit(mut self) -> Result<(), BTreeError> {
+        if self.pages.is_empty() {
+            return Ok(());
+        }
+        let header = self.pages.remove(&0);
+        let mut pages: Vec<_> = std::mem::take(&mut self.pages).into_iter().collect();
+        pages.extend(header.map(|header| (0, header)));
+
+        self.tree.storage.write_atomic(&pages)?;
+        self.tree.reload()
+    }
+
+    /// Discards every write made in the transaction.
+    pub fn rollback(self) {}
+}
+
+/// Pages written inside a [`Txn`], read back in preference to the ones in
+/// the underlying storage.
+pub struct TxnStorage<'a, S, K = i32, V = Record> {
+    inner: &'a mut S,
+    pages: &'a mut BTreeMap<usize, Node<K, V>>,
+}
+
+impl<S, K, V> Storage<K, V> for TxnStorage<'_, S, K, V>
+where
+    S: Storage<K, V>,
+    K: Clone,
+    V: Clone,
+{
+    fn read_node(&mut self, loc: usize) -> Result<Node<K, V>, BTreeError> {
+        match self.pages.get(&loc) {
+            Some(node) => Ok(node.clone()),
+            None => self.inner.read_node(loc),
+        }
+    }
+
+    fn read_keys(&mut self, loc: usize) -> Result<LeafKeys<K>, BTreeError> {
+        match self.pages.get(&loc) {
+            Some(node) => LeafKeys::of(loc, node.clone()),
+            None => self.inner.read_keys(loc),
+        }
+    }
+
+    fn write_node(&mut self, loc: usize, node: &Node<K, V>) -> Result<(), BTreeError> {
+        self.pages.insert(loc, node.clone());
+        Ok(())
+    }
+
+    /// Nothing reaches the underlying storage before the commit.
+    fn flush(&mut self) -> Result<(), BTreeError> {
+        Ok(())
+    }
+
+    fn total_nodes(&self) -> Result<usize, BTreeError> {
+        let written = self.pages.last_key_value().map_or(0, |(loc, _)| loc + 1);
+        Ok(self.inner.total_nodes()?.max(written))
+    }
+
+    fn page_capacity(&self) -> usize {
+        self.inner.page_capacity()
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn codec(&self) -> &'static str {
+        self.inner.codec()
+    }
+
+    fn io_counters(&self) -> (usize, usize) {
+        self.inner.io_counters()
+    }
+
+    fn trailing_bytes(&self) -> Result<usize, BTreeError> {
+        self.inner.trailing_bytes()
+    }
+
+    fn file_path(&self) -> Option<&Path> {
+        self.inner.file_path()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::memory_storage::InMemoryStorage;
+    use crate::storage::{Fault, FaultyStorage};
+
+    type Faulty = BPlusTree<FaultyStorage<InMemoryStorage>>;
+    type Model = BTreeMap<i32, Record>;
+
+    /// Commits a run of transactions, compacting partway, and notes the
+    /// contents after each commit along with the writes made by then.
+    fn transact(
+        tree: &mut Faulty,
+        model: &mut Model,
+        committed: &mut Vec<(usize, Model)>,
+    ) -> Result<(), BTreeError> {
+        for round in 0..8 {
+            let mut txn = tree.begin()?;
+            let mut staged = model.clone();
+            {
+                let mut inner = txn.tree()?;
+                for i in 0..10 {
+                    let key = (round * 7 + i * 13) % 60;
+                    if (round + i) % 3 == 0 {
+                        inner.delete(&key)?;
+                        staged.remove(&key);
+                    } else {
+                        inner.insert(key, [key, round, 0, 0, 0, 0, 0])?;
+                        staged.insert(key, [key, round, 0, 0, 0, 0, 0]);
+                    }
+                }
+            }
+            txn.commit()?;
+            *model = staged;
+            committed.push((tree.storage.writes(), model.clone()));
+            if round == 4 {
+                tree.compact()?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn a_crash_at_any_write_leaves_the_last_commit() {
+        let mut base: BPlusTree<InMemoryStorage> = BPlusTree::open(InMemoryStorage::new()).unwrap();
+        let mut model = Model::new();
+        for key in (0..60).step_by(2) {
+            base.insert(key, [key; 7]).unwrap();
+            model.insert(key, [key; 7]);
+        }
+        let base = base.storage;
+
+        // A run without faults counts the writes there are to crash at
+        let mut tree: Faulty =
+            BPlusTree::open(FaultyStorage::new(base.clone(), usize::MAX, Fault::Fail)).unwrap();
+        let mut committed = vec![(0, model.clone())];
+        transact(&mut tree, &mut model.clone(), &mut committed).unwrap();
+        let total = tree.storage.writes();
+
+        for fault in [Fault::Fail, Fault::Truncate] {
+            for limit in 0..total {
+                let storage = FaultyStorage::new(base.clone(), limit, fault);
+                let mut tree: Faulty = BPlusTree::open(storage).unwrap();
+                // Past the fault the tree may fail however it likes
+                let _ = transact(&mut tree, &mut model.clone(), &mut Vec::new());
+                assert!(tree.storage.tripped());
+
+                let mut reopened: BPlusTree<InMemoryStorage> =
+                    BPlusTree::open(tree.storage.into_inner()).unwrap();
+                if let Err(err) = reopened.validate() {
+                    panic!("{:?} at write {}: {}", fault, limit, err);
+                }
+                let (_, expected) = committed
+                    .iter()
+                    .rev()
+                    .find(|(writes, _)| *writes <= limit)
+                    .unwrap();
+                let entries: Model = reopened.iter().collect::<Result<_, _>>().unwrap();
+                assert_eq!(&entries, expected, "{:?} at write {}", fault, limit);
+            }
+        }
+    }
+}
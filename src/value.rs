@@ -0,0 +1,11 @@
+use std::fmt::Debug;
+
+use bincode::{Decode, Encode};
+
+use crate::record::SerializedSize;
+
+/// Types a tree can store against its keys. Values are stored in leaves
+/// with bincode, and their [`SerializedSize`] bounds how many fit a page.
+pub trait Value: Clone + PartialEq + Debug + Encode + Decode<()> + SerializedSize {}
+
+impl<T> Value for T where T: Clone + PartialEq + Debug + Encode + Decode<()> + SerializedSize {}